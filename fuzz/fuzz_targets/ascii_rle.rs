@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtrix::video::ascii_frame::{compress_ascii_image, decompress_ascii_image};
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(frame) = decompress_ascii_image(data) else {
+    return;
+  };
+
+  // only ASCII frames are compressed, other characters don't fit a byte
+  if frame.is_ascii() {
+    assert_eq!(decompress_ascii_image(&compress_ascii_image(&frame)).unwrap(), frame);
+  }
+});
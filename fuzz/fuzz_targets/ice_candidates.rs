@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtrix::ice::CandidateSet;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(candidates) = CandidateSet::from_bytes(data) else {
+    return;
+  };
+
+  // the encoding has no slack, a parsed set serializes to the same bytes
+  assert_eq!(&candidates.to_bytes()[..], data);
+});
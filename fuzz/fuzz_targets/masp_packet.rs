@@ -0,0 +1,63 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtrix::masp::fec::Parity;
+use mtrix::masp::message::{MaspPacket, PacketType};
+use mtrix::masp::payload::{Ack, HandshakeAck, HandshakeFinalAck, HandshakeReject, HandshakeRequest, RecoveryReport, RetransmissionRequest, Timestamp};
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(packet) = MaspPacket::deserialize(data) else {
+    return;
+  };
+
+  // parsing is strict, so an accepted datagram has exactly one encoding
+  assert_eq!(packet.serialize(), data);
+
+  let payload = &packet.payload;
+
+  match packet.packet_type {
+    PacketType::HandshakeRequest => {
+      if let Ok(request) = HandshakeRequest::deserialize(payload) {
+        assert_eq!(&request.serialize(), payload);
+      }
+    },
+    PacketType::HandshakeAck => {
+      if let Ok(ack) = HandshakeAck::deserialize(payload) {
+        assert_eq!(&ack.serialize(), payload);
+      }
+    },
+    PacketType::HandshakeFinalAck => {
+      let _ = HandshakeFinalAck::deserialize(payload);
+    },
+    PacketType::HandshakeReject => {
+      if let Ok(reject) = HandshakeReject::deserialize(payload) {
+        assert_eq!(&reject.serialize(), payload);
+      }
+    },
+    PacketType::Ack => {
+      if let Ok(ack) = Ack::deserialize(payload) {
+        assert_eq!(&ack.serialize(), payload);
+      }
+    },
+    PacketType::RetransmissionRequest => {
+      if let Ok(request) = RetransmissionRequest::deserialize(payload) {
+        assert_eq!(&request.serialize(), payload);
+      }
+    },
+    PacketType::RecoveryReport => {
+      if let Ok(report) = RecoveryReport::deserialize(payload) {
+        assert_eq!(&report.serialize(), payload);
+      }
+    },
+    PacketType::Ping | PacketType::Pong => {
+      let _ = Timestamp::deserialize(payload);
+    },
+    PacketType::VideoParity => {
+      if let Ok(parity) = Parity::deserialize(payload) {
+        assert_eq!(&parity.serialize(), payload);
+      }
+    },
+    // data is opaque to MASP, punches and byes carry nothing
+    _ => {}
+  }
+});
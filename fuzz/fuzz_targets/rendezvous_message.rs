@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtrix::rendezvous::RendezvousMessage;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(message) = RendezvousMessage::from_bytes(data) else {
+    return;
+  };
+
+  // the encoding has no slack, a parsed message serializes to the same bytes
+  assert_eq!(&message.to_bytes()[..], data);
+});
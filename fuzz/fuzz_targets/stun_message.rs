@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtrix::stun::StunMessage;
+
+fuzz_target!(|data: &[u8]| {
+  let Ok(message) = StunMessage::from_bytes(data) else {
+    return;
+  };
+
+  // padding bytes may differ, but whatever was parsed must survive a roundtrip
+  assert_eq!(StunMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+});
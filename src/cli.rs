@@ -1,8 +1,16 @@
 use crate::commands;
+use crate::commands::candidates::{CandidateOptions, RelayOptions};
+use crate::identity::{self, Identity};
+use crate::masp::config::{MaspConfig, DEFAULT_MAX_DATAGRAM_SIZE, DEFAULT_PEER_TIMEOUT_SECONDS};
+use crate::masp::crypto::Passphrase;
+use crate::stun::{Credentials, DEFAULT_STUN_SERVER};
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
-use clap::{Parser, Subcommand};
+use std::time::Duration;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -15,11 +23,69 @@ pub struct Cli {
   #[arg(short, long, default_value = "v4", value_parser = Versions::from_str)]
   pub ipv: Versions,
 
+  /// Largest MASP datagram to send, bigger frames are fragmented
+  #[arg(
+    long,
+    default_value_t = DEFAULT_MAX_DATAGRAM_SIZE,
+    value_parser = clap::value_parser!(u16).range(64..=65507)
+  )]
+  pub max_datagram_size: u16,
+
+  /// Seconds of silence after which the remote peer is considered gone
+  #[arg(
+    long,
+    default_value_t = DEFAULT_PEER_TIMEOUT_SECONDS,
+    value_parser = clap::value_parser!(u16).range(1..)
+  )]
+  pub peer_timeout: u16,
+
+  /// Don't protect video with parity packets, lost fragments are only resent
+  #[arg(long)]
+  pub no_fec: bool,
+
+  /// Directory holding the identity key and known peers, defaults to the platform config directory
+  #[arg(long)]
+  pub config_dir: Option<PathBuf>,
+
+  /// Relay `join` and `connect` offer as a candidate for when the peer can't be reached directly (format: host:port).
+  /// `jackin` and `jackwait` only ever use the address they are given and refuse it
+  #[arg(long, requires = "relay_token")]
+  pub relay: Option<String>,
+
+  /// Token the relay expects from its clients
+  #[arg(long)]
+  pub relay_token: Option<String>,
+
+  /// STUN server `whoami`, `join` and `connect` learn the public address from (format: host:port)
+  #[arg(long, default_value = DEFAULT_STUN_SERVER)]
+  pub stun_server: String,
+
+  /// Short-term credentials the STUN server requires, or `stun-server` requires from clients (format: username:password)
+  #[arg(long)]
+  pub stun_credentials: Option<Credentials>,
+
   /// Subcommands
   #[command(subcommand)]
   pub command: Commands,
 }
 
+impl Cli {
+  /// Rejects global options the chosen subcommand has no use for, rather than ignoring them.
+  pub fn check(&self) -> Result<(), clap::Error> {
+    let relayed = self.relay.is_some() || self.relay_token.is_some();
+
+    // without a candidate exchange the peer never learns a relayed address to answer at
+    if relayed && matches!(self.command, Commands::Jackin { .. } | Commands::Jackwait { .. }) {
+      return Err(Cli::command().error(
+        ErrorKind::ArgumentConflict,
+        "--relay and --relay-token only apply to join and connect, jackin and jackwait talk to the given address directly"
+      ));
+    }
+
+    Ok(())
+  }
+}
+
 #[derive(Clone, Debug, Copy)]
 pub enum Versions {
   V4,
@@ -40,7 +106,7 @@ impl FromStr for Versions {
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Outputs your IP address and port to connect to
+    /// Outputs your IP address and port to connect to, and whether your NAT lets peers through
     Whoami,
 
     /// Connects to a remote peer and starts video and audio chat
@@ -48,6 +114,10 @@ pub enum Commands {
         /// The IP address and port to connect to (format: ip:port)
         #[arg(value_parser = parse_socket_addr)]
         address: std::net::SocketAddr,
+
+        /// Passphrase shared with the remote peer, both sides must use the same one
+        #[arg(long)]
+        secret: Option<String>,
     },
 
     /// Makes the client go online and wait for incoming connections
@@ -55,7 +125,53 @@ pub enum Commands {
       /// The IP address and port to connect to (format: ip:port)
       #[arg(value_parser = parse_socket_addr)]
       address: std::net::SocketAddr,
+
+      /// Passphrase the connecting peer must prove to know
+      #[arg(long)]
+      secret: Option<String>,
   },
+
+    /// Meets a peer in a room of a rendezvous server and starts video and audio chat
+    Join {
+        /// Room code both peers agreed on, letters, digits and dashes
+        room: String,
+
+        /// Rendezvous server to meet at (format: host:port)
+        #[arg(long)]
+        server: String,
+
+        /// Passphrase shared with the remote peer, both sides must use the same one
+        #[arg(long)]
+        secret: Option<String>,
+    },
+
+    /// Connects to a peer by exchanging candidate tokens, e.g. over chat, and starts video and audio chat
+    Connect {
+        /// Passphrase shared with the remote peer, both sides must use the same one
+        #[arg(long)]
+        secret: Option<String>,
+    },
+
+    /// Runs a rendezvous server on --port where peers meet by room code
+    Rendezvous,
+
+    /// Runs a relay on --port for peers that can't reach each other directly
+    Relay {
+        /// Token clients must present to get a relayed address
+        #[arg(long)]
+        token: String,
+    },
+
+    /// Runs a STUN server on --port answering with the address requests come from
+    StunServer {
+        /// IP to serve on, all interfaces by default
+        #[arg(long)]
+        ip: Option<std::net::IpAddr>,
+
+        /// Second IP and port to also answer from, for RFC 5780 NAT behaviour discovery (format: ip:port)
+        #[arg(long, requires = "ip", value_parser = parse_socket_addr)]
+        other: Option<std::net::SocketAddr>,
+    },
 }
 
 /// Custom parser for SocketAddr to provide better error messages
@@ -70,9 +186,13 @@ pub struct CommandHandler {
 
 impl CommandHandler {
   pub fn new () -> Self {
-    Self {
-      cli: Cli::parse()
+    let cli = Cli::parse();
+
+    if let Err(e) = cli.check() {
+      e.exit();
     }
+
+    Self { cli }
   }
   
   /// Depending on the subcommand, perform the action
@@ -81,20 +201,82 @@ impl CommandHandler {
       Commands::Whoami => {
         Self::handle_whoami(&self).await;
       }
-      Commands::Jackin { address } => {
-        let _ = Self::handle_jackin(&self, *address).await;
+      Commands::Jackin { address, secret } => {
+        let _ = Self::handle_jackin(&self, *address, secret.as_deref()).await;
+      }
+      Commands::Jackwait { address, secret } => {
+        let _ = Self::handle_jackwait(&self, *address, secret.as_deref()).await;
+      }
+      Commands::Join { room, server, secret } => {
+        Self::handle_join(&self, server, room, secret.as_deref()).await;
+      }
+      Commands::Connect { secret } => {
+        Self::handle_connect(&self, secret.as_deref()).await;
       }
-      Commands::Jackwait { address } => {
-        let _ = Self::handle_jackwait(&self, *address).await;
+      Commands::Rendezvous => {
+        Self::handle_rendezvous(&self).await;
       }
+      Commands::Relay { token } => {
+        Self::handle_relay(&self, token).await;
+      }
+      Commands::StunServer { ip, other } => {
+        Self::handle_stun_server(&self, *ip, *other).await;
+      }
+    }
+  }
+
+  /// Builds the MASP session settings from the global options.
+  fn masp_config(&self) -> MaspConfig {
+    MaspConfig {
+      max_datagram_size: self.cli.max_datagram_size,
+      peer_timeout: Duration::from_secs(self.cli.peer_timeout as u64),
+      forward_error_correction: !self.cli.no_fec
+    }
+  }
+
+  /// Address to bind the session socket to, on all interfaces of the chosen IP version.
+  fn local_addr(&self) -> SocketAddr {
+    let ip = match self.cli.ipv {
+      Versions::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      Versions::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+
+    SocketAddr::new(ip, self.cli.port)
+  }
+
+  /// STUN server and relay given by the global options.
+  fn candidate_options(&self) -> CandidateOptions<'_> {
+    let relay = self.cli.relay
+      .as_deref()
+      .zip(self.cli.relay_token.as_deref())
+      .map(|(server, token)| RelayOptions { server, token });
+
+    CandidateOptions {
+      stun_server: &self.cli.stun_server,
+      stun_credentials: self.cli.stun_credentials.as_ref(),
+      relay
     }
   }
 
-  /// Handles the 'whoami' command by discovering the public IP and port.
+  /// Loads the identity of this install, creating it on first run.
+  fn load_identity(&self) -> Result<Identity, Box<dyn std::error::Error>> {
+    let config_dir = self.cli.config_dir
+      .clone()
+      .or_else(identity::default_config_dir)
+      .ok_or("No config directory found, pass one with --config-dir")?;
+
+    let identity = Identity::load(&config_dir)?;
+
+    println!("Your identity fingerprint: {}", identity.fingerprint());
+
+    Ok(identity)
+  }
+
+  /// Handles the 'whoami' command by discovering the public IP and port and the NAT behaviour.
   async fn handle_whoami(&self) {
-    match commands::whoami::run(self.cli.port, self.cli.ipv).await {
-      Ok(public_addr) => {
-        println!("Your are {}", public_addr);
+    match commands::whoami::run(self.cli.port, self.cli.ipv, &self.cli.stun_server, self.cli.stun_credentials.as_ref()).await {
+      Ok(report) => {
+        println!("{}", report);
       }
       Err(e) => {
         eprintln!("Failed to get public address: {}", e);
@@ -103,10 +285,19 @@ impl CommandHandler {
   }
 
   /// Connects to the remote peer and starts communication.
-  async fn handle_jackin(&self, address: SocketAddr) {
-    match commands::jackin::run(self.cli.port, address).await {
-      Ok(_) => {
-        println!("Jacked in successfully");
+  async fn handle_jackin(&self, address: SocketAddr, secret: Option<&str>) {
+    let identity = match self.load_identity() {
+      Ok(identity) => identity,
+      Err(e) => {
+        eprintln!("Failed to load identity: {}", e);
+        return;
+      }
+    };
+    let passphrase = secret.map(Passphrase::new);
+
+    match commands::jackin::run(self.cli.port, address, self.masp_config(), identity, passphrase).await {
+      Ok(reason) => {
+        println!("Session ended: {}", reason);
       }
       Err(e) => {
         eprintln!("Failed to jackin to remote peer: {}", e);
@@ -115,14 +306,92 @@ impl CommandHandler {
   }
 
   /// Activates `wait` mode for other peer to jack in.
-  async fn handle_jackwait(&self, address: SocketAddr) {
-    match commands::jackwait::run(self.cli.port, address).await {
-      Ok(_) => {
-        println!("Jacked in successfully");
+  async fn handle_jackwait(&self, address: SocketAddr, secret: Option<&str>) {
+    let identity = match self.load_identity() {
+      Ok(identity) => identity,
+      Err(e) => {
+        eprintln!("Failed to load identity: {}", e);
+        return;
+      }
+    };
+    let passphrase = secret.map(Passphrase::new);
+
+    match commands::jackwait::run(self.cli.port, address, self.masp_config(), identity, passphrase).await {
+      Ok(reason) => {
+        println!("Session ended: {}", reason);
       }
       Err(e) => {
         eprintln!("Failed to jackin to remote peer: {}", e);
       }
     }
   }
+
+  /// Meets the remote peer through the rendezvous server and starts communication.
+  async fn handle_join(&self, server: &str, room: &str, secret: Option<&str>) {
+    let identity = match self.load_identity() {
+      Ok(identity) => identity,
+      Err(e) => {
+        eprintln!("Failed to load identity: {}", e);
+        return;
+      }
+    };
+    let passphrase = secret.map(Passphrase::new);
+
+    match commands::join::run(self.local_addr(), server, room, self.candidate_options(), self.masp_config(), identity, passphrase).await {
+      Ok(reason) => {
+        println!("Session ended: {}", reason);
+      }
+      Err(e) => {
+        eprintln!("Failed to join room {}: {}", room, e);
+      }
+    }
+  }
+
+  /// Exchanges candidate tokens with the remote peer and starts communication.
+  async fn handle_connect(&self, secret: Option<&str>) {
+    let identity = match self.load_identity() {
+      Ok(identity) => identity,
+      Err(e) => {
+        eprintln!("Failed to load identity: {}", e);
+        return;
+      }
+    };
+    let passphrase = secret.map(Passphrase::new);
+
+    match commands::connect::run(self.local_addr(), self.candidate_options(), self.masp_config(), identity, passphrase).await {
+      Ok(reason) => {
+        println!("Session ended: {}", reason);
+      }
+      Err(e) => {
+        eprintln!("Failed to connect to remote peer: {}", e);
+      }
+    }
+  }
+
+  /// Serves the rendezvous service until it fails.
+  async fn handle_rendezvous(&self) {
+    if let Err(e) = commands::rendezvous::run(self.cli.port).await {
+      eprintln!("Rendezvous server failed: {}", e);
+    }
+  }
+
+  /// Serves the relay until it fails.
+  async fn handle_relay(&self, token: &str) {
+    if let Err(e) = commands::relay::run(self.cli.port, token).await {
+      eprintln!("Relay failed: {}", e);
+    }
+  }
+
+  /// Serves STUN until it fails.
+  async fn handle_stun_server(&self, ip: Option<IpAddr>, other: Option<SocketAddr>) {
+    if let Err(e) = commands::stun_server::run(self.cli.port, ip, other, self.cli.stun_credentials.clone()).await {
+      eprintln!("STUN server failed: {}", e);
+    }
+  }
+}
+
+impl Default for CommandHandler {
+  fn default() -> Self {
+    Self::new()
+  }
 }
@@ -0,0 +1,148 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::lookup_host;
+use tokio::{signal, task};
+
+use crate::cli::Versions;
+use crate::commands::session::{Session, Video};
+use crate::commands::whoami;
+use crate::ice::{self, CandidateSet};
+use crate::identity::Identity;
+use crate::masp::config::MaspConfig;
+use crate::masp::crypto::{HandshakeRole, Passphrase};
+use crate::masp::shutdown::{DisconnectReason, Shutdown};
+use crate::relay::RelayConfig;
+use crate::stun::{Credentials, StunConfig};
+use crate::transport::{self, Transport};
+
+/// Relay to list as a candidate and the token it expects.
+pub struct RelayOptions<'a> {
+  pub server: &'a str,
+  pub token: &'a str
+}
+
+/// Servers candidates are gathered from.
+pub struct CandidateOptions<'a> {
+  pub stun_server: &'a str,
+  pub stun_credentials: Option<&'a Credentials>,
+  pub relay: Option<RelayOptions<'a>>
+}
+
+/// Session sockets along with the candidates gathered for them.
+pub struct LocalCandidates {
+  /// One socket per address family, the first one of the family asked for.
+  /// Servers are reached through that one.
+  pub sockets: Vec<Arc<dyn Transport>>,
+  pub candidates: CandidateSet,
+  pub relay: Option<RelayConfig>
+}
+
+/// Resolves `host` to an address the socket can reach.
+pub async fn resolve(socket: &dyn Transport, host: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+  let ipv6 = socket.local_addr()?.is_ipv6();
+
+  lookup_host(host)
+    .await?
+    .find(|addr| addr.is_ipv6() == ipv6)
+    .ok_or_else(|| format!("Could not resolve {} to an IP{} address", host, if ipv6 { "v6" } else { "v4" }).into())
+}
+
+/// Binds the session sockets and gathers their candidates, listing the relay if one is given.
+/// Besides the socket at `local_addr`, one of the other address family is bound
+/// where the host has it, so peers only sharing that family can still be reached.
+pub async fn gather(
+  local_addr: SocketAddr,
+  options: CandidateOptions<'_>
+) -> Result<LocalCandidates, Box<dyn std::error::Error>> {
+  let mut sockets = vec![transport::bind_udp(local_addr).await?];
+  let other_ip = if local_addr.is_ipv6() { IpAddr::V4(Ipv4Addr::UNSPECIFIED) } else { IpAddr::V6(Ipv6Addr::UNSPECIFIED) };
+
+  // without the other family, e.g. IPv6 disabled, only the first socket is used
+  if let Ok(socket) = transport::bind_udp(SocketAddr::new(other_ip, 0)).await {
+    sockets.push(socket);
+  }
+
+  let mut stun = Vec::new();
+
+  for socket in &sockets {
+    let ipv6 = socket.local_addr()?.is_ipv6();
+    let version = if ipv6 { Versions::V6 } else { Versions::V4 };
+
+    match whoami::resolve_stun_server(options.stun_server, version).await {
+      Ok(server) if server.is_ipv6() == ipv6 => stun.push(StunConfig { server, credentials: options.stun_credentials.cloned() }),
+      Ok(_) => {},
+      Err(e) => {
+        eprintln!("No STUN server, only local candidates: {}", e);
+        break;
+      }
+    }
+  }
+
+  let relay = match options.relay {
+    Some(relay) => Some(RelayConfig { server: resolve(sockets[0].as_ref(), relay.server).await?, token: relay.token.to_string() }),
+    None => None
+  };
+
+  let candidates = ice::gather(&borrow(&sockets), &stun, relay.as_ref()).await?;
+
+  for candidate in &candidates.candidates {
+    println!("Candidate {:?} {}", candidate.kind, candidate.addr);
+  }
+
+  Ok(LocalCandidates { sockets, candidates, relay })
+}
+
+fn borrow(sockets: &[Arc<dyn Transport>]) -> Vec<&dyn Transport> {
+  sockets.iter().map(|socket| socket.as_ref()).collect()
+}
+
+/// Runs the connectivity checks with the peer's candidates, then the session
+/// over the pair the controlling peer nominated. The controlling peer initiates the handshake.
+pub async fn connect(
+  local: LocalCandidates,
+  peer_candidates: &CandidateSet,
+  controlling: bool,
+  config: MaspConfig,
+  identity: Identity,
+  passphrase: Option<Passphrase>
+) -> Result<DisconnectReason, Box<dyn std::error::Error>> {
+  let LocalCandidates { sockets, candidates, relay } = local;
+  let borrowed = borrow(&sockets);
+  let establish = ice::establish(&borrowed, &candidates, peer_candidates, controlling, relay.as_ref());
+
+  let (socket, remote_addr) = tokio::select! {
+    result = establish => result?,
+    _ = signal::ctrl_c() => return Ok(DisconnectReason::LocalHangup)
+  };
+
+  println!("Connecting through {}", remote_addr);
+
+  let shutdown = Shutdown::new();
+
+  // Ctrl+C ends the session from this side
+  let interrupt = {
+    let shutdown = shutdown.clone();
+
+    task::spawn(async move {
+      if signal::ctrl_c().await.is_ok() {
+        shutdown.trigger(DisconnectReason::LocalHangup);
+      }
+    })
+  };
+
+  let session = Session {
+    role: if controlling { HandshakeRole::Initiator } else { HandshakeRole::Responder },
+    transport: sockets[socket].clone(),
+    remote_addr,
+    peer_host: peer_candidates.identity_host().unwrap_or(remote_addr.ip()),
+    config,
+    identity,
+    passphrase,
+    video: Video::Terminal
+  };
+
+  let result = session.run(shutdown).await;
+  interrupt.abort();
+
+  result
+}
@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+use tokio::signal;
+
+use crate::commands::candidates::{self, CandidateOptions};
+use crate::ice::CandidateSet;
+use crate::masp::config::MaspConfig;
+use crate::identity::Identity;
+use crate::masp::crypto::Passphrase;
+use crate::masp::shutdown::DisconnectReason;
+
+/// Connects to a peer without any server in between: both sides print their
+/// candidate token and paste the other's, the larger tie breaker controls the
+/// connectivity checks and initiates the handshake.
+pub async fn run (
+  local_addr: SocketAddr,
+  options: CandidateOptions<'_>,
+  config: MaspConfig,
+  identity: Identity,
+  passphrase: Option<Passphrase>
+) -> Result<DisconnectReason, Box<dyn std::error::Error>> {
+  let local = candidates::gather(local_addr, options).await?;
+
+  println!("Send this token to your peer:\n\n{}\n", local.candidates.to_token());
+  println!("Paste the token of your peer:");
+
+  let mut lines = BufReader::new(io::stdin()).lines();
+
+  let peer_candidates = loop {
+    let line = tokio::select! {
+      line = lines.next_line() => line?.ok_or("No token pasted")?,
+      _ = signal::ctrl_c() => return Ok(DisconnectReason::LocalHangup)
+    };
+
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    match CandidateSet::from_token(&line) {
+      Ok(peer_candidates) if peer_candidates == local.candidates => eprintln!("That's your own token, paste the one of your peer:"),
+      Ok(peer_candidates) => break peer_candidates,
+      Err(e) => eprintln!("{}, paste it again:", e)
+    }
+  };
+
+  let controlling = local.candidates.is_controlling(&peer_candidates);
+
+  candidates::connect(local, &peer_candidates, controlling, config, identity, passphrase).await
+}
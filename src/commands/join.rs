@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+use tokio::signal;
+
+use crate::commands::candidates::{self, CandidateOptions};
+use crate::masp::config::MaspConfig;
+use crate::identity::Identity;
+use crate::masp::crypto::{HandshakeRole, Passphrase};
+use crate::masp::shutdown::DisconnectReason;
+use crate::rendezvous;
+
+/// Meets the peer in a room of the rendezvous server, then runs the session with it.
+/// Both sides register their candidates and get the other's, the server tells
+/// which side controls the connectivity checks and initiates the handshake.
+pub async fn run (
+  local_addr: SocketAddr,
+  server: &str,
+  room: &str,
+  options: CandidateOptions<'_>,
+  config: MaspConfig,
+  identity: Identity,
+  passphrase: Option<Passphrase>
+) -> Result<DisconnectReason, Box<dyn std::error::Error>> {
+  let local = candidates::gather(local_addr, options).await?;
+  let server_addr = candidates::resolve(local.sockets[0].as_ref(), server).await?;
+
+  let peer = tokio::select! {
+    result = rendezvous::join(local.sockets[0].as_ref(), server_addr, room, &local.candidates) => result?,
+    _ = signal::ctrl_c() => return Ok(DisconnectReason::LocalHangup)
+  };
+
+  println!("Matched with a peer in room {}", room);
+
+  let controlling = peer.role == HandshakeRole::Initiator;
+
+  candidates::connect(local, &peer.peer_candidates, controlling, config, identity, passphrase).await
+}
@@ -1,3 +1,10 @@
 pub mod whoami;
 pub mod jackin;
-pub mod jackwait;
\ No newline at end of file
+pub mod jackwait;
+pub mod session;
+pub mod candidates;
+pub mod rendezvous;
+pub mod join;
+pub mod connect;
+pub mod relay;
+pub mod stun_server;
\ No newline at end of file
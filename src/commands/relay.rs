@@ -0,0 +1,14 @@
+use crate::relay::RelayServer;
+use crate::transport;
+
+use std::net::SocketAddr;
+
+/// Serves the relay on the given port until it fails, only for clients presenting the token.
+pub async fn run(port: u16, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let local_addr = SocketAddr::new("0.0.0.0".parse()?, port);
+  let socket = transport::bind_udp(local_addr).await?;
+
+  println!("Relay listening on {}", socket.local_addr()?);
+
+  RelayServer::new(socket, token).run().await
+}
@@ -0,0 +1,14 @@
+use crate::rendezvous::RendezvousServer;
+use crate::transport;
+
+use std::net::SocketAddr;
+
+/// Serves the rendezvous service on the given port.
+pub async fn run(port: u16) -> Result<(), Box<dyn std::error::Error>> {
+  let local_addr = SocketAddr::new("0.0.0.0".parse()?, port);
+  let socket = transport::bind_udp(local_addr).await?;
+
+  println!("Rendezvous server listening on {}", socket.local_addr()?);
+
+  RendezvousServer::new().run(socket.as_ref()).await
+}
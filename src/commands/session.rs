@@ -0,0 +1,252 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinError, JoinHandle};
+
+use crate::masp::receiver::MaspReceiver;
+use crate::masp::capabilities::{Capabilities, SessionCapabilities};
+use crate::masp::config::MaspConfig;
+use crate::identity::Identity;
+use crate::masp::crypto::{HandshakeRole, KeyPair, Passphrase, SessionKeys};
+use crate::masp::handshake::{self, Handshake};
+use crate::masp::reconnect::{self, ConnectionStatus};
+use crate::masp::sender::MaspSender;
+use crate::masp::shutdown::{DisconnectReason, Shutdown};
+use crate::masp::socket::MaspSocket;
+use crate::transport::Transport;
+use crate::video;
+use crate::video::ascii_frame::{self, VideoFormat};
+
+/// Where the video of a session comes from and goes to.
+pub enum Video {
+  /// Streams the camera and renders the peer's video in the terminal.
+  Terminal,
+  /// Streams the ASCII frames of `outgoing` and hands the peer's ones to `incoming`,
+  /// leaving the camera and terminal alone.
+  Channels {
+    outgoing: mpsc::Receiver<String>,
+    incoming: mpsc::UnboundedSender<String>
+  }
+}
+
+/// Session with one peer, shared by jackin and jackwait: they only differ in
+/// which side of the handshake they play.
+pub struct Session {
+  pub role: HandshakeRole,
+  pub transport: Arc<dyn Transport>,
+  pub remote_addr: SocketAddr,
+  /// Host the peer's identity is remembered under, its own even when reached through a relay.
+  pub peer_host: IpAddr,
+  pub config: MaspConfig,
+  pub identity: Identity,
+  pub passphrase: Option<Passphrase>,
+  pub video: Video
+}
+
+impl Session {
+  /// Runs the session until one of its tasks or the given shutdown ends it.
+  pub async fn run(self, shutdown: Shutdown) -> Result<DisconnectReason, Box<dyn std::error::Error>> {
+    let Session { role, transport, remote_addr, peer_host, config, mut identity, passphrase, video } = self;
+
+    // SENDER and RECIEVER share one socket, packets are routed to them by type
+    let socket = MaspSocket::new(transport, remote_addr);
+    let (demultiplexer, sender_inbox, reciever_inbox, handshake_inbox) = socket.demultiplexer(shutdown.clone());
+    let mut masp_sender = MaspSender::new(socket.clone(), sender_inbox, config, shutdown.clone());
+    let mut masp_reciever = MaspReceiver::new(socket.clone(), reciever_inbox, shutdown.clone());
+
+    let (frames, terminal) = match video {
+      Video::Terminal => (None, true),
+      Video::Channels { outgoing, incoming } => {
+        masp_reciever.forward_frames(incoming);
+
+        (Some(outgoing), false)
+      }
+    };
+
+    // every exit from here on stops the tasks of the session
+    let mut tasks = SessionTasks { tasks: vec![task::spawn(demultiplexer.run())], status_line: None };
+
+    // UDP hole punching
+    masp_sender.punch_hole().await?;
+
+    // waiting for handshake to complete
+    let capabilities = Capabilities::local(&config);
+    let mut handshake = Handshake::new(socket.clone(), handshake_inbox, capabilities, passphrase);
+    let (session_keys, session_capabilities) = tokio::select! {
+      result = shake_hands(&mut handshake, role, &identity.key_pair) => result?,
+      reason = shutdown.triggered() => return Ok(reason)
+    };
+
+    masp_sender.set_session_capabilities(&session_capabilities).await;
+    masp_reciever.set_session_capabilities(&session_capabilities).await;
+
+    println!("Negotiated {}", session_capabilities);
+
+    // video is captured and encoded in the format both peers agreed on
+    let video_format = VideoFormat::from(&session_capabilities);
+
+    // trust on first use, a changed identity ends the session before anything is streamed
+    if let Err(e) = identity.known_peers.verify(peer_host, session_keys.remote_identity()) {
+      masp_sender.send_bye().await;
+      shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+
+      return Err(e);
+    }
+
+    // Start acknowledgment handling in a background task
+    {
+      let sender_clone = masp_sender.clone();
+      let shutdown = shutdown.clone();
+
+      tasks.spawn(async move {
+        if let Err(e) = sender_clone.handle_acknowledgments().await {
+          shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+        }
+      });
+    }
+
+    // Ping the peer to measure RTT and detect when it goes silent
+    let status = ConnectionStatus::new();
+    {
+      let sender_clone = masp_sender.clone();
+      let status = status.clone();
+
+      tasks.spawn(async move {
+        sender_clone.keep_alive(status).await;
+      });
+    }
+
+    // reconnections go on under the video, their handshakes report on the status line
+    if terminal {
+      handshake.report_to(status.clone());
+    }
+
+    tasks.status_line = terminal.then(|| {
+      let status = status.clone();
+
+      task::spawn(async move {
+        status.render().await;
+      })
+    });
+
+    // Start retransmission handling in a background task
+    {
+      let sender_clone = masp_sender.clone();
+
+      tasks.spawn(async move {
+        sender_clone.retransmit_unacknowledged().await;
+      });
+    }
+
+    {
+      let sender_clone = masp_sender.clone();
+      let shutdown = shutdown.clone();
+
+      tasks.spawn(async move {
+        let result = match frames {
+          Some(frames) => video::stream::run_frames(sender_clone, frames, video_format, shutdown.clone()).await,
+          None => video::stream::run(sender_clone, video_format, shutdown.clone()).await
+        };
+
+        if let Err(e) = result {
+          shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+        }
+      });
+    }
+
+    {
+      let shutdown = shutdown.clone();
+
+      tasks.spawn(async move {
+        if let Err(e) = masp_reciever.start_receiving().await {
+          shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+        }
+      });
+    }
+
+    // The session lasts until one of the tasks shuts it down, and is resumed
+    // in place whenever the connection is lost
+    let keep_connected = async {
+      match role {
+        HandshakeRole::Initiator => {
+          reconnect::keep_connected_as_initiator(&mut handshake, &identity.key_pair, &masp_sender, &status).await
+        },
+        HandshakeRole::Responder => {
+          reconnect::keep_connected_as_responder(&mut handshake, &identity.key_pair, &masp_sender, &status).await
+        }
+      }
+    };
+
+    let reason = tokio::select! {
+      reason = shutdown.triggered() => reason,
+      Err(e) = keep_connected => {
+        shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+        shutdown.triggered().await
+      }
+    };
+
+    if reason != DisconnectReason::RemoteHangup {
+      masp_sender.send_bye().await;
+    }
+
+    // Wait for tasks to complete
+    tasks.join().await?;
+
+    Ok(reason)
+  }
+}
+
+/// Tasks of a running session. Dropping them, on whichever path the session
+/// ends, aborts what is still running and gives the terminal back.
+struct SessionTasks {
+  tasks: Vec<JoinHandle<()>>,
+  /// Set once the peer's video is rendered in the terminal.
+  status_line: Option<JoinHandle<()>>
+}
+
+impl SessionTasks {
+  fn spawn(&mut self, future: impl std::future::Future<Output = ()> + Send + 'static) {
+    self.tasks.push(task::spawn(future));
+  }
+
+  /// Waits for every task to end, the terminal is restored before a task
+  /// that panicked is reported.
+  async fn join(mut self) -> Result<(), JoinError> {
+    let mut result = Ok(());
+
+    for task in self.tasks.drain(..) {
+      if let Err(e) = task.await {
+        result = result.and(Err(e));
+      }
+    }
+
+    drop(self);
+
+    result
+  }
+}
+
+impl Drop for SessionTasks {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+
+    if let Some(status_line) = self.status_line.take() {
+      status_line.abort();
+      ascii_frame::reset_terminal();
+    }
+  }
+}
+
+/// Runs the handshake in the given role.
+async fn shake_hands(
+  handshake: &mut Handshake,
+  role: HandshakeRole,
+  identity: &KeyPair
+) -> Result<(SessionKeys, SessionCapabilities), Box<dyn std::error::Error>> {
+  match role {
+    HandshakeRole::Initiator => handshake.initiate(identity, handshake::MAX_HANDSHAKE_ATTEMPTS).await,
+    HandshakeRole::Responder => handshake.respond(identity).await
+  }
+}
@@ -0,0 +1,50 @@
+use crate::stun::{Credentials, StunServer};
+use crate::transport;
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Serves STUN on the given IP and port until it fails. With a second IP and
+/// port, also answers from them for RFC 5780 behaviour discovery. With
+/// credentials, only authenticated requests are answered.
+pub async fn run(
+  port: u16,
+  ip: Option<IpAddr>,
+  other: Option<SocketAddr>,
+  credentials: Option<Credentials>
+) -> Result<(), Box<dyn std::error::Error>> {
+  let ip = match ip {
+    Some(ip) => ip,
+    None => "0.0.0.0".parse()?
+  };
+
+  let server = match other {
+    Some(other) => {
+      if other.ip() == ip || other.port() == port {
+        return Err("The other address needs both another IP and another port".into());
+      }
+
+      let sockets = [
+        transport::bind_udp(SocketAddr::new(ip, port)).await?,
+        transport::bind_udp(SocketAddr::new(ip, other.port())).await?,
+        transport::bind_udp(SocketAddr::new(other.ip(), port)).await?,
+        transport::bind_udp(other).await?
+      ];
+
+      println!("STUN server listening on {} and {}, with behaviour discovery", sockets[0].local_addr()?, other);
+
+      StunServer::with_behavior_discovery(sockets)
+    },
+    None => {
+      let socket = transport::bind_udp(SocketAddr::new(ip, port)).await?;
+
+      println!("STUN server listening on {}", socket.local_addr()?);
+
+      StunServer::new(socket)
+    }
+  };
+
+  match credentials {
+    Some(credentials) => server.with_credentials(credentials).run().await,
+    None => server.run().await
+  }
+}
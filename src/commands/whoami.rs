@@ -1,21 +1,56 @@
 use crate::cli::Versions;
-use crate::stun::{send_binding_request, receive_stun_response_with_timeout, StunMessage, StunAttribute};
+use crate::nat::{self, NatReport};
+use crate::stun::{Credentials, StunConfig, DEFAULT_STUN_SERVER};
+use crate::transport;
 
-use tokio::net::UdpSocket;
-use tokio::time::Duration;
 use tokio::net::lookup_host;
 use std::net::SocketAddr;
 
-static STUN_SERVER: &str = "stun.l.google.com:19302";
+/// Compared against when the default STUN server, which doesn't support RFC 5780 behaviour
+/// discovery, is in use. A server of the user's own isn't second-guessed with third parties.
+static FALLBACK_STUN_SERVERS: [&str; 2] = ["stun1.l.google.com:19302", "stun2.l.google.com:19302"];
 
-// Uses STUN protocol to get the public address.
-pub async fn run(port: u16, version: Versions) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+// Uses STUN protocol to get the public address and tell how the NAT in front of it behaves.
+pub async fn run(
+  port: u16,
+  version: Versions,
+  stun_server: &str,
+  credentials: Option<&Credentials>
+) -> Result<NatReport, Box<dyn std::error::Error>> {
+  let stun_server_addr = resolve_stun_server(stun_server, version).await?;
+
+  // Bind to a local socket with the same address family
+  let local_addr = match stun_server_addr {
+    SocketAddr::V4(_) => SocketAddr::new("0.0.0.0".parse()?, port),
+    SocketAddr::V6(_) => SocketAddr::new("::".parse()?, port)
+  };
+
+  let socket = transport::bind_udp(local_addr).await?;
+
+  // fallback servers of the same family, an unresolved one is just left out
+  let mut fallback_addrs = Vec::new();
+
+  if stun_server == DEFAULT_STUN_SERVER {
+    for fallback_server in FALLBACK_STUN_SERVERS {
+      if let Ok(mut addrs) = lookup_host(fallback_server).await {
+        fallback_addrs.extend(addrs.find(|addr| addr.is_ipv4() == stun_server_addr.is_ipv4()));
+      }
+    }
+  }
+
+  let stun = StunConfig { server: stun_server_addr, credentials: credentials.cloned() };
+
+  nat::discover(socket.as_ref(), &stun, &fallback_addrs).await
+}
+
+/// Resolves the STUN server, an address of the preferred version when it has one.
+pub async fn resolve_stun_server(stun_server: &str, version: Versions) -> Result<SocketAddr, Box<dyn std::error::Error>> {
   // Resolve the STUN server addresses
   let mut stun_server_addrs = Vec::new();
 
-  match lookup_host(STUN_SERVER).await {
+  match lookup_host(stun_server).await {
     Ok(addrs) => stun_server_addrs.extend(addrs),
-    Err(e) => eprintln!("Failed to resolve {}: {}", STUN_SERVER, e),
+    Err(e) => eprintln!("Failed to resolve {}: {}", stun_server, e),
   }
 
   if stun_server_addrs.is_empty() {
@@ -34,36 +69,5 @@ pub async fn run(port: u16, version: Versions) -> Result<SocketAddr, Box<dyn std
   
   // first el always an ip of desired version.
   // using `unwrap` beacause vec will always contain 0 el.  
-  let stun_server_addr = stun_server_addrs.first().unwrap();
-
-  // Bind to a local socket with the same address family
-  let local_addr = match stun_server_addr {
-    SocketAddr::V4(_) => SocketAddr::new("0.0.0.0".parse()?, port),
-    SocketAddr::V6(_) => SocketAddr::new("::".parse()?, port)
-  };
-
-  let socket = UdpSocket::bind(local_addr).await?;
-
-  // Construct and send the binding request
-  let request = StunMessage::new();
-  send_binding_request(&socket, stun_server_addr, &request).await?;
-
-  // Receive the response with a timeout
-  let (response, _addr) = receive_stun_response_with_timeout(
-    &socket, 
-    Duration::from_secs(5)
-  ).await?;
-
-  // Verify transaction ID
-  if request.transaction_id != response.transaction_id {
-    return Err("Transaction ID does not match".into());
-  }
-
-  // Extract the public address
-  let attr = response.attributes.first().unwrap(); 
-  
-  match attr {
-    StunAttribute::XorMappedAddress(addr) => Ok(*addr),
-    StunAttribute::Unknown(_attr_type, _attr_value) => Err("Unhandled STUN attribute".into())
-  }
+  Ok(*stun_server_addrs.first().unwrap())
 }
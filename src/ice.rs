@@ -0,0 +1,671 @@
+use bytes::{Buf, BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::Poll;
+use tokio::time::{interval, sleep_until, Duration, Instant, MissedTickBehavior};
+
+use crate::relay::{self, RelayConfig};
+use crate::stun::{self, StunConfig};
+use crate::transport::Transport;
+
+pub const ICE_MAGIC_NUMBER: [u8; 4] = [0x4D, 0x54, 0x52, 0x49]; // 'MTRI'
+pub const ICE_VERSION: u8 = 0x02;
+/// Prefix of candidate tokens, tells them apart from anything else pasted by mistake.
+pub const TOKEN_PREFIX: &str = "mtrix1-";
+pub const MAX_CANDIDATES: usize = 16;
+
+const PASSWORD_SIZE: usize = 16;
+const TRANSACTION_ID_SIZE: usize = 12;
+const CHECK_TAG_SIZE: usize = 16;
+const CHECK_HEADER_SIZE: usize = 7 + TRANSACTION_ID_SIZE;
+
+const HOST: u8 = 0x01;
+const SERVER_REFLEXIVE: u8 = 0x02;
+const RELAYED: u8 = 0x03;
+const FAMILY_IPV4: u8 = 0x04;
+const FAMILY_IPV6: u8 = 0x06;
+
+const REQUEST: u8 = 0x01;
+const RESPONSE: u8 = 0x02;
+/// Check flag: the controlling peer picked this pair for the session.
+const FLAG_NOMINATE: u8 = 0x01;
+
+/// Checks of every pair are sent again this often until they succeed.
+const CHECK_INTERVAL_MS: u16 = 100;
+/// Connectivity checks give up after this long without a nominated pair.
+const CHECK_TIMEOUT_SECONDS: u8 = 15;
+/// How long the controlling peer waits for better pairs after the first direct one succeeded.
+const NOMINATION_DELAY_MS: u16 = 300;
+/// How long the controlling peer holds out for a direct pair before settling for the relay.
+const DIRECT_CHECK_TIMEOUT_SECONDS: u8 = 5;
+/// Nominations sent before the controlling peer takes the pair without an answer,
+/// it already got through both ways.
+const NOMINATION_ATTEMPTS: u8 = 5;
+const STUN_TIMEOUT_SECONDS: u8 = 3;
+
+/// Where a candidate address comes from, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CandidateKind {
+  /// Address of a local interface, reachable on the same network.
+  Host = 0x01,
+  /// Public address the NAT maps the socket to, as a STUN server sees it.
+  ServerReflexive = 0x02,
+  /// Control address of a relay the peer can be reached through, see `relay`.
+  Relayed = 0x03
+}
+
+impl CandidateKind {
+  /// Type preferences recommended by ICE, direct paths before the relay.
+  fn type_preference(&self) -> u32 {
+    match self {
+      CandidateKind::Host => 126,
+      CandidateKind::ServerReflexive => 100,
+      CandidateKind::Relayed => 0
+    }
+  }
+}
+
+/// Address a peer may be reachable at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+  pub kind: CandidateKind,
+  pub addr: SocketAddr,
+  pub priority: u32
+}
+
+impl Candidate {
+  /// Priority as in ICE: the kind first, then the preference among candidates of the same kind.
+  pub fn new(kind: CandidateKind, addr: SocketAddr, local_preference: u16) -> Self {
+    Self {
+      kind,
+      addr,
+      priority: (kind.type_preference() << 24) | ((local_preference as u32) << 8) | 255
+    }
+  }
+}
+
+/// Candidates of one peer, along with what the connectivity checks need:
+/// the tie breaker picking the controlling peer and the password
+/// authenticating checks sent to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateSet {
+  pub tie_breaker: u64,
+  pub password: [u8; PASSWORD_SIZE],
+  pub candidates: Vec<Candidate>
+}
+
+impl CandidateSet {
+  /// Candidates past `MAX_CANDIDATES` are left out, the lowest priority ones first.
+  pub fn new(mut candidates: Vec<Candidate>) -> Self {
+    let mut rng = rand::thread_rng();
+
+    candidates.sort_by_key(|candidate| Reverse(candidate.priority));
+    candidates.truncate(MAX_CANDIDATES);
+
+    Self {
+      tie_breaker: rng.gen(),
+      password: rng.gen(),
+      candidates
+    }
+  }
+
+  pub fn to_bytes(&self) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(8 + PASSWORD_SIZE + 1 + self.candidates.len() * 24);
+
+    buf.put_u64(self.tie_breaker);
+    buf.put_slice(&self.password);
+    buf.put_u8(self.candidates.len() as u8);
+
+    for candidate in &self.candidates {
+      buf.put_u8(candidate.kind as u8);
+      put_addr(&mut buf, &candidate.addr);
+      buf.put_u32(candidate.priority);
+    }
+
+    buf
+  }
+
+  /// Parses a candidate set, rejecting anything truncated or with trailing bytes.
+  pub fn from_bytes(mut buf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    if buf.len() < 8 + PASSWORD_SIZE + 1 {
+      return Err("Candidate set too short".into());
+    }
+
+    let tie_breaker = buf.get_u64();
+    let mut password = [0u8; PASSWORD_SIZE];
+    buf.copy_to_slice(&mut password);
+
+    let count = buf.get_u8() as usize;
+
+    if count > MAX_CANDIDATES {
+      return Err(format!("Too many candidates: {}", count).into());
+    }
+
+    let mut candidates = Vec::with_capacity(count);
+
+    for _ in 0..count {
+      if !buf.has_remaining() {
+        return Err("Truncated candidate".into());
+      }
+
+      let kind = match buf.get_u8() {
+        HOST => CandidateKind::Host,
+        SERVER_REFLEXIVE => CandidateKind::ServerReflexive,
+        RELAYED => CandidateKind::Relayed,
+        kind => return Err(format!("Unknown candidate kind {}", kind).into())
+      };
+      let addr = get_addr(&mut buf)?;
+
+      if buf.remaining() < 4 {
+        return Err("Truncated candidate".into());
+      }
+
+      candidates.push(Candidate { kind, addr, priority: buf.get_u32() });
+    }
+
+    if buf.has_remaining() {
+      return Err("Trailing bytes after candidate set".into());
+    }
+
+    Ok(Self { tie_breaker, password, candidates })
+  }
+
+  /// Text form for pasting into a chat, a hex encoding behind `TOKEN_PREFIX`.
+  pub fn to_token(&self) -> String {
+    let hex: String = self.to_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("{}{}", TOKEN_PREFIX, hex)
+  }
+
+  pub fn from_token(token: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    let hex = token
+      .trim()
+      .strip_prefix(TOKEN_PREFIX)
+      .ok_or("Not an mtrix candidate token")?;
+
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+      return Err("Malformed candidate token".into());
+    }
+
+    let bytes = (0..hex.len())
+      .step_by(2)
+      .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+      .collect::<Result<Vec<u8>, _>>()
+      .map_err(|_| "Malformed candidate token")?;
+
+    Self::from_bytes(&bytes)
+  }
+
+  /// The controlling peer nominates the pair the session uses and plays the handshake initiator.
+  pub fn is_controlling(&self, remote: &CandidateSet) -> bool {
+    (self.tie_breaker, self.password) > (remote.tie_breaker, remote.password)
+  }
+
+  /// Relay both peers listed, they can always meet there.
+  pub fn shared_relay(&self, remote: &CandidateSet) -> Option<SocketAddr> {
+    self.relays().find(|relay| remote.relays().any(|remote_relay| remote_relay == *relay))
+  }
+
+  fn relays(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+    self.candidates
+      .iter()
+      .filter(|candidate| candidate.kind == CandidateKind::Relayed)
+      .map(|candidate| candidate.addr)
+  }
+
+  /// Key both peers pair their relay allocations under, derived from both check passwords.
+  pub fn pair_key(&self, remote: &CandidateSet) -> String {
+    let (first, second) = if self.password < remote.password {
+      (&self.password, &remote.password)
+    } else {
+      (&remote.password, &self.password)
+    };
+
+    Sha256::digest([first.as_slice(), second].concat())[..16]
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect()
+  }
+
+  /// Host the peer is remembered under: its public address, or its local one without NAT.
+  pub fn identity_host(&self) -> Option<IpAddr> {
+    [CandidateKind::ServerReflexive, CandidateKind::Host]
+      .iter()
+      .find_map(|kind| self.candidates.iter().find(|candidate| candidate.kind == *kind))
+      .map(|candidate| candidate.addr.ip())
+  }
+}
+
+pub(crate) fn put_addr(buf: &mut BytesMut, addr: &SocketAddr) {
+  match addr.ip() {
+    IpAddr::V4(ip) => {
+      buf.put_u8(FAMILY_IPV4);
+      buf.put_slice(&ip.octets());
+    },
+    IpAddr::V6(ip) => {
+      buf.put_u8(FAMILY_IPV6);
+      buf.put_slice(&ip.octets());
+    }
+  }
+
+  buf.put_u16(addr.port());
+}
+
+pub(crate) fn get_addr(buf: &mut &[u8]) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+  if !buf.has_remaining() {
+    return Err("Truncated address".into());
+  }
+
+  let ip = match buf.get_u8() {
+    FAMILY_IPV4 if buf.remaining() >= 4 + 2 => IpAddr::V4(Ipv4Addr::from(buf.get_u32())),
+    FAMILY_IPV6 if buf.remaining() >= 16 + 2 => {
+      let mut octets = [0u8; 16];
+      buf.copy_to_slice(&mut octets);
+
+      IpAddr::V6(Ipv6Addr::from(octets))
+    },
+    FAMILY_IPV4 | FAMILY_IPV6 => return Err("Truncated address".into()),
+    _ => return Err("Unknown address family".into())
+  };
+
+  Ok(SocketAddr::new(ip, buf.get_u16()))
+}
+
+/// Gathers the candidates of the sockets, one per address family: the addresses
+/// of local interfaces of each family, the address the STUN server of its family
+/// sees, and the relay if there is one. A STUN server that doesn't answer only
+/// costs the reflexive candidate.
+pub async fn gather(
+  sockets: &[&dyn Transport],
+  stun: &[StunConfig],
+  relay: Option<&RelayConfig>
+) -> Result<CandidateSet, Box<dyn std::error::Error>> {
+  let local_addrs = sockets.iter().map(|socket| socket.local_addr()).collect::<io::Result<Vec<SocketAddr>>>()?;
+  let mut candidates: Vec<Candidate> = if_addrs::get_if_addrs()?
+    .iter()
+    .map(|interface| interface.ip())
+    .filter(|ip| !ip.is_loopback() && !is_link_local(ip))
+    .filter_map(|ip| {
+      let local_addr = local_addrs.iter().find(|local_addr| local_addr.is_ipv6() == ip.is_ipv6())?;
+
+      Some(SocketAddr::new(ip, local_addr.port()))
+    })
+    .enumerate()
+    .map(|(index, addr)| Candidate::new(CandidateKind::Host, addr, u16::MAX.saturating_sub(index as u16)))
+    .collect();
+
+  let stun_timeout = Duration::from_secs(STUN_TIMEOUT_SECONDS as u64);
+
+  for (socket, local_addr) in sockets.iter().zip(&local_addrs) {
+    let Some(stun) = stun.iter().find(|stun| stun.server.is_ipv6() == local_addr.is_ipv6()) else {
+      continue;
+    };
+
+    match stun::query_reflexive_addr(*socket, stun, stun_timeout).await {
+      // without NAT the reflexive address is a host one already
+      Ok(reflexive_addr) if !candidates.iter().any(|candidate| candidate.addr == reflexive_addr) => {
+        candidates.push(Candidate::new(CandidateKind::ServerReflexive, reflexive_addr, u16::MAX));
+      },
+      Ok(_) => {},
+      Err(e) => eprintln!("No server-reflexive candidate for {}: {}", local_addr, e)
+    }
+  }
+
+  if let Some(relay) = relay {
+    candidates.push(Candidate::new(CandidateKind::Relayed, relay.server, u16::MAX));
+  }
+
+  Ok(CandidateSet::new(candidates))
+}
+
+/// Link-local addresses need an interface scope to be reached, which candidates don't carry.
+fn is_link_local(ip: &IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => ip.is_link_local(),
+    IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80
+  }
+}
+
+/// Pair of one of our sockets and an address of the peer, checked for connectivity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CandidatePair {
+  /// Index of the socket the pair starts from, the one of the peer address's family.
+  pub socket: usize,
+  /// Where checks and, once nominated, the session go. Our own relayed
+  /// address for the relay, it forwards to the peer.
+  pub remote_addr: SocketAddr,
+  pub priority: u64,
+  pub relayed: bool
+}
+
+/// Pairs to check, highest priority first. Remote candidates are reached from
+/// our socket of their family, bound at `local_addrs`, and the relay, allocated
+/// on the first socket, only when both peers listed it.
+pub fn candidate_pairs(
+  local: &CandidateSet,
+  remote: &CandidateSet,
+  controlling: bool,
+  local_addrs: &[SocketAddr],
+  relayed_addr: Option<SocketAddr>
+) -> Vec<CandidatePair> {
+  let best = |candidates: &CandidateSet, kind: CandidateKind, ipv6: Option<bool>| {
+    candidates.candidates
+      .iter()
+      .filter(|candidate| candidate.kind == kind && ipv6.is_none_or(|ipv6| candidate.addr.is_ipv6() == ipv6))
+      .map(|candidate| candidate.priority)
+      .max()
+      .unwrap_or(0)
+  };
+
+  let mut pairs: Vec<CandidatePair> = Vec::new();
+
+  for candidate in remote.candidates.iter().filter(|candidate| candidate.kind != CandidateKind::Relayed) {
+    let Some(socket) = local_addrs.iter().position(|local_addr| local_addr.is_ipv6() == candidate.addr.is_ipv6()) else {
+      continue;
+    };
+
+    // every direct pair starts from one of our sockets, its best host candidate stands for it
+    let local_priority = best(local, CandidateKind::Host, Some(candidate.addr.is_ipv6()));
+
+    if !pairs.iter().any(|pair| pair.remote_addr == candidate.addr) {
+      pairs.push(CandidatePair {
+        socket,
+        remote_addr: candidate.addr,
+        priority: pair_priority(local_priority, candidate.priority, controlling),
+        relayed: false
+      });
+    }
+  }
+
+  if let Some(relayed_addr) = relayed_addr {
+    pairs.push(CandidatePair {
+      socket: 0,
+      remote_addr: relayed_addr,
+      priority: pair_priority(best(local, CandidateKind::Relayed, None), best(remote, CandidateKind::Relayed, None), controlling),
+      relayed: true
+    });
+  }
+
+  pairs.sort_by_key(|pair| Reverse(pair.priority));
+
+  pairs
+}
+
+/// Pair priority as in ICE, the same on both peers.
+fn pair_priority(local: u32, remote: u32, controlling: bool) -> u64 {
+  let (controlling_priority, controlled_priority) = if controlling { (local, remote) } else { (remote, local) };
+  let (min, max) = (
+    controlling_priority.min(controlled_priority) as u64,
+    controlling_priority.max(controlled_priority) as u64
+  );
+
+  (min << 32) + 2 * max + (controlling_priority > controlled_priority) as u64
+}
+
+/// Connectivity check: a request, or the response echoing its transaction ID.
+/// Tagged with the password of the peer answering the request, so only the
+/// peer the candidates were exchanged with can take part.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Check {
+  response: bool,
+  nominate: bool,
+  transaction_id: [u8; TRANSACTION_ID_SIZE],
+  /// Where the request came from as the answering peer saw it, in responses,
+  /// and where the nominated pair's requests are seen coming from, in
+  /// nominations. Ties a nomination to its path, one replayed from another
+  /// address doesn't match where it came from.
+  addr: Option<SocketAddr>
+}
+
+impl Check {
+  fn to_bytes(self, password: &[u8; PASSWORD_SIZE]) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(CHECK_HEADER_SIZE + 1 + 16 + 2 + CHECK_TAG_SIZE);
+
+    buf.put_slice(&ICE_MAGIC_NUMBER);
+    buf.put_u8(ICE_VERSION);
+    buf.put_u8(if self.response { RESPONSE } else { REQUEST });
+    buf.put_u8(if self.nominate { FLAG_NOMINATE } else { 0 });
+    buf.put_slice(&self.transaction_id);
+
+    if let Some(addr) = &self.addr {
+      put_addr(&mut buf, addr);
+    }
+
+    let tag = check_tag(password, &buf);
+    buf.put_slice(&tag);
+
+    buf.to_vec()
+  }
+
+  /// Parses a check, verifying requests with our password and responses with the peer's.
+  fn from_bytes(
+    buf: &[u8],
+    local_password: &[u8; PASSWORD_SIZE],
+    remote_password: &[u8; PASSWORD_SIZE]
+  ) -> Option<Self> {
+    if buf.len() < CHECK_HEADER_SIZE + CHECK_TAG_SIZE
+      || buf[..4] != ICE_MAGIC_NUMBER
+      || buf[4] != ICE_VERSION
+      || buf[6] & !FLAG_NOMINATE != 0 {
+      return None;
+    }
+
+    let response = match buf[5] {
+      REQUEST => false,
+      RESPONSE => true,
+      _ => return None
+    };
+    let nominate = buf[6] & FLAG_NOMINATE != 0;
+    let password = if response { remote_password } else { local_password };
+    let (body, tag) = buf.split_at(buf.len() - CHECK_TAG_SIZE);
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(password).expect("HMAC accepts any key size");
+    mac.update(body);
+    mac.verify_truncated_left(tag).ok()?;
+
+    let mut transaction_id = [0u8; TRANSACTION_ID_SIZE];
+    transaction_id.copy_from_slice(&body[7..CHECK_HEADER_SIZE]);
+
+    let mut addr_bytes = &body[CHECK_HEADER_SIZE..];
+    let addr = if response || nominate { Some(get_addr(&mut addr_bytes).ok()?) } else { None };
+
+    if addr_bytes.has_remaining() {
+      return None;
+    }
+
+    Some(Self { response, nominate, transaction_id, addr })
+  }
+}
+
+fn check_tag(password: &[u8; PASSWORD_SIZE], body: &[u8]) -> [u8; CHECK_TAG_SIZE] {
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(password).expect("HMAC accepts any key size");
+  mac.update(body);
+
+  let mut tag = [0u8; CHECK_TAG_SIZE];
+  tag.copy_from_slice(&mac.finalize().into_bytes()[..CHECK_TAG_SIZE]);
+
+  tag
+}
+
+/// Allocates on the relay both peers listed, then runs the connectivity checks.
+/// Returns the socket and the address of the nominated pair, where the session goes.
+pub async fn establish(
+  sockets: &[&dyn Transport],
+  local: &CandidateSet,
+  remote: &CandidateSet,
+  controlling: bool,
+  relay: Option<&RelayConfig>
+) -> Result<(usize, SocketAddr), Box<dyn std::error::Error>> {
+  let relayed_addr = match (relay, local.shared_relay(remote)) {
+    (Some(relay), Some(shared_relay)) if relay.server == shared_relay => {
+      match relay::allocate(sockets[0], relay.server, &relay.token, &local.pair_key(remote)).await {
+        Ok(relayed_addr) => Some(relayed_addr),
+        Err(e) => {
+          eprintln!("Relay unavailable, trying direct paths only: {}", e);
+          None
+        }
+      }
+    },
+    _ => None
+  };
+
+  let local_addrs = sockets.iter().map(|socket| socket.local_addr()).collect::<io::Result<Vec<SocketAddr>>>()?;
+  let pairs = candidate_pairs(local, remote, controlling, &local_addrs, relayed_addr);
+
+  check_connectivity(sockets, local, remote, controlling, &pairs).await
+}
+
+/// Checks every pair both ways, both peers checking at once also opens their NATs.
+/// The controlling peer nominates the best pair that got through, preferring
+/// any direct one to the relay, and the controlled peer follows. Returns the
+/// index of the socket and the address of the nominated pair.
+pub async fn check_connectivity(
+  sockets: &[&dyn Transport],
+  local: &CandidateSet,
+  remote: &CandidateSet,
+  controlling: bool,
+  pairs: &[CandidatePair]
+) -> Result<(usize, SocketAddr), Box<dyn std::error::Error>> {
+  if pairs.is_empty() {
+    return Err("No candidate pair to check, the peers share no address family".into());
+  }
+
+  let started_at = Instant::now();
+  let deadline = started_at + Duration::from_secs(CHECK_TIMEOUT_SECONDS as u64);
+  let direct_deadline = started_at + Duration::from_secs(DIRECT_CHECK_TIMEOUT_SECONDS as u64);
+  let mut rng = rand::thread_rng();
+  let transaction_ids: Vec<[u8; TRANSACTION_ID_SIZE]> = pairs.iter().map(|_| rng.gen()).collect();
+  let nomination_id: [u8; TRANSACTION_ID_SIZE] = rng.gen();
+  // where the peer saw the requests of each pair come from, once the pair got through
+  let mut mapped_addrs: Vec<Option<SocketAddr>> = vec![None; pairs.len()];
+  let mut first_direct_success_at: Option<Instant> = None;
+  // nominated pair and how many nominations were sent for it
+  let mut nomination: Option<(usize, u8)> = None;
+  let mut check_interval = interval(Duration::from_millis(CHECK_INTERVAL_MS as u64));
+  let mut bufs = vec![[0u8; 128]; sockets.len()];
+
+  check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  loop {
+    let (socket, result) = tokio::select! {
+      _ = check_interval.tick() => {
+        if let Some((index, attempts)) = nomination {
+          let pair = &pairs[index];
+
+          if attempts >= NOMINATION_ATTEMPTS {
+            return Ok((pair.socket, pair.remote_addr));
+          }
+
+          let check = Check { response: false, nominate: true, transaction_id: nomination_id, addr: mapped_addrs[index] };
+          let _ = sockets[pair.socket].send_to(&check.to_bytes(&remote.password), pair.remote_addr).await;
+
+          nomination = Some((index, attempts + 1));
+          continue;
+        }
+
+        for (index, pair) in pairs.iter().enumerate().filter(|(index, _)| mapped_addrs[*index].is_none()) {
+          let check = Check { response: false, nominate: false, transaction_id: transaction_ids[index], addr: None };
+
+          // an unreachable candidate is just a pair that won't succeed
+          let _ = sockets[pair.socket].send_to(&check.to_bytes(&remote.password), pair.remote_addr).await;
+        }
+
+        if controlling {
+          nomination = nominate(pairs, &mapped_addrs, first_direct_success_at, direct_deadline).map(|index| (index, 0));
+        }
+
+        continue;
+      },
+      _ = sleep_until(deadline) => return Err("No candidate pair got through".into()),
+      received = recv_from_any(sockets, &mut bufs) => received
+    };
+
+    let (len, from) = result?;
+
+    let Some(check) = Check::from_bytes(&bufs[socket][..len], &local.password, &remote.password) else {
+      continue;
+    };
+
+    if !check.response {
+      // a nomination only counts from where the controlling peer nominated its requests from
+      if check.nominate && check.addr != Some(from) {
+        continue;
+      }
+
+      let response = Check { response: true, addr: Some(from), ..check };
+      let _ = sockets[socket].send_to(&response.to_bytes(&local.password), from).await;
+
+      // the source of the nomination is where the controlling peer will be heard from
+      if check.nominate && !controlling {
+        return Ok((socket, from));
+      }
+
+      continue;
+    }
+
+    if check.nominate {
+      if let Some((index, _)) = nomination.filter(|(index, _)| pairs[*index].socket == socket && pairs[*index].remote_addr == from) {
+        return Ok((socket, pairs[index].remote_addr));
+      }
+
+      continue;
+    }
+
+    // a pair only counts once its response came back from where the request went
+    let Some(index) = (0..pairs.len()).find(|index| {
+      transaction_ids[*index] == check.transaction_id && pairs[*index].socket == socket && pairs[*index].remote_addr == from
+    }) else {
+      continue;
+    };
+
+    mapped_addrs[index] = check.addr;
+
+    if !pairs[index].relayed && first_direct_success_at.is_none() {
+      first_direct_success_at = Some(Instant::now());
+    }
+  }
+}
+
+/// Receives on whichever socket gets a datagram first, into the buffer of the
+/// same index. Returns the index of the socket along with what it received.
+async fn recv_from_any(
+  sockets: &[&dyn Transport],
+  bufs: &mut [[u8; 128]]
+) -> (usize, io::Result<(usize, SocketAddr)>) {
+  let mut receives: Vec<_> = sockets.iter().zip(bufs.iter_mut()).map(|(socket, buf)| socket.recv_from(buf)).collect();
+
+  poll_fn(|cx| {
+    receives
+      .iter_mut()
+      .enumerate()
+      .find_map(|(index, receive)| match receive.as_mut().poll(cx) {
+        Poll::Ready(result) => Some((index, result)),
+        Poll::Pending => None
+      })
+      .map_or(Poll::Pending, Poll::Ready)
+  }).await
+}
+
+/// Pair the controlling peer nominates now, if any: the best direct pair once
+/// better ones had a moment to get through, or the relay once direct ones had their chance.
+fn nominate(
+  pairs: &[CandidatePair],
+  mapped_addrs: &[Option<SocketAddr>],
+  first_direct_success_at: Option<Instant>,
+  direct_deadline: Instant
+) -> Option<usize> {
+  let best = |relayed: bool| (0..pairs.len()).find(|index| mapped_addrs[*index].is_some() && pairs[*index].relayed == relayed);
+  let nomination_delay = Duration::from_millis(NOMINATION_DELAY_MS as u64);
+
+  match first_direct_success_at {
+    Some(first_success_at) if first_success_at.elapsed() >= nomination_delay => best(false),
+    Some(_) => None,
+    None if Instant::now() >= direct_deadline => best(true),
+    None => None
+  }
+}
@@ -0,0 +1,167 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::masp::crypto::{KeyPair, PUBLIC_KEY_SIZE};
+
+const CONFIG_DIR_NAME: &str = "mtrix";
+const IDENTITY_FILE_NAME: &str = "identity";
+const KNOWN_PEERS_FILE_NAME: &str = "known_peers";
+/// Groups of 4 hex digits shown in a fingerprint, 64 bits of the key hash.
+const FINGERPRINT_GROUPS: usize = 4;
+
+/// Long-term identity of this install together with the peers it has met.
+pub struct Identity {
+  pub key_pair: KeyPair,
+  pub known_peers: KnownPeers
+}
+
+impl Identity {
+  /// Loads the identity from the config directory, generating it on first run.
+  pub fn load(config_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    fs::create_dir_all(config_dir)?;
+
+    let identity_path = config_dir.join(IDENTITY_FILE_NAME);
+    let key_pair = if identity_path.exists() {
+      let secret_bytes = decode_key(fs::read_to_string(&identity_path)?.trim())
+        .ok_or_else(|| format!("Malformed identity file {}", identity_path.display()))?;
+
+      KeyPair::from_secret_bytes(secret_bytes)
+    } else {
+      let key_pair = KeyPair::generate();
+
+      write_private_file(&identity_path, &encode_key(&key_pair.secret_bytes()))?;
+
+      key_pair
+    };
+
+    Ok(Self {
+      key_pair,
+      known_peers: KnownPeers::load(config_dir.join(KNOWN_PEERS_FILE_NAME))?
+    })
+  }
+
+  pub fn fingerprint(&self) -> String {
+    fingerprint(&self.key_pair.public)
+  }
+}
+
+/// Platform config directory, e.g. ~/.config/mtrix on Linux.
+pub fn default_config_dir() -> Option<PathBuf> {
+  dirs::config_dir().map(|config_dir| config_dir.join(CONFIG_DIR_NAME))
+}
+
+/// Short human-comparable digest of an identity key.
+pub fn fingerprint(public_key: &[u8]) -> String {
+  let digest = Sha256::digest(public_key);
+
+  digest
+    .chunks(2)
+    .take(FINGERPRINT_GROUPS)
+    .map(encode_key)
+    .collect::<Vec<String>>()
+    .join("-")
+}
+
+/// Identity keys of peers seen before, keyed by host like SSH known_hosts.
+pub struct KnownPeers {
+  path: PathBuf,
+  entries: Vec<(IpAddr, [u8; PUBLIC_KEY_SIZE])>
+}
+
+impl KnownPeers {
+  pub fn load(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    let contents = match fs::read_to_string(&path) {
+      Ok(contents) => contents,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+      Err(e) => return Err(e.into())
+    };
+
+    // each line is `<ip> <hex key>`, anything else is skipped
+    let entries = contents
+      .lines()
+      .filter(|line| !line.trim_start().starts_with('#'))
+      .filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let host = fields.next()?.parse().ok()?;
+        let public_key = decode_key(fields.next()?)?;
+
+        Some((host, public_key))
+      })
+      .collect();
+
+    Ok(Self { path, entries })
+  }
+
+  /// Trusts the key of a peer met for the first time and remembers it.
+  /// Fails when a known peer shows up with a different key.
+  pub fn verify(&mut self, host: IpAddr, public_key: &[u8; PUBLIC_KEY_SIZE]) -> Result<(), Box<dyn std::error::Error>> {
+    match self.entries.iter().find(|(known_host, _)| *known_host == host) {
+      Some((_, known_key)) if known_key == public_key => Ok(()),
+      Some((_, known_key)) => {
+        eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+        eprintln!("@    WARNING: REMOTE PEER IDENTITY HAS CHANGED!           @");
+        eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+        eprintln!("Someone could be intercepting the session with {}.", host);
+        eprintln!("Known fingerprint:    {}", fingerprint(known_key));
+        eprintln!("Received fingerprint: {}", fingerprint(public_key));
+
+        Err(format!(
+          "Identity of {} changed, remove its line from {} if the change is expected",
+          host,
+          self.path.display()
+        ).into())
+      },
+      None => {
+        println!("First session with {}, peer fingerprint: {}", host, fingerprint(public_key));
+        println!("Compare it with the one your peer sees for itself before trusting it");
+
+        self.add(host, *public_key)
+      }
+    }
+  }
+
+  fn add(&mut self, host: IpAddr, public_key: [u8; PUBLIC_KEY_SIZE]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+
+    writeln!(file, "{} {}", host, encode_key(&public_key))?;
+    self.entries.push((host, public_key));
+
+    Ok(())
+  }
+}
+
+fn write_private_file(path: &Path, contents: &str) -> std::io::Result<()> {
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    options.mode(0o600);
+  }
+
+  options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn encode_key(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+  if hex.len() != 64 || !hex.is_ascii() {
+    return None;
+  }
+
+  let mut bytes = [0u8; 32];
+
+  for (index, byte) in bytes.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+  }
+
+  Some(bytes)
+}
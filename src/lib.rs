@@ -0,0 +1,15 @@
+pub mod cli;
+pub mod commands;
+
+pub mod stun;
+pub mod nat;
+pub mod rendezvous;
+pub mod relay;
+pub mod ice;
+pub mod masp;
+pub mod identity;
+pub mod transport;
+
+pub mod video;
+
+mod tests;
\ No newline at end of file
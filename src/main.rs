@@ -1,14 +1,4 @@
-mod cli;
-mod commands;
-
-mod stun;
-mod masp;
-
-mod video;
-
-mod tests;
-
-use cli::CommandHandler;
+use mtrix::cli::CommandHandler;
 
 #[tokio::main]
 async fn main() {
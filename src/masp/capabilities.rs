@@ -0,0 +1,367 @@
+use bytes::{BufMut, BytesMut};
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::config::MaspConfig;
+use super::crypto::TAG_SIZE;
+use super::fec::{self, FecParameters, PARITY_HEADER_SIZE};
+use super::message::{self, PacketError, MASP_HEADER_SIZE, MASP_VERSION};
+use crate::video::ascii_frame::{ASCII_FRAME_HEIGHT, ASCII_FRAME_WIDTH};
+
+/// Protocol versions this build can speak, the header of handshake packets is
+/// the same in all of them. v1 isn't one, see `FIRST_NEGOTIABLE_VERSION`.
+pub const SUPPORTED_VERSIONS: [u8; 1] = [MASP_VERSION];
+
+/// Smallest datagram that still carries a byte of a fragment next to the
+/// header, the AEAD tag and a parity header.
+pub const MIN_DATAGRAM_SIZE: u16 = (MASP_HEADER_SIZE + TAG_SIZE + PARITY_HEADER_SIZE + 1) as u16;
+
+const TLV_HEADER_SIZE: usize = 3;
+
+/// Type tags of the capability TLVs carried by handshake packets.
+/// Unknown tags are skipped, so new capabilities can be added without breaking older peers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CapabilityTag {
+  Versions = 0x01,
+  Codecs = 0x02,
+  FrameDimensions = 0x03,
+  ColourModes = 0x04,
+  MaxDatagramSize = 0x05,
+  EncryptionSuites = 0x06,
+  ForwardErrorCorrection = 0x07
+}
+
+impl TryFrom<u8> for CapabilityTag {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(CapabilityTag::Versions),
+      0x02 => Ok(CapabilityTag::Codecs),
+      0x03 => Ok(CapabilityTag::FrameDimensions),
+      0x04 => Ok(CapabilityTag::ColourModes),
+      0x05 => Ok(CapabilityTag::MaxDatagramSize),
+      0x06 => Ok(CapabilityTag::EncryptionSuites),
+      0x07 => Ok(CapabilityTag::ForwardErrorCorrection),
+      _ => Err(PacketError::UnknownValue { field: "capability", value })
+    }
+  }
+}
+
+/// Encodings of video frames, a higher value is preferred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoCodec {
+  /// ASCII art compressed with run-length encoding.
+  AsciiRunLength = 0x01
+}
+
+impl TryFrom<u8> for VideoCodec {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(VideoCodec::AsciiRunLength),
+      _ => Err(PacketError::UnknownValue { field: "video codec", value })
+    }
+  }
+}
+
+impl fmt::Display for VideoCodec {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      VideoCodec::AsciiRunLength => write!(f, "ascii-rle")
+    }
+  }
+}
+
+/// Colours a peer can render, a higher value is preferred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColourMode {
+  Monochrome = 0x01
+}
+
+impl TryFrom<u8> for ColourMode {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(ColourMode::Monochrome),
+      _ => Err(PacketError::UnknownValue { field: "colour mode", value })
+    }
+  }
+}
+
+impl fmt::Display for ColourMode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ColourMode::Monochrome => write!(f, "monochrome")
+    }
+  }
+}
+
+/// Key exchange and AEAD combinations, a higher value is preferred.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionSuite {
+  X25519ChaChaPolySha256 = 0x01
+}
+
+impl TryFrom<u8> for EncryptionSuite {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(EncryptionSuite::X25519ChaChaPolySha256),
+      _ => Err(PacketError::UnknownValue { field: "encryption suite", value })
+    }
+  }
+}
+
+impl fmt::Display for EncryptionSuite {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      EncryptionSuite::X25519ChaChaPolySha256 => write!(f, "x25519-chachapoly-sha256")
+    }
+  }
+}
+
+/// Everything a peer supports, as advertised in its handshake packet.
+/// Values are kept raw so that a newer peer's unknown ones can be ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+  pub versions: Vec<u8>,
+  pub codecs: Vec<u8>,
+  pub max_frame_width: u16,
+  pub max_frame_height: u16,
+  pub colour_modes: Vec<u8>,
+  pub max_datagram_size: u16,
+  pub encryption_suites: Vec<u8>,
+  /// Largest FEC group the peer handles, zero without FEC support.
+  pub max_fec_group_size: u8,
+  /// Most parity packets per group the peer handles, zero without FEC support.
+  pub max_fec_parity_count: u8
+}
+
+/// Feature set both peers settled on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionCapabilities {
+  pub version: u8,
+  pub codec: VideoCodec,
+  pub frame_width: u16,
+  pub frame_height: u16,
+  pub colour_mode: ColourMode,
+  pub max_datagram_size: u16,
+  pub encryption_suite: EncryptionSuite,
+  /// Forward error correction of video, when both peers want it.
+  pub fec: Option<FecParameters>
+}
+
+impl fmt::Display for SessionCapabilities {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "MASP v{}, {} {}x{} {}, {} byte datagrams, {}, ",
+      self.version,
+      self.codec,
+      self.frame_width,
+      self.frame_height,
+      self.colour_mode,
+      self.max_datagram_size,
+      self.encryption_suite
+    )?;
+
+    match self.fec {
+      Some(fec) => write!(f, "fec up to {} parity per {} fragments", fec.max_parity_count, fec.group_size),
+      None => write!(f, "no fec")
+    }
+  }
+}
+
+impl Capabilities {
+  /// Capabilities of this build with the given session settings.
+  pub fn local(config: &MaspConfig) -> Self {
+    let (max_fec_group_size, max_fec_parity_count) = if config.forward_error_correction {
+      (fec::MAX_GROUP_SIZE, fec::MAX_PARITY_COUNT)
+    } else {
+      (0, 0)
+    };
+
+    Self {
+      versions: SUPPORTED_VERSIONS.to_vec(),
+      codecs: vec![VideoCodec::AsciiRunLength as u8],
+      max_frame_width: ASCII_FRAME_WIDTH as u16,
+      max_frame_height: ASCII_FRAME_HEIGHT as u16,
+      colour_modes: vec![ColourMode::Monochrome as u8],
+      max_datagram_size: config.max_datagram_size,
+      encryption_suites: vec![EncryptionSuite::X25519ChaChaPolySha256 as u8],
+      max_fec_group_size,
+      max_fec_parity_count
+    }
+  }
+
+  /// Settles on the highest feature set both sides support. Both peers run
+  /// this on the same pair of advertisements and reach the same result.
+  pub fn negotiate(&self, remote: &Capabilities) -> Result<SessionCapabilities, String> {
+    let version = highest_common(&self.versions, &remote.versions)
+      .ok_or_else(|| no_overlap("protocol versions", &self.versions, &remote.versions))?;
+    let codec = highest_common(&self.codecs, &remote.codecs)
+      .and_then(|codec| VideoCodec::try_from(codec).ok())
+      .ok_or_else(|| no_overlap("video codecs", &self.codecs, &remote.codecs))?;
+    let colour_mode = highest_common(&self.colour_modes, &remote.colour_modes)
+      .and_then(|colour_mode| ColourMode::try_from(colour_mode).ok())
+      .ok_or_else(|| no_overlap("colour modes", &self.colour_modes, &remote.colour_modes))?;
+    let encryption_suite = highest_common(&self.encryption_suites, &remote.encryption_suites)
+      .and_then(|encryption_suite| EncryptionSuite::try_from(encryption_suite).ok())
+      .ok_or_else(|| no_overlap("encryption suites", &self.encryption_suites, &remote.encryption_suites))?;
+
+    let frame_width = self.max_frame_width.min(remote.max_frame_width);
+    let frame_height = self.max_frame_height.min(remote.max_frame_height);
+
+    if frame_width == 0 || frame_height == 0 {
+      return Err("Peer can't display any video frame".to_string());
+    }
+
+    let max_datagram_size = self.max_datagram_size.min(remote.max_datagram_size);
+
+    if max_datagram_size < MIN_DATAGRAM_SIZE {
+      return Err(format!(
+        "Datagrams of {} bytes are too small: we allow {}, the peer allows {}, at least {} are needed",
+        max_datagram_size,
+        self.max_datagram_size,
+        remote.max_datagram_size,
+        MIN_DATAGRAM_SIZE
+      ));
+    }
+
+    let fec_group_size = self.max_fec_group_size.min(remote.max_fec_group_size);
+    let fec_parity_count = self.max_fec_parity_count.min(remote.max_fec_parity_count);
+    // either side may do without FEC, the session then relies on retransmissions alone
+    let fec = (fec_group_size > 0 && fec_parity_count > 0).then_some(FecParameters {
+      group_size: fec_group_size,
+      max_parity_count: fec_parity_count
+    });
+
+    Ok(SessionCapabilities {
+      version,
+      codec,
+      frame_width,
+      frame_height,
+      colour_mode,
+      max_datagram_size,
+      encryption_suite,
+      fec
+    })
+  }
+
+  /// Serializes the capabilities as a length-prefixed block of TLVs.
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut tlvs = BytesMut::new();
+
+    put_tlv(&mut tlvs, CapabilityTag::Versions, &self.versions);
+    put_tlv(&mut tlvs, CapabilityTag::Codecs, &self.codecs);
+    put_tlv(&mut tlvs, CapabilityTag::FrameDimensions, &[
+      self.max_frame_width.to_be_bytes(),
+      self.max_frame_height.to_be_bytes()
+    ].concat());
+    put_tlv(&mut tlvs, CapabilityTag::ColourModes, &self.colour_modes);
+    put_tlv(&mut tlvs, CapabilityTag::MaxDatagramSize, &self.max_datagram_size.to_be_bytes());
+    put_tlv(&mut tlvs, CapabilityTag::EncryptionSuites, &self.encryption_suites);
+    put_tlv(&mut tlvs, CapabilityTag::ForwardErrorCorrection, &[self.max_fec_group_size, self.max_fec_parity_count]);
+
+    let mut buffer = BytesMut::with_capacity(2 + tlvs.len());
+
+    buffer.put_u16(tlvs.len() as u16);
+    buffer.put_slice(&tlvs);
+
+    buffer.to_vec()
+  }
+
+  /// Parses a length-prefixed capability block, returning it along with the bytes after it.
+  pub fn deserialize(buffer: &[u8]) -> Result<(Self, &[u8]), PacketError> {
+    message::ensure_length("capabilities", buffer, 2)?;
+
+    let block_size = message::read_u16(buffer, 0) as usize;
+
+    message::ensure_length("capabilities", buffer, 2 + block_size)?;
+
+    let block = &buffer[2..2 + block_size];
+
+    let mut versions = None;
+    let mut codecs = None;
+    let mut frame_dimensions = None;
+    let mut colour_modes = None;
+    let mut max_datagram_size = None;
+    let mut encryption_suites = None;
+    // peers that predate FEC don't advertise it
+    let mut forward_error_correction = (0, 0);
+
+    let mut remaining = block;
+
+    while !remaining.is_empty() {
+      message::ensure_length("capability", remaining, TLV_HEADER_SIZE)?;
+
+      let tag = remaining[0];
+      let length = message::read_u16(remaining, 1) as usize;
+
+      message::ensure_length("capability", remaining, TLV_HEADER_SIZE + length)?;
+
+      let value = &remaining[TLV_HEADER_SIZE..TLV_HEADER_SIZE + length];
+
+      remaining = &remaining[TLV_HEADER_SIZE + length..];
+
+      match CapabilityTag::try_from(tag) {
+        Ok(CapabilityTag::Versions) => versions = Some(value.to_vec()),
+        Ok(CapabilityTag::Codecs) => codecs = Some(value.to_vec()),
+        Ok(CapabilityTag::FrameDimensions) => {
+          message::ensure_exact_length("frame dimensions capability", value, 4)?;
+
+          frame_dimensions = Some((message::read_u16(value, 0), message::read_u16(value, 2)));
+        },
+        Ok(CapabilityTag::ColourModes) => colour_modes = Some(value.to_vec()),
+        Ok(CapabilityTag::MaxDatagramSize) => {
+          message::ensure_exact_length("max datagram size capability", value, 2)?;
+
+          max_datagram_size = Some(message::read_u16(value, 0));
+        },
+        Ok(CapabilityTag::EncryptionSuites) => encryption_suites = Some(value.to_vec()),
+        Ok(CapabilityTag::ForwardErrorCorrection) => {
+          message::ensure_exact_length("forward error correction capability", value, 2)?;
+
+          forward_error_correction = (value[0], value[1]);
+        },
+        // added by a newer version, nothing to negotiate here
+        Err(_) => {}
+      }
+    }
+
+    let (max_frame_width, max_frame_height) = frame_dimensions.ok_or(PacketError::MissingCapability("frame dimensions"))?;
+
+    Ok((
+      Capabilities {
+        versions: versions.ok_or(PacketError::MissingCapability("versions"))?,
+        codecs: codecs.ok_or(PacketError::MissingCapability("codecs"))?,
+        max_frame_width,
+        max_frame_height,
+        colour_modes: colour_modes.ok_or(PacketError::MissingCapability("colour modes"))?,
+        max_datagram_size: max_datagram_size.ok_or(PacketError::MissingCapability("max datagram size"))?,
+        encryption_suites: encryption_suites.ok_or(PacketError::MissingCapability("encryption suites"))?,
+        max_fec_group_size: forward_error_correction.0,
+        max_fec_parity_count: forward_error_correction.1
+      },
+      &buffer[2 + block_size..]
+    ))
+  }
+}
+
+fn put_tlv(buffer: &mut BytesMut, tag: CapabilityTag, value: &[u8]) {
+  buffer.put_u8(tag as u8);
+  buffer.put_u16(value.len() as u16);
+  buffer.put_slice(value);
+}
+
+fn highest_common(local: &[u8], remote: &[u8]) -> Option<u8> {
+  local.iter().filter(|value| remote.contains(value)).max().copied()
+}
+
+fn no_overlap(capability: &str, local: &[u8], remote: &[u8]) -> String {
+  format!("No common {}: we support {:?}, the peer supports {:?}", capability, local, remote)
+}
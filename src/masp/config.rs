@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+/// Largest payload a single UDP datagram can carry.
+pub const MAX_UDP_PAYLOAD_SIZE: usize = 65507;
+/// Conservative default that stays below common path MTUs.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: u16 = 1200;
+pub const DEFAULT_PEER_TIMEOUT_SECONDS: u16 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MaspConfig {
+  /// Upper bound for a serialized MASP packet, header included.
+  pub max_datagram_size: u16,
+  /// Silence after which the peer is considered gone.
+  pub peer_timeout: Duration,
+  /// Whether video is protected with parity packets when the peer supports them.
+  pub forward_error_correction: bool
+}
+
+impl Default for MaspConfig {
+  fn default() -> Self {
+    Self {
+      max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+      peer_timeout: Duration::from_secs(DEFAULT_PEER_TIMEOUT_SECONDS as u64),
+      forward_error_correction: true
+    }
+  }
+}
@@ -0,0 +1,122 @@
+use tokio::time::{Duration, Instant};
+
+/// Congestion window right after the handshake, in datagrams.
+const INITIAL_WINDOW_PACKETS: usize = 10;
+/// The window never shrinks below this many datagrams.
+const MIN_WINDOW_PACKETS: usize = 4;
+const MAX_WINDOW_BYTES: usize = 4 * 1024 * 1024;
+/// Pacing runs slightly faster than the window so the pipe never drains.
+const PACING_GAIN: f64 = 1.25;
+
+/// AIMD congestion controller with send pacing.
+///
+/// The window grows by the acknowledged bytes during slow start and by
+/// about one datagram per round trip afterwards. A loss halves it, at most
+/// once per round trip. New datagrams wait while the window is full of
+/// unacknowledged ones, and are spread over the round trip at the rate the
+/// window allows instead of being sent in bursts.
+pub struct CongestionController {
+  max_datagram_size: usize,
+  congestion_window: usize,
+  bytes_in_flight: usize,
+  slow_start_threshold: usize,
+  recovery_start: Option<Instant>,
+  next_send_at: Instant
+}
+
+impl CongestionController {
+  pub fn new(max_datagram_size: usize) -> Self {
+    Self {
+      max_datagram_size,
+      congestion_window: INITIAL_WINDOW_PACKETS * max_datagram_size,
+      bytes_in_flight: 0,
+      slow_start_threshold: MAX_WINDOW_BYTES,
+      recovery_start: None,
+      next_send_at: Instant::now()
+    }
+  }
+
+  /// Switches to the datagram size agreed on in the handshake, keeping the
+  /// window at the same number of datagrams.
+  pub fn set_max_datagram_size(&mut self, max_datagram_size: usize) {
+    let window_packets = self.congestion_window / self.max_datagram_size.max(1);
+
+    self.max_datagram_size = max_datagram_size;
+    self.congestion_window = (window_packets * max_datagram_size)
+      .clamp(MIN_WINDOW_PACKETS * max_datagram_size, MAX_WINDOW_BYTES);
+  }
+
+  /// Whether the window has room for a new datagram. An empty pipe always has,
+  /// so a datagram larger than the window isn't held back forever.
+  pub fn can_send(&self, bytes: usize) -> bool {
+    self.bytes_in_flight == 0 || self.bytes_in_flight + bytes <= self.congestion_window
+  }
+
+  /// Counts a new datagram as in flight until it is acknowledged or given up on.
+  /// Retransmissions are already counted.
+  pub fn on_packet_sent(&mut self, bytes: usize) {
+    self.bytes_in_flight += bytes;
+  }
+
+  /// Stops counting datagrams that expired unacknowledged as in flight.
+  pub fn on_packets_expired(&mut self, bytes: usize) {
+    self.bytes_in_flight = self.bytes_in_flight.saturating_sub(bytes);
+  }
+
+  pub fn bytes_in_flight(&self) -> usize {
+    self.bytes_in_flight
+  }
+
+  /// Grows the window for newly acknowledged bytes.
+  pub fn on_packet_acked(&mut self, bytes: usize) {
+    self.bytes_in_flight = self.bytes_in_flight.saturating_sub(bytes);
+
+    if self.congestion_window < self.slow_start_threshold {
+      self.congestion_window += bytes;
+    } else {
+      self.congestion_window += (self.max_datagram_size * bytes / self.congestion_window).max(1);
+    }
+
+    self.congestion_window = self.congestion_window.min(MAX_WINDOW_BYTES);
+  }
+
+  /// Halves the window on loss, ignoring further losses from the same round trip.
+  pub fn on_congestion_event(&mut self, smoothed_rtt: Duration) {
+    let now = Instant::now();
+
+    if let Some(recovery_start) = self.recovery_start {
+      if now.duration_since(recovery_start) < smoothed_rtt {
+        return;
+      }
+    }
+
+    self.recovery_start = Some(now);
+    self.congestion_window = (self.congestion_window / 2)
+      .max(MIN_WINDOW_PACKETS * self.max_datagram_size);
+    self.slow_start_threshold = self.congestion_window;
+  }
+
+  /// Bytes per second the window allows to be sent.
+  pub fn pacing_rate(&self, smoothed_rtt: Duration) -> f64 {
+    let rtt = smoothed_rtt.as_secs_f64().max(0.001);
+
+    self.congestion_window as f64 / rtt * PACING_GAIN
+  }
+
+  /// Bitrate the application should aim its output at, in bits per second.
+  pub fn target_bitrate(&self, smoothed_rtt: Duration) -> u64 {
+    let rtt = smoothed_rtt.as_secs_f64().max(0.001);
+
+    (self.congestion_window as f64 / rtt * 8.0) as u64
+  }
+
+  /// Reserves a send slot for a datagram and returns how long to wait for it.
+  pub fn pacing_delay(&mut self, bytes: usize, smoothed_rtt: Duration) -> Duration {
+    let now = Instant::now();
+    let send_at = self.next_send_at.max(now);
+
+    self.next_send_at = send_at + Duration::from_secs_f64(bytes as f64 / self.pacing_rate(smoothed_rtt));
+
+    send_at - now
+  }
+}
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Mutex;
+
+use chacha20poly1305::aead::{Aead, AeadInPlace, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::message::{MaspPacket, PacketError};
+use super::serial;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+/// Size of a passphrase proof, an HMAC-SHA256.
+pub const PROOF_SIZE: usize = 32;
+
+const PROTOCOL_NAME: &[u8] = b"MASP_X25519_ChaChaPoly_SHA256";
+const PASSPHRASE_LABEL: &[u8] = b"MASP_passphrase";
+/// PBKDF2-HMAC-SHA256 rounds stretching a passphrase, each guess of an offline
+/// attack costs as many once, see `Passphrase` for why only once.
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+/// Nonce stream reserved for handshake key confirmation.
+const CONFIRMATION_STREAM: u8 = 0xFF;
+/// Sequence numbers older than this, relative to the newest one, are rejected.
+const REPLAY_WINDOW_SIZE: u32 = 1024;
+
+/// Which side of the handshake this peer played.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeRole {
+  Initiator = 0x01,
+  Responder = 0x02
+}
+
+/// Secret passphrase both peers were given out of band.
+///
+/// Each side proves it knows the passphrase with an HMAC over a challenge it
+/// can't predict in advance: the initiator over its fresh handshake request,
+/// the responder over the whole handshake transcript. The HMAC key is derived
+/// from the stretched passphrase and the ephemeral keys of the handshake.
+///
+/// The stretch itself is salted with a constant, so it runs once per process
+/// rather than once per handshake, where every unauthenticated request would
+/// cost the responder all of its rounds. The price is that it isn't unique:
+/// an attacker can stretch a dictionary once and then test it cheaply against
+/// every proof they record. Passphrases should be long and random enough to
+/// hold up to that, short words are not.
+#[derive(Clone)]
+pub struct Passphrase {
+  key: [u8; 32]
+}
+
+impl Passphrase {
+  pub fn new(passphrase: &str) -> Self {
+    let keyed = <Hmac<Sha256> as Mac>::new_from_slice(passphrase.as_bytes()).expect("HMAC accepts any key size");
+    // a single PBKDF2 block, the key is exactly one digest long
+    let mut block: [u8; 32] = keyed.clone()
+      .chain_update(PASSPHRASE_LABEL)
+      .chain_update(1u32.to_be_bytes())
+      .finalize()
+      .into_bytes()
+      .into();
+    let mut key = block;
+
+    for _ in 1..PASSPHRASE_ROUNDS {
+      block = keyed.clone().chain_update(block).finalize().into_bytes().into();
+      key.iter_mut().zip(block).for_each(|(key, block)| *key ^= block);
+    }
+
+    Self { key }
+  }
+
+  /// Proof for the handshake whose ephemeral keys are `nonces`.
+  pub fn prove(&self, role: HandshakeRole, nonces: &[u8], challenge: &[u8]) -> [u8; PROOF_SIZE] {
+    self.mac(role, nonces, challenge).finalize().into_bytes().into()
+  }
+
+  pub fn verify(&self, role: HandshakeRole, nonces: &[u8], challenge: &[u8], proof: &[u8]) -> Result<(), ProofError> {
+    self.mac(role, nonces, challenge)
+      .verify_slice(proof)
+      .map_err(|_| ProofError::WrongSecret)
+  }
+
+  fn mac(&self, role: HandshakeRole, nonces: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    let mut key = [0u8; 32];
+
+    Hkdf::<Sha256>::new(Some(nonces), &self.key)
+      .expand(PASSPHRASE_LABEL, &mut key)
+      .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).expect("HMAC accepts any key size");
+
+    // the role keeps a proof from being reflected back to its sender
+    mac.update(&[role as u8]);
+    mac.update(challenge);
+
+    mac
+  }
+}
+
+/// X25519 key pair, either the ephemeral one of a single handshake or the
+/// long-term identity of an install.
+pub struct KeyPair {
+  secret: StaticSecret,
+  pub public: [u8; PUBLIC_KEY_SIZE]
+}
+
+impl KeyPair {
+  pub fn generate() -> Self {
+    Self::from_secret_bytes(StaticSecret::random_from_rng(OsRng).to_bytes())
+  }
+
+  pub fn from_secret_bytes(secret_bytes: [u8; 32]) -> Self {
+    let secret = StaticSecret::from(secret_bytes);
+    let public = PublicKey::from(&secret).to_bytes();
+
+    Self { secret, public }
+  }
+
+  pub fn secret_bytes(&self) -> [u8; 32] {
+    self.secret.to_bytes()
+  }
+
+  fn diffie_hellman(&self, remote_public: &[u8; PUBLIC_KEY_SIZE]) -> Result<[u8; 32], &'static str> {
+    let shared_secret = self.secret.diffie_hellman(&PublicKey::from(*remote_public));
+
+    if !shared_secret.was_contributory() {
+      return Err("Handshake public key is not contributory");
+    }
+
+    Ok(shared_secret.to_bytes())
+  }
+
+  /// Derives the keys of both directions from this ephemeral key pair.
+  ///
+  /// Besides the ephemeral exchange, each side's identity key is combined with
+  /// the other side's ephemeral key, so only the owners of both identities can
+  /// derive the keys and pass key confirmation. Everything is bound to the
+  /// transcript of the public keys, connection identifiers and capabilities
+  /// that were exchanged.
+  pub fn derive_session_keys(
+    &self,
+    role: HandshakeRole,
+    identity: &KeyPair,
+    remote_ephemeral: &[u8],
+    remote_identity: &[u8],
+    parameters_transcript: &[u8]
+  ) -> Result<SessionKeys, &'static str> {
+    let remote_ephemeral: [u8; PUBLIC_KEY_SIZE] = remote_ephemeral
+      .try_into()
+      .map_err(|_| "Malformed handshake public key")?;
+    let remote_identity: [u8; PUBLIC_KEY_SIZE] = remote_identity
+      .try_into()
+      .map_err(|_| "Malformed identity public key")?;
+
+    let ephemeral_secret = self.diffie_hellman(&remote_ephemeral)?;
+    let (initiator_keys, responder_keys, ephemeral_identity_secret, identity_ephemeral_secret) = match role {
+      HandshakeRole::Initiator => (
+        [self.public, identity.public],
+        [remote_ephemeral, remote_identity],
+        self.diffie_hellman(&remote_identity)?,
+        identity.diffie_hellman(&remote_ephemeral)?
+      ),
+      HandshakeRole::Responder => (
+        [remote_ephemeral, remote_identity],
+        [self.public, identity.public],
+        identity.diffie_hellman(&remote_ephemeral)?,
+        self.diffie_hellman(&remote_identity)?
+      )
+    };
+
+    let transcript_hash: [u8; 32] = Sha256::new()
+      .chain_update(PROTOCOL_NAME)
+      .chain_update(initiator_keys.concat())
+      .chain_update(responder_keys.concat())
+      .chain_update(parameters_transcript)
+      .finalize()
+      .into();
+
+    let input_key_material = [ephemeral_secret, ephemeral_identity_secret, identity_ephemeral_secret].concat();
+    let mut okm = [0u8; 64];
+
+    Hkdf::<Sha256>::new(Some(&transcript_hash), &input_key_material)
+      .expand(PROTOCOL_NAME, &mut okm)
+      .map_err(|_| "Failed to derive session keys")?;
+
+    let mut initiator_to_responder = [0u8; 32];
+    let mut responder_to_initiator = [0u8; 32];
+
+    initiator_to_responder.copy_from_slice(&okm[..32]);
+    responder_to_initiator.copy_from_slice(&okm[32..]);
+
+    Ok(SessionKeys {
+      role,
+      initiator_to_responder,
+      responder_to_initiator,
+      transcript_hash,
+      remote_identity
+    })
+  }
+}
+
+/// Directional keys agreed on during the handshake.
+#[derive(Clone)]
+pub struct SessionKeys {
+  role: HandshakeRole,
+  initiator_to_responder: [u8; 32],
+  responder_to_initiator: [u8; 32],
+  transcript_hash: [u8; 32],
+  remote_identity: [u8; PUBLIC_KEY_SIZE]
+}
+
+impl SessionKeys {
+  fn sealing_key(&self) -> &[u8; 32] {
+    match self.role {
+      HandshakeRole::Initiator => &self.initiator_to_responder,
+      HandshakeRole::Responder => &self.responder_to_initiator
+    }
+  }
+
+  fn opening_key(&self) -> &[u8; 32] {
+    match self.role {
+      HandshakeRole::Initiator => &self.responder_to_initiator,
+      HandshakeRole::Responder => &self.initiator_to_responder
+    }
+  }
+
+  /// Long-term identity key the remote peer authenticated with.
+  pub fn remote_identity(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+    &self.remote_identity
+  }
+
+  /// Hash of the public keys exchanged during the handshake.
+  pub fn transcript_hash(&self) -> &[u8; 32] {
+    &self.transcript_hash
+  }
+
+  /// Tag proving to the remote peer that we derived the same keys.
+  pub fn confirmation_tag(&self) -> [u8; TAG_SIZE] {
+    seal_confirmation(self.sealing_key(), &self.transcript_hash)
+  }
+
+  pub fn verify_confirmation_tag(&self, tag: &[u8]) -> Result<(), &'static str> {
+    let expected = seal_confirmation(self.opening_key(), &self.transcript_hash);
+
+    if expected != tag {
+      return Err("Handshake key confirmation failed");
+    }
+
+    Ok(())
+  }
+
+  pub fn cipher(&self) -> PacketCipher {
+    PacketCipher {
+      sealing: ChaCha20Poly1305::new(Key::from_slice(self.sealing_key())),
+      opening: ChaCha20Poly1305::new(Key::from_slice(self.opening_key())),
+      replay_windows: Mutex::new(HashMap::new())
+    }
+  }
+}
+
+fn seal_confirmation(key: &[u8; 32], transcript_hash: &[u8; 32]) -> [u8; TAG_SIZE] {
+  let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+  let nonce = build_nonce(CONFIRMATION_STREAM, 0);
+
+  // an empty message can't exceed the cipher's limits
+  cipher
+    .encrypt_in_place_detached(&nonce, transcript_hash, &mut [])
+    .map(Into::into)
+    .unwrap_or_default()
+}
+
+/// 96-bit nonce made of the sequence space and the sequence number.
+/// Together they never repeat under one key, retransmissions reuse the nonce
+/// of identical plaintext.
+fn build_nonce(stream: u8, sequence_number: u32) -> Nonce {
+  let mut nonce = [0u8; 12];
+
+  nonce[0] = stream;
+  nonce[8..].copy_from_slice(&sequence_number.to_be_bytes());
+
+  *Nonce::from_slice(&nonce)
+}
+
+/// Sequence space of a packet: its delivery class, or zero for control packets.
+fn stream_of(packet: &MaspPacket) -> u8 {
+  packet.packet_type.delivery_class().map_or(0, |delivery_class| delivery_class as u8)
+}
+
+/// How an authenticated packet's sequence number compares to those accepted before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arrival {
+  /// Ahead of every sequence number of its stream so far.
+  Newest,
+  /// Not seen before, but behind the newest one: reordered on the way, or an old packet re-injected.
+  Late,
+  /// Seen before, still within the replay window: a retransmission whose ack
+  /// got lost, or a replay. Worth acknowledging again, never worth delivering.
+  Duplicate
+}
+
+/// Authenticated encryption of MASP payloads, the header is bound as associated data.
+pub struct PacketCipher {
+  sealing: ChaCha20Poly1305,
+  opening: ChaCha20Poly1305,
+  replay_windows: Mutex<HashMap<u8, ReplayWindow>>
+}
+
+impl PacketCipher {
+  pub fn seal(&self, packet: &MaspPacket) -> Result<MaspPacket, &'static str> {
+    let nonce = build_nonce(stream_of(packet), packet.sequence_number);
+    // the header goes out declaring the length of the ciphertext
+    let header = packet.serialize_header(packet.payload.len() + TAG_SIZE);
+    let ciphertext = self.sealing
+      .encrypt(&nonce, Payload { msg: &packet.payload, aad: &header })
+      .map_err(|_| "Failed to encrypt packet")?;
+
+    let mut sealed = packet.clone();
+    sealed.payload = ciphertext;
+
+    Ok(sealed)
+  }
+
+  /// Decrypts the payload and tells whether its sequence number was seen before.
+  /// Sequence numbers that fell out of the replay window are rejected.
+  pub fn open(&self, packet: &MaspPacket) -> Result<(MaspPacket, Arrival), &'static str> {
+    let stream = stream_of(packet);
+    let nonce = build_nonce(stream, packet.sequence_number);
+    let header = packet.serialize_header(packet.payload.len());
+    let plaintext = self.opening
+      .decrypt(&nonce, Payload { msg: &packet.payload, aad: &header })
+      .map_err(|_| "Failed to authenticate packet")?;
+
+    let mut replay_windows = self.replay_windows.lock().map_err(|_| "Replay window poisoned")?;
+
+    let arrival = replay_windows
+      .entry(stream)
+      .or_insert_with(ReplayWindow::new)
+      .check_and_update(packet.sequence_number)
+      .ok_or("Packet too old to tell from a replay")?;
+
+    let mut opened = packet.clone();
+    opened.payload = plaintext;
+
+    Ok((opened, arrival))
+  }
+}
+
+/// Sliding window of recently accepted sequence numbers.
+struct ReplayWindow {
+  highest: Option<u32>,
+  seen: Vec<bool>
+}
+
+impl ReplayWindow {
+  fn new() -> Self {
+    Self {
+      highest: None,
+      seen: vec![false; REPLAY_WINDOW_SIZE as usize]
+    }
+  }
+
+  /// Marks the sequence number as seen, none when it is too old to tell.
+  fn check_and_update(&mut self, sequence_number: u32) -> Option<Arrival> {
+    let slot = (sequence_number % REPLAY_WINDOW_SIZE) as usize;
+
+    let Some(highest) = self.highest else {
+      self.highest = Some(sequence_number);
+      self.seen[slot] = true;
+      return Some(Arrival::Newest);
+    };
+
+    if serial::is_before(highest, sequence_number) {
+      let ahead = sequence_number.wrapping_sub(highest);
+
+      // slide forward, forgetting the slots that fall out of the window
+      for step in 1..=ahead.min(REPLAY_WINDOW_SIZE) {
+        self.seen[(highest.wrapping_add(step) % REPLAY_WINDOW_SIZE) as usize] = false;
+      }
+
+      self.highest = Some(sequence_number);
+      self.seen[slot] = true;
+      return Some(Arrival::Newest);
+    }
+
+    let behind = highest.wrapping_sub(sequence_number);
+
+    if behind >= REPLAY_WINDOW_SIZE {
+      return None;
+    }
+
+    if self.seen[slot] {
+      return Some(Arrival::Duplicate);
+    }
+
+    self.seen[slot] = true;
+    Some(Arrival::Late)
+  }
+}
+
+/// Why a passphrase proof was turned down. The peer is told with a `HandshakeReject`,
+/// so both sides can report it instead of the initiator timing out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProofError {
+  /// Both peers have a secret, but not the same one.
+  WrongSecret = 0x01,
+  /// We expect a secret the peer didn't prove.
+  MissingProof = 0x02,
+  /// The peer proved a secret we weren't given.
+  UnexpectedProof = 0x03
+}
+
+impl ProofError {
+  /// How the peer whose proof was turned down reports it.
+  pub fn rejection(&self) -> &'static str {
+    match self {
+      ProofError::WrongSecret => "Peer rejected our secret, both sides must pass the same --secret",
+      ProofError::MissingProof => "Peer requires a secret, pass the shared one with --secret",
+      ProofError::UnexpectedProof => "Peer wasn't given a secret, leave out --secret"
+    }
+  }
+}
+
+impl TryFrom<u8> for ProofError {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(ProofError::WrongSecret),
+      0x02 => Ok(ProofError::MissingProof),
+      0x03 => Ok(ProofError::UnexpectedProof),
+      _ => Err(PacketError::UnknownValue { field: "rejection reason", value })
+    }
+  }
+}
+
+impl fmt::Display for ProofError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ProofError::WrongSecret => write!(f, "Peer proved a different secret"),
+      ProofError::MissingProof => write!(f, "Peer did not prove knowledge of the secret"),
+      ProofError::UnexpectedProof => write!(f, "Peer requires a secret, pass the shared one with --secret")
+    }
+  }
+}
+
+impl std::error::Error for ProofError {}
+
+/// Checks the passphrase proof sent by the peer playing `role`, a proof must be
+/// present exactly when we expect a passphrase.
+pub fn verify_passphrase_proof(
+  passphrase: Option<&Passphrase>,
+  role: HandshakeRole,
+  nonces: &[u8],
+  challenge: &[u8],
+  proof: Option<&[u8]>
+) -> Result<(), ProofError> {
+  match (passphrase, proof) {
+    (Some(passphrase), Some(proof)) => passphrase.verify(role, nonces, challenge, proof),
+    (Some(_), None) => Err(ProofError::MissingProof),
+    (None, Some(_)) => Err(ProofError::UnexpectedProof),
+    (None, None) => Ok(())
+  }
+}
@@ -0,0 +1,308 @@
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::message::{self, MaspPacket, PacketError, PacketType};
+
+/// Largest group of data fragments one set of parity packets protects.
+pub const MAX_GROUP_SIZE: u8 = 16;
+/// Most parity packets sent for a single group.
+pub const MAX_PARITY_COUNT: u8 = 4;
+/// Bytes in front of the XOR data of a parity payload.
+pub const PARITY_HEADER_SIZE: usize = 11;
+
+/// Packets sent between two updates of the parity count.
+const LOSS_SAMPLE_PACKETS: u32 = 64;
+/// Weight of the newest loss sample in the smoothed loss rate.
+const LOSS_RATE_GAIN: f64 = 0.25;
+/// Parity provisioned per expected loss, covers bursts above the average.
+const PARITY_HEADROOM: f64 = 2.0;
+
+/// Forward error correction settings both peers agreed on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FecParameters {
+  /// Data fragments per group.
+  pub group_size: u8,
+  /// Most parity packets a group may carry, the sender picks fewer on a clean link.
+  pub max_parity_count: u8
+}
+
+/// XOR parity over one stripe of a group of data fragments.
+///
+/// With `stride` parity packets per group, stripe `s` covers every fragment
+/// `first_fragment + s + k * stride` of the group, so any loss per stripe can be
+/// rebuilt and a burst of up to `stride` consecutive losses is recovered in full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parity {
+  pub first_fragment: u16,
+  /// Sequence number of the first data fragment, the group's are consecutive.
+  pub first_sequence_number: u32,
+  /// Data fragments in the group, the last group of a frame may be short.
+  pub group_size: u8,
+  pub stride: u8,
+  pub stripe: u8,
+  /// XOR of the covered fragment lengths, gives back the length of a rebuilt one.
+  pub length_xor: u16,
+  pub data: Vec<u8>
+}
+
+impl Parity {
+  /// Offsets within the group of the fragments this parity covers.
+  fn covered_offsets(&self) -> impl Iterator<Item = u16> {
+    (self.stripe as u16..self.group_size as u16).step_by(self.stride.max(1) as usize)
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(PARITY_HEADER_SIZE + self.data.len());
+
+    buffer.put_u16(self.first_fragment);
+    buffer.put_u32(self.first_sequence_number);
+    buffer.put_u8(self.group_size);
+    buffer.put_u8(self.stride);
+    buffer.put_u8(self.stripe);
+    buffer.put_u16(self.length_xor);
+    buffer.put_slice(&self.data);
+
+    buffer.to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_length("parity", buffer, PARITY_HEADER_SIZE)?;
+
+    let parity = Self {
+      first_fragment: message::read_u16(buffer, 0),
+      first_sequence_number: message::read_u32(buffer, 2),
+      group_size: buffer[6],
+      stride: buffer[7],
+      stripe: buffer[8],
+      length_xor: message::read_u16(buffer, 9),
+      data: buffer[PARITY_HEADER_SIZE..].to_vec()
+    };
+
+    if parity.group_size == 0 || parity.stride == 0 || parity.stripe >= parity.stride {
+      return Err(PacketError::InvalidField("parity stripe"));
+    }
+
+    Ok(parity)
+  }
+}
+
+/// Computes the parity packets of a group of consecutive data fragments.
+pub fn encode_group(
+  fragments: &[Vec<u8>],
+  first_fragment: u16,
+  first_sequence_number: u32,
+  parity_count: u8
+) -> Vec<Parity> {
+  let stride = parity_count.min(fragments.len() as u8);
+
+  (0..stride)
+    .map(|stripe| {
+      let mut parity = Parity {
+        first_fragment,
+        first_sequence_number,
+        group_size: fragments.len() as u8,
+        stride,
+        stripe,
+        length_xor: 0,
+        data: Vec::new()
+      };
+
+      for offset in parity.covered_offsets().collect::<Vec<u16>>() {
+        let fragment = &fragments[offset as usize];
+
+        parity.length_xor ^= fragment.len() as u16;
+        xor_into(&mut parity.data, fragment);
+      }
+
+      parity
+    })
+    .collect()
+}
+
+fn xor_into(target: &mut Vec<u8>, source: &[u8]) {
+  if target.len() < source.len() {
+    target.resize(source.len(), 0);
+  }
+
+  target.iter_mut().zip(source).for_each(|(byte, source_byte)| *byte ^= source_byte);
+}
+
+struct FrameParities {
+  fragment_count: u16,
+  fragments: HashMap<u16, Vec<u8>>,
+  parities: Vec<Parity>,
+  first_seen: Instant
+}
+
+/// Rebuilds lost data fragments of a frame from its parity packets.
+pub struct FecDecoder {
+  frames: HashMap<u32, FrameParities>,
+  timeout: Duration
+}
+
+impl FecDecoder {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      frames: HashMap::new(),
+      timeout
+    }
+  }
+
+  /// Stores a data or parity packet and returns the data packets it let rebuild.
+  pub fn insert(&mut self, packet: &MaspPacket) -> Vec<MaspPacket> {
+    let frame = self.frames.entry(packet.frame_id).or_insert_with(|| {
+      FrameParities {
+        fragment_count: packet.fragment_count,
+        fragments: HashMap::new(),
+        parities: Vec::new(),
+        first_seen: Instant::now()
+      }
+    });
+
+    match packet.packet_type {
+      PacketType::VideoParity => match Parity::deserialize(&packet.payload) {
+        // a group reaching past the frame can't be trusted to rebuild any of it
+        Ok(parity) if parity.first_fragment as usize + parity.group_size as usize <= frame.fragment_count as usize => {
+          frame.parities.push(parity);
+        },
+        _ => return Vec::new()
+      },
+      _ => {
+        frame.fragments.insert(packet.fragment_index, packet.payload.clone());
+      }
+    }
+
+    let recovered = recover(frame);
+
+    recovered
+      .into_iter()
+      .map(|(fragment_index, sequence_number, payload)| {
+        MaspPacket::new_fragment(
+          PacketType::VideoData,
+          sequence_number,
+          packet.frame_id,
+          fragment_index,
+          frame.fragment_count,
+          payload
+        )
+      })
+      .collect()
+  }
+
+  /// Forgets a frame that no longer needs repairs.
+  pub fn remove(&mut self, frame_id: u32) {
+    self.frames.remove(&frame_id);
+  }
+
+  /// Drops frames too old to be rendered anyway.
+  pub fn evict_expired(&mut self) {
+    let timeout = self.timeout;
+
+    self.frames.retain(|_, frame| frame.first_seen.elapsed() < timeout);
+  }
+}
+
+/// Rebuilds every fragment that is the only one missing from a stripe,
+/// returning their indices, sequence numbers and payloads.
+fn recover(frame: &mut FrameParities) -> Vec<(u16, u32, Vec<u8>)> {
+  let mut recovered = Vec::new();
+  let mut progress = true;
+
+  while progress {
+    progress = false;
+
+    for parity in &frame.parities {
+      let covered: Vec<u16> = parity.covered_offsets().collect();
+      let missing: Vec<u16> = covered
+        .iter()
+        .copied()
+        .filter(|offset| !frame.fragments.contains_key(&(parity.first_fragment + offset)))
+        .collect();
+
+      let [missing_offset] = missing[..] else {
+        continue;
+      };
+
+      let mut data = parity.data.clone();
+      let mut length = parity.length_xor;
+
+      for offset in covered.iter().filter(|offset| **offset != missing_offset) {
+        let fragment = &frame.fragments[&(parity.first_fragment + offset)];
+
+        length ^= fragment.len() as u16;
+        xor_into(&mut data, fragment);
+      }
+
+      // a length past the parity data means the parity doesn't match the fragments
+      if length as usize > data.len() {
+        continue;
+      }
+
+      data.truncate(length as usize);
+
+      let fragment_index = parity.first_fragment + missing_offset;
+      let sequence_number = parity.first_sequence_number.wrapping_add(missing_offset as u32);
+
+      frame.fragments.insert(fragment_index, data.clone());
+      recovered.push((fragment_index, sequence_number, data));
+      progress = true;
+    }
+  }
+
+  recovered
+}
+
+/// Picks how many parity packets protect each group from the loss the receiver reports.
+pub struct FecController {
+  parameters: FecParameters,
+  sent: u32,
+  lost: u32,
+  loss_rate: f64,
+  parity_count: u8
+}
+
+impl FecController {
+  pub fn new(parameters: FecParameters) -> Self {
+    Self {
+      parameters,
+      sent: 0,
+      lost: 0,
+      loss_rate: 0.0,
+      // protected from the start, until the link shows how lossy it is
+      parity_count: 1.min(parameters.max_parity_count)
+    }
+  }
+
+  pub fn group_size(&self) -> u8 {
+    self.parameters.group_size
+  }
+
+  pub fn parity_count(&self) -> u8 {
+    self.parity_count
+  }
+
+  pub fn on_packets_sent(&mut self, count: u32) {
+    self.sent += count;
+
+    if self.sent < LOSS_SAMPLE_PACKETS {
+      return;
+    }
+
+    let sample = (self.lost as f64 / self.sent as f64).min(1.0);
+
+    self.loss_rate += LOSS_RATE_GAIN * (sample - self.loss_rate);
+    self.sent = 0;
+    self.lost = 0;
+
+    let parity_count = (self.loss_rate * self.parameters.group_size as f64 * PARITY_HEADROOM).ceil();
+
+    self.parity_count = (parity_count as u8).min(self.parameters.max_parity_count);
+  }
+
+  /// Counts video packets the receiver reported missing, whether or not parity
+  /// rebuilt them. Lost parity packets are left out, as they are of `on_packets_sent`.
+  pub fn on_packets_lost(&mut self, count: u32) {
+    self.lost += count;
+  }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::message::MaspPacket;
+
+/// Splits a frame payload into chunks of at most `max_fragment_size` bytes.
+/// An empty payload still produces a single empty fragment.
+pub fn split_payload(payload: &[u8], max_fragment_size: usize) -> Result<Vec<Vec<u8>>, &'static str> {
+  if max_fragment_size == 0 {
+    return Err("Max fragment size must be positive");
+  }
+
+  if payload.is_empty() {
+    return Ok(vec![Vec::new()]);
+  }
+
+  let fragments: Vec<Vec<u8>> = payload
+    .chunks(max_fragment_size)
+    .map(|chunk| chunk.to_vec())
+    .collect();
+
+  if fragments.len() > u16::MAX as usize {
+    return Err("Frame is too large to be fragmented");
+  }
+
+  Ok(fragments)
+}
+
+struct PartialFrame {
+  fragments: Vec<Option<Vec<u8>>>,
+  received: usize,
+  first_seen: Instant
+}
+
+/// Collects fragments until every piece of a frame has arrived.
+pub struct FrameReassembler {
+  frames: HashMap<u32, PartialFrame>,
+  timeout: Duration
+}
+
+impl FrameReassembler {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      frames: HashMap::new(),
+      timeout
+    }
+  }
+
+  /// Stores the fragment and returns the whole frame payload once it is complete.
+  pub fn insert(&mut self, packet: &MaspPacket) -> Option<Vec<u8>> {
+    if packet.fragment_count == 1 {
+      return Some(packet.payload.clone());
+    }
+
+    let fragment_count = packet.fragment_count as usize;
+    let frame = self.frames.entry(packet.frame_id).or_insert_with(|| {
+      PartialFrame {
+        fragments: vec![None; fragment_count],
+        received: 0,
+        first_seen: Instant::now()
+      }
+    });
+
+    // a frame id reused with a different layout means the old frame is stale
+    if frame.fragments.len() != fragment_count {
+      *frame = PartialFrame {
+        fragments: vec![None; fragment_count],
+        received: 0,
+        first_seen: Instant::now()
+      };
+    }
+
+    let slot = &mut frame.fragments[packet.fragment_index as usize];
+
+    if slot.is_none() {
+      *slot = Some(packet.payload.clone());
+      frame.received += 1;
+    }
+
+    if frame.received < fragment_count {
+      return None;
+    }
+
+    let frame = self.frames.remove(&packet.frame_id)?;
+
+    Some(frame.fragments.into_iter().flatten().flatten().collect())
+  }
+
+  /// Drops frames whose fragments did not all arrive in time.
+  pub fn evict_expired(&mut self) {
+    let timeout = self.timeout;
+
+    self.frames.retain(|_, frame| frame.first_seen.elapsed() < timeout);
+  }
+}
@@ -0,0 +1,388 @@
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::time::{sleep, Duration};
+
+use super::capabilities::{Capabilities, SessionCapabilities};
+use super::crypto::{self, HandshakeRole, KeyPair, Passphrase, ProofError, SessionKeys, PUBLIC_KEY_SIZE};
+use super::message::{MaspPacket, PacketType};
+use super::payload::{HandshakeAck, HandshakeFinalAck, HandshakeParameters, HandshakeReject, HandshakeRequest};
+use super::reconnect::ConnectionStatus;
+use super::socket::{Inbox, MaspSocket};
+
+/// Requests sent when setting up a session before giving up.
+pub const MAX_HANDSHAKE_ATTEMPTS: u8 = 3;
+const HANDSHAKE_TIMEOUT_SECONDS: u8 = 3;
+const FINAL_ACK_TIMEOUT_SECONDS: u8 = 3;
+
+/// Three-way handshake of a session, run once to set it up and again each
+/// time it has to be resumed after a network loss.
+///
+/// The request carries the initiator's ephemeral and identity X25519 public keys,
+/// the acknowledgment carries the responder's keys and proof that it derived the
+/// same session keys, and the final acknowledgment returns that proof.
+/// Both the request and the acknowledgment advertise the capabilities of their
+/// sender, and both sides settle on the highest common set. They also carry the
+/// connection identifier their sender wants to see in every packet it receives.
+/// With a passphrase, the request and the acknowledgment also prove knowledge of it,
+/// and a proof that fails is answered with a rejection instead of silence.
+pub struct Handshake {
+  socket: MaspSocket,
+  inbox: Inbox,
+  capabilities: Capabilities,
+  passphrase: Option<Passphrase>,
+  /// Identity of the peer once the session is set up, a resumed session must keep it.
+  remote_identity: Option<[u8; PUBLIC_KEY_SIZE]>,
+  /// Takes the progress reports once video is on screen, they are printed until then.
+  status: Option<ConnectionStatus>
+}
+
+impl Handshake {
+  pub fn new(socket: MaspSocket, inbox: Inbox, capabilities: Capabilities, passphrase: Option<Passphrase>) -> Self {
+    Self {
+      socket,
+      inbox,
+      capabilities,
+      passphrase,
+      remote_identity: None,
+      status: None
+    }
+  }
+
+  /// Reports the progress of later handshakes, i.e. reconnections, on the status
+  /// line rather than printing it over the video.
+  pub fn report_to(&mut self, status: ConnectionStatus) {
+    self.status = Some(status);
+  }
+
+  /// Runs the handshake as initiator, sending up to `attempts` requests.
+  /// The session keys are in use by the socket once this returns.
+  pub async fn initiate(
+    &mut self,
+    identity: &KeyPair,
+    attempts: u8
+  ) -> Result<(SessionKeys, SessionCapabilities), Box<dyn std::error::Error>> {
+    let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS as u64);
+    let key_pair = KeyPair::generate();
+    let mut request = HandshakeRequest {
+      ephemeral_key: key_pair.public,
+      identity_key: identity.public,
+      parameters: HandshakeParameters::new(self.socket.local_connection_id(), &self.capabilities),
+      proof: None
+    };
+
+    request.proof = self.passphrase
+      .as_ref()
+      .map(|passphrase| passphrase.prove(HandshakeRole::Initiator, &request.ephemeral_key, &request.challenge()));
+
+    for attempt in 0..attempts {
+      self.report(format!("Sending handshake attempt: {}", attempt));
+
+      // answers to earlier attempts would only fail key confirmation
+      while self.inbox.try_recv().is_ok() {}
+
+      // handshake packets retry on their own and stay out of the data sequence spaces
+      let request_packet = MaspPacket::new(
+        PacketType::HandshakeRequest,
+        attempt as u32,
+        request.serialize()
+      );
+
+      self.socket.send(&request_packet).await?;
+
+      match self.receive_handshake_ack(&key_pair, identity, &request.parameters, timeout).await {
+        Ok((session_keys, remote_capabilities, remote_connection_id)) => {
+          self.report("Handshake acknowledged".to_string());
+
+          // incompatible peers stay incompatible, retrying won't help
+          let session_capabilities = self.capabilities.negotiate(&remote_capabilities)?;
+
+          self.socket.set_remote_connection_id(remote_connection_id);
+
+          // Send final acknowledgment
+          let final_ack = HandshakeFinalAck { confirmation_tag: session_keys.confirmation_tag() };
+          let ack_packet = MaspPacket::new(
+            PacketType::HandshakeFinalAck,
+            attempt as u32,
+            final_ack.serialize()
+          );
+
+          self.socket.send(&ack_packet).await?;
+          self.socket.set_session_keys(&session_keys);
+          self.remote_identity = Some(*session_keys.remote_identity());
+
+          self.report("Handshake completed".to_string());
+
+          return Ok((session_keys, session_capabilities));
+        }
+        // a secret that doesn't match won't match on the next attempt either
+        Err(e) if e.is::<Rejected>() => return Err(e),
+        Err(e) => {
+          self.report(format!("Handshake attempt {} failed: {}", attempt, e));
+
+          if attempt == (attempts - 1) {
+            return Err(format!("Handshake failed after {} attempts: {}", attempts, e).into());
+          }
+        }
+      }
+    }
+
+    Err("Handshake failed".into())
+  }
+
+  /// Waits for a handshake request from the peer and answers it as responder.
+  /// Requests from other hosts than the expected peer, or without proof of the
+  /// passphrase when one is set, are rejected before anything is answered.
+  /// Resumptions of an established session are accepted from any host, as long
+  /// as they come with the identity key the session was set up with.
+  /// Peers without common capabilities still get our acknowledgment, so they
+  /// can tell what is missing, but the handshake isn't completed.
+  /// The session only moves to the new keys, address and connection identifier
+  /// once the final acknowledgment proves the request was genuine.
+  pub async fn respond(&mut self, identity: &KeyPair) -> Result<(SessionKeys, SessionCapabilities), Box<dyn std::error::Error>> {
+    loop {
+      let (packet, addr) = self.inbox.recv().await.ok_or("Session socket closed")?;
+
+      if packet.packet_type != PacketType::HandshakeRequest {
+        continue;
+      }
+
+      // the peer's NAT may pick another port, but not another host. Once a session
+      // is set up its peer may resume from anywhere, its identity key and the
+      // final acknowledgment from the new address vouch for it
+      if self.remote_identity.is_none() && self.socket.remote_addr().ip() != addr.ip() {
+        self.report(format!("Ignoring handshake request from unexpected address {}", addr));
+        continue;
+      }
+
+      self.report(format!("Received handshake request from {}", addr));
+
+      let request = match HandshakeRequest::deserialize(&packet.payload) {
+        Ok(request) => request,
+        Err(e) => {
+          self.report(format!("Handshake error: {}", e));
+          continue;
+        }
+      };
+
+      if let Err(e) = crypto::verify_passphrase_proof(
+        self.passphrase.as_ref(),
+        HandshakeRole::Initiator,
+        &request.ephemeral_key,
+        &request.challenge(),
+        request.proof.as_ref().map(|proof| proof.as_slice())
+      ) {
+        self.report(format!("Rejected handshake request from {}: {}", addr, e));
+
+        self.reject(e, addr, request.parameters.connection_id, packet.sequence_number).await?;
+        continue;
+      }
+
+      if let Err(e) = self.check_remote_identity(&request.identity_key) {
+        self.report(format!("Rejected handshake request from {}: {}", addr, e));
+        continue;
+      }
+
+      let ack_parameters = HandshakeParameters::new(self.socket.local_connection_id(), &self.capabilities);
+      let key_pair = KeyPair::generate();
+      let session_keys = match key_pair.derive_session_keys(
+        HandshakeRole::Responder,
+        identity,
+        &request.ephemeral_key,
+        &request.identity_key,
+        &[request.parameters.as_bytes(), ack_parameters.as_bytes()].concat()
+      ) {
+        Ok(session_keys) => session_keys,
+        Err(e) => {
+          self.report(format!("Handshake error: {}", e));
+          continue;
+        }
+      };
+      let remote_connection_id = request.parameters.connection_id;
+
+      // Send handshake acknowledgment
+      let ack = HandshakeAck {
+        ephemeral_key: key_pair.public,
+        identity_key: identity.public,
+        confirmation_tag: session_keys.confirmation_tag(),
+        parameters: ack_parameters,
+        proof: self.passphrase.as_ref().map(|passphrase| {
+          passphrase.prove(
+            HandshakeRole::Responder,
+            &[request.ephemeral_key, key_pair.public].concat(),
+            session_keys.transcript_hash()
+          )
+        })
+      };
+
+      let ack_packet = MaspPacket::new(
+        PacketType::HandshakeAck,
+        packet.sequence_number,
+        ack.serialize()
+      );
+
+      self.socket.send_to(&ack_packet, addr, remote_connection_id).await?;
+
+      let session_capabilities = match self.capabilities.negotiate(&request.parameters.capabilities) {
+        Ok(session_capabilities) => session_capabilities,
+        Err(e) => {
+          self.report(format!("Rejected handshake request from {}: {}", addr, e));
+          continue;
+        }
+      };
+
+      // Wait for final acknowledgment
+      let final_ack_result = self.receive_final_ack(
+        &session_keys,
+        addr,
+        Duration::from_secs(FINAL_ACK_TIMEOUT_SECONDS as u64)
+      ).await;
+
+      match final_ack_result {
+        Ok(_) => {
+          self.report(format!("Handshake completed with {}", addr));
+
+          self.socket.set_remote_addr(addr);
+          self.socket.set_remote_connection_id(remote_connection_id);
+          self.socket.set_session_keys(&session_keys);
+          self.remote_identity = Some(*session_keys.remote_identity());
+
+          return Ok((session_keys, session_capabilities));
+        }
+        Err(e) => {
+          self.report(format!("Handshake error: {}", e));
+        }
+      }
+    }
+  }
+
+  /// A resumed session must be with the same peer it was set up with.
+  fn check_remote_identity(&self, remote_identity: &[u8]) -> Result<(), &'static str> {
+    match self.remote_identity {
+      Some(known_identity) if known_identity != remote_identity => Err("Peer identity differs from the session's"),
+      _ => Ok(())
+    }
+  }
+
+  async fn receive_handshake_ack(
+    &mut self,
+    key_pair: &KeyPair,
+    identity: &KeyPair,
+    request_parameters: &HandshakeParameters,
+    timeout: Duration
+  ) -> Result<(SessionKeys, Capabilities, u32), Box<dyn std::error::Error>> {
+    let (packet, addr) = tokio::select! {
+      datagram = self.inbox.recv() => datagram.ok_or("Session socket closed")?,
+      _ = sleep(timeout) => return Err("Timeout waiting for handshake acknowledgment".into())
+    };
+
+    if addr != self.socket.remote_addr() {
+      return Err("Received packet from unexpected address".into());
+    }
+
+    if packet.packet_type == PacketType::HandshakeReject {
+      let reject = HandshakeReject::deserialize(&packet.payload)?;
+
+      return Err(Box::new(Rejected(reject.reason.rejection().to_string())));
+    }
+
+    if packet.packet_type != PacketType::HandshakeAck {
+      return Err("Received unexpected packet type".into());
+    }
+
+    let ack = HandshakeAck::deserialize(&packet.payload)?;
+
+    self.check_remote_identity(&ack.identity_key)?;
+
+    let session_keys = key_pair.derive_session_keys(
+      HandshakeRole::Initiator,
+      identity,
+      &ack.ephemeral_key,
+      &ack.identity_key,
+      &[request_parameters.as_bytes(), ack.parameters.as_bytes()].concat()
+    )?;
+
+    session_keys.verify_confirmation_tag(&ack.confirmation_tag)?;
+
+    if let Err(e) = crypto::verify_passphrase_proof(
+      self.passphrase.as_ref(),
+      HandshakeRole::Responder,
+      &[key_pair.public, ack.ephemeral_key].concat(),
+      session_keys.transcript_hash(),
+      ack.proof.as_ref().map(|proof| proof.as_slice())
+    ) {
+      self.reject(e, addr, ack.parameters.connection_id, packet.sequence_number).await?;
+
+      return Err(Box::new(Rejected(e.to_string())));
+    }
+
+    Ok((session_keys, ack.parameters.capabilities, ack.parameters.connection_id))
+  }
+
+  async fn receive_final_ack(
+    &mut self,
+    session_keys: &SessionKeys,
+    remote_addr: SocketAddr,
+    timeout: Duration
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    let (packet, addr) = tokio::select! {
+      datagram = self.inbox.recv() => datagram.ok_or("Session socket closed")?,
+      _ = sleep(timeout) => return Err("Timeout waiting for final acknowledgment".into())
+    };
+
+    if addr != remote_addr {
+      return Err("Received packet from unexpected address".into());
+    }
+
+    if packet.packet_type == PacketType::HandshakeReject {
+      let reject = HandshakeReject::deserialize(&packet.payload)?;
+
+      return Err(reject.reason.rejection().into());
+    }
+
+    if packet.packet_type != PacketType::HandshakeFinalAck {
+      return Err("Received unexpected packet type".into());
+    }
+
+    let final_ack = HandshakeFinalAck::deserialize(&packet.payload)?;
+
+    Ok(session_keys.verify_confirmation_tag(&final_ack.confirmation_tag)?)
+  }
+
+  fn report(&self, message: String) {
+    match &self.status {
+      Some(status) => status.report(message),
+      None => println!("{}", message)
+    }
+  }
+
+  /// Tells the peer its passphrase proof failed, so it can report why.
+  async fn reject(
+    &self,
+    reason: ProofError,
+    addr: SocketAddr,
+    remote_connection_id: u32,
+    sequence_number: u32
+  ) -> Result<(), Box<dyn std::error::Error>> {
+    let reject_packet = MaspPacket::new(
+      PacketType::HandshakeReject,
+      sequence_number,
+      HandshakeReject { reason }.serialize()
+    );
+
+    self.socket.send_to(&reject_packet, addr, remote_connection_id).await?;
+
+    Ok(())
+  }
+}
+
+/// Handshake failure that retrying can't fix: one side turned down the other's passphrase proof.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl fmt::Display for Rejected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for Rejected {}
@@ -1,37 +1,161 @@
 use bytes::{BufMut, BytesMut};
 use std::convert::TryFrom;
+use std::fmt;
+
+use super::reliability::DeliveryClass;
 
 pub const MASP_MAGIC_NUMBER: [u8; 4] = [0x4D, 0x41, 0x53, 0x50]; // 'MASP'
-pub const MASP_VERSION: u8 = 0x01;
+pub const MASP_VERSION: u8 = 0x02;
+/// Oldest version a handshake is negotiated with. Dropping v1 is a deliberate wire
+/// break: its header had no length, flags nor connection identifier and its
+/// handshake carried neither keys nor capabilities, so there is nothing to agree on.
+pub const FIRST_NEGOTIABLE_VERSION: u8 = 0x02;
+/// Fixed part of the header, the checksum follows it when the packet carries one.
+pub const MASP_HEADER_SIZE: usize = 25;
+pub const CHECKSUM_SIZE: usize = 4;
+pub const CONNECTION_ID_SIZE: usize = 4;
+
+/// Header flag: a CRC32C over the header and payload follows the fixed header.
+pub const FLAG_CHECKSUM: u8 = 0x01;
+const KNOWN_FLAGS: u8 = FLAG_CHECKSUM;
+
+/// Why a datagram or one of its payloads was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+  /// Fewer bytes than the structure needs.
+  Truncated { structure: &'static str, expected: usize, actual: usize },
+  /// Bytes left over after the end of the structure.
+  TrailingBytes { structure: &'static str, count: usize },
+  InvalidMagicNumber,
+  UnsupportedVersion(u8),
+  UnknownFlags(u8),
+  /// A field holding a value this build doesn't know.
+  UnknownValue { field: &'static str, value: u8 },
+  PayloadLengthMismatch { declared: usize, actual: usize },
+  ChecksumMismatch { expected: u32, actual: u32 },
+  InvalidFragmentHeader { index: u16, count: u16 },
+  /// A field whose value can't be valid, whatever the version.
+  InvalidField(&'static str),
+  MissingCapability(&'static str)
+}
+
+impl fmt::Display for PacketError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PacketError::Truncated { structure, expected, actual } => {
+        write!(f, "Truncated {}: expected {} bytes, got {}", structure, expected, actual)
+      },
+      PacketError::TrailingBytes { structure, count } => write!(f, "{} trailing bytes after {}", count, structure),
+      PacketError::InvalidMagicNumber => write!(f, "Invalid magic number"),
+      PacketError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}", version),
+      PacketError::UnknownFlags(flags) => write!(f, "Unknown header flags {:#04x}", flags),
+      PacketError::UnknownValue { field, value } => write!(f, "Unknown {} {:#04x}", field, value),
+      PacketError::PayloadLengthMismatch { declared, actual } => {
+        write!(f, "Payload length mismatch: header declares {} bytes, got {}", declared, actual)
+      },
+      PacketError::ChecksumMismatch { expected, actual } => {
+        write!(f, "Checksum mismatch: expected {:#010x}, got {:#010x}", expected, actual)
+      },
+      PacketError::InvalidFragmentHeader { index, count } => write!(f, "Invalid fragment {} of {}", index, count),
+      PacketError::InvalidField(field) => write!(f, "Invalid {}", field),
+      PacketError::MissingCapability(capability) => write!(f, "Missing {} capability", capability)
+    }
+  }
+}
+
+impl std::error::Error for PacketError {}
+
+/// Fails unless `buffer` holds at least `expected` bytes.
+pub fn ensure_length(structure: &'static str, buffer: &[u8], expected: usize) -> Result<(), PacketError> {
+  if buffer.len() < expected {
+    return Err(PacketError::Truncated { structure, expected, actual: buffer.len() });
+  }
+
+  Ok(())
+}
+
+/// Fails unless `buffer` holds exactly `expected` bytes.
+pub fn ensure_exact_length(structure: &'static str, buffer: &[u8], expected: usize) -> Result<(), PacketError> {
+  ensure_length(structure, buffer, expected)?;
+
+  if buffer.len() > expected {
+    return Err(PacketError::TrailingBytes { structure, count: buffer.len() - expected });
+  }
+
+  Ok(())
+}
+
+pub fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+  u16::from_be_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+pub fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+  u32::from_be_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]])
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PacketType {
   HandshakeRequest = 0x01,
   HandshakeAck = 0x02,
   HandshakeFinalAck = 0x03,
+  /// Turns down a handshake whose passphrase proof failed, see `HandshakeReject`.
+  HandshakeReject = 0x04,
   TextData = 0x10,
   AudioData = 0x20,
   VideoData = 0x30,
+  /// XOR parity over a group of video fragments, see `fec`.
+  VideoParity = 0x31,
   Ack = 0x40,
   RetransmissionRequest = 0x50,
-  Punch = 0x60
+  /// Video fragments parity rebuilt, see `RecoveryReport`.
+  RecoveryReport = 0x51,
+  Punch = 0x60,
+  Ping = 0x70,
+  Pong = 0x71,
+  Bye = 0x80
+}
+
+impl PacketType {
+  /// Delivery class of data packets, control packets have none.
+  pub fn delivery_class(&self) -> Option<DeliveryClass> {
+    match self {
+      PacketType::TextData => Some(DeliveryClass::ReliableOrdered),
+      PacketType::AudioData => Some(DeliveryClass::PartiallyReliable),
+      // parity shares the sequence space of the video it protects
+      PacketType::VideoData | PacketType::VideoParity => Some(DeliveryClass::LatestOnly),
+      _ => None
+    }
+  }
+
+  pub fn is_handshake(&self) -> bool {
+    matches!(
+      self,
+      PacketType::HandshakeRequest | PacketType::HandshakeAck | PacketType::HandshakeFinalAck | PacketType::HandshakeReject
+    )
+  }
 }
 
 impl TryFrom<u8> for PacketType {
-  type Error = &'static str;
+  type Error = PacketError;
 
   fn try_from(value: u8) -> Result<Self, Self::Error> {
     match value {
       0x01 => Ok(PacketType::HandshakeRequest),
       0x02 => Ok(PacketType::HandshakeAck),
       0x03 => Ok(PacketType::HandshakeFinalAck),
+      0x04 => Ok(PacketType::HandshakeReject),
       0x10 => Ok(PacketType::TextData),
       0x20 => Ok(PacketType::AudioData),
       0x30 => Ok(PacketType::VideoData),
+      0x31 => Ok(PacketType::VideoParity),
       0x40 => Ok(PacketType::Ack),
       0x50 => Ok(PacketType::RetransmissionRequest),
+      0x51 => Ok(PacketType::RecoveryReport),
       0x60 => Ok(PacketType::Punch),
-      _ => Err("Invalid packet type"),
+      0x70 => Ok(PacketType::Ping),
+      0x71 => Ok(PacketType::Pong),
+      0x80 => Ok(PacketType::Bye),
+      _ => Err(PacketError::UnknownValue { field: "packet type", value }),
     }
   }
 }
@@ -40,7 +164,14 @@ impl TryFrom<u8> for PacketType {
 pub struct MaspPacket {
   pub version: u8,
   pub packet_type: PacketType,
+  /// Header flags, see `FLAG_CHECKSUM`.
+  pub flags: u8,
+  /// Identifier the receiving peer chose for the session, zero until it is known.
+  pub connection_id: u32,
   pub sequence_number: u32,
+  pub frame_id: u32,
+  pub fragment_index: u16,
+  pub fragment_count: u16,
   pub payload: Vec<u8>,
 }
 
@@ -49,56 +180,157 @@ impl MaspPacket {
     Self {
       version: MASP_VERSION,
       packet_type,
+      flags: 0,
+      connection_id: 0,
+      sequence_number,
+      frame_id: 0,
+      fragment_index: 0,
+      fragment_count: 1,
+      payload
+    }
+  }
+
+  /// Creates a packet carrying one fragment of a larger frame.
+  pub fn new_fragment(
+    packet_type: PacketType,
+    sequence_number: u32,
+    frame_id: u32,
+    fragment_index: u16,
+    fragment_count: u16,
+    payload: Vec<u8>
+  ) -> Self {
+    Self {
+      version: MASP_VERSION,
+      packet_type,
+      flags: 0,
+      connection_id: 0,
       sequence_number,
+      frame_id,
+      fragment_index,
+      fragment_count,
       payload
     }
   }
 
+  pub fn has_checksum(&self) -> bool {
+    self.flags & FLAG_CHECKSUM != 0
+  }
+
   pub fn serialize(&self) -> Vec<u8> {
-    let mut buffer = BytesMut::with_capacity(10 + self.payload.len());
+    let header = self.serialize_header(self.payload.len());
+    let mut buffer = BytesMut::with_capacity(MASP_HEADER_SIZE + CHECKSUM_SIZE + self.payload.len());
+
+    buffer.put_slice(&header);
+
+    if self.has_checksum() {
+      buffer.put_u32(checksum(&header, &self.payload));
+    }
+
+    buffer.put_slice(&self.payload);
+
+    buffer.to_vec()
+  }
+
+  /// Fixed header bytes as sent with a payload of the given length,
+  /// authenticated alongside encrypted payloads.
+  /// Payloads are bounded by the datagram size, so their length always fits the field.
+  pub fn serialize_header(&self, payload_length: usize) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(MASP_HEADER_SIZE);
 
     buffer.put_slice(&MASP_MAGIC_NUMBER);
     buffer.put_u8(self.version);
     buffer.put_u8(self.packet_type as u8);
+    buffer.put_u8(self.flags);
+    buffer.put_u32(self.connection_id);
     buffer.put_u32(self.sequence_number);
-    buffer.put_slice(&self.payload);
+    buffer.put_u32(self.frame_id);
+    buffer.put_u16(self.fragment_index);
+    buffer.put_u16(self.fragment_count);
+    buffer.put_u16(payload_length as u16);
 
     buffer.to_vec()
   }
 
-  pub fn deserialize(buffer: &[u8]) -> Result<Self, &'static str> {
-    if buffer.len() < 10 {
-      return Err("Packet too short");
-    }
+  /// Parses a datagram, rejecting anything that doesn't match the header exactly:
+  /// unknown flags, a payload of another length than declared or a wrong checksum.
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    // magic, version and type lead every version's header, so a shorter one of an
+    // older version is still refused for its version
+    ensure_length("header", buffer, 6)?;
 
-    if &buffer[0..4] != MASP_MAGIC_NUMBER {
-      return Err("Invalid magic number");
+    if buffer[0..4] != MASP_MAGIC_NUMBER {
+      return Err(PacketError::InvalidMagicNumber);
     }
 
     let version = buffer[4];
+    let packet_type = PacketType::try_from(buffer[5])?;
 
-    if version != MASP_VERSION {
-      return Err("Unsupported protocol version");
+    // from v2 on handshake packets keep the same header, so peers on different
+    // builds can still agree on a version they both speak. v1 peers are refused
+    if version < FIRST_NEGOTIABLE_VERSION || (version != MASP_VERSION && !packet_type.is_handshake()) {
+      return Err(PacketError::UnsupportedVersion(version));
     }
 
-    let packet_type = PacketType::try_from(buffer[5])?;
+    ensure_length("header", buffer, MASP_HEADER_SIZE)?;
+
+    let flags = buffer[6];
+
+    if flags & !KNOWN_FLAGS != 0 {
+      return Err(PacketError::UnknownFlags(flags));
+    }
+
+    let connection_id = read_u32(buffer, 7);
+    let sequence_number = read_u32(buffer, 11);
+    let frame_id = read_u32(buffer, 15);
+    let fragment_index = read_u16(buffer, 19);
+    let fragment_count = read_u16(buffer, 21);
+    let payload_length = read_u16(buffer, 23) as usize;
+
+    if fragment_count == 0 || fragment_index >= fragment_count {
+      return Err(PacketError::InvalidFragmentHeader { index: fragment_index, count: fragment_count });
+    }
 
-    let sequence_number = u32::from_be_bytes([
-      buffer[6],
-      buffer[7],
-      buffer[8],
-      buffer[9]
-    ]);
+    let header_size = if flags & FLAG_CHECKSUM != 0 {
+      MASP_HEADER_SIZE + CHECKSUM_SIZE
+    } else {
+      MASP_HEADER_SIZE
+    };
 
-    let payload = buffer[10..].to_vec();
+    ensure_length("header", buffer, header_size)?;
+
+    let payload = &buffer[header_size..];
+
+    if payload.len() != payload_length {
+      return Err(PacketError::PayloadLengthMismatch { declared: payload_length, actual: payload.len() });
+    }
+
+    if flags & FLAG_CHECKSUM != 0 {
+      let expected = read_u32(buffer, MASP_HEADER_SIZE);
+      let actual = checksum(&buffer[..MASP_HEADER_SIZE], payload);
+
+      if expected != actual {
+        return Err(PacketError::ChecksumMismatch { expected, actual });
+      }
+    }
 
     Ok(
       MaspPacket {
         version,
         packet_type,
+        flags,
+        connection_id,
         sequence_number,
-        payload
+        frame_id,
+        fragment_index,
+        fragment_count,
+        payload: payload.to_vec()
       }
     )
   }
 }
+
+/// CRC32C over the fixed header and the payload, catches corruption of packets
+/// that travel without the protection of the session keys.
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+  crc32c::crc32c_append(crc32c::crc32c(header), payload)
+}
\ No newline at end of file
@@ -1,3 +1,18 @@
 pub mod receiver;
 pub mod sender;
-pub mod message;
\ No newline at end of file
+pub mod message;
+pub mod config;
+pub mod fragment;
+pub mod window;
+pub mod reliability;
+pub mod congestion;
+pub mod rtt;
+pub mod shutdown;
+pub mod crypto;
+pub mod capabilities;
+pub mod socket;
+pub mod handshake;
+pub mod reconnect;
+pub mod fec;
+pub mod serial;
+pub mod payload;
\ No newline at end of file
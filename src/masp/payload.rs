@@ -0,0 +1,333 @@
+use bytes::{BufMut, BytesMut};
+use std::convert::TryFrom;
+
+use super::capabilities::Capabilities;
+use super::crypto::{ProofError, PROOF_SIZE, PUBLIC_KEY_SIZE, TAG_SIZE};
+use super::message::{self, PacketError, CONNECTION_ID_SIZE};
+use super::reliability::DeliveryClass;
+
+/// Upper bound for sequence ranges carried by one retransmission request.
+pub const MAX_SEQUENCE_RANGES: usize = 64;
+
+const ACK_SIZE: usize = 13;
+const SEQUENCE_RANGE_SIZE: usize = 8;
+const RECOVERY_REPORT_SIZE: usize = 4;
+const TIMESTAMP_SIZE: usize = 8;
+
+/// Inclusive `(start, end)` range of sequence numbers.
+pub type SequenceRange = (u32, u32);
+
+/// Payload of `Ack`: a cumulative ack of one delivery class and its selective-ack bitmap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ack {
+  pub delivery_class: DeliveryClass,
+  pub cumulative: u32,
+  pub bitmap: u64
+}
+
+impl Ack {
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(ACK_SIZE);
+
+    buffer.put_u8(self.delivery_class as u8);
+    buffer.put_u32(self.cumulative);
+    buffer.put_u64(self.bitmap);
+
+    buffer.to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_exact_length("acknowledgment", buffer, ACK_SIZE)?;
+
+    let mut bitmap = [0u8; 8];
+    bitmap.copy_from_slice(&buffer[5..ACK_SIZE]);
+
+    Ok(Self {
+      delivery_class: DeliveryClass::try_from(buffer[0])?,
+      cumulative: message::read_u32(buffer, 1),
+      bitmap: u64::from_be_bytes(bitmap)
+    })
+  }
+}
+
+/// Payload of `RetransmissionRequest`: the delivery class followed by inclusive
+/// sequence ranges as big-endian `(start, end)` pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetransmissionRequest {
+  pub delivery_class: DeliveryClass,
+  pub ranges: Vec<SequenceRange>
+}
+
+impl RetransmissionRequest {
+  /// Ranges past `MAX_SEQUENCE_RANGES` are left out, callers split longer lists.
+  pub fn serialize(&self) -> Vec<u8> {
+    let ranges = &self.ranges[..self.ranges.len().min(MAX_SEQUENCE_RANGES)];
+    let mut buffer = BytesMut::with_capacity(1 + ranges.len() * SEQUENCE_RANGE_SIZE);
+
+    buffer.put_u8(self.delivery_class as u8);
+
+    for (start, end) in ranges {
+      buffer.put_u32(*start);
+      buffer.put_u32(*end);
+    }
+
+    buffer.to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_length("retransmission request", buffer, 1)?;
+
+    let delivery_class = DeliveryClass::try_from(buffer[0])?;
+    let chunks = buffer[1..].chunks_exact(SEQUENCE_RANGE_SIZE);
+
+    if !chunks.remainder().is_empty() {
+      return Err(PacketError::TrailingBytes {
+        structure: "retransmission request",
+        count: chunks.remainder().len()
+      });
+    }
+
+    if chunks.len() > MAX_SEQUENCE_RANGES {
+      return Err(PacketError::InvalidField("sequence range count"));
+    }
+
+    let ranges = chunks
+      .map(|chunk| (message::read_u32(chunk, 0), message::read_u32(chunk, 4)))
+      .collect();
+
+    Ok(Self { delivery_class, ranges })
+  }
+}
+
+/// Payload of `RecoveryReport`: how many video fragments parity rebuilt since
+/// the last report. They count as loss for FEC, but aren't resent and don't
+/// make the sender back off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryReport {
+  pub recovered: u32
+}
+
+impl RecoveryReport {
+  pub fn serialize(&self) -> Vec<u8> {
+    self.recovered.to_be_bytes().to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_exact_length("recovery report", buffer, RECOVERY_REPORT_SIZE)?;
+
+    Ok(Self { recovered: message::read_u32(buffer, 0) })
+  }
+}
+
+/// Payload of `Ping`: its send time, echoed back untouched in the `Pong`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+  pub micros: u64
+}
+
+impl Timestamp {
+  pub fn serialize(&self) -> Vec<u8> {
+    self.micros.to_be_bytes().to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_exact_length("timestamp", buffer, TIMESTAMP_SIZE)?;
+
+    let mut micros = [0u8; TIMESTAMP_SIZE];
+    micros.copy_from_slice(buffer);
+
+    Ok(Self { micros: u64::from_be_bytes(micros) })
+  }
+}
+
+/// Connection identifier and capabilities a handshake packet advertises.
+/// Both sides bind the session keys to them, so they are kept exactly as sent,
+/// capabilities unknown to this build included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeParameters {
+  pub connection_id: u32,
+  pub capabilities: Capabilities,
+  bytes: Vec<u8>
+}
+
+impl HandshakeParameters {
+  pub fn new(connection_id: u32, capabilities: &Capabilities) -> Self {
+    Self {
+      connection_id,
+      capabilities: capabilities.clone(),
+      bytes: [connection_id.to_be_bytes().as_slice(), &capabilities.serialize()].concat()
+    }
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// Parses the parameters, returning them along with the bytes after them.
+  fn deserialize(buffer: &[u8]) -> Result<(Self, &[u8]), PacketError> {
+    message::ensure_length("handshake parameters", buffer, CONNECTION_ID_SIZE)?;
+
+    let connection_id = message::read_u32(buffer, 0);
+
+    // zero marks packets sent before the identifier is known
+    if connection_id == 0 {
+      return Err(PacketError::InvalidField("connection identifier"));
+    }
+
+    let (capabilities, rest) = Capabilities::deserialize(&buffer[CONNECTION_ID_SIZE..])?;
+    let (bytes, rest) = buffer.split_at(buffer.len() - rest.len());
+
+    Ok((Self { connection_id, capabilities, bytes: bytes.to_vec() }, rest))
+  }
+}
+
+/// Parses the optional passphrase proof closing a handshake packet.
+fn deserialize_proof(buffer: &[u8]) -> Result<Option<[u8; PROOF_SIZE]>, PacketError> {
+  if buffer.is_empty() {
+    return Ok(None);
+  }
+
+  message::ensure_exact_length("passphrase proof", buffer, PROOF_SIZE)?;
+
+  let mut proof = [0u8; PROOF_SIZE];
+  proof.copy_from_slice(buffer);
+
+  Ok(Some(proof))
+}
+
+fn read_key(buffer: &[u8], offset: usize) -> [u8; PUBLIC_KEY_SIZE] {
+  let mut key = [0u8; PUBLIC_KEY_SIZE];
+  key.copy_from_slice(&buffer[offset..offset + PUBLIC_KEY_SIZE]);
+
+  key
+}
+
+/// Payload of `HandshakeRequest`: the initiator's ephemeral and identity keys,
+/// its parameters and, with a passphrase, proof of it over everything before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeRequest {
+  pub ephemeral_key: [u8; PUBLIC_KEY_SIZE],
+  pub identity_key: [u8; PUBLIC_KEY_SIZE],
+  pub parameters: HandshakeParameters,
+  pub proof: Option<[u8; PROOF_SIZE]>
+}
+
+impl HandshakeRequest {
+  /// Bytes the passphrase proof covers.
+  pub fn challenge(&self) -> Vec<u8> {
+    [self.ephemeral_key.as_slice(), &self.identity_key, self.parameters.as_bytes()].concat()
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut buffer = self.challenge();
+
+    if let Some(proof) = &self.proof {
+      buffer.extend(proof);
+    }
+
+    buffer
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    let keys_size = 2 * PUBLIC_KEY_SIZE;
+
+    message::ensure_length("handshake request", buffer, keys_size)?;
+
+    let (parameters, proof) = HandshakeParameters::deserialize(&buffer[keys_size..])?;
+
+    Ok(Self {
+      ephemeral_key: read_key(buffer, 0),
+      identity_key: read_key(buffer, PUBLIC_KEY_SIZE),
+      parameters,
+      proof: deserialize_proof(proof)?
+    })
+  }
+}
+
+/// Payload of `HandshakeAck`: the responder's ephemeral and identity keys, proof
+/// that it derived the session keys, its parameters and, with a passphrase,
+/// proof of it over the handshake transcript.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeAck {
+  pub ephemeral_key: [u8; PUBLIC_KEY_SIZE],
+  pub identity_key: [u8; PUBLIC_KEY_SIZE],
+  pub confirmation_tag: [u8; TAG_SIZE],
+  pub parameters: HandshakeParameters,
+  pub proof: Option<[u8; PROOF_SIZE]>
+}
+
+impl HandshakeAck {
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut buffer = [
+      self.ephemeral_key.as_slice(),
+      &self.identity_key,
+      &self.confirmation_tag,
+      self.parameters.as_bytes()
+    ].concat();
+
+    if let Some(proof) = &self.proof {
+      buffer.extend(proof);
+    }
+
+    buffer
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    let keys_size = 2 * PUBLIC_KEY_SIZE + TAG_SIZE;
+
+    message::ensure_length("handshake acknowledgment", buffer, keys_size)?;
+
+    let (parameters, proof) = HandshakeParameters::deserialize(&buffer[keys_size..])?;
+    let mut confirmation_tag = [0u8; TAG_SIZE];
+    confirmation_tag.copy_from_slice(&buffer[2 * PUBLIC_KEY_SIZE..keys_size]);
+
+    Ok(Self {
+      ephemeral_key: read_key(buffer, 0),
+      identity_key: read_key(buffer, PUBLIC_KEY_SIZE),
+      confirmation_tag,
+      parameters,
+      proof: deserialize_proof(proof)?
+    })
+  }
+}
+
+/// Payload of `HandshakeFinalAck`: the initiator's proof that it derived the session keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandshakeFinalAck {
+  pub confirmation_tag: [u8; TAG_SIZE]
+}
+
+impl HandshakeFinalAck {
+  pub fn serialize(&self) -> Vec<u8> {
+    self.confirmation_tag.to_vec()
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_exact_length("final acknowledgment", buffer, TAG_SIZE)?;
+
+    let mut confirmation_tag = [0u8; TAG_SIZE];
+    confirmation_tag.copy_from_slice(buffer);
+
+    Ok(Self { confirmation_tag })
+  }
+}
+
+/// Payload of `HandshakeReject`: why the peer turned our passphrase proof down.
+/// Like the rest of the handshake it isn't encrypted, a forged one can only end
+/// a handshake an on-path attacker could stall anyway.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandshakeReject {
+  pub reason: ProofError
+}
+
+impl HandshakeReject {
+  pub fn serialize(&self) -> Vec<u8> {
+    vec![self.reason as u8]
+  }
+
+  pub fn deserialize(buffer: &[u8]) -> Result<Self, PacketError> {
+    message::ensure_exact_length("handshake rejection", buffer, 1)?;
+
+    Ok(Self { reason: ProofError::try_from(buffer[0])? })
+  }
+}
@@ -1,154 +1,276 @@
+use crate::masp::capabilities::SessionCapabilities;
+use crate::masp::fec::FecDecoder;
+use crate::masp::fragment::FrameReassembler;
 use crate::masp::message::{MaspPacket, PacketType};
-use tokio::net::UdpSocket;
-use tokio::time::{sleep, Duration};
-use std::net::SocketAddr;
+use crate::masp::payload::{self, Ack, RecoveryReport, RetransmissionRequest};
+use crate::masp::reliability::{DeliveryClass, DELIVERY_CLASSES, FIRST_SEQUENCE_NUMBER};
+use crate::masp::serial;
+use crate::masp::shutdown::Shutdown;
+use crate::masp::socket::{Inbox, MaspSocket};
+use crate::masp::window::ReceiveWindow;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::{sync::Mutex, task};
+use tokio::{sync::{mpsc, Mutex}, task};
 
-use crate::video::ascii_frame;
+use crate::video::ascii_frame::{self, VideoFormat};
 
-const FINAL_ACK_TIMEOUT_SECONDS: u8 = 3;
+const FRAME_REASSEMBLY_TIMEOUT_MS: u16 = 500;
+const NACK_INTERVAL_MS: u16 = 100;
+/// With FEC, gaps in video wait this long for the parity closing their group
+/// before they are requested, so a loss parity rebuilds is never resent.
+const FEC_NACK_DELAY_MS: u16 = 50;
+const ACK_INTERVAL_MS: u8 = 20;
 
+/// Receiving role of a session: acknowledges and renders what the peer's
+/// sender role sends.
 #[derive(Clone)]
 pub struct MaspReceiver {
-  socket: Arc<UdpSocket>,
-  pub remote_addr: Option<SocketAddr>,
-  expected_sequence_number: u32,
-  ascii_frames_buffer: Arc<Mutex<Vec<(String, u32)>>>
+  socket: MaspSocket,
+  inbox: Arc<Mutex<Inbox>>,
+  receive_windows: Arc<Mutex<HashMap<DeliveryClass, ReceiveWindow>>>,
+  pending_ordered_packets: Arc<Mutex<Vec<MaspPacket>>>,
+  reassembler: Arc<Mutex<FrameReassembler>>,
+  /// Rebuilds lost video fragments when the peers agreed on FEC.
+  fec_decoder: Arc<Mutex<FecDecoder>>,
+  fec_enabled: bool,
+  /// Fragments parity rebuilt since the last recovery report.
+  recovered_fragments: Arc<Mutex<u32>>,
+  last_video_frame_id: Option<u32>,
+  /// Codec and largest size of the frames the peer may send.
+  video_format: VideoFormat,
+  ascii_frames_buffer: Arc<Mutex<Vec<(String, u32)>>>,
+  /// Takes the decoded video frames instead of the terminal when set.
+  frame_sink: Option<mpsc::UnboundedSender<String>>,
+  shutdown: Shutdown
 }
 
 impl MaspReceiver {
-  pub async fn new (local_addr: SocketAddr, remote_addr: Option<SocketAddr>) -> Result<Self, Box<dyn std::error::Error>> {
-    let local_socket = UdpSocket::bind(local_addr).await?;
+  pub fn new (
+    socket: MaspSocket,
+    inbox: Inbox,
+    shutdown: Shutdown
+  ) -> Self {
+    let nack_interval = Duration::from_millis(NACK_INTERVAL_MS as u64);
+    let receive_windows = DELIVERY_CLASSES
+      .iter()
+      .map(|delivery_class| {
+        (*delivery_class, ReceiveWindow::new(nack_interval, delivery_class.deadline(), FIRST_SEQUENCE_NUMBER))
+      })
+      .collect();
     
-    Ok(
-      MaspReceiver {
-        socket: Arc::new(local_socket),
-        remote_addr,
-        expected_sequence_number: 0,
-        ascii_frames_buffer: Arc::new(Mutex::new(Vec::<(String, u32)>::new()))
-      }
-    )
+    MaspReceiver {
+      socket,
+      inbox: Arc::new(Mutex::new(inbox)),
+      receive_windows: Arc::new(Mutex::new(receive_windows)),
+      pending_ordered_packets: Arc::new(Mutex::new(Vec::new())),
+      reassembler: Arc::new(Mutex::new(FrameReassembler::new(
+        Duration::from_millis(FRAME_REASSEMBLY_TIMEOUT_MS as u64)
+      ))),
+      fec_decoder: Arc::new(Mutex::new(FecDecoder::new(
+        Duration::from_millis(FRAME_REASSEMBLY_TIMEOUT_MS as u64)
+      ))),
+      fec_enabled: false,
+      recovered_fragments: Arc::new(Mutex::new(0)),
+      last_video_frame_id: None,
+      video_format: VideoFormat::default(),
+      ascii_frames_buffer: Arc::new(Mutex::new(Vec::<(String, u32)>::new())),
+      frame_sink: None,
+      shutdown
+    }
   }
 
-  /// Waits for a handshake initiation from the sender.
-  pub async fn wait_for_handshake(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-      let mut buf = [0u8; 1024];
-      let (len, addr) = self.socket.recv_from(&mut buf).await?;
-
-      let packet = MaspPacket::deserialize(&buf[..len])?;
-
-      match packet.packet_type {
-        PacketType::HandshakeRequest => {
-          self.remote_addr = Some(addr);
-
-          println!("Received handshake request from {}", addr);
-
-          // Send handshake acknowledgment
-          let ack_packet = MaspPacket::new(
-            PacketType::HandshakeAck, 
-            packet.sequence_number, 
-            Vec::new()
-          );
-          
-          self.send_packet(&ack_packet, &addr).await?;
-
-          // Wait for final acknowledgment
-          let final_ack_result = self.receive_final_ack(
-            Duration::from_secs(FINAL_ACK_TIMEOUT_SECONDS as u64)
-          ).await;
-
-          match final_ack_result {
-            Ok(_) => {
-              println!("Handshake completed with {}", addr);
-              return Ok(());
-            }
-            Err(e) => {
-              println!("Handshake error: {}", e);
-            }
-          }
-        },
-        _ => ()
-      }
+  /// Decodes video in the negotiated format, and repairs it with parity packets
+  /// when both peers agreed on FEC.
+  pub async fn set_session_capabilities(&mut self, session_capabilities: &SessionCapabilities) {
+    self.fec_enabled = session_capabilities.fec.is_some();
+    self.video_format = VideoFormat::from(session_capabilities);
+
+    let nack_delay = if self.fec_enabled { Duration::from_millis(FEC_NACK_DELAY_MS as u64) } else { Duration::ZERO };
+
+    if let Some(window) = self.receive_windows.lock().await.get_mut(&DeliveryClass::LatestOnly) {
+      window.set_nack_delay(nack_delay);
     }
   }
 
-  async fn receive_final_ack(&self, timeout: Duration) -> Result<(), &'static str> {
-    let mut buf = [0u8; 1024];
+  /// Hands decoded video frames to the channel instead of rendering them.
+  pub fn forward_frames(&mut self, frame_sink: mpsc::UnboundedSender<String>) {
+    self.frame_sink = Some(frame_sink);
+  }
 
-    tokio::select! {
-      result = self.socket.recv_from(&mut buf) => {
-        match result {
-          Ok((len, addr)) => {
-            if Some(addr) != self.remote_addr {
-              return Err("Received packet from unexpected address");
-            }
+  /// Starts receiving data packets until the session ends.
+  /// Liveness is left to the sender role, which shares the same socket and NAT mapping.
+  pub async fn start_receiving(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    let inbox = Arc::clone(&self.inbox);
+    let mut inbox = inbox.lock().await;
+    let mut ack_interval = interval(Duration::from_millis(ACK_INTERVAL_MS as u64));
 
-            let packet = MaspPacket::deserialize(&buf[..len])?;
+    ack_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-            match packet.packet_type {
-              PacketType::HandshakeFinalAck => Ok(()),
-              _ => Err("Received unexpected packet type")
-            }
-          }
-          Err(_) => Err("Failed to receive data"),
+    loop {
+      // acknowledgments are batched and sent at most once per interval
+      let packet = tokio::select! {
+        datagram = inbox.recv() => match datagram {
+          Some((packet, _)) => packet,
+          None => return Ok(())
+        },
+        _ = ack_interval.tick() => {
+          self.send_ack().await?;
+          self.send_recovery_report().await?;
+          self.send_retransmission_request().await?;
+          continue;
+        }
+        _ = self.shutdown.triggered() => return Ok(())
+      };
+
+      // anything without a delivery class isn't data
+      let Some(delivery_class) = packet.packet_type.delivery_class() else {
+        continue;
+      };
+
+      let deliverable_packets = self.accept_packet(delivery_class, packet).await;
+
+      // parity gets its chance to fill the gaps before any of them is requested
+      for packet in deliverable_packets {
+        for packet in self.repair(packet).await {
+          self.handle_data_packet(packet).await?;
         }
       }
-      _ = sleep(timeout) => {
-        Err("Timeout waiting for final acknowledgment")
-      }
+
+      self.send_retransmission_request().await?;
     }
   }
 
-    /// Starts receiving data packets.
-  pub async fn start_receiving(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = [0u8; 10000];
+  /// Records the packet in the window of its delivery class and returns the
+  /// packets that can be handed over. Duplicates are dropped, and ordered
+  /// packets are held back until every earlier sequence number has arrived
+  /// or was given up on.
+  async fn accept_packet(&self, delivery_class: DeliveryClass, packet: MaspPacket) -> Vec<MaspPacket> {
+    let mut receive_windows = self.receive_windows.lock().await;
+    let Some(window) = receive_windows.get_mut(&delivery_class) else {
+      return Vec::new();
+    };
+
+    if !window.record(packet.sequence_number) {
+      return Vec::new();
+    }
 
-    loop {
-      let (len, addr) = self.socket.recv_from(&mut buf).await?;
+    if !delivery_class.is_ordered() {
+      return vec![packet];
+    }
 
-      if Some(addr) != self.remote_addr {
-        continue;
-      }
+    let mut pending_packets = self.pending_ordered_packets.lock().await;
+    pending_packets.push(packet);
 
-      let packet = match MaspPacket::deserialize(&buf[..len]) {
-        Ok(pkt) => pkt,
-        Err(e) => {
-          println!("Failed to deserialize packet: {}", e);
-          continue;
-        }
+    let lowest_missing = window.lowest_missing();
+    let (mut deliverable_packets, still_pending): (Vec<MaspPacket>, Vec<MaspPacket>) = pending_packets
+      .drain(..)
+      .partition(|pending| match lowest_missing {
+        Some(lowest_missing) => serial::is_before(pending.sequence_number, lowest_missing),
+        None => true
+      });
+
+    *pending_packets = still_pending;
+    deliverable_packets.sort_by(|a, b| serial::compare(a.sequence_number, b.sequence_number));
+
+    deliverable_packets
+  }
+
+  /// Feeds video to the FEC decoder and returns the packet, if it's data, along
+  /// with the fragments parity let rebuild. Rebuilt fragments count as received,
+  /// so they get acknowledged instead of requested again.
+  async fn repair(&self, packet: MaspPacket) -> Vec<MaspPacket> {
+    let is_video = matches!(packet.packet_type, PacketType::VideoData | PacketType::VideoParity);
+
+    if !self.fec_enabled || !is_video {
+      return match packet.packet_type {
+        PacketType::VideoParity => Vec::new(),
+        _ => vec![packet]
       };
+    }
 
-      self.expected_sequence_number = self.expected_sequence_number.wrapping_add(1);
-      self.send_ack(packet.sequence_number).await?;
+    let mut recovered = {
+      let mut fec_decoder = self.fec_decoder.lock().await;
 
-      match packet.packet_type {
-        PacketType::TextData => {
-          // Handle text data          
-        }
-        PacketType::AudioData => {
-          // Handle audio data
-        }
-        PacketType::VideoData => {
-          self.save_frame(packet).await?;
-          self.render_frame().await;
-        }
-        PacketType::HandshakeRequest | PacketType::HandshakeAck | PacketType::HandshakeFinalAck => {
-          // Ignore handshake packets after establishment
+      fec_decoder.evict_expired();
+      fec_decoder.insert(&packet)
+    };
+
+    if let Some(window) = self.receive_windows.lock().await.get_mut(&DeliveryClass::LatestOnly) {
+      recovered.retain(|recovered| window.record(recovered.sequence_number));
+    }
+
+    if !recovered.is_empty() {
+      *self.recovered_fragments.lock().await += recovered.len() as u32;
+    }
+
+    let mut packets = recovered;
+
+    if packet.packet_type == PacketType::VideoData {
+      packets.insert(0, packet);
+    }
+
+    packets
+  }
+
+  async fn handle_data_packet(&mut self, packet: MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
+    let frame = {
+      let mut reassembler = self.reassembler.lock().await;
+
+      reassembler.evict_expired();
+      reassembler.insert(&packet)
+    };
+
+    // wait for the remaining fragments of the frame
+    let Some(frame) = frame else {
+      return Ok(());
+    };
+
+    match packet.packet_type {
+      PacketType::TextData => {
+        // Handle text data          
+      }
+      PacketType::AudioData => {
+        // Handle audio data
+      }
+      PacketType::VideoData => {
+        // only the newest video frame is worth rendering
+        if let Some(last_frame_id) = self.last_video_frame_id {
+          if !serial::is_before(last_frame_id, packet.frame_id) {
+            return Ok(());
+          }
         }
-        _ => {
-          // Handle other packet types if necessary
+
+        self.last_video_frame_id = Some(packet.frame_id);
+        self.fec_decoder.lock().await.remove(packet.frame_id);
+
+        if let Some(frame_sink) = &self.frame_sink {
+          // a frame that doesn't decode is dropped like a lost one
+          if let Ok(frame) = self.video_format.decode(&frame) {
+            let _ = frame_sink.send(frame);
+          }
+
+          return Ok(());
         }
+
+        self.save_frame(packet.frame_id, frame).await?;
+        self.render_frame().await;
+      }
+      _ => {
+        // Handle other packet types if necessary
       }
     }
+
+    Ok(())
   }
 
-  async fn save_frame(&mut self, packet: MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
-    let sequence_number = packet.sequence_number;
-    let decompressed_frame = ascii_frame::decompress_ascii_image(packet.payload.clone());
+  async fn save_frame(&mut self, frame_id: u32, payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    // a frame that doesn't decode is dropped like a lost one
+    let Ok(decompressed_frame) = self.video_format.decode(&payload) else {
+      return Ok(());
+    };
 
-    let frame_data = (decompressed_frame, sequence_number);
+    let frame_data = (decompressed_frame, frame_id);
 
     self.ascii_frames_buffer.lock().await.push(frame_data);
 
@@ -164,8 +286,9 @@ impl MaspReceiver {
         ()
       }
       
+      // newest frame first, frame ids wrap around like sequence numbers
       cloned_buf.sort_by(|prv, nxt| {
-        nxt.1.cmp(&prv.1)
+        serial::compare(nxt.1, prv.1)
       });
 
       let (frame, _) = cloned_buf.first().unwrap();
@@ -177,23 +300,76 @@ impl MaspReceiver {
     locked_buf.remove(0);
   }
 
-  async fn send_ack(&self, sequence_number: u32 ) -> Result<(), Box<dyn std::error::Error>> {
-    let ack_packet = MaspPacket::new(
-      PacketType::Ack,
-      0,
-      sequence_number.to_be_bytes().to_vec(),
+  /// Sends a cumulative ack with a selective-ack bitmap for every delivery
+  /// class that received something since the last one.
+  async fn send_ack(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let acks: Vec<(DeliveryClass, u32, u64)> = self.receive_windows
+      .lock()
+      .await
+      .iter_mut()
+      .filter_map(|(delivery_class, window)| {
+        window.take_ack().map(|(cumulative, bitmap)| (*delivery_class, cumulative, bitmap))
+      })
+      .collect();
+
+    for (delivery_class, cumulative, bitmap) in acks {
+      let ack_packet = MaspPacket::new(
+        PacketType::Ack,
+        self.socket.next_control_sequence_number(),
+        Ack { delivery_class, cumulative, bitmap }.serialize()
+      );
+
+      self.send_packet(&ack_packet).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Tells the sender how many fragments parity rebuilt, so FEC keeps adapting
+  /// to the loss it never hears about as retransmission requests.
+  async fn send_recovery_report(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let recovered = std::mem::take(&mut *self.recovered_fragments.lock().await);
+
+    if recovered == 0 {
+      return Ok(());
+    }
+
+    let report_packet = MaspPacket::new(
+      PacketType::RecoveryReport,
+      self.socket.next_control_sequence_number(),
+      RecoveryReport { recovered }.serialize()
     );
-    
-    if let Some(addr) = self.remote_addr {
-      self.send_packet(&ack_packet, &addr).await?;
+
+    self.send_packet(&report_packet).await
+  }
+
+  /// Asks the sender to resend the sequence ranges detected as missing.
+  async fn send_retransmission_request(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let requests: Vec<(DeliveryClass, Vec<(u32, u32)>)> = self.receive_windows
+      .lock()
+      .await
+      .iter_mut()
+      .map(|(delivery_class, window)| (*delivery_class, window.take_nack_ranges()))
+      .filter(|(_, ranges)| !ranges.is_empty())
+      .collect();
+
+    for (delivery_class, ranges) in requests {
+      for chunk in ranges.chunks(payload::MAX_SEQUENCE_RANGES) {
+        let request_packet = MaspPacket::new(
+          PacketType::RetransmissionRequest,
+          self.socket.next_control_sequence_number(),
+          RetransmissionRequest { delivery_class, ranges: chunk.to_vec() }.serialize()
+        );
+
+        self.send_packet(&request_packet).await?;
+      }
     }
 
     Ok(())
   }
 
-  async fn send_packet(&self, packet: &MaspPacket, addr: &SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
-    let data = packet.serialize();
-    self.socket.send_to(&data, addr).await?;
+  async fn send_packet(&self, packet: &MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
+    self.socket.send(packet).await?;
     Ok(())
   }
 }
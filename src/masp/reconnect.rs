@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+
+use super::crypto::KeyPair;
+use super::handshake::Handshake;
+use super::sender::MaspSender;
+use crate::video::ascii_frame;
+
+const INITIAL_BACKOFF_MS: u16 = 250;
+const MAX_BACKOFF_MS: u16 = 4000;
+
+/// Whether packets currently get through to the peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+  Connected,
+  /// The peer went silent, the session is being resumed.
+  Reconnecting
+}
+
+impl fmt::Display for ConnectionState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConnectionState::Connected => write!(f, "connected"),
+      ConnectionState::Reconnecting => write!(f, "reconnecting…")
+    }
+  }
+}
+
+/// Connection state shared by every task of a session, along with the latest
+/// progress report of a reconnection.
+#[derive(Clone)]
+pub struct ConnectionStatus {
+  state: Arc<watch::Sender<ConnectionState>>,
+  detail: Arc<watch::Sender<String>>
+}
+
+impl ConnectionStatus {
+  pub fn new() -> Self {
+    let (state, _) = watch::channel(ConnectionState::Connected);
+    let (detail, _) = watch::channel(String::new());
+
+    Self {
+      state: Arc::new(state),
+      detail: Arc::new(detail)
+    }
+  }
+
+  pub fn set(&self, state: ConnectionState) {
+    let changed = self.state.send_if_modified(|current| {
+      let changed = *current != state;
+
+      *current = state;
+      changed
+    });
+
+    // progress of a finished reconnection is stale
+    if changed && state == ConnectionState::Connected {
+      self.detail.send_replace(String::new());
+    }
+  }
+
+  /// Shows what a reconnection is up to next to the state, instead of printing
+  /// it over the video.
+  pub fn report(&self, detail: String) {
+    self.detail.send_replace(detail);
+  }
+
+  pub fn get(&self) -> ConnectionState {
+    *self.state.borrow()
+  }
+
+  pub fn detail(&self) -> String {
+    self.detail.borrow().clone()
+  }
+
+  /// Resolves once the connection is in the given state.
+  pub async fn wait_for(&self, state: ConnectionState) {
+    let mut receiver = self.state.subscribe();
+
+    // the sender lives as long as `self`, so waiting can't fail
+    let _ = receiver.wait_for(|current| *current == state).await;
+  }
+
+  /// Keeps the status line under the video in sync with the connection state.
+  pub async fn render(&self) {
+    let (mut state, mut detail) = (self.state.subscribe(), self.detail.subscribe());
+
+    loop {
+      let changed = tokio::select! {
+        changed = state.changed() => changed,
+        changed = detail.changed() => changed
+      };
+
+      if changed.is_err() {
+        return;
+      }
+
+      let current_state = *state.borrow_and_update();
+      let current_detail = detail.borrow_and_update().clone();
+
+      match current_state {
+        ConnectionState::Connected => ascii_frame::render_status_line(""),
+        state if current_detail.is_empty() => ascii_frame::render_status_line(&state.to_string()),
+        state => ascii_frame::render_status_line(&format!("{} {}", state, current_detail))
+      }
+    }
+  }
+}
+
+impl Default for ConnectionStatus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Exponentially growing delay between reconnection attempts.
+pub struct Backoff {
+  next_delay: Duration
+}
+
+impl Backoff {
+  pub fn new() -> Self {
+    Self {
+      next_delay: Duration::from_millis(INITIAL_BACKOFF_MS as u64)
+    }
+  }
+
+  /// Delay before the next attempt, doubled each time up to the maximum.
+  pub fn next_delay(&mut self) -> Duration {
+    let delay = self.next_delay;
+
+    self.next_delay = (delay * 2).min(Duration::from_millis(MAX_BACKOFF_MS as u64));
+
+    delay
+  }
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Resumes the session as initiator each time the connection is lost: re-punches
+/// the NAT and re-handshakes with backoff until the peer answers.
+/// The roles keep their sequence spaces, only the session keys are renewed.
+/// Runs until the session ends, or fails if punching does.
+pub async fn keep_connected_as_initiator(
+  handshake: &mut Handshake,
+  identity: &KeyPair,
+  sender: &MaspSender,
+  status: &ConnectionStatus
+) -> Result<(), Box<dyn std::error::Error>> {
+  loop {
+    status.wait_for(ConnectionState::Reconnecting).await;
+
+    let mut backoff = Backoff::new();
+
+    while status.get() == ConnectionState::Reconnecting {
+      sender.punch_hole().await?;
+
+      // the peer stays the same, so a failed attempt is just lost or early
+      if handshake.initiate(identity, 1).await.is_ok() {
+        sender.heard_from_peer().await;
+        status.set(ConnectionState::Connected);
+        break;
+      }
+
+      sleep(backoff.next_delay()).await;
+    }
+  }
+}
+
+/// Resumes the session as responder: answers the peer's re-handshakes, and
+/// re-punches the NAT with backoff while the connection is lost so they get through.
+/// Runs until the session ends, or fails once the session socket is gone.
+pub async fn keep_connected_as_responder(
+  handshake: &mut Handshake,
+  identity: &KeyPair,
+  sender: &MaspSender,
+  status: &ConnectionStatus
+) -> Result<(), Box<dyn std::error::Error>> {
+  tokio::select! {
+    result = answer_handshakes(handshake, identity, sender, status) => result,
+    result = punch_while_reconnecting(sender, status) => result
+  }
+}
+
+async fn answer_handshakes(
+  handshake: &mut Handshake,
+  identity: &KeyPair,
+  sender: &MaspSender,
+  status: &ConnectionStatus
+) -> Result<(), Box<dyn std::error::Error>> {
+  loop {
+    handshake.respond(identity).await?;
+    sender.heard_from_peer().await;
+    status.set(ConnectionState::Connected);
+  }
+}
+
+async fn punch_while_reconnecting(sender: &MaspSender, status: &ConnectionStatus) -> Result<(), Box<dyn std::error::Error>> {
+  loop {
+    status.wait_for(ConnectionState::Reconnecting).await;
+
+    let mut backoff = Backoff::new();
+
+    while status.get() == ConnectionState::Reconnecting {
+      sender.punch_hole().await?;
+      sleep(backoff.next_delay()).await;
+    }
+  }
+}
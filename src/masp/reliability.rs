@@ -0,0 +1,56 @@
+use std::convert::TryFrom;
+use tokio::time::Duration;
+
+use super::message::PacketError;
+
+/// How hard MASP tries to deliver a data packet.
+/// Every class has its own sequence space, acknowledged independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeliveryClass {
+  /// Resent until acknowledged and handed over in sequence order.
+  ReliableOrdered = 0x01,
+  /// Resent only while it can still arrive before its deadline.
+  PartiallyReliable = 0x02,
+  /// Only the newest frame is worth repairing, older ones are dropped.
+  LatestOnly = 0x03
+}
+
+/// Sequence number every delivery class starts from. The receiver expects it
+/// first, so an earlier packet overtaken by a later one is still waited for.
+pub const FIRST_SEQUENCE_NUMBER: u32 = 1;
+
+pub const DELIVERY_CLASSES: [DeliveryClass; 3] = [
+  DeliveryClass::ReliableOrdered,
+  DeliveryClass::PartiallyReliable,
+  DeliveryClass::LatestOnly
+];
+
+impl DeliveryClass {
+  /// How long the sender keeps a packet for retransmission and
+  /// the receiver keeps waiting for a missing one. Reliable packets have no
+  /// deadline, they are kept until acknowledged or the session ends.
+  pub fn deadline(&self) -> Option<Duration> {
+    match self {
+      DeliveryClass::ReliableOrdered => None,
+      DeliveryClass::PartiallyReliable => Some(Duration::from_millis(200)),
+      DeliveryClass::LatestOnly => Some(Duration::from_millis(250))
+    }
+  }
+
+  pub fn is_ordered(&self) -> bool {
+    matches!(self, DeliveryClass::ReliableOrdered)
+  }
+}
+
+impl TryFrom<u8> for DeliveryClass {
+  type Error = PacketError;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    match value {
+      0x01 => Ok(DeliveryClass::ReliableOrdered),
+      0x02 => Ok(DeliveryClass::PartiallyReliable),
+      0x03 => Ok(DeliveryClass::LatestOnly),
+      _ => Err(PacketError::UnknownValue { field: "delivery class", value }),
+    }
+  }
+}
@@ -0,0 +1,59 @@
+use tokio::time::Duration;
+
+/// Round-trip time assumed until the first sample arrives.
+const INITIAL_RTT_MS: u64 = 100;
+const MIN_RTO_MS: u64 = 50;
+const MAX_RTO_MS: u64 = 2000;
+const RTT_SMOOTHING_FACTOR: f64 = 0.125;
+const RTT_VARIANCE_FACTOR: f64 = 0.25;
+
+/// Smoothed round-trip time and variance estimator (RFC 6298).
+pub struct RttEstimator {
+  smoothed_rtt: Option<Duration>,
+  rtt_variance: Duration
+}
+
+impl RttEstimator {
+  pub fn new() -> Self {
+    Self {
+      smoothed_rtt: None,
+      rtt_variance: Duration::from_millis(INITIAL_RTT_MS / 2)
+    }
+  }
+
+  pub fn on_sample(&mut self, sample: Duration) {
+    match self.smoothed_rtt {
+      None => {
+        self.smoothed_rtt = Some(sample);
+        self.rtt_variance = sample / 2;
+      }
+      Some(smoothed_rtt) => {
+        let deviation = smoothed_rtt.abs_diff(sample);
+
+        self.rtt_variance = self.rtt_variance.mul_f64(1.0 - RTT_VARIANCE_FACTOR)
+          + deviation.mul_f64(RTT_VARIANCE_FACTOR);
+        self.smoothed_rtt = Some(
+          smoothed_rtt.mul_f64(1.0 - RTT_SMOOTHING_FACTOR) + sample.mul_f64(RTT_SMOOTHING_FACTOR)
+        );
+      }
+    }
+  }
+
+  pub fn smoothed_rtt(&self) -> Duration {
+    self.smoothed_rtt.unwrap_or(Duration::from_millis(INITIAL_RTT_MS))
+  }
+
+  /// Retransmission timeout derived from the smoothed RTT and its variance.
+  pub fn retransmission_timeout(&self) -> Duration {
+    (self.smoothed_rtt() + self.rtt_variance * 4).clamp(
+      Duration::from_millis(MIN_RTO_MS),
+      Duration::from_millis(MAX_RTO_MS)
+    )
+  }
+}
+
+impl Default for RttEstimator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -1,140 +1,328 @@
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::net::SocketAddr;
 
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-
-use super::message::{MaspPacket, PacketType};
-
-const MAX_HANDSHAKE_ATTEMPTS: u8 = 3;
-const HANDSHAKE_TIMEOUT_SECONDS: u8 = 3;
-const RETRANSMIT_TIMEOUT_MS: u8 = 100;
+use tokio::sync::{watch, Mutex, Notify};
+
+use super::capabilities::SessionCapabilities;
+use super::config::MaspConfig;
+use super::congestion::CongestionController;
+use super::crypto;
+use super::fec::{self, FecController};
+use super::fragment;
+use super::message::{MaspPacket, PacketType, MASP_HEADER_SIZE};
+use super::payload::{Ack, RecoveryReport, RetransmissionRequest, Timestamp};
+use super::reconnect::{ConnectionState, ConnectionStatus};
+use super::reliability::{DeliveryClass, DELIVERY_CLASSES, FIRST_SEQUENCE_NUMBER};
+use super::rtt::RttEstimator;
+use super::serial;
+use super::shutdown::{DisconnectReason, Shutdown};
+use super::socket::{Inbox, MaspSocket};
+use super::window::SELECTIVE_ACK_BITS;
+
+const KEEPALIVE_INTERVAL_MS: u16 = 1000;
+/// Silence after which the connection is considered lost and the session is resumed.
+const RECONNECT_AFTER_MS: u16 = 3000;
+/// Bye is unacknowledged, so it is repeated to survive some loss.
+const BYE_REPEAT_COUNT: u8 = 3;
 
 const HOLE_PUNCHES_COUNT: u8 = 10;
 const HOLE_PUNCH_DELAY_MS: u8 = 5;
 
+#[derive(Clone)]
+struct UnacknowledgedPacket {
+  /// Dropped once the packet is no longer worth resending, the entry
+  /// itself stays until acknowledged or expired for congestion control.
+  packet: Option<MaspPacket>,
+  /// Parity is never missed by the viewer, its loss stays out of the video
+  /// loss FEC adapts to.
+  parity: bool,
+  size: usize,
+  first_sent_at: Instant,
+  last_sent_at: Instant
+}
+
+/// Sending role of a session: sends data, handles what the peer answers to it
+/// and keeps the session alive.
 #[derive(Clone)]
 pub struct MaspSender {
-  socket: Arc<UdpSocket>,
-  pub remote_addr: SocketAddr,
-  sequence_number: u32,
-  unacknowledged_packets: Arc<Mutex<HashMap<u32, MaspPacket>>>
+  socket: MaspSocket,
+  inbox: Arc<Mutex<Inbox>>,
+  sequence_numbers: HashMap<DeliveryClass, u32>,
+  frame_id: u32,
+  max_datagram_size: usize,
+  unacknowledged_packets: Arc<Mutex<HashMap<(DeliveryClass, u32), UnacknowledgedPacket>>>,
+  congestion_controller: Arc<Mutex<CongestionController>>,
+  /// Wakes senders waiting for room in the congestion window.
+  window_opened: Arc<Notify>,
+  rtt_estimator: Arc<Mutex<RttEstimator>>,
+  target_bitrate: Arc<watch::Sender<u64>>,
+  /// Parity protection of video, set when both peers agreed on FEC.
+  fec_controller: Arc<Mutex<Option<FecController>>>,
+  /// Reference point for ping timestamps.
+  epoch: Instant,
+  last_heard_at: Arc<Mutex<Instant>>,
+  peer_timeout: Duration,
+  shutdown: Shutdown
 }
 
 impl MaspSender {
-  pub async fn new (local_addr: SocketAddr, remote_addr: SocketAddr) -> Result<Self, Box<dyn std::error::Error>> {
-    let local_socket = UdpSocket::bind(local_addr).await?;
-
-    Ok(
-      MaspSender {
-        socket: Arc::new(local_socket),
-        remote_addr,
-        sequence_number: 0,
-        unacknowledged_packets: Arc::new(Mutex::new(HashMap::new()))
-      }
-    )
+  pub fn new (
+    socket: MaspSocket,
+    inbox: Inbox,
+    config: MaspConfig,
+    shutdown: Shutdown
+  ) -> Self {
+    let congestion_controller = CongestionController::new(config.max_datagram_size as usize);
+    let rtt_estimator = RttEstimator::new();
+    let (target_bitrate, _) = watch::channel(
+      congestion_controller.target_bitrate(rtt_estimator.smoothed_rtt())
+    );
+
+    MaspSender {
+      socket,
+      inbox: Arc::new(Mutex::new(inbox)),
+      sequence_numbers: HashMap::new(),
+      frame_id: 0,
+      max_datagram_size: config.max_datagram_size as usize,
+      unacknowledged_packets: Arc::new(Mutex::new(HashMap::new())),
+      congestion_controller: Arc::new(Mutex::new(congestion_controller)),
+      window_opened: Arc::new(Notify::new()),
+      rtt_estimator: Arc::new(Mutex::new(rtt_estimator)),
+      target_bitrate: Arc::new(target_bitrate),
+      fec_controller: Arc::new(Mutex::new(None)),
+      epoch: Instant::now(),
+      last_heard_at: Arc::new(Mutex::new(Instant::now())),
+      peer_timeout: config.peer_timeout,
+      shutdown
+    }
   }
 
-  /// Sends a packet and stores it in unacknowledged_packets for retransmission if needed.
+  /// Splits the payload into datagram-sized fragments, sends them and stores
+  /// each one in unacknowledged_packets for retransmission if needed.
+  /// Each delivery class numbers its packets in its own sequence space.
+  /// With FEC, every group of video fragments is followed by its parity packets,
+  /// which share the sequence space but are never resent.
   pub async fn send_data(&mut self, packet_type: PacketType, payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
-    self.sequence_number = self.sequence_number.wrapping_add(1);
+    let delivery_class = packet_type
+      .delivery_class()
+      .ok_or("Only data packets can be sent as data")?;
+    let fec = match packet_type {
+      PacketType::VideoData => self.fec_controller
+        .lock()
+        .await
+        .as_ref()
+        .map(|fec_controller| (fec_controller.group_size() as usize, fec_controller.parity_count())),
+      _ => None
+    };
+    // parity carries its own header, so fragments leave room for it
+    let overhead = MASP_HEADER_SIZE + crypto::TAG_SIZE + if fec.is_some() { fec::PARITY_HEADER_SIZE } else { 0 };
+    let max_fragment_size = self.max_datagram_size.saturating_sub(overhead);
+    let fragments = fragment::split_payload(&payload, max_fragment_size)?;
+    let fragment_count = fragments.len() as u16;
+
+    self.frame_id = self.frame_id.wrapping_add(1);
+
+    // a new frame supersedes everything still pending for older ones
+    if delivery_class == DeliveryClass::LatestOnly {
+      self.unacknowledged_packets
+        .lock()
+        .await
+        .iter_mut()
+        .filter(|((pending_class, _), _)| *pending_class == DeliveryClass::LatestOnly)
+        .for_each(|(_, unacknowledged)| unacknowledged.packet = None);
+    }
 
-    let packet = MaspPacket::new(packet_type, self.sequence_number, payload);
+    let Some((group_size, parity_count)) = fec else {
+      for (fragment_index, fragment_payload) in fragments.into_iter().enumerate() {
+        self.send_fragment(packet_type, delivery_class, fragment_index as u16, fragment_count, fragment_payload, true).await?;
+      }
 
-    self.send_packet(&packet).await?;
+      return Ok(());
+    };
 
-    self.unacknowledged_packets.lock().await.insert(self.sequence_number, packet);
+    for (group_index, group) in fragments.chunks(group_size).enumerate() {
+      let first_fragment = (group_index * group_size) as u16;
+      let mut first_sequence_number = None;
 
-    Ok(())
-  }
+      for (offset, fragment_payload) in group.iter().enumerate() {
+        let sequence_number = self.send_fragment(
+          packet_type,
+          delivery_class,
+          first_fragment + offset as u16,
+          fragment_count,
+          fragment_payload.clone(),
+          true
+        ).await?;
 
-  /// Sends empty packets to punch UDP hole.
-  pub async fn punch_hole(&mut self, remote_reciever_port: u16, remote_sender_port: u16) -> Result<(), Box<dyn std::error::Error>> {
-    let remote_ports = [remote_reciever_port, remote_sender_port];
-    // pre-save remote address
-    let original_remote_addr = self.remote_addr.clone();
-
-    for port in remote_ports {
-      for _ in 0..HOLE_PUNCHES_COUNT {
-        self.remote_addr.set_port(port);
-
-        self.send_data(PacketType::Punch, Vec::new()).await?;
-  
-        sleep(Duration::from_millis(HOLE_PUNCH_DELAY_MS as u64)).await;
+        first_sequence_number.get_or_insert(sequence_number);
       }
-    }
 
-    // reset remote address to the provided one
-    self.remote_addr = original_remote_addr;
+      let Some(first_sequence_number) = first_sequence_number else {
+        continue;
+      };
+      let parities = fec::encode_group(group, first_fragment, first_sequence_number, parity_count);
+
+      for parity in &parities {
+        self.send_fragment(PacketType::VideoParity, delivery_class, first_fragment, fragment_count, parity.serialize(), false).await?;
+      }
+
+      if let Some(fec_controller) = self.fec_controller.lock().await.as_mut() {
+        fec_controller.on_packets_sent(group.len() as u32);
+      }
+    }
 
     Ok(())
-  } 
+  }
 
-  pub async fn init_handshake(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS as u64);
+  /// Sends one fragment of the current frame under the next sequence number of its
+  /// class, once the congestion window has room for it, and tracks it until
+  /// acknowledged. Returns the sequence number used.
+  async fn send_fragment(
+    &mut self,
+    packet_type: PacketType,
+    delivery_class: DeliveryClass,
+    fragment_index: u16,
+    fragment_count: u16,
+    payload: Vec<u8>,
+    resendable: bool
+  ) -> Result<u32, Box<dyn std::error::Error>> {
+    let next_sequence_number = self.sequence_numbers.entry(delivery_class).or_insert(FIRST_SEQUENCE_NUMBER);
+    let sequence_number = *next_sequence_number;
+    *next_sequence_number = sequence_number.wrapping_add(1);
+
+    let packet = MaspPacket::new_fragment(
+      packet_type,
+      sequence_number,
+      self.frame_id,
+      fragment_index,
+      fragment_count,
+      payload
+    );
+
+    self.wait_for_window(&packet).await?;
+
+    let size = self.send_paced(&packet).await?;
+    let now = Instant::now();
+
+    self.congestion_controller.lock().await.on_packet_sent(size);
+
+    self.unacknowledged_packets.lock().await.insert((delivery_class, sequence_number), UnacknowledgedPacket {
+      packet: resendable.then_some(packet),
+      parity: packet_type == PacketType::VideoParity,
+      size,
+      first_sent_at: now,
+      last_sent_at: now
+    });
+
+    Ok(sequence_number)
+  }
 
-    for attempt in 0..MAX_HANDSHAKE_ATTEMPTS {
-      println!("Sending handshake attempt: {}", attempt);
+  /// Subscribes to the bitrate the congestion controller currently allows, in bits per second.
+  pub fn subscribe_target_bitrate(&self) -> watch::Receiver<u64> {
+    self.target_bitrate.subscribe()
+  }
 
-      self.send_data(
-        PacketType::HandshakeRequest, 
-        Vec::new()
-      ).await?;
+  /// Sends empty packets to punch UDP hole.
+  /// Punches are unsequenced, so the remote never asks for them to be resent.
+  pub async fn punch_hole(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let punch_packet = MaspPacket::new(PacketType::Punch, 0, Vec::new());
 
-      match self.receive_handshake_ack(timeout).await {
-        Ok(_) => {
-          println!("Handshake acknowledged");
+    for _ in 0..HOLE_PUNCHES_COUNT {
+      self.send_packet(&punch_packet).await?;
 
-          // Send final acknowledgment
-          let ack_packet = MaspPacket::new(
-            PacketType::HandshakeFinalAck,
-            self.sequence_number,
-            Vec::new()
-          );
-          
-          self.send_packet(&ack_packet).await?;
+      sleep(Duration::from_millis(HOLE_PUNCH_DELAY_MS as u64)).await;
+    }
 
-          println!("Handshake completed");
+    Ok(())
+  }
 
-          return Ok(());
-        }
-        Err(e) => {
-          println!("Handshake attempt {} failed: {}", attempt, e);
+  /// Keeps datagrams, and the congestion window counted in them, within the size
+  /// both peers agreed on during the handshake, and protects video with parity
+  /// when they agreed on FEC.
+  pub async fn set_session_capabilities(&mut self, session_capabilities: &SessionCapabilities) {
+    self.max_datagram_size = session_capabilities.max_datagram_size as usize;
 
-          if attempt == (MAX_HANDSHAKE_ATTEMPTS - 1) {
-            return Err("Handshake failed after 3 attempts".into());
-          }
-        }
-      }
+    {
+      let smoothed_rtt = self.rtt_estimator.lock().await.smoothed_rtt();
+      let mut congestion_controller = self.congestion_controller.lock().await;
+
+      // the window counts datagrams, so it follows their size
+      congestion_controller.set_max_datagram_size(self.max_datagram_size);
+      self.target_bitrate.send_replace(congestion_controller.target_bitrate(smoothed_rtt));
     }
 
-    Ok(())
+    *self.fec_controller.lock().await = session_capabilities.fec.map(FecController::new);
   }
 
+  /// Handles acknowledgments and control packets from the remote peer until the session ends.
   pub async fn handle_acknowledgments(&self) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = [0u8; 1024];
+    let mut inbox = self.inbox.lock().await;
 
     loop {
-      let (len, addr) = self.socket.recv_from(&mut buf).await?;
-      
-      if addr != self.remote_addr {
-        continue;
-      }
+      let packet = tokio::select! {
+        datagram = inbox.recv() => match datagram {
+          Some((packet, _)) => packet,
+          None => return Ok(())
+        },
+        _ = self.shutdown.triggered() => return Ok(())
+      };
 
-      let packet = MaspPacket::deserialize(&buf[..len])?;
+      *self.last_heard_at.lock().await = Instant::now();
 
       match packet.packet_type {
         PacketType::Ack => {
-          let acked_sequence_number = u32::from_be_bytes([
-            packet.payload[0], 
-            packet.payload[1], 
-            packet.payload[2], 
-            packet.payload[3]
-          ]);
-
-          self.unacknowledged_packets.lock().await.remove(&acked_sequence_number);
+          let Ok(ack) = Ack::deserialize(&packet.payload) else {
+            continue;
+          };
+
+          self.acknowledge(ack.delivery_class, ack.cumulative, ack.bitmap).await;
+        },
+        PacketType::RetransmissionRequest => {
+          let Ok(request) = RetransmissionRequest::deserialize(&packet.payload) else {
+            continue;
+          };
+
+          self.retransmit_requested(request.delivery_class, &request.ranges).await;
+        },
+        PacketType::RecoveryReport => {
+          let Ok(report) = RecoveryReport::deserialize(&packet.payload) else {
+            continue;
+          };
+
+          // parity made up for the loss, nothing to resend and no reason to back off
+          if let Some(fec_controller) = self.fec_controller.lock().await.as_mut() {
+            fec_controller.on_packets_lost(report.recovered);
+          }
+        },
+        PacketType::Ping => {
+          let Ok(timestamp) = Timestamp::deserialize(&packet.payload) else {
+            continue;
+          };
+
+          let pong_packet = MaspPacket::new(
+            PacketType::Pong,
+            self.socket.next_control_sequence_number(),
+            timestamp.serialize()
+          );
+
+          self.send_packet(&pong_packet).await?;
+        },
+        PacketType::Pong => {
+          let Ok(timestamp) = Timestamp::deserialize(&packet.payload) else {
+            continue;
+          };
+
+          let sent_at = self.epoch + Duration::from_micros(timestamp.micros);
+
+          if let Some(rtt_sample) = Instant::now().checked_duration_since(sent_at) {
+            self.rtt_estimator.lock().await.on_sample(rtt_sample);
+          }
+        },
+        PacketType::Bye => {
+          self.shutdown.trigger(DisconnectReason::RemoteHangup);
+
+          return Ok(());
         },
         // Handle other packet types if necessary
         _ => {}
@@ -142,53 +330,267 @@ impl MaspSender {
     }
   }
 
-  /// Retransmits unacknowledged packets after a timeout.
-  pub async fn retransmit_unacknowledged(&self) {
-    let timeout = Duration::from_millis(RETRANSMIT_TIMEOUT_MS as u64);
+  /// Clears every packet covered by the cumulative ack or the selective-ack bitmap
+  /// and lets the congestion controller grow its window for them.
+  async fn acknowledge(&self, delivery_class: DeliveryClass, cumulative: u32, bitmap: u64) {
+    let mut acknowledged = Vec::new();
+
+    self.unacknowledged_packets.lock().await.retain(|(pending_class, sequence_number), unacknowledged| {
+      if *pending_class != delivery_class {
+        return true;
+      }
+
+      let offset = sequence_number.wrapping_sub(cumulative);
+      let is_acknowledged = !serial::is_before(cumulative, *sequence_number)
+        || ((2..SELECTIVE_ACK_BITS + 2).contains(&offset) && bitmap & (1 << (offset - 2)) != 0);
+
+      if is_acknowledged {
+        acknowledged.push(unacknowledged.clone());
+      }
+
+      !is_acknowledged
+    });
+
+    if acknowledged.is_empty() {
+      return;
+    }
+
+    let now = Instant::now();
+    let smoothed_rtt = {
+      let mut rtt_estimator = self.rtt_estimator.lock().await;
+
+      // a resent packet can't tell which transmission was acknowledged
+      acknowledged
+        .iter()
+        .filter(|unacknowledged| unacknowledged.first_sent_at == unacknowledged.last_sent_at)
+        .for_each(|unacknowledged| rtt_estimator.on_sample(now.duration_since(unacknowledged.first_sent_at)));
+
+      rtt_estimator.smoothed_rtt()
+    };
+
+    let mut congestion_controller = self.congestion_controller.lock().await;
+
+    for unacknowledged in acknowledged {
+      congestion_controller.on_packet_acked(unacknowledged.size);
+    }
+
+    self.target_bitrate.send_replace(congestion_controller.target_bitrate(smoothed_rtt));
+    self.window_opened.notify_waiters();
+  }
+
+  /// Resends the packets from the requested ranges that are still awaiting acknowledgment.
+  /// Packets past the deadline of their delivery class are already gone and get skipped.
+  /// Every request reports loss, so the congestion controller backs off.
+  async fn retransmit_requested(&self, delivery_class: DeliveryClass, ranges: &[(u32, u32)]) {
+    self.on_congestion_event().await;
+
+    // video loss decides how much parity protects the next frames
+    if delivery_class == DeliveryClass::LatestOnly {
+      let lost = self.unacknowledged_packets
+        .lock()
+        .await
+        .iter()
+        .filter(|((pending_class, sequence_number), unacknowledged)| {
+          *pending_class == delivery_class
+            && !unacknowledged.parity
+            && ranges.iter().any(|(start, end)| serial::in_range(*sequence_number, *start, *end))
+        })
+        .count();
+
+      if let Some(fec_controller) = self.fec_controller.lock().await.as_mut() {
+        fec_controller.on_packets_lost(lost as u32);
+      }
+    }
+
+    let packets: Vec<MaspPacket> = {
+      let mut unacknowledged_packets = self.unacknowledged_packets.lock().await;
+      let now = Instant::now();
+
+      unacknowledged_packets
+        .iter_mut()
+        .filter(|((pending_class, sequence_number), unacknowledged)| {
+          *pending_class == delivery_class
+            && unacknowledged.packet.is_some()
+            && ranges.iter().any(|(start, end)| serial::in_range(*sequence_number, *start, *end))
+        })
+        .filter_map(|(_, unacknowledged)| {
+          unacknowledged.last_sent_at = now;
+          unacknowledged.packet.clone()
+        })
+        .collect()
+    };
+
+    for packet in packets {
+      let _ = self.send_paced(&packet).await;
+    }
+  }
+
+  async fn on_congestion_event(&self) {
+    let smoothed_rtt = self.rtt_estimator.lock().await.smoothed_rtt();
+    let mut congestion_controller = self.congestion_controller.lock().await;
+
+    congestion_controller.on_congestion_event(smoothed_rtt);
+    self.target_bitrate.send_replace(congestion_controller.target_bitrate(smoothed_rtt));
+  }
+
+  /// Pings the peer periodically to measure RTT and keep the NAT mapping alive.
+  /// A short silence marks the connection as lost so the session gets resumed,
+  /// the session is shut down once nothing was heard from the peer for longer than the peer timeout.
+  pub async fn keep_alive(&self, status: ConnectionStatus) {
+    let interval = Duration::from_millis(KEEPALIVE_INTERVAL_MS as u64);
+    let reconnect_after = Duration::from_millis(RECONNECT_AFTER_MS as u64);
 
     loop {
-      sleep(timeout).await;
-      let packets = self.unacknowledged_packets.lock().await.clone();
-      
-      for packet in packets.values() {
-        // println!("Retransmitting packet with sequence number {}", packet.sequence_number);
-        
-        let _ = self.send_packet(packet).await;
+      let silence = self.last_heard_at.lock().await.elapsed();
+
+      if silence > self.peer_timeout {
+        self.shutdown.trigger(DisconnectReason::PeerTimeout);
+
+        return;
       }
+
+      status.set(if silence > reconnect_after {
+        ConnectionState::Reconnecting
+      } else {
+        ConnectionState::Connected
+      });
+
+      let timestamp_micros = self.epoch.elapsed().as_micros() as u64;
+      let ping_packet = MaspPacket::new(
+        PacketType::Ping,
+        self.socket.next_control_sequence_number(),
+        Timestamp { micros: timestamp_micros }.serialize()
+      );
+
+      // a failed ping is just a missed sample, silence is caught above
+      let _ = self.send_packet(&ping_packet).await;
+
+      tokio::select! {
+        _ = sleep(interval) => {},
+        _ = self.shutdown.triggered() => return
+      }
+    }
+  }
+
+  /// Records a sign of life from the peer that didn't come through the inbox, like a completed handshake.
+  pub async fn heard_from_peer(&self) {
+    *self.last_heard_at.lock().await = Instant::now();
+  }
+
+  /// Tells the remote peer that the session is over.
+  pub async fn send_bye(&self) {
+    for _ in 0..BYE_REPEAT_COUNT {
+      let bye_packet = MaspPacket::new(PacketType::Bye, self.socket.next_control_sequence_number(), Vec::new());
+
+      let _ = self.send_packet(&bye_packet).await;
     }
   }
 
-  async fn receive_handshake_ack(&self, timeout: Duration) -> Result<(), &'static str> {
-    let mut buf = [0u8; 1024];
+  /// Expires unacknowledged packets past their class deadline and probes for tail loss.
+  ///
+  /// Gaps are reported by the receiver through retransmission requests, but
+  /// nothing reveals a lost packet at the end of a burst. When the newest
+  /// packet of a class stays unacknowledged past the timeout it is resent
+  /// alone, so the receiver can notice and request everything missing before it.
+  /// The timeout is the adaptive RTO of the RTT estimator.
+  pub async fn retransmit_unacknowledged(&self) {
+    loop {
+      let timeout = self.rtt_estimator.lock().await.retransmission_timeout();
+
+      tokio::select! {
+        _ = sleep(timeout) => {},
+        _ = self.shutdown.triggered() => return
+      }
+
+      let (expired_bytes, tail_packets): (usize, Vec<MaspPacket>) = {
+        let mut unacknowledged_packets = self.unacknowledged_packets.lock().await;
+        let now = Instant::now();
+        let mut expired_bytes = 0;
 
-    tokio::select! {
-      result = self.socket.recv_from(&mut buf) => {
-        match result {
-          Ok((len, addr)) => {
-            if addr != self.remote_addr {
-              return Err("Received packet from unexpected address");
-            }
+        unacknowledged_packets.retain(|(delivery_class, _), unacknowledged| {
+          let in_time = delivery_class
+            .deadline()
+            .is_none_or(|deadline| now.duration_since(unacknowledged.first_sent_at) < deadline);
 
-            let packet = MaspPacket::deserialize(&buf[..len])?;
+          if !in_time {
+            expired_bytes += unacknowledged.size;
+          }
 
-            match packet.packet_type {
-              PacketType::HandshakeAck => Ok(()),
-              _ => Err("Received unexpected packet type")
-            }
-          },
-          Err(_) => Err("Failed to receive data")
-        }
+          in_time
+        });
+
+        let tail_packets = DELIVERY_CLASSES
+          .iter()
+          .filter_map(|delivery_class| {
+            unacknowledged_packets
+              .iter_mut()
+              .filter(|((pending_class, _), unacknowledged)| {
+                pending_class == delivery_class && unacknowledged.packet.is_some()
+              })
+              .map(|(_, unacknowledged)| unacknowledged)
+              .max_by_key(|unacknowledged| unacknowledged.first_sent_at)
+              .filter(|unacknowledged| now.duration_since(unacknowledged.last_sent_at) >= timeout)
+              .and_then(|unacknowledged| {
+                unacknowledged.last_sent_at = now;
+                unacknowledged.packet.clone()
+              })
+          })
+          .collect();
+
+        (expired_bytes, tail_packets)
+      };
+
+      // packets that were never acknowledged in time count as lost
+      if expired_bytes > 0 {
+        self.congestion_controller.lock().await.on_packets_expired(expired_bytes);
+        self.on_congestion_event().await;
+        self.window_opened.notify_waiters();
       }
-      _ = sleep(timeout) => {
-        return Err("Timeout waiting for handshake acknowledgment");
+
+      for packet in tail_packets {
+        let _ = self.send_paced(&packet).await;
       }
     }
   }
 
-  async fn send_packet(&self, packet: &MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
-    let data = packet.serialize();
+  /// Waits until the congestion window has room for the packet, acknowledgments
+  /// and expired packets make some.
+  async fn wait_for_window(&self, packet: &MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
+    let size = MASP_HEADER_SIZE + packet.payload.len() + crypto::TAG_SIZE;
 
-    self.socket.send_to(&data, &self.remote_addr).await?;
+    loop {
+      // registered before checking, so room made in between isn't missed
+      let window_opened = self.window_opened.notified();
+      tokio::pin!(window_opened);
+      window_opened.as_mut().enable();
+
+      if self.congestion_controller.lock().await.can_send(size) {
+        return Ok(());
+      }
+
+      tokio::select! {
+        _ = window_opened => {},
+        _ = self.shutdown.triggered() => return Err("Session ended while waiting for the congestion window".into())
+      }
+    }
+  }
+
+  /// Waits for the pacing slot granted by the congestion controller, then sends
+  /// the data packet. Returns the size of the sent datagram.
+  async fn send_paced(&self, packet: &MaspPacket) -> Result<usize, Box<dyn std::error::Error>> {
+    let size = MASP_HEADER_SIZE + packet.payload.len() + crypto::TAG_SIZE;
+    let smoothed_rtt = self.rtt_estimator.lock().await.smoothed_rtt();
+    let delay = self.congestion_controller.lock().await.pacing_delay(size, smoothed_rtt);
+
+    if !delay.is_zero() {
+      sleep(delay).await;
+    }
+
+    self.socket.send(packet).await
+  }
+
+  async fn send_packet(&self, packet: &MaspPacket) -> Result<(), Box<dyn std::error::Error>> {
+    self.socket.send(packet).await?;
 
     Ok(())
   }
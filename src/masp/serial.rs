@@ -0,0 +1,28 @@
+use std::cmp::Ordering;
+
+/// Half of the u32 space, the furthest two serial numbers can be apart and still be ordered.
+const HALF_RANGE: u32 = 1 << 31;
+
+/// Compares sequence numbers and frame ids as serial numbers (RFC 1982), so
+/// ordering survives their wraparound at 2^32: a number is before another when
+/// it is less than half the space behind it.
+///
+/// Numbers exactly half the space apart are unordered and compare as equal,
+/// callers only ever compare numbers from a window far smaller than that.
+pub fn compare(a: u32, b: u32) -> Ordering {
+  match b.wrapping_sub(a) {
+    0 | HALF_RANGE => Ordering::Equal,
+    distance if distance < HALF_RANGE => Ordering::Less,
+    _ => Ordering::Greater
+  }
+}
+
+/// Whether `a` comes before `b`.
+pub fn is_before(a: u32, b: u32) -> bool {
+  compare(a, b) == Ordering::Less
+}
+
+/// Whether `value` lies in the inclusive range from `start` to `end`, which may wrap around.
+pub fn in_range(value: u32, start: u32, end: u32) -> bool {
+  value.wrapping_sub(start) <= end.wrapping_sub(start)
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Why a session ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+  /// The local user pressed Ctrl+C.
+  LocalHangup,
+  /// The remote peer sent a Bye.
+  RemoteHangup,
+  /// Nothing was heard from the remote peer for too long.
+  PeerTimeout,
+  /// A session task failed with an error.
+  Failed(String)
+}
+
+impl fmt::Display for DisconnectReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DisconnectReason::LocalHangup => write!(f, "you hung up"),
+      DisconnectReason::RemoteHangup => write!(f, "the remote peer hung up"),
+      DisconnectReason::PeerTimeout => write!(f, "the remote peer stopped responding"),
+      DisconnectReason::Failed(e) => write!(f, "session failed: {}", e)
+    }
+  }
+}
+
+/// Cancellation handle shared by every task of a session.
+/// The first reason to be triggered wins, later ones are ignored.
+#[derive(Clone)]
+pub struct Shutdown {
+  reason: Arc<watch::Sender<Option<DisconnectReason>>>
+}
+
+impl Shutdown {
+  pub fn new() -> Self {
+    let (reason, _) = watch::channel(None);
+
+    Self {
+      reason: Arc::new(reason)
+    }
+  }
+
+  pub fn trigger(&self, reason: DisconnectReason) {
+    self.reason.send_if_modified(|current| {
+      if current.is_some() {
+        return false;
+      }
+
+      *current = Some(reason);
+      true
+    });
+  }
+
+  pub fn is_triggered(&self) -> bool {
+    self.reason.borrow().is_some()
+  }
+
+  /// Resolves once the session is shutting down.
+  pub async fn triggered(&self) -> DisconnectReason {
+    let mut receiver = self.reason.subscribe();
+
+    // the sender lives as long as `self`, so waiting can't fail
+    let reason = match receiver.wait_for(Option::is_some).await {
+      Ok(reason) => reason.clone(),
+      Err(_) => None
+    };
+
+    reason.unwrap_or(DisconnectReason::LocalHangup)
+  }
+}
+
+impl Default for Shutdown {
+  fn default() -> Self {
+    Self::new()
+  }
+}
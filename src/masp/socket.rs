@@ -0,0 +1,312 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rand::Rng;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+
+use super::config::MAX_UDP_PAYLOAD_SIZE;
+use super::crypto::{Arrival, PacketCipher, SessionKeys};
+use super::message::{MaspPacket, PacketType, FLAG_CHECKSUM};
+use super::payload::Timestamp;
+use super::shutdown::Shutdown;
+use crate::transport::{self, Transport};
+
+/// Packets queued per role before the demultiplexer starts dropping them,
+/// like a full socket buffer would.
+const INBOX_CAPACITY: usize = 1024;
+/// Pause after a failed read, so a network that stays down doesn't spin the demultiplexer.
+const RECEIVE_ERROR_DELAY_MS: u8 = 50;
+/// Pause before challenging the same new address again, in case the challenge or its answer got lost.
+const PATH_CHALLENGE_INTERVAL_MS: u16 = 500;
+
+/// Packet together with the address it came from.
+pub type Datagram = (MaspPacket, SocketAddr);
+/// Packets the demultiplexer routed to one role.
+pub type Inbox = mpsc::Receiver<Datagram>;
+
+/// The single socket of a session, shared by the sender and receiver roles.
+#[derive(Clone)]
+pub struct MaspSocket {
+  transport: Arc<dyn Transport>,
+  /// Current address of the peer, follows it when its NAT rebinds mid-session.
+  remote_addr: Arc<RwLock<SocketAddr>>,
+  /// Identifier the peer puts in every packet it sends us, chosen by us.
+  local_connection_id: u32,
+  /// Identifier we put in every packet we send, chosen by the peer during the handshake.
+  remote_connection_id: Arc<AtomicU32>,
+  /// Set once the handshake agreed on session keys, every later packet is encrypted.
+  /// Replaced when a resumed session agrees on new ones.
+  cipher: Arc<RwLock<Option<Arc<PacketCipher>>>>,
+  /// Sequence space of control packets of both roles, keeps their nonces unique.
+  control_sequence_number: Arc<AtomicU32>
+}
+
+impl MaspSocket {
+  /// Binds a UDP socket for a session with the given peer.
+  pub async fn bind(local_addr: SocketAddr, remote_addr: SocketAddr) -> Result<Self, Box<dyn std::error::Error>> {
+    Ok(Self::new(transport::bind_udp(local_addr).await?, remote_addr))
+  }
+
+  /// Runs a session with the given peer over an already bound transport.
+  pub fn new(transport: Arc<dyn Transport>, remote_addr: SocketAddr) -> Self {
+    MaspSocket {
+      transport,
+      remote_addr: Arc::new(RwLock::new(remote_addr)),
+      // zero marks packets sent before the peer's identifier is known
+      local_connection_id: rand::thread_rng().gen_range(1..=u32::MAX),
+      remote_connection_id: Arc::new(AtomicU32::new(0)),
+      cipher: Arc::new(RwLock::new(None)),
+      control_sequence_number: Arc::new(AtomicU32::new(0))
+    }
+  }
+
+  pub fn remote_addr(&self) -> SocketAddr {
+    *self.remote_addr.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  pub fn set_remote_addr(&self, remote_addr: SocketAddr) {
+    *self.remote_addr.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = remote_addr;
+  }
+
+  pub fn local_connection_id(&self) -> u32 {
+    self.local_connection_id
+  }
+
+  /// Tags everything sent from now on with the identifier the peer chose during the handshake.
+  pub fn set_remote_connection_id(&self, connection_id: u32) {
+    self.remote_connection_id.store(connection_id, Ordering::Relaxed);
+  }
+
+  /// Encrypts everything sent from now on with the keys agreed on during the handshake.
+  pub fn set_session_keys(&self, session_keys: &SessionKeys) {
+    *self.cipher.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(session_keys.cipher()));
+  }
+
+  fn cipher(&self) -> Option<Arc<PacketCipher>> {
+    self.cipher.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+  }
+
+  pub fn next_control_sequence_number(&self) -> u32 {
+    self.control_sequence_number.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+  }
+
+  /// Sends the packet to the remote peer, tagged with its connection identifier
+  /// and encrypted once the session keys are set. Returns the size of the sent datagram.
+  pub async fn send(&self, packet: &MaspPacket) -> Result<usize, Box<dyn std::error::Error>> {
+    let connection_id = self.remote_connection_id.load(Ordering::Relaxed);
+
+    self.send_to(packet, self.remote_addr(), connection_id).await
+  }
+
+  /// Sends the packet to the given address and connection identifier instead of the current ones.
+  /// A datagram the network refuses, e.g. while offline, counts as lost rather than as an error.
+  pub async fn send_to(
+    &self,
+    packet: &MaspPacket,
+    remote_addr: SocketAddr,
+    connection_id: u32
+  ) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut packet = packet.clone();
+    packet.connection_id = connection_id;
+
+    let data = match self.cipher() {
+      Some(cipher) if is_encrypted(packet.packet_type) => cipher.seal(&packet)?.serialize(),
+      // the AEAD tag already covers sealed packets, the rest gets a checksum
+      _ => {
+        packet.flags |= FLAG_CHECKSUM;
+        packet.serialize()
+      }
+    };
+
+    let _ = self.transport.send_to(&data, remote_addr).await;
+
+    Ok(data.len())
+  }
+
+  /// Creates the demultiplexer of this socket along with the inboxes of the
+  /// sender role, the receiver role and the handshake.
+  pub fn demultiplexer(&self, shutdown: Shutdown) -> (Demultiplexer, Inbox, Inbox, Inbox) {
+    let (sender_route, sender_inbox) = mpsc::channel(INBOX_CAPACITY);
+    let (receiver_route, receiver_inbox) = mpsc::channel(INBOX_CAPACITY);
+    let (handshake_route, handshake_inbox) = mpsc::channel(INBOX_CAPACITY);
+
+    (
+      Demultiplexer {
+        socket: self.clone(),
+        sender_route,
+        receiver_route,
+        handshake_route,
+        path_challenge: None,
+        shutdown
+      },
+      sender_inbox,
+      receiver_inbox,
+      handshake_inbox
+    )
+  }
+}
+
+/// Handshake packets carry their own proofs, punches nothing worth hiding.
+fn is_encrypted(packet_type: PacketType) -> bool {
+  !packet_type.is_handshake() && packet_type != PacketType::Punch
+}
+
+/// Which role handles a packet type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+  Sender,
+  Receiver,
+  /// Handshakes run beside the roles, so a session can be resumed while they keep going.
+  Handshake,
+  Drop
+}
+
+fn route(packet_type: PacketType) -> Route {
+  match packet_type {
+    // answers to what the sender role sent, and liveness of the whole session
+    PacketType::Ack
+    | PacketType::RetransmissionRequest
+    | PacketType::RecoveryReport
+    | PacketType::Ping
+    | PacketType::Pong
+    | PacketType::Bye => Route::Sender,
+    PacketType::TextData
+    | PacketType::AudioData
+    | PacketType::VideoData
+    | PacketType::VideoParity => Route::Receiver,
+    PacketType::HandshakeRequest
+    | PacketType::HandshakeAck
+    | PacketType::HandshakeFinalAck
+    | PacketType::HandshakeReject => Route::Handshake,
+    PacketType::Punch => Route::Drop
+  }
+}
+
+/// Ping sent to a new address of the peer, carrying a random value in place of its timestamp.
+struct PathChallenge {
+  addr: SocketAddr,
+  value: u64,
+  sent_at: Instant
+}
+
+/// Reads every datagram arriving on the session socket and routes it to the role that handles it.
+pub struct Demultiplexer {
+  socket: MaspSocket,
+  sender_route: mpsc::Sender<Datagram>,
+  receiver_route: mpsc::Sender<Datagram>,
+  handshake_route: mpsc::Sender<Datagram>,
+  /// Challenge of the address the peer seems to have moved to, until it is answered.
+  path_challenge: Option<PathChallenge>,
+  shutdown: Shutdown
+}
+
+impl Demultiplexer {
+  /// Runs until the session ends. Packets are matched to the session by their
+  /// connection identifier, those of other sessions and those that fail to
+  /// authenticate are dropped here.
+  ///
+  /// The newest authenticated packet from a new address means the peer may
+  /// have moved, e.g. its NAT rebound. Like QUIC path validation, the address
+  /// is challenged with a ping first, and later packets only follow the peer
+  /// there once the pong comes back from it. Reordered or re-injected old
+  /// packets never move the session.
+  pub async fn run(mut self) {
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD_SIZE];
+
+    loop {
+      let result = tokio::select! {
+        result = self.socket.transport.recv_from(&mut buf) => result,
+        _ = self.shutdown.triggered() => return
+      };
+
+      // errors like an unreachable network pass once it is back, silence detection
+      // decides whether the session survives them
+      let Ok((len, addr)) = result else {
+        sleep(Duration::from_millis(RECEIVE_ERROR_DELAY_MS as u64)).await;
+        continue;
+      };
+
+      let Ok(packet) = MaspPacket::deserialize(&buf[..len]) else {
+        continue;
+      };
+
+      // handshake requests are sent before the peer knows our identifier
+      if packet.packet_type != PacketType::HandshakeRequest
+        && packet.connection_id != self.socket.local_connection_id {
+        continue;
+      }
+
+      // nothing but handshakes and punches is valid before the session keys are set
+      let packet = if is_encrypted(packet.packet_type) {
+        let Some(Ok((packet, arrival))) = self.socket.cipher().map(|cipher| cipher.open(&packet)) else {
+          continue;
+        };
+
+        // a repeated data packet means our ack was lost, the receiver acknowledges
+        // it again without delivering it twice. Repeated control packets are dropped
+        if arrival == Arrival::Duplicate && route(packet.packet_type) != Route::Receiver {
+          continue;
+        }
+
+        if self.answers_path_challenge(&packet, addr) {
+          self.socket.set_remote_addr(addr);
+          self.path_challenge = None;
+          continue;
+        }
+
+        if arrival == Arrival::Newest && addr != self.socket.remote_addr() {
+          self.challenge_path(addr).await;
+        }
+
+        packet
+      } else {
+        packet
+      };
+
+      let route = match route(packet.packet_type) {
+        Route::Sender => &self.sender_route,
+        Route::Receiver => &self.receiver_route,
+        Route::Handshake => &self.handshake_route,
+        Route::Drop => continue
+      };
+
+      // a role that can't keep up loses packets, as it would with its own socket
+      let _ = route.try_send((packet, addr));
+    }
+  }
+
+  /// Pings the address unless it was challenged moments ago.
+  async fn challenge_path(&mut self, addr: SocketAddr) {
+    if let Some(path_challenge) = &self.path_challenge {
+      if path_challenge.addr == addr
+        && path_challenge.sent_at.elapsed() < Duration::from_millis(PATH_CHALLENGE_INTERVAL_MS as u64) {
+        return;
+      }
+    }
+
+    let value = rand::thread_rng().gen();
+    let ping_packet = MaspPacket::new(
+      PacketType::Ping,
+      self.socket.next_control_sequence_number(),
+      Timestamp { micros: value }.serialize()
+    );
+    let connection_id = self.socket.remote_connection_id.load(Ordering::Relaxed);
+
+    let _ = self.socket.send_to(&ping_packet, addr, connection_id).await;
+
+    self.path_challenge = Some(PathChallenge { addr, value, sent_at: Instant::now() });
+  }
+
+  /// Whether the packet is the pong to the pending challenge, from the challenged address.
+  fn answers_path_challenge(&self, packet: &MaspPacket, addr: SocketAddr) -> bool {
+    let Some(path_challenge) = &self.path_challenge else {
+      return false;
+    };
+
+    packet.packet_type == PacketType::Pong
+      && path_challenge.addr == addr
+      && Timestamp::deserialize(&packet.payload).is_ok_and(|timestamp| timestamp.micros == path_challenge.value)
+  }
+}
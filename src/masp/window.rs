@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::serial;
+
+/// Gaps wider than this are treated as a resync instead of being tracked.
+const MAX_TRACKED_GAP: u32 = 1024;
+/// Number of sequence numbers after the cumulative ack covered by the bitmap.
+pub const SELECTIVE_ACK_BITS: u32 = 64;
+
+struct MissingPacket {
+  detected_at: Instant,
+  requested_at: Option<Instant>
+}
+
+/// Tracks received sequence numbers and the gaps between them.
+pub struct ReceiveWindow {
+  expected_sequence_number: u32,
+  missing: HashMap<u32, MissingPacket>,
+  ack_pending: bool,
+  nack_interval: Duration,
+  /// How long a gap waits before it is first requested, zero unless something
+  /// else may still fill it.
+  nack_delay: Duration,
+  /// How long a gap is requested before it is given up on, forever without one.
+  missing_deadline: Option<Duration>
+}
+
+impl ReceiveWindow {
+  /// Window expecting `first_sequence_number` first, anything later that shows
+  /// up before it leaves a gap down to it.
+  pub fn new(nack_interval: Duration, missing_deadline: Option<Duration>, first_sequence_number: u32) -> Self {
+    Self {
+      expected_sequence_number: first_sequence_number,
+      missing: HashMap::new(),
+      ack_pending: false,
+      nack_interval,
+      nack_delay: Duration::ZERO,
+      missing_deadline
+    }
+  }
+
+  /// Holds back the first request for a gap, e.g. while parity may still rebuild it.
+  pub fn set_nack_delay(&mut self, nack_delay: Duration) {
+    self.nack_delay = nack_delay;
+  }
+
+  /// Records a received sequence number and marks any skipped ones as missing.
+  /// Returns false for a duplicate: a number that was neither ahead of the window
+  /// nor still missing, e.g. a retransmission whose ack was lost, which the
+  /// cipher lets through as a duplicate, or one of a packet FEC already rebuilt.
+  /// Sequence numbers are compared as serial numbers, so the window keeps
+  /// working when they wrap around.
+  pub fn record(&mut self, sequence_number: u32) -> bool {
+    // even a duplicate is worth acknowledging, the sender resends until it hears an ack
+    self.ack_pending = true;
+
+    let expected = self.expected_sequence_number;
+
+    if serial::is_before(sequence_number, expected) {
+      return self.missing.remove(&sequence_number).is_some();
+    }
+
+    let gap = sequence_number.wrapping_sub(expected);
+
+    if gap > MAX_TRACKED_GAP {
+      self.missing.clear();
+    } else {
+      let now = Instant::now();
+
+      for offset in 0..gap {
+        self.missing.insert(expected.wrapping_add(offset), MissingPacket {
+          detected_at: now,
+          requested_at: None
+        });
+      }
+    }
+
+    self.expected_sequence_number = sequence_number.wrapping_add(1);
+
+    true
+  }
+
+  /// Returns the missing sequence ranges (inclusive) that are due for a retransmission
+  /// request, once past the NACK delay. Packets missing for longer than the deadline,
+  /// if there is one, are given up on.
+  pub fn take_nack_ranges(&mut self) -> Vec<(u32, u32)> {
+    let now = Instant::now();
+    let missing_deadline = self.missing_deadline;
+    let nack_interval = self.nack_interval;
+    let nack_delay = self.nack_delay;
+
+    if let Some(missing_deadline) = missing_deadline {
+      self.missing.retain(|_, packet| now.duration_since(packet.detected_at) < missing_deadline);
+    }
+
+    let mut due: Vec<u32> = self.missing
+      .iter_mut()
+      .filter(|(_, packet)| match packet.requested_at {
+        Some(requested_at) => now.duration_since(requested_at) >= nack_interval,
+        None => now.duration_since(packet.detected_at) >= nack_delay
+      })
+      .map(|(sequence_number, packet)| {
+        packet.requested_at = Some(now);
+        *sequence_number
+      })
+      .collect();
+
+    // oldest first, wherever the numbers wrapped around
+    let expected = self.expected_sequence_number;
+    due.sort_unstable_by_key(|sequence_number| std::cmp::Reverse(expected.wrapping_sub(*sequence_number)));
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+
+    for sequence_number in due {
+      match ranges.last_mut() {
+        Some((_, end)) if end.wrapping_add(1) == sequence_number => *end = sequence_number,
+        _ => ranges.push((sequence_number, sequence_number))
+      }
+    }
+
+    ranges
+  }
+
+  /// Returns the oldest sequence number that is still being waited for.
+  pub fn lowest_missing(&self) -> Option<u32> {
+    let expected = self.expected_sequence_number;
+
+    self.missing
+      .keys()
+      .max_by_key(|sequence_number| expected.wrapping_sub(**sequence_number))
+      .copied()
+  }
+
+  /// Returns the cumulative ack and selective-ack bitmap if anything new
+  /// arrived since the last call.
+  ///
+  /// Every sequence number up to the cumulative ack was received or given up
+  /// on. Bit `i` of the bitmap reports sequence number `cumulative + 2 + i`,
+  /// since `cumulative + 1` is missing by definition.
+  pub fn take_ack(&mut self) -> Option<(u32, u64)> {
+    if !self.ack_pending {
+      return None;
+    }
+
+    let expected = self.expected_sequence_number;
+
+    self.ack_pending = false;
+
+    let cumulative = self.lowest_missing().unwrap_or(expected).wrapping_sub(1);
+    let received_span = expected.wrapping_sub(cumulative);
+    let mut bitmap = 0u64;
+
+    for bit in 0..SELECTIVE_ACK_BITS {
+      let offset = bit + 2;
+      let sequence_number = cumulative.wrapping_add(offset);
+
+      if offset < received_span && !self.missing.contains_key(&sequence_number) {
+        bitmap |= 1 << bit;
+      }
+    }
+
+    Some((cumulative, bitmap))
+  }
+}
\ No newline at end of file
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::time::Duration;
+
+use crate::stun::{self, Credentials, StunAttribute, StunConfig, StunMessage};
+use crate::transport::Transport;
+
+/// How long each behaviour test waits for its answer, a filtered one never comes.
+const TEST_TIMEOUT_SECONDS: u8 = 3;
+/// Largest port step between mappings that is still worth predicting.
+const MAX_PREDICTABLE_PORT_DELTA: i32 = 16;
+
+/// How a NAT treats different destinations, as classified by RFC 4787.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+  EndpointIndependent,
+  AddressDependent,
+  AddressAndPortDependent,
+  /// Depends on the destination, the servers couldn't tell whether on its port too.
+  Dependent
+}
+
+impl fmt::Display for Behavior {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Behavior::EndpointIndependent => write!(f, "endpoint-independent"),
+      Behavior::AddressDependent => write!(f, "address-dependent"),
+      Behavior::AddressAndPortDependent => write!(f, "address and port-dependent"),
+      Behavior::Dependent => write!(f, "destination-dependent")
+    }
+  }
+}
+
+/// What the behaviour tests found out about the NAT in front of a socket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatReport {
+  pub mapped_addr: SocketAddr,
+  /// False when the mapped address is the socket's own, nothing translates it.
+  pub behind_nat: bool,
+  /// Whether the public address is the same towards every destination.
+  /// Unknown when only one server answered.
+  pub mapping: Option<Behavior>,
+  /// Who may send to the public address once it is mapped. Unknown when the
+  /// server doesn't support RFC 5780.
+  pub filtering: Option<Behavior>,
+  /// Port step between the mappings towards successive destinations, when it was steady.
+  pub port_delta: Option<i32>
+}
+
+impl NatReport {
+  /// Whether, and with whom, peers get through to each other directly.
+  pub fn p2p_verdict(&self) -> &'static str {
+    match (self.behind_nat, self.mapping, self.filtering) {
+      (false, _, _) => "works with anyone, you can be reached directly unless a firewall filters",
+      (true, Some(Behavior::EndpointIndependent), Some(Behavior::EndpointIndependent)) => {
+        "works with anyone, your public address accepts everybody once mapped"
+      },
+      (true, Some(Behavior::EndpointIndependent), _) => {
+        "works when both peers punch at once (join, connect), unless the peer's NAT is symmetric"
+      },
+      (true, Some(_), _) if self.port_delta.is_some() => {
+        "hard, your NAT is symmetric: only peers with endpoint-independent NATs may get through by port prediction, use a relay"
+      },
+      (true, Some(_), _) => "unlikely, your NAT is symmetric: only peers without NAT get through, use a relay",
+      (true, None, _) => "unknown, only one STUN server answered"
+    }
+  }
+
+  /// Whether a peer can guess the port the NAT maps towards it.
+  pub fn port_prediction(&self) -> String {
+    match (self.behind_nat, self.mapping, self.port_delta) {
+      (false, _, _) | (true, Some(Behavior::EndpointIndependent), _) => "not needed, the port is the same towards everyone".to_string(),
+      (true, _, Some(delta)) => format!("possible, the port moves by {} per destination", delta),
+      (true, Some(_), None) => "not possible, the ports look random".to_string(),
+      (true, None, None) => "unknown".to_string()
+    }
+  }
+}
+
+impl fmt::Display for NatReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let unknown = |behavior: Option<Behavior>| behavior.map_or("unknown".to_string(), |behavior| behavior.to_string());
+
+    writeln!(f, "You are {}", self.mapped_addr)?;
+
+    if self.behind_nat {
+      writeln!(f, "NAT mapping: {}, filtering: {}", unknown(self.mapping), unknown(self.filtering))?;
+    } else {
+      writeln!(f, "No NAT, the address is your own")?;
+    }
+
+    writeln!(f, "Peer-to-peer: {}", self.p2p_verdict())?;
+    write!(f, "Port prediction: {}", self.port_prediction())
+  }
+}
+
+/// Runs the behaviour tests of RFC 5780 against the STUN server when it tells its
+/// other address, and otherwise compares the mappings towards `fallback_servers`.
+pub async fn discover(
+  socket: &dyn Transport,
+  stun: &StunConfig,
+  fallback_servers: &[SocketAddr]
+) -> Result<NatReport, Box<dyn std::error::Error>> {
+  let (server, credentials) = (stun.server, stun.credentials.as_ref());
+  let response = binding(socket, server, credentials, None).await?.0;
+  let mapped_addr = response.reflexive_addr()?;
+  let other_addr = response.attributes.iter().find_map(|attribute| match attribute {
+    StunAttribute::OtherAddress(addr) => Some(*addr),
+    _ => None
+  });
+
+  // filtering first, mapping tests open the NAT towards the server's other address
+  let filtering = match other_addr {
+    Some(_) => discover_filtering(socket, server, credentials).await,
+    None => None
+  };
+
+  let mut mappings = vec![mapped_addr];
+
+  let mapping = match other_addr {
+    Some(other_addr) => discover_mapping(socket, server, credentials, other_addr, &mut mappings).await,
+    None => {
+      for fallback_server in fallback_servers {
+        if let Ok(addr) = binding(socket, *fallback_server, None, None).await.and_then(|(response, _)| response.reflexive_addr()) {
+          mappings.push(addr);
+        }
+      }
+
+      match mappings.len() {
+        1 => None,
+        _ if mappings.iter().all(|addr| *addr == mapped_addr) => Some(Behavior::EndpointIndependent),
+        _ => Some(Behavior::Dependent)
+      }
+    }
+  };
+
+  Ok(NatReport {
+    mapped_addr,
+    behind_nat: !is_own_addr(mapped_addr, socket.local_addr()?),
+    mapping,
+    filtering,
+    port_delta: port_delta(&mappings)
+  })
+}
+
+/// Tests II and III of RFC 5780 mapping discovery: the other IP, then the other IP and port.
+async fn discover_mapping(
+  socket: &dyn Transport,
+  server: SocketAddr,
+  credentials: Option<&Credentials>,
+  other_addr: SocketAddr,
+  mappings: &mut Vec<SocketAddr>
+) -> Option<Behavior> {
+  let (response, _) = binding(socket, SocketAddr::new(other_addr.ip(), server.port()), credentials, None).await.ok()?;
+  let other_ip_mapping = response.reflexive_addr().ok()?;
+  mappings.push(other_ip_mapping);
+
+  if other_ip_mapping == mappings[0] {
+    return Some(Behavior::EndpointIndependent);
+  }
+
+  let (response, _) = binding(socket, other_addr, credentials, None).await.ok()?;
+  let other_addr_mapping = response.reflexive_addr().ok()?;
+  mappings.push(other_addr_mapping);
+
+  if other_addr_mapping == other_ip_mapping {
+    Some(Behavior::AddressDependent)
+  } else {
+    Some(Behavior::AddressAndPortDependent)
+  }
+}
+
+/// Tests II and III of RFC 5780 filtering discovery: answers from the other IP
+/// and port, then from the other port only. Unknown when the server answers
+/// from where it was asked anyway.
+async fn discover_filtering(socket: &dyn Transport, server: SocketAddr, credentials: Option<&Credentials>) -> Option<Behavior> {
+  match binding(socket, server, credentials, Some((true, true))).await {
+    Ok((_, from)) if from.ip() != server.ip() && from.port() != server.port() => {
+      return Some(Behavior::EndpointIndependent);
+    },
+    Ok(_) => return None,
+    Err(_) => {}
+  }
+
+  match binding(socket, server, credentials, Some((false, true))).await {
+    Ok((_, from)) if from.ip() == server.ip() && from.port() != server.port() => Some(Behavior::AddressDependent),
+    Ok(_) => None,
+    Err(_) => Some(Behavior::AddressAndPortDependent)
+  }
+}
+
+/// Binding request, asking the server to answer from another IP and/or port when `change` is given.
+async fn binding(
+  socket: &dyn Transport,
+  server: SocketAddr,
+  credentials: Option<&Credentials>,
+  change: Option<(bool, bool)>
+) -> Result<(StunMessage, SocketAddr), Box<dyn std::error::Error>> {
+  let mut request = StunMessage::new();
+
+  if let Some((change_ip, change_port)) = change {
+    request.attributes.push(StunAttribute::ChangeRequest { change_ip, change_port });
+  }
+
+  stun::transact(socket, server, &request, credentials, Duration::from_secs(TEST_TIMEOUT_SECONDS as u64)).await
+}
+
+/// Whether nothing translated the address: it is one of our interfaces, on the socket's port.
+fn is_own_addr(mapped_addr: SocketAddr, local_addr: SocketAddr) -> bool {
+  if mapped_addr.port() != local_addr.port() {
+    return false;
+  }
+
+  if mapped_addr.ip() == local_addr.ip() {
+    return true;
+  }
+
+  if_addrs::get_if_addrs()
+    .map(|interfaces| interfaces.iter().any(|interface| interface.ip() == mapped_addr.ip()))
+    .unwrap_or(false)
+}
+
+/// Port step between successive mappings, if it was the same small one at least twice.
+pub fn port_delta(mappings: &[SocketAddr]) -> Option<i32> {
+  let deltas: Vec<i32> = mappings
+    .windows(2)
+    .map(|pair| pair[1].port() as i32 - pair[0].port() as i32)
+    .collect();
+
+  let delta = *deltas.first()?;
+  let steady = deltas.len() >= 2 && deltas.iter().all(|step| *step == delta);
+
+  (steady && delta != 0 && delta.abs() <= MAX_PREDICTABLE_PORT_DELTA).then_some(delta)
+}
@@ -0,0 +1,375 @@
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::task;
+use tokio::time::{interval, timeout, Duration, Instant, MissedTickBehavior};
+
+use crate::masp::config::MAX_UDP_PAYLOAD_SIZE;
+use crate::transport::{self, Transport};
+
+pub const RELAY_MAGIC_NUMBER: [u8; 4] = [0x4D, 0x54, 0x52, 0x4C]; // 'MTRL'
+pub const RELAY_VERSION: u8 = 0x01;
+pub const MAX_TOKEN_LENGTH: usize = 255;
+pub const MAX_PAIR_KEY_LENGTH: usize = 255;
+
+const ALLOCATE: u8 = 0x01;
+const ALLOCATED: u8 = 0x02;
+const REFUSED: u8 = 0x03;
+
+const HEADER_SIZE: usize = 6;
+
+/// Clients ask again this often until the relay answers.
+const ALLOCATE_INTERVAL_MS: u16 = 500;
+/// How long a client waits for the relay to answer at all.
+const RELAY_TIMEOUT_SECONDS: u8 = 5;
+/// Allocations whose client stayed silent this long are released.
+/// Session keepalives arrive well within it.
+const ALLOCATION_TTL_SECONDS: u8 = 30;
+/// Upper bound on allocations a relay holds at once, each one is a socket.
+const MAX_ALLOCATIONS: usize = 256;
+
+/// Why the relay refused an allocation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefusalReason {
+  /// The token doesn't match the relay's.
+  Unauthorized = 0x01,
+  /// Two other clients already share the pair key.
+  PairFull = 0x02,
+  /// The relay holds as many allocations as it can.
+  Exhausted = 0x03
+}
+
+/// Messages exchanged between clients and the control port of the relay.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayMessage {
+  /// Client to relay: allocate a relayed address, paired with the other client using the same key.
+  Allocate { token: String, pair_key: String },
+  /// Relay to client: port of the relayed address on the relay's host.
+  /// Datagrams the client sends there go to its pair, and the pair's come back from there.
+  Allocated { port: u16 },
+  Refused(RefusalReason)
+}
+
+impl RelayMessage {
+  pub fn to_bytes(&self) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(64);
+
+    buf.put_slice(&RELAY_MAGIC_NUMBER);
+    buf.put_u8(RELAY_VERSION);
+
+    match self {
+      RelayMessage::Allocate { token, pair_key } => {
+        buf.put_u8(ALLOCATE);
+        buf.put_u8(token.len() as u8);
+        buf.put_slice(token.as_bytes());
+        buf.put_u8(pair_key.len() as u8);
+        buf.put_slice(pair_key.as_bytes());
+      },
+      RelayMessage::Allocated { port } => {
+        buf.put_u8(ALLOCATED);
+        buf.put_u16(*port);
+      },
+      RelayMessage::Refused(reason) => {
+        buf.put_u8(REFUSED);
+        buf.put_u8(*reason as u8);
+      }
+    }
+
+    buf
+  }
+
+  /// Parses a relay control message, rejecting anything truncated or with trailing bytes.
+  pub fn from_bytes(mut buf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    if buf.len() < HEADER_SIZE {
+      return Err("Relay message too short".into());
+    }
+
+    if buf[..4] != RELAY_MAGIC_NUMBER {
+      return Err("Invalid magic number".into());
+    }
+
+    buf.advance(4);
+
+    let version = buf.get_u8();
+
+    if version != RELAY_VERSION {
+      return Err(format!("Unsupported relay version {}", version).into());
+    }
+
+    let message = match buf.get_u8() {
+      ALLOCATE => RelayMessage::Allocate {
+        token: get_string(&mut buf, "token")?,
+        pair_key: get_string(&mut buf, "pair key")?
+      },
+      ALLOCATED => {
+        if buf.remaining() < 2 {
+          return Err("Truncated relayed port".into());
+        }
+
+        RelayMessage::Allocated { port: buf.get_u16() }
+      },
+      REFUSED => {
+        if !buf.has_remaining() {
+          return Err("Truncated refusal reason".into());
+        }
+
+        let reason = match buf.get_u8() {
+          0x01 => RefusalReason::Unauthorized,
+          0x02 => RefusalReason::PairFull,
+          0x03 => RefusalReason::Exhausted,
+          reason => return Err(format!("Unknown refusal reason {}", reason).into())
+        };
+
+        RelayMessage::Refused(reason)
+      },
+      message_type => return Err(format!("Unknown relay message type {}", message_type).into())
+    };
+
+    if buf.has_remaining() {
+      return Err("Trailing bytes after relay message".into());
+    }
+
+    Ok(message)
+  }
+}
+
+/// Reads a string prefixed with its length in one byte.
+fn get_string(buf: &mut &[u8], field: &str) -> Result<String, Box<dyn std::error::Error>> {
+  if !buf.has_remaining() {
+    return Err(format!("Truncated {}", field).into());
+  }
+
+  let length = buf.get_u8() as usize;
+
+  if buf.remaining() < length {
+    return Err(format!("Truncated {}", field).into());
+  }
+
+  let value = String::from_utf8(buf[..length].to_vec())?;
+  buf.advance(length);
+
+  Ok(value)
+}
+
+/// Relay a peer lists as a candidate, and the token it expects.
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+  pub server: SocketAddr,
+  pub token: String
+}
+
+/// Asks the relay for a relayed address paired by `pair_key`.
+/// Runs on the session socket, the relay only forwards what comes from the address that asked.
+pub async fn allocate(
+  socket: &dyn Transport,
+  relay: SocketAddr,
+  token: &str,
+  pair_key: &str
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+  if token.len() > MAX_TOKEN_LENGTH || pair_key.len() > MAX_PAIR_KEY_LENGTH {
+    return Err("Relay token or pair key too long".into());
+  }
+
+  let request = RelayMessage::Allocate { token: token.to_string(), pair_key: pair_key.to_string() }.to_bytes();
+  let mut allocate_interval = interval(Duration::from_millis(ALLOCATE_INTERVAL_MS as u64));
+  let mut buf = [0u8; 64];
+
+  allocate_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  let answer = timeout(Duration::from_secs(RELAY_TIMEOUT_SECONDS as u64), async {
+    loop {
+      let result = tokio::select! {
+        _ = allocate_interval.tick() => {
+          socket.send_to(&request, relay).await?;
+          continue;
+        },
+        result = socket.recv_from(&mut buf) => result
+      };
+
+      let (len, addr) = result?;
+
+      if addr != relay {
+        continue;
+      }
+
+      if let Ok(answer @ (RelayMessage::Allocated { .. } | RelayMessage::Refused(_))) = RelayMessage::from_bytes(&buf[..len]) {
+        return Ok::<RelayMessage, Box<dyn std::error::Error>>(answer);
+      }
+    }
+  }).await;
+
+  match answer {
+    Ok(Ok(RelayMessage::Allocated { port })) => Ok(SocketAddr::new(relay.ip(), port)),
+    Ok(Ok(RelayMessage::Refused(reason))) => Err(format!("Relay {} refused the allocation: {:?}", relay, reason).into()),
+    Ok(Ok(_)) => Err("Unexpected relay answer".into()),
+    Ok(Err(e)) => Err(e),
+    Err(_) => Err(format!("Relay {} isn't answering", relay).into())
+  }
+}
+
+/// Relayed address of one client.
+#[derive(Clone)]
+struct Allocation {
+  client_addr: SocketAddr,
+  socket: Arc<dyn Transport>,
+  port: u16
+}
+
+/// Relay for peers that can't reach each other directly, e.g. both behind
+/// symmetric NAT. Each client gets a relayed address of its own, and what it
+/// sends there is forwarded to the other client of its pair from the other's
+/// relayed address. Only holders of the token get an allocation.
+pub struct RelayServer {
+  control: Arc<dyn Transport>,
+  /// Digest of the token, so comparing doesn't leak how much of a guess matched.
+  token_digest: [u8; 32],
+  pairs: Arc<Mutex<HashMap<String, Vec<Allocation>>>>
+}
+
+impl RelayServer {
+  pub fn new(control: Arc<dyn Transport>, token: &str) -> Self {
+    Self {
+      control,
+      token_digest: Sha256::digest(token.as_bytes()).into(),
+      pairs: Arc::new(Mutex::new(HashMap::new()))
+    }
+  }
+
+  /// Serves allocations on the control socket. A failed receive, e.g. the
+  /// ICMP error of a client that went away surfacing as a reset on Windows,
+  /// is only logged.
+  pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = [0u8; 2 * (MAX_TOKEN_LENGTH + 1) + HEADER_SIZE];
+
+    loop {
+      let (len, from) = match self.control.recv_from(&mut buf).await {
+        Ok(received) => received,
+        Err(e) => {
+          eprintln!("Failed to receive: {}", e);
+          continue;
+        }
+      };
+
+      let Ok(RelayMessage::Allocate { token, pair_key }) = RelayMessage::from_bytes(&buf[..len]) else {
+        continue;
+      };
+
+      let answer = match self.allocate(&token, pair_key, from).await {
+        Ok(port) => RelayMessage::Allocated { port },
+        Err(reason) => RelayMessage::Refused(reason)
+      };
+
+      // a client that missed the answer asks again
+      let _ = self.control.send_to(&answer.to_bytes(), from).await;
+    }
+  }
+
+  async fn allocate(&self, token: &str, pair_key: String, from: SocketAddr) -> Result<u16, RefusalReason> {
+    let token_digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+
+    if token_digest != self.token_digest {
+      return Err(RefusalReason::Unauthorized);
+    }
+
+    {
+      let pairs = lock(&self.pairs);
+      let pair = pairs.get(&pair_key).map(Vec::as_slice).unwrap_or_default();
+
+      if let Some(allocation) = pair.iter().find(|allocation| allocation.client_addr == from) {
+        return Ok(allocation.port);
+      }
+
+      if pair.len() >= 2 {
+        return Err(RefusalReason::PairFull);
+      }
+
+      if pairs.values().map(Vec::len).sum::<usize>() >= MAX_ALLOCATIONS {
+        return Err(RefusalReason::Exhausted);
+      }
+    }
+
+    // relayed addresses live on the interface the control port is bound to
+    let local_ip = self.control.local_addr().map_err(|_| RefusalReason::Exhausted)?.ip();
+    let socket = transport::bind_udp(SocketAddr::new(local_ip, 0)).await.map_err(|_| RefusalReason::Exhausted)?;
+    let port = socket.local_addr().map_err(|_| RefusalReason::Exhausted)?.port();
+    let allocation = Allocation { client_addr: from, socket, port };
+
+    {
+      let mut pairs = lock(&self.pairs);
+      let pair = pairs.entry(pair_key.clone()).or_default();
+
+      // another request of the same client may have won the race
+      if let Some(allocation) = pair.iter().find(|allocation| allocation.client_addr == from) {
+        return Ok(allocation.port);
+      }
+
+      if pair.len() >= 2 {
+        return Err(RefusalReason::PairFull);
+      }
+
+      pair.push(allocation.clone());
+    }
+
+    task::spawn(forward(Arc::clone(&self.pairs), pair_key, allocation));
+
+    Ok(port)
+  }
+}
+
+/// Forwards what the client sends to its relayed address to the other client
+/// of the pair, until the client stays silent for too long.
+async fn forward(pairs: Arc<Mutex<HashMap<String, Vec<Allocation>>>>, pair_key: String, allocation: Allocation) {
+  let ttl = Duration::from_secs(ALLOCATION_TTL_SECONDS as u64);
+  let mut buf = vec![0u8; MAX_UDP_PAYLOAD_SIZE];
+  let mut last_heard_at = Instant::now();
+
+  loop {
+    let remaining = ttl.saturating_sub(last_heard_at.elapsed());
+
+    let Ok(result) = timeout(remaining, allocation.socket.recv_from(&mut buf)).await else {
+      break;
+    };
+
+    // a failed receive doesn't end the allocation, only its silence does
+    let (len, from) = match result {
+      Ok(received) => received,
+      Err(e) => {
+        eprintln!("Failed to receive: {}", e);
+        continue;
+      }
+    };
+
+    // only the client that allocated the address may use it
+    if from != allocation.client_addr {
+      continue;
+    }
+
+    last_heard_at = Instant::now();
+
+    let partner = lock(&pairs)
+      .get(&pair_key)
+      .and_then(|pair| pair.iter().find(|other| other.port != allocation.port).cloned());
+
+    // until the other client shows up, there is nobody to forward to
+    if let Some(partner) = partner {
+      let _ = partner.socket.send_to(&buf[..len], partner.client_addr).await;
+    }
+  }
+
+  let mut pairs = lock(&pairs);
+
+  if let Some(pair) = pairs.get_mut(&pair_key) {
+    pair.retain(|other| other.port != allocation.port);
+
+    if pair.is_empty() {
+      pairs.remove(&pair_key);
+    }
+  }
+}
+
+/// The relay holds its lock only for plain updates, a poisoned one is still consistent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,341 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::{interval, Duration, Instant, MissedTickBehavior};
+
+use crate::ice::{Candidate, CandidateKind, CandidateSet, MAX_CANDIDATES};
+use crate::masp::crypto::HandshakeRole;
+use crate::transport::Transport;
+
+pub const RENDEZVOUS_MAGIC_NUMBER: [u8; 4] = [0x4D, 0x54, 0x52, 0x5A]; // 'MTRZ'
+pub const RENDEZVOUS_VERSION: u8 = 0x02;
+pub const MAX_ROOM_CODE_LENGTH: usize = 32;
+/// Rooms the server holds at once, registrations opening more are dropped
+/// until some expire.
+pub const MAX_ROOMS: usize = 4096;
+
+const REGISTER: u8 = 0x01;
+const WAITING: u8 = 0x02;
+const MATCHED: u8 = 0x03;
+
+const HEADER_SIZE: usize = 6;
+/// Room code and a full candidate set fit with room to spare.
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// Clients register again this often until they are matched, which also keeps
+/// their NAT mapping towards the server alive.
+const REGISTER_INTERVAL_MS: u16 = 1000;
+/// How long a client waits for the server to answer at all.
+const SERVER_TIMEOUT_SECONDS: u8 = 5;
+/// Rooms whose peers stopped registering are forgotten after this long.
+const ROOM_TTL_SECONDS: u8 = 30;
+/// How often the server sweeps out expired rooms.
+const ROOM_EXPIRY_INTERVAL_SECONDS: u8 = 5;
+
+/// Messages exchanged between clients and the rendezvous server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RendezvousMessage {
+  /// Client to server: wait in the room, or meet the peer already in it.
+  Register { room: String, candidates: CandidateSet },
+  /// Server to client: registered, nobody else is in the room yet.
+  Waiting,
+  /// Server to client: the peer's candidates to check and which side of the handshake to play.
+  Matched { role: HandshakeRole, peer_candidates: CandidateSet }
+}
+
+impl RendezvousMessage {
+  pub fn to_bytes(&self) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(MAX_MESSAGE_SIZE);
+
+    buf.put_slice(&RENDEZVOUS_MAGIC_NUMBER);
+    buf.put_u8(RENDEZVOUS_VERSION);
+
+    match self {
+      RendezvousMessage::Register { room, candidates } => {
+        buf.put_u8(REGISTER);
+        buf.put_u8(room.len() as u8);
+        buf.put_slice(room.as_bytes());
+        buf.put_slice(&candidates.to_bytes());
+      },
+      RendezvousMessage::Waiting => buf.put_u8(WAITING),
+      RendezvousMessage::Matched { role, peer_candidates } => {
+        buf.put_u8(MATCHED);
+        buf.put_u8(*role as u8);
+        buf.put_slice(&peer_candidates.to_bytes());
+      }
+    }
+
+    buf
+  }
+
+  /// Parses a rendezvous message, rejecting anything truncated or with trailing bytes.
+  pub fn from_bytes(mut buf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    if buf.len() < HEADER_SIZE {
+      return Err("Rendezvous message too short".into());
+    }
+
+    if buf[..4] != RENDEZVOUS_MAGIC_NUMBER {
+      return Err("Invalid magic number".into());
+    }
+
+    buf.advance(4);
+
+    let version = buf.get_u8();
+
+    if version != RENDEZVOUS_VERSION {
+      return Err(format!("Unsupported rendezvous version {}", version).into());
+    }
+
+    let message = match buf.get_u8() {
+      REGISTER => {
+        if !buf.has_remaining() {
+          return Err("Truncated room code".into());
+        }
+
+        let room_length = buf.get_u8() as usize;
+
+        if buf.remaining() < room_length {
+          return Err("Truncated room code".into());
+        }
+
+        let room = String::from_utf8(buf[..room_length].to_vec())?;
+        buf.advance(room_length);
+
+        validate_room_code(&room)?;
+
+        // the candidate set takes the rest of the message
+        let candidates = CandidateSet::from_bytes(buf)?;
+        buf.advance(buf.remaining());
+
+        RendezvousMessage::Register { room, candidates }
+      },
+      WAITING => RendezvousMessage::Waiting,
+      MATCHED => {
+        if !buf.has_remaining() {
+          return Err("Truncated role".into());
+        }
+
+        let role = match buf.get_u8() {
+          0x01 => HandshakeRole::Initiator,
+          0x02 => HandshakeRole::Responder,
+          role => return Err(format!("Unknown handshake role {}", role).into())
+        };
+
+        let peer_candidates = CandidateSet::from_bytes(buf)?;
+        buf.advance(buf.remaining());
+
+        RendezvousMessage::Matched { role, peer_candidates }
+      },
+      message_type => return Err(format!("Unknown rendezvous message type {}", message_type).into())
+    };
+
+    if buf.has_remaining() {
+      return Err("Trailing bytes after rendezvous message".into());
+    }
+
+    Ok(message)
+  }
+}
+
+/// Room codes are short and typed by hand: letters, digits and dashes.
+pub fn validate_room_code(room: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let valid = !room.is_empty()
+    && room.len() <= MAX_ROOM_CODE_LENGTH
+    && room.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-');
+
+  if !valid {
+    return Err(format!(
+      "Invalid room code '{}', use up to {} letters, digits and dashes",
+      room,
+      MAX_ROOM_CODE_LENGTH
+    ).into());
+  }
+
+  Ok(())
+}
+
+/// A peer met through the rendezvous server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+  pub role: HandshakeRole,
+  pub peer_candidates: CandidateSet
+}
+
+/// Registers our candidates in the room and waits until the server matches us with a peer.
+/// Runs on the session socket, so the server sees the NAT mapping the session will use.
+pub async fn join(
+  socket: &dyn Transport,
+  server: SocketAddr,
+  room: &str,
+  candidates: &CandidateSet
+) -> Result<Match, Box<dyn std::error::Error>> {
+  validate_room_code(room)?;
+
+  let register = RendezvousMessage::Register { room: room.to_string(), candidates: candidates.clone() }.to_bytes();
+  let mut register_interval = interval(Duration::from_millis(REGISTER_INTERVAL_MS as u64));
+  let mut buf = [0u8; MAX_MESSAGE_SIZE];
+  let mut waiting = false;
+  let started_at = Instant::now();
+
+  register_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  loop {
+    let result = tokio::select! {
+      _ = register_interval.tick() => {
+        if !waiting && started_at.elapsed() > Duration::from_secs(SERVER_TIMEOUT_SECONDS as u64) {
+          return Err(format!("Rendezvous server {} isn't answering", server).into());
+        }
+
+        socket.send_to(&register, server).await?;
+        continue;
+      },
+      result = socket.recv_from(&mut buf) => result
+    };
+
+    let (len, addr) = result?;
+
+    // the peer may already be punching towards us
+    if addr != server {
+      continue;
+    }
+
+    match RendezvousMessage::from_bytes(&buf[..len]) {
+      Ok(RendezvousMessage::Waiting) if !waiting => {
+        waiting = true;
+        println!("Waiting for a peer to join room {}", room);
+      },
+      Ok(RendezvousMessage::Matched { role, peer_candidates }) => {
+        return Ok(Match { role, peer_candidates });
+      },
+      _ => {}
+    }
+  }
+}
+
+/// A peer registered in a room.
+#[derive(Debug, Clone)]
+struct Member {
+  reflexive_addr: SocketAddr,
+  candidates: CandidateSet
+}
+
+#[derive(Debug, Clone)]
+enum Room {
+  Waiting(Member),
+  /// Kept until it expires, so a peer whose answer got lost is answered again.
+  Matched { responder: Member, initiator: Member }
+}
+
+/// State of the rendezvous server: who waits in which room.
+/// The peer that waited plays the handshake responder, the one that joined it the initiator.
+pub struct RendezvousServer {
+  rooms: HashMap<String, (Room, Instant)>,
+  room_ttl: Duration
+}
+
+impl RendezvousServer {
+  pub fn new() -> Self {
+    Self {
+      rooms: HashMap::new(),
+      room_ttl: Duration::from_secs(ROOM_TTL_SECONDS as u64)
+    }
+  }
+
+  /// Handles a message received from `from`, returning the answers to send.
+  pub fn handle(&mut self, message: RendezvousMessage, from: SocketAddr, now: Instant) -> Vec<(RendezvousMessage, SocketAddr)> {
+    let RendezvousMessage::Register { room, mut candidates } = message else {
+      return Vec::new();
+    };
+
+    // the address the client came from is a reflexive candidate, whether or not it found it over STUN
+    if !candidates.candidates.iter().any(|candidate| candidate.addr == from) {
+      candidates.candidates.truncate(MAX_CANDIDATES - 1);
+      candidates.candidates.push(Candidate::new(CandidateKind::ServerReflexive, from, 0));
+    }
+
+    let member = Member { reflexive_addr: from, candidates };
+
+    // an expired room not swept out yet is as good as gone
+    let current = self.rooms
+      .get(&room)
+      .filter(|(_, updated_at)| now.duration_since(*updated_at) < self.room_ttl)
+      .map(|(room, _)| room.clone());
+
+    if current.is_none() && !self.rooms.contains_key(&room) && self.rooms.len() >= MAX_ROOMS {
+      return Vec::new();
+    }
+
+    let next = match current {
+      None => Room::Waiting(member),
+      Some(Room::Waiting(waiting)) if waiting.reflexive_addr == from => Room::Waiting(member),
+      Some(Room::Waiting(responder)) => Room::Matched { responder, initiator: member },
+      Some(Room::Matched { responder, initiator })
+        if responder.reflexive_addr == from || initiator.reflexive_addr == from => Room::Matched { responder, initiator },
+      // a third peer has to wait for the room to free up
+      Some(Room::Matched { .. }) => return Vec::new()
+    };
+
+    self.rooms.insert(room, (next.clone(), now));
+
+    match next {
+      Room::Waiting(_) => vec![(RendezvousMessage::Waiting, from)],
+      Room::Matched { responder, initiator } => vec![
+        (matched(HandshakeRole::Responder, &initiator), responder.reflexive_addr),
+        (matched(HandshakeRole::Initiator, &responder), initiator.reflexive_addr)
+      ]
+    }
+  }
+
+  /// Forgets the rooms whose peers stopped registering.
+  pub fn expire(&mut self, now: Instant) {
+    let room_ttl = self.room_ttl;
+
+    self.rooms.retain(|_, (_, updated_at)| now.duration_since(*updated_at) < room_ttl);
+  }
+
+  /// Serves clients on the socket. A failed receive, e.g. the ICMP error of an
+  /// unreachable client surfacing as a reset on Windows, is only logged.
+  pub async fn run(&mut self, socket: &dyn Transport) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = [0u8; MAX_MESSAGE_SIZE];
+    let mut expiry_interval = interval(Duration::from_secs(ROOM_EXPIRY_INTERVAL_SECONDS as u64));
+
+    expiry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+      let result = tokio::select! {
+        _ = expiry_interval.tick() => {
+          self.expire(Instant::now());
+          continue;
+        },
+        result = socket.recv_from(&mut buf) => result
+      };
+
+      let (len, from) = match result {
+        Ok(received) => received,
+        Err(e) => {
+          eprintln!("Failed to receive: {}", e);
+          continue;
+        }
+      };
+
+      let Ok(message) = RendezvousMessage::from_bytes(&buf[..len]) else {
+        continue;
+      };
+
+      for (answer, to) in self.handle(message, from, Instant::now()) {
+        // an unreachable client registers again or gives up
+        let _ = socket.send_to(&answer.to_bytes(), to).await;
+      }
+    }
+  }
+}
+
+impl Default for RendezvousServer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn matched(role: HandshakeRole, peer: &Member) -> RendezvousMessage {
+  RendezvousMessage::Matched { role, peer_candidates: peer.candidates.clone() }
+}
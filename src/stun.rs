@@ -1,25 +1,137 @@
 use bytes::{Buf, BufMut, BytesMut};
+use hmac::{Hmac, Mac};
 use rand::Rng;
+use sha1::Sha1;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use tokio::net::UdpSocket;
-use tokio::time::timeout;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+
+use crate::transport::Transport;
 
 pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+pub const BINDING_ERROR_RESPONSE: u16 = 0x0111;
 pub const MAGIC_COOKIE: u32 = 0x2112A442;
+/// STUN server used unless another one is given.
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
 
+pub const MAPPED_ADDRESS: u16 = 0x0001;
+pub const CHANGE_REQUEST: u16 = 0x0003;
+pub const USERNAME: u16 = 0x0006;
+pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ERROR_CODE: u16 = 0x0009;
+pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
 pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const SOFTWARE: u16 = 0x8022;
+pub const FINGERPRINT: u16 = 0x8028;
+pub const RESPONSE_ORIGIN: u16 = 0x802B;
+pub const OTHER_ADDRESS: u16 = 0x802C;
+
+/// Largest SOFTWARE description, 128 characters of up to 6 bytes each per RFC 5389.
+pub const MAX_SOFTWARE_LENGTH: usize = 763;
+/// Largest USERNAME per RFC 5389.
+pub const MAX_USERNAME_LENGTH: usize = 513;
+
+const HEADER_SIZE: usize = 20;
+const ATTRIBUTE_HEADER_SIZE: usize = 4;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+const MESSAGE_INTEGRITY_SIZE: usize = 20;
+/// XORed into the CRC-32 of FINGERPRINT, tells STUN apart from other protocols on the port.
+const FINGERPRINT_XOR: u32 = 0x5354554E;
+/// Class bits of the message type, spread between the method bits.
+const CLASS_MASK: u16 = 0x0110;
+
+/// Requests are sent again this often until answered, as UDP may drop them.
+const RETRANSMIT_INTERVAL_MS: u16 = 500;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct StunMessage {
   pub message_type: u16,
   pub transaction_id: [u8; 12],
   pub attributes: Vec<StunAttribute>,
 }
 
+/// Class of a STUN message, encoded in its type along with the method.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageClass {
+  Request,
+  Indication,
+  SuccessResponse,
+  ErrorResponse
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum StunAttribute {
+  /// Reflexive address in the clear, what RFC 3489 servers answer with.
+  MappedAddress(SocketAddr),
   XorMappedAddress(SocketAddr),
+  /// Why a request failed, e.g. 400 Bad Request or 401 Unauthorized.
+  ErrorCode { code: u16, reason: String },
+  /// Name and version of the agent that sent the message.
+  Software(String),
+  /// Who the short-term credentials of MESSAGE-INTEGRITY belong to.
+  Username(String),
+  /// HMAC-SHA1 of the message up to this attribute, see `StunMessage::sign`.
+  MessageIntegrity([u8; MESSAGE_INTEGRITY_SIZE]),
+  /// CRC-32 of the message up to this attribute, always the last one.
+  /// Checked when parsing and computed when encoding, so it carries no value.
+  Fingerprint,
+  /// RFC 5780: asks the server to answer from its other IP and/or port.
+  ChangeRequest { change_ip: bool, change_port: bool },
+  /// RFC 5780: the address the response was sent from.
+  ResponseOrigin(SocketAddr),
+  /// RFC 5780: the other IP and port of the server, for the behaviour tests.
+  OtherAddress(SocketAddr),
   Unknown(u16, Vec<u8>), // For attributes we don't parse
 }
 
+impl StunAttribute {
+  fn attribute_type(&self) -> u16 {
+    match self {
+      StunAttribute::MappedAddress(_) => MAPPED_ADDRESS,
+      StunAttribute::XorMappedAddress(_) => XOR_MAPPED_ADDRESS,
+      StunAttribute::ErrorCode { .. } => ERROR_CODE,
+      StunAttribute::Software(_) => SOFTWARE,
+      StunAttribute::Username(_) => USERNAME,
+      StunAttribute::MessageIntegrity(_) => MESSAGE_INTEGRITY,
+      StunAttribute::Fingerprint => FINGERPRINT,
+      StunAttribute::ChangeRequest { .. } => CHANGE_REQUEST,
+      StunAttribute::ResponseOrigin(_) => RESPONSE_ORIGIN,
+      StunAttribute::OtherAddress(_) => OTHER_ADDRESS,
+      StunAttribute::Unknown(attr_type, _) => *attr_type
+    }
+  }
+
+  /// Value of the attribute, FINGERPRINT's is computed over the encoded message instead.
+  fn value(&self, transaction_id: &[u8; 12]) -> Vec<u8> {
+    match self {
+      StunAttribute::MappedAddress(addr) => encode_address(addr),
+      StunAttribute::XorMappedAddress(addr) => encode_xor_mapped_address(addr, transaction_id),
+      StunAttribute::ErrorCode { code, reason } => {
+        let mut buf = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        buf.extend_from_slice(reason.as_bytes());
+
+        buf
+      },
+      StunAttribute::Software(software) | StunAttribute::Username(software) => software.as_bytes().to_vec(),
+      StunAttribute::MessageIntegrity(hmac) => hmac.to_vec(),
+      StunAttribute::Fingerprint => vec![0; 4],
+      StunAttribute::ChangeRequest { change_ip, change_port } => {
+        let flags = if *change_ip { CHANGE_IP } else { 0 } | if *change_port { CHANGE_PORT } else { 0 };
+
+        flags.to_be_bytes().to_vec()
+      },
+      StunAttribute::ResponseOrigin(addr) | StunAttribute::OtherAddress(addr) => encode_address(addr),
+      StunAttribute::Unknown(_, value) => value.clone()
+    }
+  }
+}
+
 impl StunMessage {
   /// Creates a new STUN binding request with a random transaction ID.
   pub fn new() -> Self {
@@ -32,31 +144,70 @@ impl StunMessage {
     }
   }
 
+  pub fn class(&self) -> MessageClass {
+    match self.message_type & CLASS_MASK {
+      0x0000 => MessageClass::Request,
+      0x0010 => MessageClass::Indication,
+      0x0100 => MessageClass::SuccessResponse,
+      _ => MessageClass::ErrorResponse
+    }
+  }
+
   /// Converts the STUN message into bytes for sending over UDP.
   pub fn to_bytes(&self) -> BytesMut {
-    let mut buf = BytesMut::with_capacity(20);
+    let values: Vec<Vec<u8>> = self.attributes
+      .iter()
+      .map(|attribute| attribute.value(&self.transaction_id))
+      .collect();
+
+    // each value is padded to a multiple of 4 bytes
+    let message_length: usize = values
+      .iter()
+      .map(|value| ATTRIBUTE_HEADER_SIZE + value.len() + padding(value.len()))
+      .sum();
+
+    let mut buf = BytesMut::with_capacity(HEADER_SIZE + message_length);
 
     buf.put_u16(self.message_type);
-    buf.put_u16(0); // Placeholder for message length
+    buf.put_u16(message_length as u16);
     buf.put_u32(MAGIC_COOKIE);
     buf.put_slice(&self.transaction_id);
 
-    // Encode attributes (none for binding request)
-    let attributes_bytes = BytesMut::new();
-    let message_length = attributes_bytes.len() as u16;
-    buf[2..4].copy_from_slice(&message_length.to_be_bytes());
+    for (attribute, value) in self.attributes.iter().zip(values) {
+      let value = match attribute {
+        StunAttribute::Fingerprint => fingerprint(&buf).to_be_bytes().to_vec(),
+        _ => value
+      };
+
+      buf.put_u16(attribute.attribute_type());
+      buf.put_u16(value.len() as u16);
+      buf.put_slice(&value);
+      buf.put_bytes(0, padding(value.len()));
+    }
 
-    buf.unsplit(attributes_bytes);
     buf
   }
 
   /// Parses a STUN message from bytes received.
+  /// Anything truncated or longer than the datagram is rejected, as is a
+  /// FINGERPRINT that doesn't match or isn't the last attribute.
   pub fn from_bytes(mut buf: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    let message = buf;
+
+    if buf.len() < HEADER_SIZE {
+      return Err("STUN message too short".into());
+    }
+
     // Read header
     let message_type = buf.get_u16();
-    let message_length = buf.get_u16();
+    let message_length = buf.get_u16() as usize;
     let magic_cookie = buf.get_u32();
 
+    // the two most significant bits tell STUN apart from other protocols
+    if message_type & 0xC000 != 0 {
+      return Err("Not a STUN message".into());
+    }
+
     if magic_cookie != MAGIC_COOKIE {
       return Err("Invalid magic cookie".into());
     }
@@ -64,24 +215,82 @@ impl StunMessage {
     let mut transaction_id = [0u8; 12];
     buf.copy_to_slice(&mut transaction_id);
 
+    // attributes are padded, so the length is always a multiple of 4
+    if !message_length.is_multiple_of(4) || message_length > buf.len() {
+      return Err("Invalid STUN message length".into());
+    }
+
     // Read attributes
     let mut attributes = Vec::new();
-    let mut attributes_buf = &buf[..message_length as usize];
+    let mut attributes_buf = &buf[..message_length];
 
     while attributes_buf.has_remaining() {
+      if attributes.last() == Some(&StunAttribute::Fingerprint) {
+        return Err("STUN attribute after FINGERPRINT".into());
+      }
+
+      if attributes_buf.remaining() < ATTRIBUTE_HEADER_SIZE {
+        return Err("Truncated STUN attribute".into());
+      }
+
+      let attribute_offset = HEADER_SIZE + message_length - attributes_buf.remaining();
       let attr_type = attributes_buf.get_u16();
-      let attr_length = attributes_buf.get_u16();
-      let attr_value = attributes_buf.copy_to_bytes(attr_length as usize);
+      let attr_length = attributes_buf.get_u16() as usize;
 
-      match attr_type {
-        XOR_MAPPED_ADDRESS => {
-          let addr = parse_xor_mapped_address(&attr_value, &transaction_id)?;
-          attributes.push(StunAttribute::XorMappedAddress(addr));
+      if attr_length > attributes_buf.remaining() {
+        return Err("Truncated STUN attribute".into());
+      }
+
+      let attr_value = &attributes_buf[..attr_length];
+
+      let attribute = match attr_type {
+        MAPPED_ADDRESS => StunAttribute::MappedAddress(parse_address(attr_value, "MAPPED-ADDRESS")?),
+        XOR_MAPPED_ADDRESS => StunAttribute::XorMappedAddress(parse_xor_mapped_address(attr_value, &transaction_id)?),
+        ERROR_CODE => parse_error_code(attr_value)?,
+        SOFTWARE => {
+          if attr_length > MAX_SOFTWARE_LENGTH {
+            return Err("SOFTWARE too long".into());
+          }
+
+          StunAttribute::Software(String::from_utf8(attr_value.to_vec())?)
         }
-        _ => {
-          attributes.push(StunAttribute::Unknown(attr_type, attr_value.to_vec()));
+        USERNAME => {
+          if attr_length > MAX_USERNAME_LENGTH {
+            return Err("USERNAME too long".into());
+          }
+
+          StunAttribute::Username(String::from_utf8(attr_value.to_vec())?)
         }
-      }
+        MESSAGE_INTEGRITY => {
+          StunAttribute::MessageIntegrity(attr_value.try_into().map_err(|_| "Malformed MESSAGE-INTEGRITY")?)
+        }
+        FINGERPRINT => {
+          let value: [u8; 4] = attr_value.try_into().map_err(|_| "Malformed FINGERPRINT")?;
+
+          if u32::from_be_bytes(value) != fingerprint(&message[..attribute_offset]) {
+            return Err("FINGERPRINT mismatch".into());
+          }
+
+          StunAttribute::Fingerprint
+        }
+        CHANGE_REQUEST => {
+          let flags: [u8; 4] = attr_value.try_into().map_err(|_| "Malformed CHANGE-REQUEST")?;
+          let flags = u32::from_be_bytes(flags);
+
+          StunAttribute::ChangeRequest {
+            change_ip: flags & CHANGE_IP != 0,
+            change_port: flags & CHANGE_PORT != 0
+          }
+        }
+        RESPONSE_ORIGIN => StunAttribute::ResponseOrigin(parse_address(attr_value, "RESPONSE-ORIGIN")?),
+        OTHER_ADDRESS => StunAttribute::OtherAddress(parse_address(attr_value, "OTHER-ADDRESS")?),
+        _ => StunAttribute::Unknown(attr_type, attr_value.to_vec())
+      };
+
+      attributes.push(attribute);
+
+      // the message length being a multiple of 4 leaves room for the padding
+      attributes_buf.advance(attr_length + padding(attr_length));
     }
 
     Ok(StunMessage {
@@ -90,64 +299,251 @@ impl StunMessage {
       attributes,
     })
   }
+
+  /// Appends MESSAGE-INTEGRITY keyed with the short-term credential password,
+  /// once every other attribute but FINGERPRINT is in place. Requests carry
+  /// the USERNAME of the credentials before it, see `authenticate`.
+  /// The password is used as is, SASLprep leaves ASCII ones unchanged.
+  pub fn sign(&mut self, password: &str) {
+    self.attributes.push(StunAttribute::MessageIntegrity([0; MESSAGE_INTEGRITY_SIZE]));
+
+    // the length in the header covers MESSAGE-INTEGRITY, the HMAC what precedes it
+    let bytes = self.to_bytes();
+    let hmac = message_integrity(password, &bytes[..bytes.len() - ATTRIBUTE_HEADER_SIZE - MESSAGE_INTEGRITY_SIZE]);
+
+    self.attributes.pop();
+    self.attributes.push(StunAttribute::MessageIntegrity(hmac));
+  }
+
+  /// Adds the USERNAME and MESSAGE-INTEGRITY of short-term credentials to a request.
+  pub fn authenticate(&mut self, credentials: &Credentials) {
+    self.attributes.push(StunAttribute::Username(credentials.username.clone()));
+    self.sign(&credentials.password);
+  }
+
+  /// Checks the MESSAGE-INTEGRITY of a received message against the short-term
+  /// credential password. Attributes after it, but FINGERPRINT, aren't covered.
+  ///
+  /// As RFC 5389 specifies, the HMAC covers the bytes received up to the
+  /// attribute, with the length in the header counting up to its end. They
+  /// may hold what the parser doesn't keep, like padding or reserved bits.
+  pub fn verify_integrity(buf: &[u8], password: &str) -> Result<(), Box<dyn std::error::Error>> {
+    StunMessage::from_bytes(buf)?;
+
+    let offset = attribute_offset(buf, MESSAGE_INTEGRITY).ok_or("No MESSAGE-INTEGRITY")?;
+    let end = offset + ATTRIBUTE_HEADER_SIZE + MESSAGE_INTEGRITY_SIZE;
+    let mut covered = buf[..offset].to_vec();
+
+    covered[2..4].copy_from_slice(&((end - HEADER_SIZE) as u16).to_be_bytes());
+
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(password.as_bytes()).expect("HMAC accepts any key size");
+
+    mac.update(&covered);
+    mac.verify_slice(&buf[offset + ATTRIBUTE_HEADER_SIZE..end]).map_err(|_| "MESSAGE-INTEGRITY mismatch")?;
+
+    Ok(())
+  }
+
+  /// Reflexive address of a success response, preferring XOR-MAPPED-ADDRESS as NATs may rewrite
+  /// the other. An error response fails with its code and reason.
+  pub fn reflexive_addr(&self) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    match self.class() {
+      MessageClass::SuccessResponse => {},
+      MessageClass::ErrorResponse => {
+        let error = self.attributes.iter().find_map(|attribute| match attribute {
+          StunAttribute::ErrorCode { code, reason } => Some(format!("{} {}", code, reason)),
+          _ => None
+        });
+
+        return Err(format!("STUN server answered with error {}", error.as_deref().unwrap_or("without code")).into());
+      },
+      class => return Err(format!("Expected a STUN response, got a {:?}", class).into())
+    }
+
+    let xor_mapped = self.attributes.iter().find_map(|attribute| match attribute {
+      StunAttribute::XorMappedAddress(addr) => Some(*addr),
+      _ => None
+    });
+    let mapped = self.attributes.iter().find_map(|attribute| match attribute {
+      StunAttribute::MappedAddress(addr) => Some(*addr),
+      _ => None
+    });
+
+    xor_mapped.or(mapped).ok_or_else(|| "STUN response carries no address".into())
+  }
+}
+
+impl Default for StunMessage {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Short-term credentials a STUN server requires, as `username:password`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+  pub username: String,
+  pub password: String
+}
+
+impl FromStr for Credentials {
+  type Err = String;
+
+  fn from_str(credentials: &str) -> Result<Self, Self::Err> {
+    match credentials.split_once(':') {
+      Some((username, password)) if !username.is_empty() && username.len() <= MAX_USERNAME_LENGTH => {
+        Ok(Self { username: username.to_string(), password: password.to_string() })
+      },
+      _ => Err("Invalid STUN credentials, expected username:password".to_string()),
+    }
+  }
+}
+
+/// STUN server to query, and the credentials it requires if any.
+#[derive(Debug, Clone)]
+pub struct StunConfig {
+  pub server: SocketAddr,
+  pub credentials: Option<Credentials>
+}
+
+/// Offset of the first attribute of the given type in a well-formed message.
+fn attribute_offset(message: &[u8], attr_type: u16) -> Option<usize> {
+  let end = HEADER_SIZE + u16::from_be_bytes([message[2], message[3]]) as usize;
+  let mut offset = HEADER_SIZE;
+
+  while offset + ATTRIBUTE_HEADER_SIZE <= end {
+    let length = u16::from_be_bytes([message[offset + 2], message[offset + 3]]) as usize;
+
+    if u16::from_be_bytes([message[offset], message[offset + 1]]) == attr_type {
+      return Some(offset);
+    }
+
+    offset += ATTRIBUTE_HEADER_SIZE + length + padding(length);
+  }
+
+  None
+}
+
+/// CRC-32 of the message so far, as carried by FINGERPRINT.
+fn fingerprint(message: &[u8]) -> u32 {
+  crc32fast::hash(message) ^ FINGERPRINT_XOR
+}
+
+fn message_integrity(password: &str, message: &[u8]) -> [u8; MESSAGE_INTEGRITY_SIZE] {
+  let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(password.as_bytes()).expect("HMAC accepts any key size");
+  mac.update(message);
+
+  mac.finalize().into_bytes().into()
+}
+
+/// ERROR-CODE value: reserved bytes, the hundreds of the code, the rest of it and the reason phrase.
+fn parse_error_code(buf: &[u8]) -> Result<StunAttribute, Box<dyn std::error::Error>> {
+  if buf.len() < 4 {
+    return Err("Truncated ERROR-CODE".into());
+  }
+
+  let class = (buf[2] & 0x07) as u16;
+  let number = buf[3] as u16;
+
+  if !(3..=6).contains(&class) || number > 99 {
+    return Err("Malformed ERROR-CODE".into());
+  }
+
+  Ok(StunAttribute::ErrorCode { code: class * 100 + number, reason: String::from_utf8(buf[4..].to_vec())? })
+}
+
+/// Zero bytes after an attribute value of the given length, up to the next multiple of 4.
+fn padding(length: usize) -> usize {
+  (4 - length % 4) % 4
+}
+
+/// XOR of the address with the magic cookie, followed by the transaction ID for IPv6.
+fn xor_ip(ip: IpAddr, transaction_id: &[u8; 12]) -> IpAddr {
+  match ip {
+    IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) ^ MAGIC_COOKIE)),
+    IpAddr::V6(ip) => {
+      let mut octets = ip.octets();
+      let mask = [MAGIC_COOKIE.to_be_bytes().as_slice(), transaction_id].concat();
+
+      octets.iter_mut().zip(mask).for_each(|(octet, mask)| *octet ^= mask);
+
+      IpAddr::V6(Ipv6Addr::from(octets))
+    }
+  }
+}
+
+fn encode_xor_mapped_address(addr: &SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+  let xaddr = SocketAddr::new(xor_ip(addr.ip(), transaction_id), addr.port() ^ ((MAGIC_COOKIE >> 16) as u16));
+
+  encode_address(&xaddr)
 }
 
 /// Parses the XOR-MAPPED-ADDRESS attribute to extract the public IP and port.
 fn parse_xor_mapped_address(
-  mut buf: &[u8],
+  buf: &[u8],
   transaction_id: &[u8; 12]
 ) -> Result<SocketAddr, Box<dyn std::error::Error>> {
-    let _reserved = buf.get_u8();
-    let family = buf.get_u8();
-    let xport = buf.get_u16();
-    let port = xport ^ ((MAGIC_COOKIE >> 16) as u16);
-    
-    let ip_addr = match family {
-      0x01 => {
-        // IPv4
-        let xip = buf.get_u32();
-        let ip = xip ^ MAGIC_COOKIE;
-        IpAddr::V4(Ipv4Addr::from(ip))
-      }
-      0x02 => {
-        let mut xip = [0u8; 16];
-        buf.copy_to_slice(&mut xip);
+  let xaddr = parse_address(buf, "XOR-MAPPED-ADDRESS")?;
 
-        let mut ip = [0u8; 16];
-        let magic_cookie_bytes = MAGIC_COOKIE.to_be_bytes();
+  Ok(SocketAddr::new(xor_ip(xaddr.ip(), transaction_id), xaddr.port() ^ ((MAGIC_COOKIE >> 16) as u16)))
+}
 
-        for i in 0..4 {
-          ip[i] = xip[i] ^ magic_cookie_bytes[i];
-        }
+/// Address attribute value: reserved byte, family, port and IP.
+fn encode_address(addr: &SocketAddr) -> Vec<u8> {
+  let mut buf = BytesMut::with_capacity(20);
 
-        for i in 4..16 {
-          ip[i] = xip[i] ^ transaction_id[i - 4];
-        } 
+  buf.put_u8(0);
+  buf.put_u8(if addr.is_ipv4() { FAMILY_IPV4 } else { FAMILY_IPV6 });
+  buf.put_u16(addr.port());
 
-        IpAddr::V6(Ipv6Addr::from(ip))
-      }
-      _ => return Err("Unknown address family".into()),
-    };
+  match addr.ip() {
+    IpAddr::V4(ip) => buf.put_slice(&ip.octets()),
+    IpAddr::V6(ip) => buf.put_slice(&ip.octets())
+  }
+
+  buf.to_vec()
+}
 
-    Ok(SocketAddr::new(ip_addr, port))
+fn parse_address(mut buf: &[u8], name: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+  if buf.len() < 4 {
+    return Err(format!("Truncated {}", name).into());
+  }
+
+  let _reserved = buf.get_u8();
+  let family = buf.get_u8();
+  let port = buf.get_u16();
+
+  let ip = match (family, buf.len()) {
+    (FAMILY_IPV4, 4) => IpAddr::V4(Ipv4Addr::from(buf.get_u32())),
+    (FAMILY_IPV6, 16) => {
+      let mut ip = [0u8; 16];
+      buf.copy_to_slice(&mut ip);
+
+      IpAddr::V6(Ipv6Addr::from(ip))
+    }
+    (FAMILY_IPV4 | FAMILY_IPV6, _) => return Err(format!("Malformed {}", name).into()),
+    _ => return Err("Unknown address family".into()),
+  };
+
+  Ok(SocketAddr::new(ip, port))
 }
 
 /// Sends a STUN binding request to the specified STUN server.
 pub async fn send_binding_request(
-  socket: &UdpSocket,
+  socket: &dyn Transport,
   stun_server: &SocketAddr,
   message: &StunMessage,
 ) -> Result<(), Box<dyn std::error::Error>> {
   let buf = message.to_bytes();
   
-  socket.send_to(&buf, stun_server).await?;
+  socket.send_to(&buf, *stun_server).await?;
   
   Ok(())
 }
 
 /// Receives a STUN response with a timeout.
 pub async fn receive_stun_response_with_timeout(
-  socket: &UdpSocket,
+  socket: &dyn Transport,
   timeout_duration: std::time::Duration,
 ) -> Result<(StunMessage, SocketAddr), Box<dyn std::error::Error>> {
   let mut buf = [0u8; 1024];
@@ -162,3 +558,248 @@ pub async fn receive_stun_response_with_timeout(
   
   Ok((message, addr))
 }
+
+/// Sends the request until a response with its transaction ID arrives, from
+/// whichever address: servers answer CHANGE-REQUESTs from their other one.
+/// With credentials, the request is authenticated and only success responses
+/// signed with the same password are taken. Anything else arriving on the
+/// socket meanwhile is dropped.
+pub async fn transact(
+  socket: &dyn Transport,
+  stun_server: SocketAddr,
+  request: &StunMessage,
+  credentials: Option<&Credentials>,
+  timeout_duration: std::time::Duration,
+) -> Result<(StunMessage, SocketAddr), Box<dyn std::error::Error>> {
+  let mut retransmit_interval = interval(std::time::Duration::from_millis(RETRANSMIT_INTERVAL_MS as u64));
+  let mut buf = [0u8; 1024];
+  let mut request = request.clone();
+
+  if let Some(credentials) = credentials {
+    request.authenticate(credentials);
+  }
+
+  retransmit_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  timeout(timeout_duration, async {
+    loop {
+      let result = tokio::select! {
+        _ = retransmit_interval.tick() => {
+          send_binding_request(socket, &stun_server, &request).await?;
+          continue;
+        },
+        result = socket.recv_from(&mut buf) => result
+      };
+
+      let (len, addr) = result?;
+
+      let Ok(response) = StunMessage::from_bytes(&buf[..len]) else {
+        continue;
+      };
+
+      // errors like 401 Unauthorized come from servers that can't sign them
+      let authentic = match credentials {
+        Some(credentials) if response.class() == MessageClass::SuccessResponse => {
+          StunMessage::verify_integrity(&buf[..len], &credentials.password).is_ok()
+        },
+        _ => true
+      };
+
+      if response.transaction_id == request.transaction_id
+        && matches!(response.class(), MessageClass::SuccessResponse | MessageClass::ErrorResponse)
+        && authentic {
+        return Ok::<_, Box<dyn std::error::Error>>((response, addr));
+      }
+    }
+  }).await.map_err(|_| "Timeout waiting for STUN response")?
+}
+
+/// Asks the STUN server which address it sees the socket come from.
+pub async fn query_reflexive_addr(
+  socket: &dyn Transport,
+  stun: &StunConfig,
+  timeout_duration: std::time::Duration,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+  let (response, _addr) = transact(socket, stun.server, &StunMessage::new(), stun.credentials.as_ref(), timeout_duration).await?;
+
+  response.reflexive_addr()
+}
+
+/// STUN server answering Binding Requests with the address they came from.
+/// Given sockets on a second IP and port, it also follows CHANGE-REQUEST and
+/// tells OTHER-ADDRESS, for the RFC 5780 behaviour tests.
+pub struct StunServer {
+  /// The primary socket, then with behaviour discovery the ones on the other
+  /// port, the other IP, and the other IP and port. Bit 0 of an index flips
+  /// the port, bit 1 the IP.
+  sockets: Vec<Arc<dyn Transport>>,
+  /// Short-term credentials every request must be authenticated with, when set.
+  credentials: Option<Credentials>
+}
+
+impl StunServer {
+  pub fn new(socket: Arc<dyn Transport>) -> Self {
+    Self { sockets: vec![socket], credentials: None }
+  }
+
+  /// Server on two IPs and two ports: the primary address, the other port,
+  /// the other IP, and the other IP and port.
+  pub fn with_behavior_discovery(sockets: [Arc<dyn Transport>; 4]) -> Self {
+    Self { sockets: sockets.to_vec(), credentials: None }
+  }
+
+  /// Only answers requests authenticated with the credentials, and signs the answers.
+  pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+    self.credentials = Some(credentials);
+    self
+  }
+
+  /// Answers a datagram received on socket `received_on` from `from`, returning
+  /// the response and the index of the socket to send it from.
+  pub fn handle(&self, request: &[u8], from: SocketAddr, received_on: usize) -> Option<(StunMessage, usize)> {
+    let message = StunMessage::from_bytes(request).ok()?;
+
+    if message.message_type != BINDING_REQUEST {
+      return None;
+    }
+
+    if let Some(credentials) = &self.credentials {
+      if let Err((code, reason)) = check_credentials(request, &message, credentials) {
+        let mut response = error_response(&message, code, reason);
+        response.attributes.push(StunAttribute::Fingerprint);
+
+        return Some((response, received_on));
+      }
+    }
+
+    let discovery = self.sockets.len() == 4;
+    let mut change = (false, false);
+    let mut unknown = Vec::new();
+
+    for attribute in &message.attributes {
+      match attribute {
+        StunAttribute::ChangeRequest { change_ip, change_port } if discovery => change = (*change_ip, *change_port),
+        // attributes below 0x8000 must be understood, or the request is refused
+        StunAttribute::ChangeRequest { .. } => unknown.push(CHANGE_REQUEST),
+        StunAttribute::Unknown(attr_type, _) if *attr_type < 0x8000 => unknown.push(*attr_type),
+        _ => {}
+      }
+    }
+
+    if !unknown.is_empty() {
+      let mut response = error_response(&message, 420, "Unknown Attribute");
+      response.attributes.push(StunAttribute::Unknown(
+        UNKNOWN_ATTRIBUTES,
+        unknown.iter().flat_map(|attr_type| attr_type.to_be_bytes()).collect()
+      ));
+
+      return Some((self.seal(response), received_on));
+    }
+
+    let mut response = StunMessage {
+      message_type: BINDING_SUCCESS_RESPONSE,
+      transaction_id: message.transaction_id,
+      attributes: Vec::new()
+    };
+
+    let (change_ip, change_port) = change;
+    let send_on = received_on ^ (change_port as usize) ^ ((change_ip as usize) << 1);
+
+    response.attributes.push(StunAttribute::XorMappedAddress(from));
+
+    if discovery {
+      if let (Ok(origin), Ok(other)) = (self.sockets[send_on].local_addr(), self.sockets[received_on ^ 3].local_addr()) {
+        response.attributes.push(StunAttribute::ResponseOrigin(origin));
+        response.attributes.push(StunAttribute::OtherAddress(other));
+      }
+    }
+
+    response.attributes.push(StunAttribute::Software(software()));
+
+    Some((self.seal(response), send_on))
+  }
+
+  /// Signs a response to an authenticated request, then adds FINGERPRINT.
+  fn seal(&self, mut response: StunMessage) -> StunMessage {
+    if let Some(credentials) = &self.credentials {
+      response.sign(&credentials.password);
+    }
+
+    response.attributes.push(StunAttribute::Fingerprint);
+    response
+  }
+
+  /// Serves requests on every socket. A failed receive, e.g. the ICMP error
+  /// of an unreachable client surfacing as a reset on Windows, is only logged.
+  pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Arc::new(Self { sockets: self.sockets.clone(), credentials: self.credentials.clone() });
+    let mut tasks = JoinSet::new();
+
+    for received_on in 0..self.sockets.len() {
+      tasks.spawn(serve(Arc::clone(&server), received_on));
+    }
+
+    match tasks.join_next().await {
+      Some(Err(e)) => Err(e.into()),
+      _ => Ok(())
+    }
+  }
+}
+
+/// Answers what arrives on one socket of the server.
+async fn serve(server: Arc<StunServer>, received_on: usize) {
+  let socket = &server.sockets[received_on];
+  let mut buf = [0u8; 1024];
+
+  loop {
+    let (len, from) = match socket.recv_from(&mut buf).await {
+      Ok(received) => received,
+      Err(e) => {
+        eprintln!("Failed to receive: {}", e);
+        continue;
+      }
+    };
+
+    if let Some((response, send_on)) = server.handle(&buf[..len], from, received_on) {
+      // a client that missed the answer asks again
+      let _ = server.sockets[send_on].send_to(&response.to_bytes(), from).await;
+    }
+  }
+}
+
+/// RFC 5389 short-term credential checks: 400 when the request carries no
+/// USERNAME or MESSAGE-INTEGRITY, 401 when either is wrong.
+fn check_credentials(request: &[u8], message: &StunMessage, credentials: &Credentials) -> Result<(), (u16, &'static str)> {
+  let username = message.attributes.iter().find_map(|attribute| match attribute {
+    StunAttribute::Username(username) => Some(username),
+    _ => None
+  });
+  let signed = message.attributes.iter().any(|attribute| matches!(attribute, StunAttribute::MessageIntegrity(_)));
+
+  match username {
+    Some(username) if signed => {
+      if *username != credentials.username || StunMessage::verify_integrity(request, &credentials.password).is_err() {
+        return Err((401, "Unauthorized"));
+      }
+
+      Ok(())
+    },
+    _ => Err((400, "Bad Request"))
+  }
+}
+
+/// Error response to the request, without FINGERPRINT yet.
+fn error_response(request: &StunMessage, code: u16, reason: &str) -> StunMessage {
+  StunMessage {
+    message_type: BINDING_ERROR_RESPONSE,
+    transaction_id: request.transaction_id,
+    attributes: vec![
+      StunAttribute::ErrorCode { code, reason: reason.to_string() },
+      StunAttribute::Software(software())
+    ]
+  }
+}
+
+fn software() -> String {
+  format!("mtrix {}", env!("CARGO_PKG_VERSION"))
+}
@@ -3,7 +3,11 @@ use std::{fs::File, io::BufReader};
 #[cfg(test)]
 use crate::video::ascii_frame::{jpeg_to_ascii_image, yuv_to_ascii_image}; // Import your function from the main module.
 #[cfg(test)]
+use crate::video::ascii_frame::{compress_ascii_image, decompress_ascii_image, VideoFormat};
+#[cfg(test)]
 use std::{io::Read, env::current_dir};
+#[cfg(test)]
+use proptest::prelude::*;
 
 #[test]
 fn test_jpeg_to_ascii() {
@@ -17,7 +21,7 @@ fn test_jpeg_to_ascii() {
     let mut buffer = Vec::new();
     reader.read_to_end(&mut buffer).unwrap();
 
-    let output = jpeg_to_ascii_image(&buffer);
+    let output = jpeg_to_ascii_image(&buffer, &VideoFormat::default());
 
     let mut expected_jpeg_output_file = File::open(assets_path.join("jpeg_output.txt")).unwrap();
     let mut expected_jpeg_output = Vec::<u8>::new();
@@ -38,7 +42,7 @@ fn test_yuv_to_ascii() {
     let mut buffer = Vec::new();
     reader.read_to_end(&mut buffer).unwrap();
 
-    let output = yuv_to_ascii_image(&buffer, 192, 54);
+    let output = yuv_to_ascii_image(&buffer, 192, 54, &VideoFormat::default());
 
     let mut expected_yuv_output_file = File::open(assets_path.join("yuv_output.txt")).unwrap();
     let mut expected_yuv_output = Vec::<u8>::new();
@@ -47,3 +51,44 @@ fn test_yuv_to_ascii() {
 
     assert_eq!(String::from_utf8(expected_yuv_output).unwrap(), output);
 }
+
+#[test]
+fn test_frames_follow_the_negotiated_size() {
+    let current_dir = current_dir().unwrap();
+    let mut buffer = Vec::new();
+    File::open(current_dir.join("src").join("tests").join("assets").join("mock.yuv")).unwrap().read_to_end(&mut buffer).unwrap();
+
+    let small = VideoFormat { width: 96, height: 27, ..VideoFormat::default() };
+    let output = yuv_to_ascii_image(&buffer, 192, 54, &small);
+
+    assert_eq!(output.lines().count(), 27);
+    assert!(output.lines().all(|line| line.len() == 96));
+    assert_eq!(small.decode(&small.encode(&output)).unwrap(), output);
+
+    // a peer sending larger frames than agreed on has them dropped
+    let large = yuv_to_ascii_image(&buffer, 192, 54, &VideoFormat::default());
+    assert_eq!(small.decode(&small.encode(&large)), Err("Frame larger than negotiated"));
+}
+
+#[test]
+fn test_decompress_rejects_truncated_pair() {
+    assert_eq!(decompress_ascii_image(&[b'@', 3, b'#']), Err("Truncated run-length pair"));
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn prop_run_length_roundtrip(runs in prop::collection::vec((prop::char::range(' ', '~'), 1..600usize), 0..32)) {
+        let ascii_image: String = runs
+            .iter()
+            .map(|(character, count)| character.to_string().repeat(*count))
+            .collect();
+
+        prop_assert_eq!(decompress_ascii_image(&compress_ascii_image(&ascii_image)).unwrap(), ascii_image);
+    }
+
+    #[test]
+    fn prop_decompress_never_panics(payload in prop::collection::vec(any::<u8>(), 0..512)) {
+        let _ = decompress_ascii_image(&payload);
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(test)]
+use crate::masp::capabilities::{self, Capabilities, ColourMode, VideoCodec};
+#[cfg(test)]
+use crate::masp::config::MaspConfig;
+#[cfg(test)]
+use crate::masp::fec::FecParameters;
+
+#[test]
+fn test_capabilities_roundtrip_skips_unknown_tags() {
+    let capabilities = Capabilities::local(&MaspConfig::default());
+    let mut serialized = capabilities.serialize();
+
+    // a capability added by a newer version, followed by a passphrase proof
+    let block_size = u16::from_be_bytes([serialized[0], serialized[1]]) + 5;
+    serialized[..2].copy_from_slice(&block_size.to_be_bytes());
+    serialized.extend([0x7F, 0x00, 0x02, 0xAA, 0xBB]);
+    serialized.extend([0x01, 0x02, 0x03]);
+
+    let (parsed, rest) = Capabilities::deserialize(&serialized).unwrap();
+
+    assert_eq!(parsed, capabilities);
+    assert_eq!(rest, &[0x01, 0x02, 0x03]);
+}
+
+#[test]
+fn test_negotiate_highest_common_set() {
+    let local = Capabilities::local(&MaspConfig::default());
+    let mut remote = Capabilities::local(&MaspConfig::default());
+
+    remote.versions = vec![0x02, 0x03];
+    remote.codecs = vec![VideoCodec::AsciiRunLength as u8, 0x09];
+    remote.max_frame_width = 80;
+    remote.max_datagram_size = 900;
+    remote.max_fec_group_size = 8;
+
+    let session = local.negotiate(&remote).unwrap();
+
+    assert_eq!(session, remote.negotiate(&local).unwrap());
+    assert_eq!(session.version, 0x02);
+    assert_eq!(session.codec, VideoCodec::AsciiRunLength);
+    assert_eq!(session.colour_mode, ColourMode::Monochrome);
+    assert_eq!(session.frame_width, 80);
+    assert_eq!(session.max_datagram_size, 900);
+    assert_eq!(session.fec, Some(FecParameters { group_size: 8, max_parity_count: 4 }));
+
+    // a peer without FEC support turns it off for the session
+    remote.max_fec_parity_count = 0;
+
+    assert_eq!(local.negotiate(&remote).unwrap().fec, None);
+
+    remote.versions = vec![0x03];
+
+    let error = local.negotiate(&remote).unwrap_err();
+
+    assert!(error.contains("protocol versions"));
+}
+
+#[test]
+fn test_negotiate_rejects_datagrams_too_small_to_carry_a_fragment() {
+    let local = Capabilities::local(&MaspConfig::default());
+    let mut remote = Capabilities::local(&MaspConfig::default());
+
+    remote.max_datagram_size = capabilities::MIN_DATAGRAM_SIZE;
+
+    assert_eq!(local.negotiate(&remote).unwrap().max_datagram_size, capabilities::MIN_DATAGRAM_SIZE);
+
+    remote.max_datagram_size = capabilities::MIN_DATAGRAM_SIZE - 1;
+
+    let error = local.negotiate(&remote).unwrap_err();
+
+    assert!(error.contains("too small"));
+    assert!(remote.negotiate(&local).is_err());
+}
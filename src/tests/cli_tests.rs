@@ -0,0 +1,17 @@
+#[cfg(test)]
+use crate::cli::Cli;
+#[cfg(test)]
+use clap::Parser;
+
+#[test]
+fn test_relay_is_refused_where_it_would_be_ignored() {
+    let relay = ["--relay", "relay.example.org:3479", "--relay-token", "t0ken"];
+    let parse = |command: &[&str]| Cli::try_parse_from(["mtrix"].iter().chain(&relay).chain(command)).unwrap();
+
+    assert!(parse(&["jackin", "203.0.113.7:55000"]).check().is_err());
+    assert!(parse(&["jackwait", "203.0.113.7:55000"]).check().is_err());
+    assert!(parse(&["connect"]).check().is_ok());
+    assert!(parse(&["join", "room-42", "--server", "rendezvous.example.org:4000"]).check().is_ok());
+
+    assert!(Cli::try_parse_from(["mtrix", "jackin", "203.0.113.7:55000"]).unwrap().check().is_ok());
+}
@@ -0,0 +1,44 @@
+#[cfg(test)]
+use crate::masp::congestion::CongestionController;
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[test]
+fn test_window_limits_bytes_in_flight() {
+    let mut congestion_controller = CongestionController::new(1000);
+    let mut sent = 0;
+
+    while congestion_controller.can_send(1000) {
+        congestion_controller.on_packet_sent(1000);
+        sent += 1;
+    }
+
+    // the initial window, then nothing until some of it is acknowledged
+    assert_eq!(sent, 10);
+    assert_eq!(congestion_controller.bytes_in_flight(), 10_000);
+
+    congestion_controller.on_packet_acked(1000);
+    assert!(congestion_controller.can_send(1000));
+
+    // giving up on packets frees their room without growing the window
+    congestion_controller.on_congestion_event(Duration::from_millis(100));
+    assert!(!congestion_controller.can_send(1000));
+
+    congestion_controller.on_packets_expired(9000);
+    assert_eq!(congestion_controller.bytes_in_flight(), 0);
+    assert!(congestion_controller.can_send(20_000));
+}
+
+#[test]
+fn test_window_follows_the_negotiated_datagram_size() {
+    let mut congestion_controller = CongestionController::new(1000);
+
+    congestion_controller.set_max_datagram_size(500);
+
+    // still the initial window of ten datagrams, now of the smaller size
+    assert!(congestion_controller.can_send(500));
+    congestion_controller.on_packet_sent(4500);
+    assert!(congestion_controller.can_send(500));
+    congestion_controller.on_packet_sent(500);
+    assert!(!congestion_controller.can_send(500));
+}
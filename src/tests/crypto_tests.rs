@@ -0,0 +1,127 @@
+#[cfg(test)]
+use crate::masp::crypto::{verify_passphrase_proof, Arrival, HandshakeRole, KeyPair, Passphrase, ProofError, SessionKeys};
+#[cfg(test)]
+use crate::masp::message::{MaspPacket, PacketType};
+
+#[cfg(test)]
+fn derive_both_sides() -> (SessionKeys, SessionKeys) {
+    let (initiator_identity, responder_identity) = (KeyPair::generate(), KeyPair::generate());
+    let (initiator, responder) = (KeyPair::generate(), KeyPair::generate());
+
+    let initiator_keys = initiator
+        .derive_session_keys(HandshakeRole::Initiator, &initiator_identity, &responder.public, &responder_identity.public, &[])
+        .unwrap();
+    let responder_keys = responder
+        .derive_session_keys(HandshakeRole::Responder, &responder_identity, &initiator.public, &initiator_identity.public, &[])
+        .unwrap();
+
+    assert_eq!(initiator_keys.remote_identity(), &responder_identity.public);
+    assert_eq!(responder_keys.remote_identity(), &initiator_identity.public);
+
+    (initiator_keys, responder_keys)
+}
+
+#[test]
+fn test_handshake_keys_seal_and_open() {
+    let (initiator_keys, responder_keys) = derive_both_sides();
+
+    assert!(initiator_keys.verify_confirmation_tag(&responder_keys.confirmation_tag()).is_ok());
+    assert!(responder_keys.verify_confirmation_tag(&initiator_keys.confirmation_tag()).is_ok());
+
+    // what the initiator seals only the responder can open
+    let sealing = initiator_keys.cipher();
+    let opening = responder_keys.cipher();
+
+    let packet = MaspPacket::new(PacketType::TextData, 1, b"hello".to_vec());
+    let sealed = sealing.seal(&packet).unwrap();
+
+    assert_ne!(sealed.payload, packet.payload);
+    let (opened, arrival) = opening.open(&sealed).unwrap();
+
+    assert_eq!(opened.payload, packet.payload);
+    assert_eq!(arrival, Arrival::Newest);
+
+    // a replay is told apart, tampered headers are rejected
+    assert_eq!(opening.open(&sealed).unwrap().1, Arrival::Duplicate);
+
+    let mut tampered = sealing.seal(&MaspPacket::new(PacketType::TextData, 2, b"bye".to_vec())).unwrap();
+    tampered.sequence_number = 3;
+
+    assert!(opening.open(&tampered).is_err());
+
+    // the connection identifier is authenticated like the rest of the header
+    let mut packet = MaspPacket::new(PacketType::TextData, 4, b"moved".to_vec());
+    packet.connection_id = 7;
+
+    let mut tampered = sealing.seal(&packet).unwrap();
+    tampered.connection_id = 8;
+
+    assert!(opening.open(&tampered).is_err());
+}
+
+#[test]
+fn test_replay_window_accepts_reordered_packets() {
+    let (initiator_keys, responder_keys) = derive_both_sides();
+
+    let sealing = responder_keys.cipher();
+    let opening = initiator_keys.cipher();
+
+    let sealed: Vec<MaspPacket> = (1..=3)
+        .map(|sequence_number| sealing.seal(&MaspPacket::new(PacketType::Ack, sequence_number, Vec::new())).unwrap())
+        .collect();
+
+    assert_eq!(opening.open(&sealed[2]).unwrap().1, Arrival::Newest);
+    assert_eq!(opening.open(&sealed[0]).unwrap().1, Arrival::Late);
+    assert_eq!(opening.open(&sealed[1]).unwrap().1, Arrival::Late);
+    assert_eq!(opening.open(&sealed[0]).unwrap().1, Arrival::Duplicate);
+
+    // once the window moved on, an old number can't be told from a replay
+    let newest = sealing.seal(&MaspPacket::new(PacketType::Ack, 5000, Vec::new())).unwrap();
+
+    assert_eq!(opening.open(&newest).unwrap().1, Arrival::Newest);
+    assert!(opening.open(&sealed[1]).is_err());
+}
+
+#[test]
+fn test_passphrase_proof() {
+    let passphrase = Passphrase::new("correct horse");
+    let (nonces, challenge) = (KeyPair::generate().public, KeyPair::generate().public);
+    let proof = passphrase.prove(HandshakeRole::Initiator, &nonces, &challenge);
+
+    assert!(verify_passphrase_proof(Some(&passphrase), HandshakeRole::Initiator, &nonces, &challenge, Some(&proof)).is_ok());
+    // a proof can't be reflected back as the other role, nor replayed in another handshake
+    assert!(verify_passphrase_proof(Some(&passphrase), HandshakeRole::Responder, &nonces, &challenge, Some(&proof)).is_err());
+    assert!(verify_passphrase_proof(Some(&passphrase), HandshakeRole::Initiator, &challenge, &challenge, Some(&proof)).is_err());
+
+    let wrong_passphrase = Passphrase::new("battery staple");
+
+    assert_eq!(
+        verify_passphrase_proof(Some(&wrong_passphrase), HandshakeRole::Initiator, &nonces, &challenge, Some(&proof)),
+        Err(ProofError::WrongSecret)
+    );
+    assert_eq!(
+        verify_passphrase_proof(Some(&passphrase), HandshakeRole::Initiator, &nonces, &challenge, None),
+        Err(ProofError::MissingProof)
+    );
+    assert_eq!(
+        verify_passphrase_proof(None, HandshakeRole::Initiator, &nonces, &challenge, Some(&proof)),
+        Err(ProofError::UnexpectedProof)
+    );
+}
+
+#[test]
+fn test_impersonated_identity_fails_key_confirmation() {
+    let (initiator_identity, responder_identity) = (KeyPair::generate(), KeyPair::generate());
+    let (initiator, responder) = (KeyPair::generate(), KeyPair::generate());
+    let impostor_identity = KeyPair::generate();
+
+    let initiator_keys = initiator
+        .derive_session_keys(HandshakeRole::Initiator, &initiator_identity, &responder.public, &responder_identity.public, &[])
+        .unwrap();
+    // claims the responder's identity key without owning its secret
+    let impostor_keys = responder
+        .derive_session_keys(HandshakeRole::Responder, &impostor_identity, &initiator.public, &initiator_identity.public, &[])
+        .unwrap();
+
+    assert!(initiator_keys.verify_confirmation_tag(&impostor_keys.confirmation_tag()).is_err());
+}
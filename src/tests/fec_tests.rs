@@ -0,0 +1,74 @@
+#[cfg(test)]
+use crate::masp::fec::{self, FecController, FecDecoder, FecParameters, Parity};
+#[cfg(test)]
+use crate::masp::message::{MaspPacket, PacketType};
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[cfg(test)]
+fn parity_packet(parity: &Parity, frame_id: u32, fragment_count: u16) -> MaspPacket {
+    MaspPacket::new_fragment(PacketType::VideoParity, 100, frame_id, parity.first_fragment, fragment_count, parity.serialize())
+}
+
+#[test]
+fn test_parity_rebuilds_a_burst_of_losses() {
+    let fragments: Vec<Vec<u8>> = vec![vec![1; 8], vec![2; 8], vec![3; 8], vec![4; 8], vec![5; 3]];
+    let parities = fec::encode_group(&fragments, 0, 10, 2);
+    let mut decoder = FecDecoder::new(Duration::from_secs(1));
+
+    assert_eq!(parities.len(), 2);
+
+    // fragments 2 and 3 are lost, each in its own stripe
+    for index in [0, 1, 4] {
+        let packet = MaspPacket::new_fragment(PacketType::VideoData, 10 + index as u32, 7, index, 5, fragments[index as usize].clone());
+
+        assert!(decoder.insert(&packet).is_empty());
+    }
+
+    let mut recovered: Vec<MaspPacket> = parities
+        .iter()
+        .flat_map(|parity| decoder.insert(&parity_packet(parity, 7, 5)))
+        .collect();
+
+    recovered.sort_by_key(|packet| packet.fragment_index);
+
+    assert_eq!(recovered.len(), 2);
+    assert_eq!((recovered[0].fragment_index, recovered[0].sequence_number), (2, 12));
+    assert_eq!(recovered[0].payload, fragments[2]);
+    assert_eq!((recovered[1].fragment_index, recovered[1].sequence_number), (3, 13));
+    assert_eq!(recovered[1].payload, fragments[3]);
+}
+
+#[test]
+fn test_parity_restores_the_length_of_a_short_fragment() {
+    let fragments: Vec<Vec<u8>> = vec![vec![9; 6], vec![7; 2]];
+    let parity = &fec::encode_group(&fragments, 4, 1, 1)[0];
+    let mut decoder = FecDecoder::new(Duration::from_secs(1));
+
+    decoder.insert(&MaspPacket::new_fragment(PacketType::VideoData, 1, 3, 4, 6, fragments[0].clone()));
+
+    let recovered = decoder.insert(&parity_packet(&Parity::deserialize(&parity.serialize()).unwrap(), 3, 6));
+
+    assert_eq!(recovered.len(), 1);
+    assert_eq!(recovered[0].fragment_index, 5);
+    assert_eq!(recovered[0].payload, vec![7; 2]);
+}
+
+#[test]
+fn test_parity_count_follows_observed_loss() {
+    let mut controller = FecController::new(FecParameters { group_size: 10, max_parity_count: 4 });
+
+    assert_eq!(controller.parity_count(), 1);
+
+    // a clean link needs no parity
+    controller.on_packets_sent(64);
+    assert_eq!(controller.parity_count(), 0);
+
+    // heavy loss asks for as much as was negotiated
+    for _ in 0..8 {
+        controller.on_packets_lost(32);
+        controller.on_packets_sent(64);
+    }
+
+    assert_eq!(controller.parity_count(), 4);
+}
@@ -0,0 +1,42 @@
+#[cfg(test)]
+use crate::masp::fragment::{split_payload, FrameReassembler};
+#[cfg(test)]
+use crate::masp::message::{MaspPacket, PacketType};
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[test]
+fn test_split_and_reassemble_out_of_order() {
+    let payload: Vec<u8> = (0..2500).map(|i| (i % 251) as u8).collect();
+    let fragments = split_payload(&payload, 1000).unwrap();
+
+    assert_eq!(fragments.len(), 3);
+
+    let packets: Vec<MaspPacket> = fragments
+        .into_iter()
+        .enumerate()
+        .map(|(index, fragment)| {
+            MaspPacket::new_fragment(PacketType::VideoData, index as u32, 7, index as u16, 3, fragment)
+        })
+        .collect();
+
+    let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+
+    assert_eq!(reassembler.insert(&packets[2]), None);
+    assert_eq!(reassembler.insert(&packets[0]), None);
+    // duplicates must not complete the frame early
+    assert_eq!(reassembler.insert(&packets[0]), None);
+    assert_eq!(reassembler.insert(&packets[1]), Some(payload));
+}
+
+#[test]
+fn test_serialized_fragment_roundtrip() {
+    let packet = MaspPacket::new_fragment(PacketType::VideoData, 42, 3, 1, 4, vec![1, 2, 3]);
+    let parsed = MaspPacket::deserialize(&packet.serialize()).unwrap();
+
+    assert_eq!(parsed.sequence_number, 42);
+    assert_eq!(parsed.frame_id, 3);
+    assert_eq!(parsed.fragment_index, 1);
+    assert_eq!(parsed.fragment_count, 4);
+    assert_eq!(parsed.payload, vec![1, 2, 3]);
+}
@@ -0,0 +1,68 @@
+#[cfg(test)]
+use crate::masp::capabilities::Capabilities;
+#[cfg(test)]
+use crate::masp::config::MaspConfig;
+#[cfg(test)]
+use crate::masp::crypto::KeyPair;
+#[cfg(test)]
+use crate::masp::handshake::Handshake;
+#[cfg(test)]
+use crate::masp::shutdown::Shutdown;
+#[cfg(test)]
+use crate::masp::socket::MaspSocket;
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::time::{timeout, Duration};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// Handshake of a socket bound to `local_addr` on the network, with its
+/// demultiplexer running until the shutdown.
+#[cfg(test)]
+fn handshake(network: &SimulatedNetwork, local_addr: &str, remote_addr: &str, shutdown: &Shutdown) -> (Handshake, MaspSocket) {
+    let socket = MaspSocket::new(network.bind(addr(local_addr)).unwrap(), addr(remote_addr));
+    let (demultiplexer, _, _, handshake_inbox) = socket.demultiplexer(shutdown.clone());
+
+    tokio::spawn(demultiplexer.run());
+
+    let capabilities = Capabilities::local(&MaspConfig::default());
+
+    (Handshake::new(socket.clone(), handshake_inbox, capabilities, None), socket)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_session_resumes_from_another_host_with_the_same_identity() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 14);
+    let shutdown = Shutdown::new();
+    let (alice_identity, bob_identity) = (KeyPair::generate(), KeyPair::generate());
+    let (mut bob, bob_socket) = handshake(&network, "192.0.2.1:5000", "203.0.113.7:40000", &shutdown);
+    let (mut alice, _) = handshake(&network, "203.0.113.7:40000", "192.0.2.1:5000", &shutdown);
+
+    let (initiated, responded) = tokio::join!(alice.initiate(&alice_identity, 1), bob.respond(&bob_identity));
+    assert!(initiated.is_ok() && responded.is_ok());
+
+    // somebody else can't take over the session from a new host
+    let (mut mallory, _) = handshake(&network, "198.51.100.9:41000", "192.0.2.1:5000", &shutdown);
+    let mallory_identity = KeyPair::generate();
+
+    tokio::select! {
+        result = mallory.initiate(&mallory_identity, 1) => assert!(result.is_err()),
+        _ = bob.respond(&bob_identity) => panic!("Handshake with another identity completed")
+    }
+
+    // alice's network changed, the next handshake comes from another host
+    let (mut alice, _) = handshake(&network, "198.51.100.3:41000", "192.0.2.1:5000", &shutdown);
+
+    let (initiated, responded) = timeout(Duration::from_secs(10), async {
+        tokio::join!(alice.initiate(&alice_identity, 1), bob.respond(&bob_identity))
+    }).await.unwrap();
+
+    assert!(initiated.is_ok() && responded.is_ok());
+    assert_eq!(bob_socket.remote_addr(), addr("198.51.100.3:41000"));
+}
@@ -0,0 +1,214 @@
+#[cfg(test)]
+use crate::ice::{self, Candidate, CandidateKind, CandidateSet};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use crate::transport::{BoxFuture, Transport};
+#[cfg(test)]
+use std::io;
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use tokio::time::{timeout, Duration};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[cfg(test)]
+fn candidates(host_addr: &str, reflexive_addr: &str) -> CandidateSet {
+    CandidateSet::new(vec![
+        Candidate::new(CandidateKind::ServerReflexive, addr(reflexive_addr), u16::MAX),
+        Candidate::new(CandidateKind::Host, addr(host_addr), u16::MAX)
+    ])
+}
+
+#[test]
+fn test_candidate_token_roundtrip_and_rejects_malformed_tokens() {
+    let local = CandidateSet::new(vec![
+        Candidate::new(CandidateKind::Relayed, addr("192.0.2.9:3479"), u16::MAX),
+        Candidate::new(CandidateKind::Host, addr("[2001:db8::5]:55000"), u16::MAX),
+        Candidate::new(CandidateKind::ServerReflexive, addr("203.0.113.7:40000"), u16::MAX)
+    ]);
+
+    // host before reflexive before relayed
+    let kinds: Vec<CandidateKind> = local.candidates.iter().map(|candidate| candidate.kind).collect();
+    assert_eq!(kinds, [CandidateKind::Host, CandidateKind::ServerReflexive, CandidateKind::Relayed]);
+
+    let token = local.to_token();
+
+    assert_eq!(CandidateSet::from_token(&format!("  {}\n", token)).unwrap(), local);
+    assert!(CandidateSet::from_token(&token[..token.len() - 2]).is_err());
+    assert!(CandidateSet::from_token(&token[ice::TOKEN_PREFIX.len()..]).is_err());
+    assert!(CandidateSet::from_token(&format!("{}zz", token)).is_err());
+
+    let remote = candidates("10.0.0.5:55000", "198.51.100.3:41000");
+
+    assert_ne!(local.is_controlling(&remote), remote.is_controlling(&local));
+    assert_eq!(local.pair_key(&remote), remote.pair_key(&local));
+    assert_eq!(local.identity_host(), Some(addr("203.0.113.7:40000").ip()));
+}
+
+#[test]
+fn test_pairs_prefer_the_lan_and_keep_the_relay_last() {
+    let local = candidates("192.168.1.10:55000", "203.0.113.7:40000");
+    let mut remote = candidates("192.168.1.11:55000", "203.0.113.7:40001");
+    remote.candidates.push(Candidate::new(CandidateKind::Host, addr("[2001:db8::5]:55000"), 0));
+
+    let pairs = ice::candidate_pairs(&local, &remote, true, &[addr("0.0.0.0:55000")], Some(addr("192.0.2.9:50000")));
+    let targets: Vec<SocketAddr> = pairs.iter().map(|pair| pair.remote_addr).collect();
+
+    // the IPv6 candidate can't be reached from an IPv4 socket
+    assert_eq!(targets, [addr("192.168.1.11:55000"), addr("203.0.113.7:40001"), addr("192.0.2.9:50000")]);
+    assert!(pairs[2].relayed);
+
+    // both peers order pairs the same way
+    let remote_pairs = ice::candidate_pairs(&remote, &local, false, &[addr("0.0.0.0:55000")], None);
+    assert_eq!(remote_pairs[0].priority, pairs[0].priority);
+
+    // with a socket of each family, the IPv6 candidate is paired with the second one
+    let pairs = ice::candidate_pairs(&local, &remote, true, &[addr("0.0.0.0:55000"), addr("[::]:41000")], None);
+    let ipv6_pair = pairs.iter().find(|pair| pair.remote_addr.is_ipv6()).unwrap();
+
+    assert_eq!(pairs.len(), 3);
+    assert_eq!(ipv6_pair.socket, 1);
+    assert!(pairs.iter().filter(|pair| pair.remote_addr.is_ipv4()).all(|pair| pair.socket == 0));
+}
+
+#[tokio::test]
+async fn test_connectivity_checks_nominate_the_best_reachable_pair() {
+    let conditions = LinkConditions { loss: 0.2, delay: Duration::from_millis(10), ..LinkConditions::default() };
+
+    // on the same LAN the host candidates get through and win
+    // behind different NATs only the reflexive ones do
+    for (alice_bound, bob_bound) in [("192.168.1.10:55000", "192.168.1.11:55000"), ("203.0.113.7:40000", "198.51.100.3:41000")] {
+        let network = SimulatedNetwork::new(conditions.clone(), 22);
+        let alice = network.bind(addr(alice_bound)).unwrap();
+        let bob = network.bind(addr(bob_bound)).unwrap();
+        let alice_candidates = candidates("192.168.1.10:55000", "203.0.113.7:40000");
+        let bob_candidates = candidates("192.168.1.11:55000", "198.51.100.3:41000");
+        let alice_controlling = alice_candidates.is_controlling(&bob_candidates);
+
+        let alice_pairs = ice::candidate_pairs(&alice_candidates, &bob_candidates, alice_controlling, &[addr(alice_bound)], None);
+        let bob_pairs = ice::candidate_pairs(&bob_candidates, &alice_candidates, !alice_controlling, &[addr(bob_bound)], None);
+
+        let (alice_sockets, bob_sockets) = ([alice.as_ref() as &dyn Transport], [bob.as_ref() as &dyn Transport]);
+
+        let (alice_nominated, bob_nominated) = timeout(Duration::from_secs(30), async {
+            tokio::join!(
+                ice::check_connectivity(&alice_sockets, &alice_candidates, &bob_candidates, alice_controlling, &alice_pairs),
+                ice::check_connectivity(&bob_sockets, &bob_candidates, &alice_candidates, !alice_controlling, &bob_pairs)
+            )
+        }).await.unwrap();
+
+        assert_eq!(alice_nominated.unwrap(), (0, addr(bob_bound)));
+        assert_eq!(bob_nominated.unwrap(), (0, addr(alice_bound)));
+    }
+}
+
+#[tokio::test]
+async fn test_peers_sharing_only_ipv6_meet_on_the_second_socket() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 6);
+    let alice_ipv4 = network.bind(addr("192.168.1.10:55000")).unwrap();
+    let alice_ipv6 = network.bind(addr("[2001:db8::10]:41000")).unwrap();
+    let bob = network.bind(addr("[2001:db8::11]:55000")).unwrap();
+    let alice_candidates = CandidateSet::new(vec![
+        Candidate::new(CandidateKind::Host, addr("192.168.1.10:55000"), u16::MAX),
+        Candidate::new(CandidateKind::Host, addr("[2001:db8::10]:41000"), u16::MAX - 1)
+    ]);
+    let bob_candidates = CandidateSet::new(vec![Candidate::new(CandidateKind::Host, addr("[2001:db8::11]:55000"), u16::MAX)]);
+    let alice_controlling = alice_candidates.is_controlling(&bob_candidates);
+
+    let alice_addrs = [addr("192.168.1.10:55000"), addr("[2001:db8::10]:41000")];
+    let alice_pairs = ice::candidate_pairs(&alice_candidates, &bob_candidates, alice_controlling, &alice_addrs, None);
+    let bob_pairs = ice::candidate_pairs(&bob_candidates, &alice_candidates, !alice_controlling, &[addr("[2001:db8::11]:55000")], None);
+
+    let alice_sockets = [alice_ipv4.as_ref() as &dyn Transport, alice_ipv6.as_ref()];
+    let bob_sockets = [bob.as_ref() as &dyn Transport];
+
+    let (alice_nominated, bob_nominated) = timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            ice::check_connectivity(&alice_sockets, &alice_candidates, &bob_candidates, alice_controlling, &alice_pairs),
+            ice::check_connectivity(&bob_sockets, &bob_candidates, &alice_candidates, !alice_controlling, &bob_pairs)
+        )
+    }).await.unwrap();
+
+    assert_eq!(alice_nominated.unwrap(), (1, addr("[2001:db8::11]:55000")));
+    assert_eq!(bob_nominated.unwrap(), (0, addr("[2001:db8::10]:41000")));
+}
+
+/// Socket whose nominations never make it out, kept for replaying them.
+#[cfg(test)]
+struct HeldNominations {
+    socket: Arc<dyn Transport>,
+    held: Mutex<Vec<Vec<u8>>>
+}
+
+#[cfg(test)]
+impl Transport for HeldNominations {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+        // a request with the nominate flag
+        if buf.len() > 6 && buf[5] == 0x01 && buf[6] & 0x01 != 0 {
+            self.held.lock().unwrap().push(buf.to_vec());
+            return Box::pin(async move { Ok(buf.len()) });
+        }
+
+        self.socket.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        self.socket.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[tokio::test]
+async fn test_nomination_replayed_from_another_address_is_ignored() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 9);
+    let (alice_addr, bob_addr) = (addr("192.168.1.10:55000"), addr("192.168.1.11:55000"));
+    let mallory = network.bind(addr("192.168.1.66:55000")).unwrap();
+    let mut alice_candidates = candidates("192.168.1.10:55000", "203.0.113.7:40000");
+    let mut bob_candidates = candidates("192.168.1.11:55000", "198.51.100.3:41000");
+
+    // alice controls, whatever the random tie breakers came out as
+    alice_candidates.tie_breaker = u64::MAX;
+    bob_candidates.tie_breaker = 0;
+
+    let alice = Arc::new(HeldNominations { socket: network.bind(alice_addr).unwrap(), held: Mutex::new(Vec::new()) });
+    let bob = network.bind(bob_addr).unwrap();
+    let alice_pairs = ice::candidate_pairs(&alice_candidates, &bob_candidates, true, &[alice_addr], None);
+    let bob_pairs = ice::candidate_pairs(&bob_candidates, &alice_candidates, false, &[bob_addr], None);
+
+    let bob_sockets = [bob.as_ref() as &dyn Transport];
+    let bob_checks = ice::check_connectivity(&bob_sockets, &bob_candidates, &alice_candidates, false, &bob_pairs);
+    tokio::pin!(bob_checks);
+
+    let alice_sockets = [alice.as_ref() as &dyn Transport];
+
+    tokio::select! {
+        _ = &mut bob_checks => panic!("bob followed a nomination from the wrong address"),
+        _ = async {
+            // alice takes the pair without an answer, none of its nominations got out
+            let alice_nominated = ice::check_connectivity(&alice_sockets, &alice_candidates, &bob_candidates, true, &alice_pairs).await;
+            assert_eq!(alice_nominated.unwrap(), (0, bob_addr));
+
+            // replayed from elsewhere, the nomination doesn't move bob there
+            let nomination = alice.held.lock().unwrap()[0].clone();
+            mallory.send_to(&nomination, bob_addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        } => {}
+    }
+
+    // from where alice's requests came from, it does
+    let nomination = alice.held.lock().unwrap()[0].clone();
+    alice.socket.send_to(&nomination, bob_addr).await.unwrap();
+
+    let bob_nominated = timeout(Duration::from_secs(1), bob_checks).await.unwrap();
+    assert_eq!(bob_nominated.unwrap(), (0, alice_addr));
+}
@@ -1 +1,21 @@
-pub mod ascii_frame_tests;
\ No newline at end of file
+pub mod ascii_frame_tests;
+pub mod capabilities_tests;
+pub mod cli_tests;
+pub mod congestion_tests;
+pub mod crypto_tests;
+pub mod fec_tests;
+pub mod fragment_tests;
+pub mod handshake_tests;
+pub mod ice_tests;
+pub mod message_tests;
+pub mod nat_tests;
+pub mod reconnect_tests;
+pub mod relay_tests;
+pub mod rendezvous_tests;
+pub mod serial_tests;
+pub mod session_tests;
+pub mod simulator_tests;
+pub mod socket_tests;
+pub mod stun_server_tests;
+pub mod stun_tests;
+pub mod window_tests;
\ No newline at end of file
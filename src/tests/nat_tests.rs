@@ -0,0 +1,117 @@
+#[cfg(test)]
+use crate::nat::{self, Behavior};
+#[cfg(test)]
+use crate::stun::{StunAttribute, StunConfig, StunMessage};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork, SimulatedSocket};
+#[cfg(test)]
+use crate::transport::Transport;
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// STUN server on every socket of `sockets`, answering as if the client were behind a NAT
+/// mapping each new destination to the next port, and filtering answers from other IPs.
+/// Tells its other address only when `rfc5780` is set.
+#[cfg(test)]
+fn spawn_symmetric_nat_server(sockets: Vec<Arc<SimulatedSocket>>, other_addr: SocketAddr, rfc5780: bool) {
+    let destinations: Arc<Mutex<Vec<SocketAddr>>> = Arc::new(Mutex::new(Vec::new()));
+
+    for socket in sockets.clone() {
+        let sockets = sockets.clone();
+        let destinations = destinations.clone();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let local_addr = socket.local_addr().unwrap();
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = StunMessage::from_bytes(&buf[..len]).unwrap();
+
+                let port = {
+                    let mut destinations = destinations.lock().unwrap();
+
+                    if !destinations.contains(&local_addr) {
+                        destinations.push(local_addr);
+                    }
+
+                    40000 + destinations.iter().position(|destination| *destination == local_addr).unwrap() as u16
+                };
+
+                let (change_ip, change_port) = request.attributes.iter().find_map(|attribute| match attribute {
+                    StunAttribute::ChangeRequest { change_ip, change_port } => Some((*change_ip, *change_port)),
+                    _ => None
+                }).unwrap_or((false, false));
+
+                // address-dependent filtering drops whatever comes from another IP
+                if change_ip {
+                    continue;
+                }
+
+                let source = SocketAddr::new(local_addr.ip(), if change_port { other_addr.port() } else { local_addr.port() });
+                let mut response = StunMessage { message_type: 0x0101, ..request };
+                response.attributes = vec![StunAttribute::XorMappedAddress(SocketAddr::new(addr("203.0.113.7:0").ip(), port))];
+
+                if rfc5780 {
+                    response.attributes.push(StunAttribute::OtherAddress(other_addr));
+                }
+
+                let sender = sockets.iter().find(|socket| socket.local_addr().unwrap() == source).unwrap();
+                sender.send_to(&response.to_bytes(), from).await.unwrap();
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_rfc5780_discovery_classifies_a_symmetric_nat() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 23);
+    let client = network.bind(addr("198.51.100.2:55000")).unwrap();
+    let sockets = ["192.0.2.1:3478", "192.0.2.1:3479", "192.0.2.2:3478", "192.0.2.2:3479"]
+        .iter()
+        .map(|server| network.bind(addr(server)).unwrap())
+        .collect();
+
+    spawn_symmetric_nat_server(sockets, addr("192.0.2.2:3479"), true);
+
+    let stun = StunConfig { server: addr("192.0.2.1:3478"), credentials: None };
+    let report = nat::discover(client.as_ref(), &stun, &[]).await.unwrap();
+
+    assert!(report.behind_nat);
+    assert_eq!(report.mapped_addr, addr("203.0.113.7:40000"));
+    assert_eq!(report.mapping, Some(Behavior::AddressAndPortDependent));
+    assert_eq!(report.filtering, Some(Behavior::AddressDependent));
+    assert_eq!(report.port_delta, Some(1));
+}
+
+#[tokio::test]
+async fn test_discovery_falls_back_to_comparing_servers() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 24);
+    let client = network.bind(addr("198.51.100.2:55000")).unwrap();
+    let sockets = ["192.0.2.1:3478", "192.0.2.5:3478", "192.0.2.9:3478"]
+        .iter()
+        .map(|server| network.bind(addr(server)).unwrap())
+        .collect();
+
+    spawn_symmetric_nat_server(sockets, addr("192.0.2.1:3478"), false);
+
+    let stun = StunConfig { server: addr("192.0.2.1:3478"), credentials: None };
+    let report = nat::discover(client.as_ref(), &stun, &[addr("192.0.2.5:3478"), addr("192.0.2.9:3478")])
+        .await
+        .unwrap();
+
+    assert_eq!(report.mapping, Some(Behavior::Dependent));
+    assert_eq!(report.filtering, None);
+    assert_eq!(report.port_delta, Some(1));
+
+    // random ports can't be predicted, nor can a single step
+    assert_eq!(nat::port_delta(&[addr("203.0.113.7:40000"), addr("203.0.113.7:52113"), addr("203.0.113.7:1042")]), None);
+    assert_eq!(nat::port_delta(&[addr("203.0.113.7:40000"), addr("203.0.113.7:40002")]), None);
+}
@@ -0,0 +1,42 @@
+#[cfg(test)]
+use crate::masp::reconnect::{Backoff, ConnectionState, ConnectionStatus};
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[test]
+fn test_backoff_doubles_up_to_the_maximum() {
+    let mut backoff = Backoff::new();
+
+    let delays: Vec<Duration> = (0..7).map(|_| backoff.next_delay()).collect();
+
+    assert_eq!(delays, [250, 500, 1000, 2000, 4000, 4000, 4000].map(Duration::from_millis));
+}
+
+#[tokio::test]
+async fn test_connection_status_is_shared_by_clones() {
+    let status = ConnectionStatus::new();
+    let watcher = status.clone();
+
+    assert_eq!(status.get(), ConnectionState::Connected);
+
+    let reconnecting = tokio::spawn(async move {
+        watcher.wait_for(ConnectionState::Reconnecting).await;
+    });
+
+    status.set(ConnectionState::Reconnecting);
+
+    tokio::time::timeout(Duration::from_secs(1), reconnecting).await.unwrap().unwrap();
+    assert_eq!(status.get().to_string(), "reconnecting…");
+}
+
+#[test]
+fn test_reconnection_progress_is_cleared_once_connected() {
+    let status = ConnectionStatus::new();
+
+    status.set(ConnectionState::Reconnecting);
+    status.report("Sending handshake attempt: 0".to_string());
+    assert_eq!(status.detail(), "Sending handshake attempt: 0");
+
+    status.set(ConnectionState::Connected);
+    assert_eq!(status.detail(), "");
+}
@@ -0,0 +1,87 @@
+#[cfg(test)]
+use crate::relay::{self, RefusalReason, RelayMessage, RelayServer};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use crate::transport::{self, Transport};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::time::{timeout, Duration};
+
+#[cfg(test)]
+async fn bind_localhost() -> std::sync::Arc<dyn Transport> {
+    transport::bind_udp("127.0.0.1:0".parse().unwrap()).await.unwrap()
+}
+
+#[test]
+fn test_relay_messages_roundtrip_and_reject_malformed_bytes() {
+    let messages = [
+        RelayMessage::Allocate { token: "s3cret".to_string(), pair_key: "blue-fox-42".to_string() },
+        RelayMessage::Allocated { port: 49152 },
+        RelayMessage::Refused(RefusalReason::PairFull)
+    ];
+
+    for message in messages {
+        let bytes = message.to_bytes();
+
+        assert_eq!(RelayMessage::from_bytes(&bytes).unwrap(), message);
+        assert!(RelayMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(RelayMessage::from_bytes(&[&bytes[..], &[0]].concat()).is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_relay_forwards_between_paired_clients_on_localhost() {
+    let control = bind_localhost().await;
+    let relay_addr = control.local_addr().unwrap();
+    let server = tokio::spawn(async move { RelayServer::new(control, "s3cret").run().await.map_err(|e| e.to_string()) });
+
+    let alice = bind_localhost().await;
+    let bob = bind_localhost().await;
+    let mallory = bind_localhost().await;
+
+    assert!(relay::allocate(mallory.as_ref(), relay_addr, "guess", "room").await.is_err());
+
+    let alice_relayed = relay::allocate(alice.as_ref(), relay_addr, "s3cret", "room").await.unwrap();
+    let bob_relayed = relay::allocate(bob.as_ref(), relay_addr, "s3cret", "room").await.unwrap();
+
+    assert_ne!(alice_relayed, bob_relayed);
+    assert!(relay::allocate(mallory.as_ref(), relay_addr, "s3cret", "room").await.is_err());
+
+    let mut buf = [0u8; 64];
+
+    // each side talks to its own relayed address, and hears the other from there
+    alice.send_to(b"hello bob", alice_relayed).await.unwrap();
+    let (len, from): (usize, SocketAddr) = timeout(Duration::from_secs(1), bob.recv_from(&mut buf)).await.unwrap().unwrap();
+    assert_eq!((&buf[..len], from), (&b"hello bob"[..], bob_relayed));
+
+    bob.send_to(b"hello alice", bob_relayed).await.unwrap();
+    let (len, from) = timeout(Duration::from_secs(1), alice.recv_from(&mut buf)).await.unwrap().unwrap();
+    assert_eq!((&buf[..len], from), (&b"hello alice"[..], alice_relayed));
+
+    // strangers can't inject through someone else's relayed address
+    mallory.send_to(b"spoofed", alice_relayed).await.unwrap();
+    assert!(timeout(Duration::from_millis(100), bob.recv_from(&mut buf)).await.is_err());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_relay_keeps_serving_after_a_failed_receive() {
+    // relayed addresses are bound on the control socket's IP, so stay on loopback
+    let network = SimulatedNetwork::new(LinkConditions::default(), 21);
+    let control = network.bind("127.0.0.1:3479".parse().unwrap()).unwrap();
+    let client = network.bind("127.0.0.1:40000".parse().unwrap()).unwrap();
+
+    // like the ICMP error of a client that went away, surfacing as a reset on Windows
+    control.fail_next_receive();
+
+    let server = tokio::spawn(async move { RelayServer::new(control, "s3cret").run().await.map_err(|e| e.to_string()) });
+    let relayed = relay::allocate(client.as_ref(), "127.0.0.1:3479".parse().unwrap(), "s3cret", "room").await;
+
+    assert!(relayed.is_ok());
+    assert!(!server.is_finished());
+
+    server.abort();
+}
@@ -0,0 +1,167 @@
+#[cfg(test)]
+use crate::ice::{Candidate, CandidateKind, CandidateSet};
+#[cfg(test)]
+use crate::masp::crypto::HandshakeRole;
+#[cfg(test)]
+use crate::rendezvous::{self, RendezvousMessage, RendezvousServer};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::time::{timeout, Duration, Instant};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[cfg(test)]
+fn host_candidates(local_addr: &str) -> CandidateSet {
+    CandidateSet::new(vec![Candidate::new(CandidateKind::Host, addr(local_addr), u16::MAX)])
+}
+
+#[cfg(test)]
+fn with_reflexive(candidates: &CandidateSet, reflexive_addr: &str) -> CandidateSet {
+    let mut candidates = candidates.clone();
+    candidates.candidates.push(Candidate::new(CandidateKind::ServerReflexive, addr(reflexive_addr), 0));
+
+    candidates
+}
+
+#[test]
+fn test_rendezvous_messages_roundtrip_and_reject_malformed_bytes() {
+    let candidates = CandidateSet::new(vec![
+        Candidate::new(CandidateKind::Host, addr("[fe80::1]:55000"), u16::MAX),
+        Candidate::new(CandidateKind::ServerReflexive, addr("203.0.113.7:40000"), u16::MAX),
+        Candidate::new(CandidateKind::Relayed, addr("192.0.2.9:3479"), u16::MAX)
+    ]);
+    let messages = [
+        RendezvousMessage::Register { room: "blue-fox-42".to_string(), candidates: candidates.clone() },
+        RendezvousMessage::Waiting,
+        RendezvousMessage::Matched { role: HandshakeRole::Initiator, peer_candidates: candidates }
+    ];
+
+    for message in messages {
+        let bytes = message.to_bytes();
+
+        assert_eq!(RendezvousMessage::from_bytes(&bytes).unwrap(), message);
+        assert!(RendezvousMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(RendezvousMessage::from_bytes(&[&bytes[..], &[0]].concat()).is_err());
+    }
+
+    let invalid_room = RendezvousMessage::Register { room: "no spaces".to_string(), candidates: host_candidates("10.0.0.1:1") };
+    assert!(RendezvousMessage::from_bytes(&invalid_room.to_bytes()).is_err());
+}
+
+#[test]
+fn test_server_matches_the_second_peer_of_a_room() {
+    let mut server = RendezvousServer::new();
+    let now = Instant::now();
+    let alice = host_candidates("192.168.1.10:55000");
+    let bob = host_candidates("10.0.0.5:55000");
+    let register = |candidates: &CandidateSet| RendezvousMessage::Register {
+        room: "room".to_string(),
+        candidates: candidates.clone()
+    };
+
+    let answers = server.handle(register(&alice), addr("203.0.113.7:40000"), now);
+    assert_eq!(answers, [(RendezvousMessage::Waiting, addr("203.0.113.7:40000"))]);
+
+    // each peer learns the other's candidates, along with where the server saw it come from
+    let answers = server.handle(register(&bob), addr("198.51.100.3:41000"), now);
+    assert_eq!(answers, [
+        (
+            RendezvousMessage::Matched {
+                role: HandshakeRole::Responder,
+                peer_candidates: with_reflexive(&bob, "198.51.100.3:41000")
+            },
+            addr("203.0.113.7:40000")
+        ),
+        (
+            RendezvousMessage::Matched {
+                role: HandshakeRole::Initiator,
+                peer_candidates: with_reflexive(&alice, "203.0.113.7:40000")
+            },
+            addr("198.51.100.3:41000")
+        )
+    ]);
+
+    // a third peer isn't let into a full room
+    assert!(server.handle(register(&host_candidates("10.0.0.9:55000")), addr("192.0.2.1:42000"), now).is_empty());
+}
+
+#[tokio::test]
+async fn test_peers_meet_through_rendezvous_server_over_lossy_network() {
+    let network = SimulatedNetwork::new(LinkConditions { loss: 0.2, ..LinkConditions::default() }, 20);
+    let server_socket = network.bind(addr("192.0.2.1:3478")).unwrap();
+    let alice = network.bind(addr("203.0.113.7:40000")).unwrap();
+    let bob = network.bind(addr("198.51.100.3:41000")).unwrap();
+    let alice_candidates = host_candidates("192.168.1.10:55000");
+    let bob_candidates = host_candidates("10.0.0.5:55000");
+
+    let server = tokio::spawn(async move { RendezvousServer::new().run(server_socket.as_ref()).await.map_err(|e| e.to_string()) });
+
+    let (alice_match, bob_match) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            rendezvous::join(alice.as_ref(), addr("192.0.2.1:3478"), "room", &alice_candidates),
+            rendezvous::join(bob.as_ref(), addr("192.0.2.1:3478"), "room", &bob_candidates)
+        )
+    }).await.unwrap();
+
+    let (alice_match, bob_match) = (alice_match.unwrap(), bob_match.unwrap());
+
+    assert_ne!(alice_match.role, bob_match.role);
+    assert_eq!(alice_match.peer_candidates, with_reflexive(&bob_candidates, "198.51.100.3:41000"));
+    assert_eq!(bob_match.peer_candidates, with_reflexive(&alice_candidates, "203.0.113.7:40000"));
+
+    server.abort();
+}
+
+#[test]
+fn test_server_caps_rooms_until_they_expire() {
+    let mut server = RendezvousServer::new();
+    let now = Instant::now();
+    let register = |room: String| RendezvousMessage::Register { room, candidates: host_candidates("10.0.0.5:55000") };
+
+    for index in 0..rendezvous::MAX_ROOMS {
+        assert_eq!(server.handle(register(format!("room-{}", index)), addr("203.0.113.7:40000"), now).len(), 1);
+    }
+
+    // a full server turns away new rooms, the ones it holds still work
+    assert!(server.handle(register("one-more".to_string()), addr("198.51.100.3:41000"), now).is_empty());
+    assert_eq!(server.handle(register("room-0".to_string()), addr("198.51.100.3:41000"), now).len(), 2);
+
+    // only once they expire is there room again
+    server.expire(now + Duration::from_secs(10));
+    assert!(server.handle(register("one-more".to_string()), addr("198.51.100.3:41000"), now).is_empty());
+
+    let later = now + Duration::from_secs(60);
+    server.expire(later);
+    assert_eq!(server.handle(register("one-more".to_string()), addr("198.51.100.3:41000"), later).len(), 1);
+}
+
+/// Socket whose first receive fails, like one reset by an ICMP error.
+#[tokio::test]
+async fn test_server_keeps_serving_after_a_failed_receive() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 21);
+    let server_socket = network.bind(addr("192.0.2.1:3478")).unwrap();
+    server_socket.fail_next_receive();
+    let alice = network.bind(addr("203.0.113.7:40000")).unwrap();
+    let bob = network.bind(addr("198.51.100.3:41000")).unwrap();
+    let (alice_candidates, bob_candidates) = (host_candidates("192.168.1.10:55000"), host_candidates("10.0.0.5:55000"));
+
+    let server = tokio::spawn(async move { RendezvousServer::new().run(server_socket.as_ref()).await.map_err(|e| e.to_string()) });
+
+    let (alice_match, bob_match) = timeout(Duration::from_secs(10), async {
+        tokio::join!(
+            rendezvous::join(alice.as_ref(), addr("192.0.2.1:3478"), "room", &alice_candidates),
+            rendezvous::join(bob.as_ref(), addr("192.0.2.1:3478"), "room", &bob_candidates)
+        )
+    }).await.unwrap();
+
+    assert!(alice_match.is_ok() && bob_match.is_ok());
+    assert!(!server.is_finished());
+
+    server.abort();
+}
@@ -0,0 +1,26 @@
+#[cfg(test)]
+use crate::masp::serial;
+#[cfg(test)]
+use std::cmp::Ordering;
+
+#[test]
+fn test_serial_order_survives_wraparound() {
+    assert!(serial::is_before(1, 2));
+    assert!(serial::is_before(u32::MAX, 0));
+    assert!(serial::is_before(u32::MAX - 5, 3));
+    assert!(!serial::is_before(3, u32::MAX - 5));
+    assert_eq!(serial::compare(7, 7), Ordering::Equal);
+
+    let mut frame_ids = vec![2, u32::MAX, 0, u32::MAX - 1, 1];
+    frame_ids.sort_by(|a, b| serial::compare(*a, *b));
+
+    assert_eq!(frame_ids, vec![u32::MAX - 1, u32::MAX, 0, 1, 2]);
+}
+
+#[test]
+fn test_serial_range_may_wrap() {
+    assert!(serial::in_range(u32::MAX, u32::MAX - 1, 1));
+    assert!(serial::in_range(0, u32::MAX - 1, 1));
+    assert!(!serial::in_range(2, u32::MAX - 1, 1));
+    assert!(serial::in_range(5, 5, 5));
+}
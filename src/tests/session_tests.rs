@@ -0,0 +1,262 @@
+#[cfg(test)]
+use crate::commands::session::{Session, Video};
+#[cfg(test)]
+use crate::identity::Identity;
+#[cfg(test)]
+use crate::masp::config::{MaspConfig, DEFAULT_MAX_DATAGRAM_SIZE};
+#[cfg(test)]
+use crate::masp::crypto::{HandshakeRole, Passphrase};
+#[cfg(test)]
+use crate::masp::handshake::MAX_HANDSHAKE_ATTEMPTS;
+#[cfg(test)]
+use crate::masp::shutdown::{DisconnectReason, Shutdown};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use crate::video::ascii_frame::{ASCII_FRAME_HEIGHT, ASCII_FRAME_WIDTH};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
+use tokio::sync::mpsc;
+#[cfg(test)]
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+#[cfg(test)]
+const FRAME_COUNT: usize = 48;
+
+/// Frame that changes with its number, so a received frame tells which one was sent.
+#[cfg(test)]
+fn synthetic_frame(number: usize) -> String {
+    let palette = ['@', '#', '0', 'O', '*', ';', ':', '.', ',', '\'', ' '];
+
+    let rows = (1..ASCII_FRAME_HEIGHT).map(|row| {
+        (0..ASCII_FRAME_WIDTH)
+            .map(|column| palette[(number + row + column / 8) % palette.len()])
+            .collect::<String>()
+    });
+
+    // the first row carries the frame number, the pattern alone repeats
+    std::iter::once(format!("{:<width$}", number, width = ASCII_FRAME_WIDTH))
+        .chain(rows)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+fn config_dir(name: &str) -> PathBuf {
+    let config_dir = std::env::temp_dir().join(format!("mtrix-session-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&config_dir);
+
+    config_dir
+}
+
+#[cfg(test)]
+fn session(
+    network: &SimulatedNetwork,
+    role: HandshakeRole,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    video: Video
+) -> Session {
+    let name = format!("{:?}", role);
+
+    Session {
+        role,
+        transport: network.bind(local_addr).unwrap(),
+        remote_addr,
+        peer_host: remote_addr.ip(),
+        config: MaspConfig {
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            peer_timeout: Duration::from_secs(10),
+            forward_error_correction: true
+        },
+        identity: Identity::load(&config_dir(&name)).unwrap(),
+        passphrase: None,
+        video
+    }
+}
+
+/// Session of `role` whose video goes over channels, along with the ends of
+/// them the test feeds and reads.
+#[cfg(test)]
+fn channel_session(
+    network: &SimulatedNetwork,
+    role: HandshakeRole,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr
+) -> (Session, mpsc::Sender<String>, mpsc::UnboundedReceiver<String>) {
+    let (frames, outgoing) = mpsc::channel(FRAME_COUNT);
+    let (incoming, received_frames) = mpsc::unbounded_channel();
+
+    (session(network, role, local_addr, remote_addr, Video::Channels { outgoing, incoming }), frames, received_frames)
+}
+
+// time is paused: it only moves on when every task waits on a timer, so the
+// simulated delays and the session's timeouts don't depend on the machine's load
+#[tokio::test(start_paused = true)]
+async fn test_session_streams_frames_over_lossy_network() {
+    let initiator_addr: SocketAddr = "10.0.0.1:55000".parse().unwrap();
+    let responder_addr: SocketAddr = "10.0.0.2:55000".parse().unwrap();
+    let conditions = LinkConditions {
+        delay: Duration::from_millis(10),
+        jitter: Duration::from_millis(5),
+        duplication: 0.02,
+        reordering: 0.05,
+        ..LinkConditions::default()
+    };
+    let network = SimulatedNetwork::new(conditions, 19);
+
+    let (frames, outgoing) = mpsc::channel(FRAME_COUNT);
+    let (incoming, mut received_frames) = mpsc::unbounded_channel();
+    let (_idle_frames, idle_outgoing) = mpsc::channel(1);
+    let (idle_incoming, _ignored_frames) = mpsc::unbounded_channel();
+
+    let initiator = session(
+        &network,
+        HandshakeRole::Initiator,
+        initiator_addr,
+        responder_addr,
+        Video::Channels { outgoing, incoming: idle_incoming }
+    );
+    let responder = session(
+        &network,
+        HandshakeRole::Responder,
+        responder_addr,
+        initiator_addr,
+        Video::Channels { outgoing: idle_outgoing, incoming }
+    );
+
+    let initiator_shutdown = Shutdown::new();
+    let sent_frames: Vec<String> = (0..FRAME_COUNT).map(synthetic_frame).collect();
+
+    let stream = async {
+        // packets get lost only once the handshake is through
+        let first_frame = received_frames.recv().await.unwrap();
+        network.set_conditions(LinkConditions { loss: 0.05, ..conditions });
+
+        let mut received = vec![first_frame];
+
+        while let Ok(Some(frame)) = timeout(Duration::from_secs(2), received_frames.recv()).await {
+            received.push(frame);
+        }
+
+        initiator_shutdown.trigger(DisconnectReason::LocalHangup);
+
+        received
+    };
+
+    let feed = async {
+        for frame in &sent_frames {
+            frames.send(frame.clone()).await.unwrap();
+            sleep(Duration::from_millis(40)).await;
+        }
+    };
+
+    let (initiator_reason, responder_reason, received, _) = timeout(
+        Duration::from_secs(60),
+        async { tokio::join!(initiator.run(initiator_shutdown.clone()), responder.run(Shutdown::new()), stream, feed) }
+    ).await.unwrap();
+
+    assert_eq!(initiator_reason.unwrap(), DisconnectReason::LocalHangup);
+    assert_eq!(responder_reason.unwrap(), DisconnectReason::RemoteHangup);
+
+    // latest-only video may skip frames, but never delivers one twice, out of order or mangled
+    let positions: Vec<usize> = received
+        .iter()
+        .map(|frame| sent_frames.iter().position(|sent| sent == frame).expect("received frame was never sent"))
+        .collect();
+
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(positions.len() > FRAME_COUNT / 2);
+}
+
+#[tokio::test]
+async fn test_wrong_secret_is_rejected_rather_than_timing_out() {
+    let initiator_addr: SocketAddr = "10.0.0.1:55000".parse().unwrap();
+    let responder_addr: SocketAddr = "10.0.0.2:55000".parse().unwrap();
+    let network = SimulatedNetwork::new(LinkConditions::default(), 9);
+
+    let (_idle_frames, outgoing) = mpsc::channel(1);
+    let (incoming, _ignored_frames) = mpsc::unbounded_channel();
+    let mut initiator = session(&network, HandshakeRole::Initiator, initiator_addr, responder_addr, Video::Channels { outgoing, incoming });
+    initiator.passphrase = Some(Passphrase::new("battery staple"));
+
+    let (_idle_frames, outgoing) = mpsc::channel(1);
+    let (incoming, _ignored_frames) = mpsc::unbounded_channel();
+    let mut responder = session(&network, HandshakeRole::Responder, responder_addr, initiator_addr, Video::Channels { outgoing, incoming });
+    responder.passphrase = Some(Passphrase::new("correct horse"));
+
+    // well within the first attempt's timeout, the responder answers a bad proof right away
+    let result = timeout(Duration::from_secs(2), async {
+        tokio::select! {
+            result = initiator.run(Shutdown::new()) => result.map_err(|e| e.to_string()),
+            _ = responder.run(Shutdown::new()) => unreachable!("responder gave up waiting")
+        }
+    }).await.expect("initiator timed out instead of being rejected");
+
+    assert!(result.unwrap_err().contains("Peer rejected our secret"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_is_retried_when_the_first_request_is_lost() {
+    let initiator_addr: SocketAddr = "10.0.0.1:55000".parse().unwrap();
+    let responder_addr: SocketAddr = "10.0.0.2:55000".parse().unwrap();
+    let network = SimulatedNetwork::new(LinkConditions { loss: 1.0, ..LinkConditions::default() }, 4);
+
+    // like jackwait and jackin, the responder waits and the initiator sends its requests
+    let (initiator, frames, _ignored_frames) = channel_session(&network, HandshakeRole::Initiator, initiator_addr, responder_addr);
+    let (responder, _idle_frames, mut received_frames) = channel_session(&network, HandshakeRole::Responder, responder_addr, initiator_addr);
+
+    let initiator_shutdown = Shutdown::new();
+    let started_at = Instant::now();
+
+    let connect = async {
+        // the first request goes nowhere, the link only comes up before the next
+        sleep(Duration::from_secs(1)).await;
+        network.set_conditions(LinkConditions::default());
+
+        let feed = async {
+            for number in 0.. {
+                let _ = frames.try_send(synthetic_frame(number));
+                sleep(Duration::from_millis(40)).await;
+            }
+        };
+
+        tokio::select! {
+            frame = received_frames.recv() => assert!(frame.is_some()),
+            _ = feed => unreachable!()
+        }
+
+        initiator_shutdown.trigger(DisconnectReason::LocalHangup);
+
+        started_at.elapsed()
+    };
+
+    let (initiator_reason, responder_reason, connected_after) = timeout(
+        Duration::from_secs(30),
+        async { tokio::join!(initiator.run(initiator_shutdown.clone()), responder.run(Shutdown::new()), connect) }
+    ).await.unwrap();
+
+    assert_eq!(initiator_reason.unwrap(), DisconnectReason::LocalHangup);
+    assert_eq!(responder_reason.unwrap(), DisconnectReason::RemoteHangup);
+
+    // only the second attempt got through, once the first one timed out
+    assert!(connected_after >= Duration::from_secs(3));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_gives_up_when_nobody_answers() {
+    let initiator_addr: SocketAddr = "10.0.0.1:55000".parse().unwrap();
+    let network = SimulatedNetwork::new(LinkConditions::default(), 5);
+
+    // jackin towards an address where nobody waits
+    let (initiator, _frames, _ignored_frames) = channel_session(&network, HandshakeRole::Initiator, initiator_addr, "10.0.0.2:55000".parse().unwrap());
+    let started_at = Instant::now();
+
+    let result = timeout(Duration::from_secs(60), initiator.run(Shutdown::new())).await.unwrap();
+
+    assert!(result.unwrap_err().to_string().contains(&format!("Handshake failed after {} attempts", MAX_HANDSHAKE_ATTEMPTS)));
+    assert!(started_at.elapsed() >= Duration::from_secs(3 * MAX_HANDSHAKE_ATTEMPTS as u64));
+}
@@ -0,0 +1,55 @@
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use crate::transport::Transport;
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::time::{timeout, Duration};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_simulated_sockets_exchange_datagrams() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 1);
+    let alice = network.bind(addr("10.0.0.1:5000")).unwrap();
+    let bob = network.bind(addr("10.0.0.2:5000")).unwrap();
+
+    assert!(network.bind(addr("10.0.0.1:5000")).is_err());
+
+    alice.send_to(b"hello", addr("10.0.0.2:5000")).await.unwrap();
+
+    // like UDP, what doesn't fit the buffer is cut off
+    let mut buf = [0u8; 4];
+    let (len, from) = timeout(Duration::from_secs(1), bob.recv_from(&mut buf)).await.unwrap().unwrap();
+
+    assert_eq!(&buf[..len], b"hell");
+    assert_eq!(from, addr("10.0.0.1:5000"));
+
+    // the address is free again once its socket is gone
+    drop(bob);
+    assert!(network.bind(addr("10.0.0.2:5000")).is_ok());
+}
+
+#[tokio::test]
+async fn test_link_conditions_apply_to_every_datagram() {
+    let network = SimulatedNetwork::new(LinkConditions { duplication: 1.0, ..LinkConditions::default() }, 1);
+    let alice = network.bind(addr("10.0.0.1:5000")).unwrap();
+    let bob = network.bind(addr("10.0.0.2:5000")).unwrap();
+    let mut buf = [0u8; 16];
+
+    alice.send_to(b"twice", addr("10.0.0.2:5000")).await.unwrap();
+
+    for _ in 0..2 {
+        let (len, _) = timeout(Duration::from_secs(1), bob.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"twice");
+    }
+
+    network.set_conditions(LinkConditions { loss: 1.0, ..LinkConditions::default() });
+    alice.send_to(b"lost", addr("10.0.0.2:5000")).await.unwrap();
+
+    assert!(timeout(Duration::from_millis(100), bob.recv_from(&mut buf)).await.is_err());
+}
@@ -0,0 +1,337 @@
+#[cfg(test)]
+use crate::masp::capabilities::Capabilities;
+#[cfg(test)]
+use crate::masp::config::MaspConfig;
+#[cfg(test)]
+use crate::masp::crypto::{HandshakeRole, KeyPair, SessionKeys};
+#[cfg(test)]
+use crate::masp::fec;
+#[cfg(test)]
+use crate::masp::message::{MaspPacket, PacketType};
+#[cfg(test)]
+use crate::masp::payload::{Ack, RecoveryReport, RetransmissionRequest, Timestamp};
+#[cfg(test)]
+use crate::masp::receiver::MaspReceiver;
+#[cfg(test)]
+use crate::masp::reliability::DeliveryClass;
+#[cfg(test)]
+use crate::masp::sender::MaspSender;
+#[cfg(test)]
+use crate::masp::shutdown::{DisconnectReason, Shutdown};
+#[cfg(test)]
+use crate::masp::socket::{Inbox, MaspSocket};
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::sync::mpsc;
+#[cfg(test)]
+use tokio::time::{sleep, timeout, Duration};
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// Two sockets that went through the handshake, as the sides of a session
+/// would, along with the keys of the initiator.
+#[cfg(test)]
+fn connected_sockets(
+    network: &SimulatedNetwork,
+    initiator_addr: SocketAddr,
+    responder_addr: SocketAddr
+) -> (MaspSocket, MaspSocket, SessionKeys) {
+    let (initiator_identity, responder_identity) = (KeyPair::generate(), KeyPair::generate());
+    let (initiator, responder) = (KeyPair::generate(), KeyPair::generate());
+
+    let initiator_keys = initiator
+        .derive_session_keys(HandshakeRole::Initiator, &initiator_identity, &responder.public, &responder_identity.public, &[])
+        .unwrap();
+    let responder_keys = responder
+        .derive_session_keys(HandshakeRole::Responder, &responder_identity, &initiator.public, &initiator_identity.public, &[])
+        .unwrap();
+
+    let initiator_socket = MaspSocket::new(network.bind(initiator_addr).unwrap(), responder_addr);
+    let responder_socket = MaspSocket::new(network.bind(responder_addr).unwrap(), initiator_addr);
+
+    initiator_socket.set_remote_connection_id(responder_socket.local_connection_id());
+    responder_socket.set_remote_connection_id(initiator_socket.local_connection_id());
+    initiator_socket.set_session_keys(&initiator_keys);
+    responder_socket.set_session_keys(&responder_keys);
+
+    (initiator_socket, responder_socket, initiator_keys)
+}
+
+#[cfg(test)]
+async fn next_packet(inbox: &mut Inbox, packet_type: PacketType, wait: Duration) -> Option<MaspPacket> {
+    timeout(wait, async {
+        loop {
+            let (packet, _) = inbox.recv().await?;
+
+            if packet.packet_type == packet_type {
+                return Some(packet);
+            }
+        }
+    }).await.ok().flatten()
+}
+
+#[cfg(test)]
+async fn next_ack(inbox: &mut Inbox) -> Option<Ack> {
+    let packet = next_packet(inbox, PacketType::Ack, Duration::from_secs(1)).await?;
+
+    Ack::deserialize(&packet.payload).ok()
+}
+
+#[tokio::test]
+async fn test_retransmit_is_acknowledged_again_when_the_ack_was_lost() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 8);
+    let shutdown = Shutdown::new();
+    let (sender_socket, receiver_socket, _) = connected_sockets(&network, addr("10.0.0.1:55000"), addr("10.0.0.2:55000"));
+
+    let (demultiplexer, mut acks, _, _) = sender_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let (demultiplexer, _, receiver_inbox, _) = receiver_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let mut receiver = MaspReceiver::new(receiver_socket, receiver_inbox, shutdown.clone());
+    tokio::spawn(async move { receiver.start_receiving().await.map_err(|e| e.to_string()) });
+
+    let packet = MaspPacket::new(PacketType::TextData, 1, b"hello".to_vec());
+    sender_socket.send(&packet).await.unwrap();
+
+    // the first ack is lost on the way back, the sender never sees it
+    let lost_ack = next_ack(&mut acks).await.unwrap();
+    assert_eq!((lost_ack.delivery_class, lost_ack.cumulative), (DeliveryClass::ReliableOrdered, 1));
+
+    // so it resends under the same sequence number, which has to be acknowledged again
+    sender_socket.send(&packet).await.unwrap();
+
+    let ack = next_ack(&mut acks).await.expect("retransmit was never acknowledged");
+    assert_eq!((ack.delivery_class, ack.cumulative), (DeliveryClass::ReliableOrdered, 1));
+
+    shutdown.trigger(DisconnectReason::LocalHangup);
+}
+
+#[tokio::test]
+async fn test_peer_address_moves_only_once_the_new_path_answers() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 13);
+    let shutdown = Shutdown::new();
+    let (initiator_addr, moved_addr) = (addr("10.0.0.1:55000"), addr("10.0.0.3:41000"));
+    let (initiator_socket, responder_socket, initiator_keys) = connected_sockets(&network, initiator_addr, addr("10.0.0.2:55000"));
+
+    // stands in for the initiator behind its rebound NAT, so it gets what the
+    // responder sends. The initiator's own socket only sends from here on
+    let moved_socket = MaspSocket::new(network.bind(moved_addr).unwrap(), addr("10.0.0.2:55000"));
+    moved_socket.set_remote_connection_id(responder_socket.local_connection_id());
+    moved_socket.set_session_keys(&initiator_keys);
+    responder_socket.set_remote_connection_id(moved_socket.local_connection_id());
+
+    let (demultiplexer, _, _, _) = responder_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let (demultiplexer, mut moved_inbox, _, _) = moved_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    for sequence_number in [1, 5] {
+        initiator_socket.send(&MaspPacket::new(PacketType::TextData, sequence_number, b"data".to_vec())).await.unwrap();
+    }
+
+    // an old packet showing up from elsewhere, reordered or re-injected, isn't even challenged
+    moved_socket.send(&MaspPacket::new(PacketType::TextData, 3, b"data".to_vec())).await.unwrap();
+
+    assert!(next_packet(&mut moved_inbox, PacketType::Ping, Duration::from_millis(200)).await.is_none());
+    assert_eq!(responder_socket.remote_addr(), initiator_addr);
+
+    // the newest one is, but the session stays put until the challenge is answered
+    moved_socket.send(&MaspPacket::new(PacketType::TextData, 6, b"data".to_vec())).await.unwrap();
+
+    let challenge = next_packet(&mut moved_inbox, PacketType::Ping, Duration::from_secs(1)).await.unwrap();
+    assert_eq!(responder_socket.remote_addr(), initiator_addr);
+
+    // a pong with another value, e.g. one answering a keepalive, proves nothing
+    let wrong_answer = Timestamp { micros: Timestamp::deserialize(&challenge.payload).unwrap().micros.wrapping_add(1) };
+    moved_socket.send(&MaspPacket::new(PacketType::Pong, 1, wrong_answer.serialize())).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(responder_socket.remote_addr(), initiator_addr);
+
+    moved_socket.send(&MaspPacket::new(PacketType::Pong, 2, challenge.payload)).await.unwrap();
+
+    timeout(Duration::from_secs(1), async {
+        while responder_socket.remote_addr() != moved_addr {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("session never moved to the answering address");
+
+    shutdown.trigger(DisconnectReason::LocalHangup);
+}
+
+#[tokio::test]
+async fn test_sender_waits_for_room_in_the_congestion_window() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 5);
+    let shutdown = Shutdown::new();
+    let (sender_socket, receiver_socket, _) = connected_sockets(&network, addr("10.0.0.1:55000"), addr("10.0.0.2:55000"));
+
+    let (demultiplexer, sender_inbox, _, _) = sender_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    // the receiver side stays silent, nothing gets acknowledged
+    let (demultiplexer, _, mut receiver_inbox, _) = receiver_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let mut sender = MaspSender::new(sender_socket, sender_inbox, MaspConfig::default(), shutdown.clone());
+    let acks = sender.clone();
+    tokio::spawn(async move { acks.handle_acknowledgments().await.map_err(|e| e.to_string()) });
+
+    let mut sent = 0;
+
+    while timeout(Duration::from_millis(500), sender.send_data(PacketType::TextData, vec![0; 1000])).await.is_ok() {
+        sent += 1;
+        assert!(sent <= 20, "sender never stopped for the congestion window");
+    }
+
+    assert!(sent >= 4);
+
+    // the stalled send goes out once an ack makes room
+    let stalled = tokio::spawn(async move {
+        sender.send_data(PacketType::TextData, vec![0; 1000]).await.map_err(|e| e.to_string())
+    });
+
+    let mut received = 0;
+
+    while next_packet(&mut receiver_inbox, PacketType::TextData, Duration::from_millis(100)).await.is_some() {
+        received += 1;
+    }
+
+    assert_eq!(received, sent);
+
+    let ack = Ack { delivery_class: DeliveryClass::ReliableOrdered, cumulative: sent, bitmap: 0 };
+    receiver_socket.send(&MaspPacket::new(PacketType::Ack, 1, ack.serialize())).await.unwrap();
+
+    timeout(Duration::from_secs(1), stalled).await.expect("send stayed stalled after the ack").unwrap().unwrap();
+    assert!(next_packet(&mut receiver_inbox, PacketType::TextData, Duration::from_secs(1)).await.is_some());
+
+    shutdown.trigger(DisconnectReason::LocalHangup);
+}
+
+#[tokio::test]
+async fn test_lost_parity_stays_out_of_the_loss_fec_adapts_to() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 15);
+    let shutdown = Shutdown::new();
+    let (sender_socket, receiver_socket, _) = connected_sockets(&network, addr("10.0.0.1:55000"), addr("10.0.0.2:55000"));
+
+    let (demultiplexer, sender_inbox, _, _) = sender_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let (demultiplexer, _, mut receiver_inbox, _) = receiver_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let config = MaspConfig::default();
+    let capabilities = Capabilities::local(&config);
+    let mut sender = MaspSender::new(sender_socket, sender_inbox, config, shutdown.clone());
+    sender.set_session_capabilities(&capabilities.negotiate(&capabilities).unwrap()).await;
+
+    let acks = sender.clone();
+    tokio::spawn(async move { acks.handle_acknowledgments().await.map_err(|e| e.to_string()) });
+
+    // every parity packet goes missing, none of the video does
+    let receiver = tokio::spawn(async move {
+        let mut parities_per_frame = [0usize; 21];
+        let mut control_sequence_number = 0;
+
+        while let Ok(Some((packet, _))) = timeout(Duration::from_millis(500), receiver_inbox.recv()).await {
+            let mut answers = Vec::new();
+
+            if packet.packet_type == PacketType::VideoParity {
+                parities_per_frame[packet.frame_id as usize] += 1;
+
+                let ranges = vec![(packet.sequence_number, packet.sequence_number)];
+                answers.push((PacketType::RetransmissionRequest, RetransmissionRequest { delivery_class: DeliveryClass::LatestOnly, ranges }.serialize()));
+            }
+
+            let ack = Ack { delivery_class: DeliveryClass::LatestOnly, cumulative: packet.sequence_number, bitmap: 0 };
+            answers.push((PacketType::Ack, ack.serialize()));
+
+            for (packet_type, payload) in answers {
+                control_sequence_number += 1;
+                receiver_socket.send(&MaspPacket::new(packet_type, control_sequence_number, payload)).await.unwrap();
+            }
+        }
+
+        parities_per_frame
+    });
+
+    for _ in 0..20 {
+        timeout(Duration::from_secs(1), sender.send_data(PacketType::VideoData, vec![0; 4000]))
+            .await
+            .expect("sender stalled")
+            .unwrap();
+        sleep(Duration::from_millis(20)).await;
+    }
+
+    let parities_per_frame = receiver.await.unwrap();
+
+    // protected from the start, then dropped once the video itself turned out never to go missing
+    assert!(parities_per_frame[1] > 0);
+    assert_eq!(parities_per_frame[20], 0);
+
+    shutdown.trigger(DisconnectReason::LocalHangup);
+}
+
+#[tokio::test]
+async fn test_loss_parity_rebuilds_is_reported_rather_than_requested() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 16);
+    let shutdown = Shutdown::new();
+    let (sender_socket, receiver_socket, _) = connected_sockets(&network, addr("10.0.0.1:55000"), addr("10.0.0.2:55000"));
+
+    let (demultiplexer, mut sender_inbox, _, _) = sender_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let (demultiplexer, _, receiver_inbox, _) = receiver_socket.demultiplexer(shutdown.clone());
+    tokio::spawn(demultiplexer.run());
+
+    let capabilities = Capabilities::local(&MaspConfig::default());
+    let (frame_sink, _frames) = mpsc::unbounded_channel();
+    let mut receiver = MaspReceiver::new(receiver_socket, receiver_inbox, shutdown.clone());
+    receiver.set_session_capabilities(&capabilities.negotiate(&capabilities).unwrap()).await;
+    receiver.forward_frames(frame_sink);
+    tokio::spawn(async move { receiver.start_receiving().await.map_err(|e| e.to_string()) });
+
+    // one group of four fragments and its parity, the second fragment gets lost
+    let fragments: Vec<Vec<u8>> = (0..4u8).map(|index| vec![index; 100]).collect();
+    let parity = fec::encode_group(&fragments, 0, 1, 1).remove(0);
+
+    for (index, fragment) in fragments.iter().enumerate().filter(|(index, _)| *index != 1) {
+        let packet = MaspPacket::new_fragment(PacketType::VideoData, index as u32 + 1, 1, index as u16, 4, fragment.clone());
+        sender_socket.send(&packet).await.unwrap();
+    }
+
+    sender_socket.send(&MaspPacket::new_fragment(PacketType::VideoParity, 5, 1, 0, 4, parity.serialize())).await.unwrap();
+
+    // a gap parity can't fill is still requested
+    for sequence_number in [6, 8] {
+        let packet = MaspPacket::new_fragment(PacketType::VideoData, sequence_number, 2, sequence_number as u16 - 6, 3, vec![0; 100]);
+        sender_socket.send(&packet).await.unwrap();
+    }
+
+    let mut requested = Vec::new();
+    let mut recovered = 0;
+
+    while let Ok(Some((packet, _))) = timeout(Duration::from_millis(300), sender_inbox.recv()).await {
+        match packet.packet_type {
+            PacketType::RetransmissionRequest => requested.extend(RetransmissionRequest::deserialize(&packet.payload).unwrap().ranges),
+            PacketType::RecoveryReport => recovered += RecoveryReport::deserialize(&packet.payload).unwrap().recovered,
+            _ => {}
+        }
+    }
+
+    // requested again each NACK interval until it shows up
+    requested.dedup();
+
+    assert_eq!(recovered, 1);
+    assert_eq!(requested, [(7, 7)]);
+
+    shutdown.trigger(DisconnectReason::LocalHangup);
+}
@@ -0,0 +1,123 @@
+#[cfg(test)]
+use crate::nat::{self, Behavior};
+#[cfg(test)]
+use crate::stun::{self, Credentials, MessageClass, StunAttribute, StunConfig, StunMessage, StunServer};
+#[cfg(test)]
+use crate::transport;
+#[cfg(test)]
+use crate::transport::simulator::{LinkConditions, SimulatedNetwork};
+#[cfg(test)]
+use std::net::SocketAddr;
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_whoami_against_own_stun_server_on_loopback() {
+    // two ports on two loopback IPs, the whole 127.0.0.0/8 is local on Linux
+    let primary = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let other_port = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let (port, other) = (primary.local_addr().unwrap().port(), other_port.local_addr().unwrap().port());
+
+    // but only 127.0.0.1 out of the box on macOS and the BSDs
+    let Ok(other_ip) = transport::bind_udp(SocketAddr::new(addr("127.0.0.2:0").ip(), port)).await else {
+        eprintln!("skipping, 127.0.0.2 isn't a local address here");
+        return;
+    };
+    let other_ip_and_port = transport::bind_udp(SocketAddr::new(addr("127.0.0.2:0").ip(), other)).await.unwrap();
+
+    let server = StunServer::with_behavior_discovery([primary, other_port, other_ip, other_ip_and_port]);
+    let server = tokio::spawn(async move { server.run().await.map_err(|e| e.to_string()) });
+
+    let client = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let stun = StunConfig { server: SocketAddr::new(addr("127.0.0.1:0").ip(), port), credentials: None };
+
+    let reflexive_addr = stun::query_reflexive_addr(client.as_ref(), &stun, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(reflexive_addr, client.local_addr().unwrap());
+
+    let report = nat::discover(client.as_ref(), &stun, &[]).await.unwrap();
+
+    assert!(!report.behind_nat);
+    assert_eq!(report.mapping, Some(Behavior::EndpointIndependent));
+    assert_eq!(report.filtering, Some(Behavior::EndpointIndependent));
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_stun_server_refuses_what_it_doesnt_understand() {
+    let socket = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let server = StunServer::new(socket);
+    let from = addr("203.0.113.7:40000");
+
+    let (response, send_on) = server.handle(&StunMessage::new().to_bytes(), from, 0).unwrap();
+    let response = StunMessage::from_bytes(&response.to_bytes()).unwrap();
+
+    assert_eq!(send_on, 0);
+    assert_eq!(response.reflexive_addr().unwrap(), from);
+    assert!(response.attributes.contains(&StunAttribute::Fingerprint));
+
+    // without a second IP and port, CHANGE-REQUEST is an attribute it can't honour
+    let mut request = StunMessage::new();
+    request.attributes.push(StunAttribute::ChangeRequest { change_ip: true, change_port: true });
+
+    let (response, _) = server.handle(&request.to_bytes(), from, 0).unwrap();
+
+    assert_eq!(response.class(), MessageClass::ErrorResponse);
+    assert!(response.attributes.contains(&StunAttribute::ErrorCode { code: 420, reason: "Unknown Attribute".to_string() }));
+
+    // responses and indications aren't answered
+    assert!(server.handle(&response.to_bytes(), from, 0).is_none());
+}
+
+#[tokio::test]
+async fn test_stun_server_with_credentials_only_answers_authenticated_requests() {
+    let socket = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let credentials: Credentials = "mtrix:s3cret".parse().unwrap();
+
+    let server = StunServer::new(socket).with_credentials(credentials.clone());
+    let server = tokio::spawn(async move { server.run().await.map_err(|e| e.to_string()) });
+
+    let client = transport::bind_udp(addr("127.0.0.1:0")).await.unwrap();
+    let query = |credentials: Option<Credentials>| {
+        let client = client.clone();
+
+        async move {
+            let stun = StunConfig { server: server_addr, credentials };
+
+            stun::query_reflexive_addr(client.as_ref(), &stun, Duration::from_secs(5)).await.map_err(|e| e.to_string())
+        }
+    };
+
+    assert!(query(None).await.unwrap_err().contains("400"));
+    assert!(query(Some("mtrix:guess".parse().unwrap())).await.unwrap_err().contains("401"));
+    assert_eq!(query(Some(credentials)).await.unwrap(), client.local_addr().unwrap());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_stun_server_keeps_serving_after_a_failed_receive() {
+    let network = SimulatedNetwork::new(LinkConditions::default(), 25);
+    let server_socket = network.bind(addr("192.0.2.1:3478")).unwrap();
+    let client = network.bind(addr("203.0.113.7:40000")).unwrap();
+
+    // like the ICMP error of a client that went away, surfacing as a reset on Windows
+    server_socket.fail_next_receive();
+
+    let server = StunServer::new(server_socket);
+    let server = tokio::spawn(async move { server.run().await.map_err(|e| e.to_string()) });
+    let stun = StunConfig { server: addr("192.0.2.1:3478"), credentials: None };
+
+    let reflexive_addr = stun::query_reflexive_addr(client.as_ref(), &stun, Duration::from_secs(5)).await.unwrap();
+
+    assert_eq!(reflexive_addr, addr("203.0.113.7:40000"));
+    assert!(!server.is_finished());
+
+    server.abort();
+}
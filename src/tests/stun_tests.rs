@@ -0,0 +1,147 @@
+#[cfg(test)]
+use crate::stun::{self, Credentials, MessageClass, StunAttribute, StunMessage, BINDING_ERROR_RESPONSE, BINDING_REQUEST, BINDING_SUCCESS_RESPONSE};
+#[cfg(test)]
+use hmac::{Hmac, Mac};
+#[cfg(test)]
+use proptest::prelude::*;
+#[cfg(test)]
+use sha1::Sha1;
+#[cfg(test)]
+use std::net::{IpAddr, SocketAddr};
+
+#[test]
+fn test_attributes_are_padded() {
+    let mut message = StunMessage::new();
+    message.attributes = vec![
+        StunAttribute::Software("mtrix".to_string()),
+        StunAttribute::XorMappedAddress("203.0.113.7:4000".parse().unwrap())
+    ];
+
+    let bytes = message.to_bytes();
+
+    // 5 byte value padded to 8, then an 8 byte IPv4 address
+    assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), 4 + 8 + 4 + 8);
+    assert_eq!(StunMessage::from_bytes(&bytes).unwrap(), message);
+
+    // a message claiming more attributes than it carries
+    assert!(StunMessage::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+    assert!(StunMessage::from_bytes(&[]).is_err());
+}
+
+#[test]
+fn test_integrity_and_fingerprint_protect_the_message() {
+    let mut message = StunMessage::new();
+    message.attributes.push(StunAttribute::Software("mtrix".to_string()));
+    message.sign("s3cret");
+    message.attributes.push(StunAttribute::Fingerprint);
+
+    let mut bytes = message.to_bytes().to_vec();
+
+    assert_eq!(StunMessage::from_bytes(&bytes).unwrap(), message);
+    assert!(StunMessage::verify_integrity(&bytes, "s3cret").is_ok());
+    assert!(StunMessage::verify_integrity(&bytes, "guess").is_err());
+
+    // any changed bit breaks the fingerprint
+    bytes[20 + 4] ^= 0x01;
+    assert!(StunMessage::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn test_integrity_covers_the_bytes_as_received() {
+    // SOFTWARE "abc" with garbage in its padding, which a re-encoded message would zero
+    let mut bytes = StunMessage::new().to_bytes().to_vec();
+    bytes.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'a', b'b', b'c', 0xff]);
+    bytes[2..4].copy_from_slice(&(8u16 + 4 + 20).to_be_bytes());
+
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(b"s3cret").unwrap();
+    mac.update(&bytes);
+    bytes.extend_from_slice(&[0x00, 0x08, 0x00, 0x14]);
+    bytes.extend_from_slice(&mac.finalize().into_bytes());
+
+    assert!(StunMessage::verify_integrity(&bytes, "s3cret").is_ok());
+
+    assert!(StunMessage::verify_integrity(&bytes, "guess").is_err());
+}
+
+#[test]
+fn test_authenticated_request_carries_the_username() {
+    let credentials: Credentials = "mtrix:pass:word".parse().unwrap();
+    assert_eq!((credentials.username.as_str(), credentials.password.as_str()), ("mtrix", "pass:word"));
+    assert!("nopassword".parse::<Credentials>().is_err());
+
+    let mut request = StunMessage::new();
+    request.authenticate(&credentials);
+
+    let bytes = request.to_bytes();
+    let parsed = StunMessage::from_bytes(&bytes).unwrap();
+
+    assert_eq!(parsed.attributes[0], StunAttribute::Username("mtrix".to_string()));
+    assert!(StunMessage::verify_integrity(&bytes, "pass:word").is_ok());
+}
+
+#[test]
+fn test_responses_are_told_apart_by_class() {
+    let mut success = StunMessage { message_type: BINDING_SUCCESS_RESPONSE, ..StunMessage::new() };
+    success.attributes = vec![
+        StunAttribute::MappedAddress("198.51.100.3:41000".parse().unwrap()),
+        StunAttribute::XorMappedAddress("203.0.113.7:40000".parse().unwrap())
+    ];
+
+    assert_eq!(success.class(), MessageClass::SuccessResponse);
+    assert_eq!(success.reflexive_addr().unwrap(), "203.0.113.7:40000".parse().unwrap());
+
+    let mut error = StunMessage { message_type: BINDING_ERROR_RESPONSE, ..StunMessage::new() };
+    error.attributes = vec![StunAttribute::ErrorCode { code: 420, reason: "Unknown Attribute".to_string() }];
+
+    let error = StunMessage::from_bytes(&error.to_bytes()).unwrap();
+
+    assert_eq!(error.class(), MessageClass::ErrorResponse);
+    assert!(error.reflexive_addr().unwrap_err().to_string().contains("420 Unknown Attribute"));
+    assert_eq!(StunMessage::new().class(), MessageClass::Request);
+}
+
+#[cfg(test)]
+fn attributes() -> impl Strategy<Value = StunAttribute> {
+    prop_oneof![
+        (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| StunAttribute::XorMappedAddress(SocketAddr::new(ip, port))),
+        (any::<bool>(), any::<bool>()).prop_map(|(change_ip, change_port)| StunAttribute::ChangeRequest { change_ip, change_port }),
+        (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| StunAttribute::OtherAddress(SocketAddr::new(ip, port))),
+        (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| StunAttribute::MappedAddress(SocketAddr::new(ip, port))),
+        (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| StunAttribute::ResponseOrigin(SocketAddr::new(ip, port))),
+        (300..700u16, "[ -~]{0,40}").prop_map(|(code, reason)| StunAttribute::ErrorCode { code, reason }),
+        "\\PC{0,40}".prop_map(StunAttribute::Software),
+        "\\PC{0,40}".prop_map(StunAttribute::Username),
+        any::<[u8; 20]>().prop_map(StunAttribute::MessageIntegrity),
+        (
+            any::<u16>().prop_filter("known attribute", |attr_type| ![
+                stun::MAPPED_ADDRESS,
+                stun::USERNAME,
+                stun::CHANGE_REQUEST,
+                stun::MESSAGE_INTEGRITY,
+                stun::ERROR_CODE,
+                stun::XOR_MAPPED_ADDRESS,
+                stun::SOFTWARE,
+                stun::FINGERPRINT,
+                stun::RESPONSE_ORIGIN,
+                stun::OTHER_ADDRESS
+            ].contains(attr_type)),
+            prop::collection::vec(any::<u8>(), 0..64)
+        )
+            .prop_map(|(attr_type, value)| StunAttribute::Unknown(attr_type, value))
+    ]
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn prop_stun_roundtrip(transaction_id in any::<[u8; 12]>(), attributes in prop::collection::vec(attributes(), 0..8)) {
+        let message = StunMessage { message_type: BINDING_REQUEST, transaction_id, attributes };
+
+        prop_assert_eq!(StunMessage::from_bytes(&message.to_bytes()).unwrap(), message);
+    }
+
+    #[test]
+    fn prop_stun_parser_never_panics(buffer in prop::collection::vec(any::<u8>(), 0..128)) {
+        let _ = StunMessage::from_bytes(&buffer);
+    }
+}
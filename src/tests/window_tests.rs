@@ -0,0 +1,135 @@
+#[cfg(test)]
+use crate::masp::window::ReceiveWindow;
+#[cfg(test)]
+use crate::masp::payload::RetransmissionRequest;
+#[cfg(test)]
+use crate::masp::reliability::{DeliveryClass, FIRST_SEQUENCE_NUMBER};
+#[cfg(test)]
+use tokio::time::Duration;
+
+#[test]
+fn test_gaps_are_requested_as_ranges() {
+    let mut window = ReceiveWindow::new(Duration::from_secs(1), Some(Duration::from_secs(5)), 1);
+
+    for sequence_number in [1, 2, 5, 6, 9] {
+        window.record(sequence_number);
+    }
+
+    assert_eq!(window.take_nack_ranges(), vec![(3, 4), (7, 8)]);
+    // already requested within the interval
+    assert_eq!(window.take_nack_ranges(), vec![]);
+
+    window.record(7);
+    window.record(10);
+
+    assert_eq!(window.take_nack_ranges(), vec![]);
+}
+
+#[test]
+fn test_sequence_ranges_roundtrip() {
+    let request = RetransmissionRequest {
+        delivery_class: DeliveryClass::ReliableOrdered,
+        ranges: vec![(3, 4), (7, 8), (u32::MAX, 0)]
+    };
+    let payload = request.serialize();
+
+    assert_eq!(RetransmissionRequest::deserialize(&payload).unwrap(), request);
+    assert!(RetransmissionRequest::deserialize(&payload[1..]).is_err());
+}
+
+#[test]
+fn test_cumulative_ack_with_selective_bitmap() {
+    let mut window = ReceiveWindow::new(Duration::from_secs(1), Some(Duration::from_secs(5)), 10);
+
+    for sequence_number in [10, 11, 13, 15] {
+        window.record(sequence_number);
+    }
+
+    // 12 is the first gap, so 13 is bit 0 and 15 is bit 2
+    assert_eq!(window.take_ack(), Some((11, 0b101)));
+    assert_eq!(window.take_ack(), None);
+
+    window.record(12);
+    window.record(14);
+
+    assert_eq!(window.take_ack(), Some((15, 0)));
+}
+
+#[test]
+fn test_duplicates_are_filtered_across_wraparound() {
+    let mut window = ReceiveWindow::new(Duration::from_secs(1), Some(Duration::from_secs(5)), u32::MAX - 1);
+
+    assert!(window.record(u32::MAX - 1));
+    assert!(window.record(1));
+    // the gap spans the wraparound instead of being read as a huge jump back
+    assert_eq!(window.take_nack_ranges(), vec![(u32::MAX, 0)]);
+
+    assert!(window.record(0));
+    assert!(!window.record(0));
+    assert!(!window.record(1));
+    assert!(!window.record(u32::MAX - 1));
+    assert!(window.record(u32::MAX));
+    assert_eq!(window.lowest_missing(), None);
+}
+
+#[test]
+fn test_duplicate_is_acknowledged_again() {
+    let mut window = ReceiveWindow::new(Duration::from_secs(1), Some(Duration::from_secs(5)), 1);
+
+    assert!(window.record(1));
+    assert_eq!(window.take_ack(), Some((1, 0)));
+
+    // the sender never heard the ack and resent
+    assert!(!window.record(1));
+    assert_eq!(window.take_ack(), Some((1, 0)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_gaps_wait_out_the_nack_delay() {
+    let mut window = ReceiveWindow::new(Duration::from_millis(100), Some(Duration::from_secs(5)), 1);
+    window.set_nack_delay(Duration::from_millis(50));
+
+    for sequence_number in [1, 3] {
+        window.record(sequence_number);
+    }
+
+    // parity may still fill the gap
+    assert_eq!(window.take_nack_ranges(), vec![]);
+
+    tokio::time::advance(Duration::from_millis(50)).await;
+    assert_eq!(window.take_nack_ranges(), vec![(2, 2)]);
+}
+
+#[test]
+fn test_first_packet_overtaken_by_the_next_still_arrives() {
+    let mut window = ReceiveWindow::new(Duration::from_secs(1), Some(Duration::from_secs(5)), FIRST_SEQUENCE_NUMBER);
+
+    assert!(window.record(2));
+    // the first one is waited for, not acknowledged as if it had arrived
+    assert_eq!(window.lowest_missing(), Some(1));
+    assert_eq!(window.take_ack(), Some((0, 1)));
+
+    assert!(window.record(1));
+    assert_eq!(window.lowest_missing(), None);
+    assert_eq!(window.take_ack(), Some((2, 0)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_gaps_without_a_deadline_are_requested_until_filled() {
+    let deadline = DeliveryClass::ReliableOrdered.deadline();
+    let mut window = ReceiveWindow::new(Duration::from_millis(100), deadline, FIRST_SEQUENCE_NUMBER);
+
+    for sequence_number in [1, 3] {
+        window.record(sequence_number);
+    }
+
+    assert_eq!(deadline, None);
+    assert_eq!(window.take_nack_ranges(), vec![(2, 2)]);
+
+    // long past any deadline of the other classes
+    tokio::time::advance(Duration::from_secs(60)).await;
+    assert_eq!(window.take_nack_ranges(), vec![(2, 2)]);
+
+    assert!(window.record(2));
+    assert_eq!(window.take_nack_ranges(), vec![]);
+}
@@ -0,0 +1,44 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+pub mod simulator;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Datagram transport sessions and STUN run over: a UDP socket, or a
+/// simulated network in tests.
+///
+/// Like UDP, sending never waits for the peer and datagrams may be lost,
+/// duplicated or reordered on the way. Receiving is cancel safe.
+pub trait Transport: Send + Sync {
+  fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<usize>>;
+
+  /// Receives one datagram into `buf`, returning its size and where it came from.
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+
+  fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+  fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    Box::pin(UdpSocket::send_to(self, buf, target))
+  }
+
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    Box::pin(UdpSocket::recv_from(self, buf))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    UdpSocket::local_addr(self)
+  }
+}
+
+/// Binds a UDP socket to serve as the transport of a session.
+pub async fn bind_udp(local_addr: SocketAddr) -> io::Result<Arc<dyn Transport>> {
+  Ok(Arc::new(UdpSocket::bind(local_addr).await?))
+}
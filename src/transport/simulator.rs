@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::{mpsc, Mutex as TokioMutex};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use super::{BoxFuture, Transport};
+
+/// Longest a datagram waits for a bandwidth-capped link before it is dropped,
+/// like a full router queue would.
+const MAX_QUEUEING_DELAY_MS: u16 = 1000;
+
+type Route = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+
+/// Conditions of every link of a simulated network, applied to each datagram independently.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConditions {
+  /// Share of datagrams dropped, from 0 to 1.
+  pub loss: f64,
+  pub delay: Duration,
+  /// Extra delay of up to this much, drawn per datagram.
+  pub jitter: Duration,
+  /// Share of datagrams delivered twice.
+  pub duplication: f64,
+  /// Share of datagrams held back long enough to arrive after later ones.
+  pub reordering: f64,
+  /// Bits per second a socket can send, unlimited when unset.
+  pub bandwidth: Option<u64>
+}
+
+/// In-process network of datagram sockets with configurable loss, delay,
+/// jitter, duplication, reordering and bandwidth, so sessions can be tested
+/// without touching the real network. Randomness is seeded, a run can be repeated.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+  state: Arc<NetworkState>
+}
+
+struct NetworkState {
+  routes: Mutex<HashMap<SocketAddr, Route>>,
+  conditions: Mutex<LinkConditions>,
+  rng: Mutex<StdRng>
+}
+
+impl SimulatedNetwork {
+  pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+    Self {
+      state: Arc::new(NetworkState {
+        routes: Mutex::new(HashMap::new()),
+        conditions: Mutex::new(conditions),
+        rng: Mutex::new(StdRng::seed_from_u64(seed))
+      })
+    }
+  }
+
+  /// Changes the conditions of every link, e.g. to cut the network off for a while.
+  pub fn set_conditions(&self, conditions: LinkConditions) {
+    *lock(&self.state.conditions) = conditions;
+  }
+
+  /// Creates a socket reachable at the given address.
+  pub fn bind(&self, local_addr: SocketAddr) -> io::Result<Arc<SimulatedSocket>> {
+    let mut routes = lock(&self.state.routes);
+
+    if routes.contains_key(&local_addr) {
+      return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already bound", local_addr)));
+    }
+
+    let (route, inbox) = mpsc::unbounded_channel();
+    routes.insert(local_addr, route);
+
+    Ok(Arc::new(SimulatedSocket {
+      local_addr,
+      network: self.clone(),
+      inbox: TokioMutex::new(inbox),
+      link_free_at: Mutex::new(Instant::now()),
+      reset_pending: AtomicBool::new(false)
+    }))
+  }
+
+  /// Delivery times of the copies of a datagram that make it through, none when it is lost.
+  fn schedule(&self, size: usize, link_free_at: &mut Instant) -> Vec<Instant> {
+    let conditions = *lock(&self.state.conditions);
+    let mut rng = lock(&self.state.rng);
+    let now = Instant::now();
+
+    // a capped link sends one datagram after the other
+    let sent_at = match conditions.bandwidth {
+      Some(bandwidth) => {
+        let start = (*link_free_at).max(now);
+
+        if start - now > Duration::from_millis(MAX_QUEUEING_DELAY_MS as u64) {
+          return Vec::new();
+        }
+
+        *link_free_at = start + Duration::from_secs_f64(size as f64 * 8.0 / bandwidth.max(1) as f64);
+        *link_free_at
+      },
+      None => now
+    };
+
+    if rng.gen_bool(conditions.loss.clamp(0.0, 1.0)) {
+      return Vec::new();
+    }
+
+    let copies = if rng.gen_bool(conditions.duplication.clamp(0.0, 1.0)) { 2 } else { 1 };
+
+    (0..copies)
+      .map(|_| {
+        let mut delay = conditions.delay + conditions.jitter.mul_f64(rng.gen::<f64>());
+
+        if rng.gen_bool(conditions.reordering.clamp(0.0, 1.0)) {
+          delay += conditions.delay + conditions.jitter + Duration::from_millis(1);
+        }
+
+        sent_at + delay
+      })
+      .collect()
+  }
+
+  fn route(&self, addr: &SocketAddr) -> Option<Route> {
+    lock(&self.state.routes).get(addr).cloned()
+  }
+}
+
+/// Socket of a `SimulatedNetwork`, unbound once dropped.
+pub struct SimulatedSocket {
+  local_addr: SocketAddr,
+  network: SimulatedNetwork,
+  inbox: TokioMutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+  /// When the socket's bandwidth-capped link is done with what was sent before.
+  link_free_at: Mutex<Instant>,
+  reset_pending: AtomicBool
+}
+
+impl SimulatedSocket {
+  /// Fails the next receive with a connection reset, the way Windows reports
+  /// the ICMP error of a datagram sent to a closed port.
+  pub fn fail_next_receive(&self) {
+    self.reset_pending.store(true, Ordering::Relaxed);
+  }
+}
+
+impl Transport for SimulatedSocket {
+  fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, io::Result<usize>> {
+    let delivery_times = self.network.schedule(buf.len(), &mut lock(&self.link_free_at));
+
+    for delivery_time in delivery_times {
+      let network = self.network.clone();
+      let datagram = (buf.to_vec(), self.local_addr);
+
+      tokio::spawn(async move {
+        sleep_until(delivery_time).await;
+
+        // like UDP, nobody listening means the datagram is gone
+        if let Some(route) = network.route(&target) {
+          let _ = route.send(datagram);
+        }
+      });
+    }
+
+    Box::pin(async move { Ok(buf.len()) })
+  }
+
+  fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+    Box::pin(async move {
+      if self.reset_pending.swap(false, Ordering::Relaxed) {
+        return Err(io::Error::new(io::ErrorKind::ConnectionReset, "Simulated connection reset"));
+      }
+
+      let (datagram, from) = self.inbox
+        .lock()
+        .await
+        .recv()
+        .await
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Simulated network is gone"))?;
+
+      // like UDP, what doesn't fit the buffer is cut off
+      let len = datagram.len().min(buf.len());
+      buf[..len].copy_from_slice(&datagram[..len]);
+
+      Ok((len, from))
+    })
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    Ok(self.local_addr)
+  }
+}
+
+impl Drop for SimulatedSocket {
+  fn drop(&mut self) {
+    lock(&self.network.state.routes).remove(&self.local_addr);
+  }
+}
+
+/// The simulator holds its locks only for plain updates, a poisoned one is still consistent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
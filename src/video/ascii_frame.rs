@@ -5,10 +5,66 @@ use image::{self, ImageFormat};
 use std::{process, thread};
 use std::io::{self, Write, Cursor};
 
+use crate::masp::capabilities::{ColourMode, SessionCapabilities, VideoCodec};
+
 const ASCII_CHARS: [&str; 11] = ["@", "#", "0", "O", "*", ";", ":", ".", ",", "'", " "];
 
-const ASCII_FRAME_WIDTH: usize = 192;
-const ASCII_FRAME_HEIGHT: usize = 54;
+pub const ASCII_FRAME_WIDTH: usize = 192;
+pub const ASCII_FRAME_HEIGHT: usize = 54;
+
+/// Size and encoding of the frames of a session, as both peers negotiated them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoFormat {
+  pub codec: VideoCodec,
+  pub width: usize,
+  pub height: usize,
+  pub colour_mode: ColourMode
+}
+
+impl Default for VideoFormat {
+  /// The largest frames this build captures, used until a handshake says otherwise.
+  fn default() -> Self {
+    Self {
+      codec: VideoCodec::AsciiRunLength,
+      width: ASCII_FRAME_WIDTH,
+      height: ASCII_FRAME_HEIGHT,
+      colour_mode: ColourMode::Monochrome
+    }
+  }
+}
+
+impl From<&SessionCapabilities> for VideoFormat {
+  fn from(session_capabilities: &SessionCapabilities) -> Self {
+    Self {
+      codec: session_capabilities.codec,
+      width: session_capabilities.frame_width as usize,
+      height: session_capabilities.frame_height as usize,
+      colour_mode: session_capabilities.colour_mode
+    }
+  }
+}
+
+impl VideoFormat {
+  /// Encodes a frame with the negotiated codec.
+  pub fn encode(&self, ascii_frame: &str) -> Vec<u8> {
+    match self.codec {
+      VideoCodec::AsciiRunLength => compress_ascii_image(ascii_frame)
+    }
+  }
+
+  /// Decodes a frame with the negotiated codec, failing on one larger than the negotiated size.
+  pub fn decode(&self, payload: &[u8]) -> Result<String, &'static str> {
+    let ascii_frame = match self.codec {
+      VideoCodec::AsciiRunLength => decompress_ascii_image(payload)?
+    };
+
+    if ascii_frame.lines().count() > self.height || ascii_frame.lines().any(|line| line.chars().count() > self.width) {
+      return Err("Frame larger than negotiated");
+    }
+
+    Ok(ascii_frame)
+  }
+}
 
 pub fn render(ascii_frame: &String) {
   print!("\x1B[2J\x1B[1;1H");
@@ -16,15 +72,30 @@ pub fn render(ascii_frame: &String) {
   io::stdout().flush().unwrap();
 }
 
+/// Shows a status on the line under the rendered frame, an empty one clears it.
+pub fn render_status_line(status: &str) {
+  // the cursor is saved and restored around the write, so frame rendering isn't disturbed
+  print!("\x1B7\x1B[{};1H\x1B[2K{}\x1B8", ASCII_FRAME_HEIGHT + 2, status);
+  io::stdout().flush().unwrap();
+}
+
+/// Clears the last rendered frame and gives the terminal back to the shell.
+pub fn reset_terminal() {
+  print!("\x1B[2J\x1B[1;1H\x1B[?25h");
+  io::stdout().flush().unwrap();
+}
+
+/// Run-length encodes an ASCII frame as `(character, count)` byte pairs,
+/// runs longer than a count byte holds are split.
 pub fn compress_ascii_image(ascii_image: &str) -> Vec<u8> {
   let mut compressed: Vec<u8> = Vec::new();
   let mut chars = ascii_image.chars().peekable();
   
   while let Some(current_char) = chars.next() {
-    let mut count = 1;
+    let mut count: u8 = 1;
 
     while let Some(&next_char) = chars.peek() {
-      if next_char == current_char {
+      if next_char == current_char && count < u8::MAX {
         count += 1;
         chars.next();
       } else {
@@ -33,37 +104,36 @@ pub fn compress_ascii_image(ascii_image: &str) -> Vec<u8> {
     }
 
     compressed.push(current_char as u8);
-    compressed.push(count as u8);
+    compressed.push(count);
   }
 
   compressed
 }
 
-pub fn decompress_ascii_image(payload: Vec<u8>) -> String {
-  let mut decompressed = String::new();
-  let mut index = 0;
-
-  while index < payload.len() {
-    let symbol_code = payload[index];
-    let count = payload[index + 1];
+/// Expands a frame encoded by `compress_ascii_image`, failing on a truncated pair.
+pub fn decompress_ascii_image(payload: &[u8]) -> Result<String, &'static str> {
+  if !payload.len().is_multiple_of(2) {
+    return Err("Truncated run-length pair");
+  }
 
-    let character = symbol_code as char;
-    decompressed.push_str(&character.to_string().repeat(count as usize));
+  let mut decompressed = String::new();
 
-    index += 2;
+  for pair in payload.chunks_exact(2) {
+    let character = pair[0] as char;
+    decompressed.extend(std::iter::repeat(character).take(pair[1] as usize));
   }
 
-  decompressed
+  Ok(decompressed)
 }
 
-fn build_ascii_from_grayscale (grayscaled: Vec<u8>) -> String {
+fn build_ascii_from_grayscale (grayscaled: Vec<u8>, width: usize) -> String {
   let mut ascii_image = String::with_capacity(grayscaled.len());
 
   for (i, &gray) in grayscaled.iter().enumerate() {
     let ascii_index = (gray as usize * (ASCII_CHARS.len() - 1)) / 255;
     ascii_image.push_str(ASCII_CHARS[ascii_index]);
 
-    if (i + 1) % ASCII_FRAME_WIDTH == 0 {
+    if (i + 1) % width == 0 {
       ascii_image.push('\n');
     }
   }
@@ -71,21 +141,23 @@ fn build_ascii_from_grayscale (grayscaled: Vec<u8>) -> String {
   ascii_image
 }
 
-pub fn jpeg_to_ascii_image(jpeg: &[u8]) -> String {
-  let image_buf = image::load(Cursor::new(jpeg), ImageFormat::Jpeg)
+pub fn jpeg_to_ascii_image(jpeg: &[u8], format: &VideoFormat) -> String {
+  let image = image::load(Cursor::new(jpeg), ImageFormat::Jpeg)
     .unwrap()
     .resize_exact(
-      ASCII_FRAME_WIDTH as u32, 
-      ASCII_FRAME_HEIGHT as u32, 
+      format.width as u32, 
+      format.height as u32, 
       image::imageops::FilterType::Nearest
-    )
-    .to_luma8()
-    .to_vec();
+    );
+  let image_buf = match format.colour_mode {
+    ColourMode::Monochrome => image.to_luma8().to_vec()
+  };
 
-  build_ascii_from_grayscale(image_buf)
+  build_ascii_from_grayscale(image_buf, format.width)
 }
 
-pub fn yuv_to_ascii_image(yuv: &[u8], original_width: usize, original_height: usize) -> String {
+/// Only the luma plane is used, which is the image in monochrome.
+pub fn yuv_to_ascii_image(yuv: &[u8], original_width: usize, original_height: usize, format: &VideoFormat) -> String {
   let yuv_444_size = original_height * original_width * 3;
   let yuv_422_size = original_height * original_width * 2;
   let yuv_420_size = ((original_height * original_width) as f32 * 1.5) as usize;
@@ -126,13 +198,13 @@ pub fn yuv_to_ascii_image(yuv: &[u8], original_width: usize, original_height: us
   // let new_width = original_width / DESCALE_FACTOR_X as usize;
   // let new_height = original_height / DESCALE_FACTOR_Y as usize;
 
-  let mut downscaled_grayscale = Vec::with_capacity(ASCII_FRAME_WIDTH * ASCII_FRAME_HEIGHT);
+  let mut downscaled_grayscale = Vec::with_capacity(format.width * format.height);
   
-  let block_width = original_width / ASCII_FRAME_WIDTH;
-  let block_height = original_height / ASCII_FRAME_HEIGHT;
+  let block_width = original_width / format.width;
+  let block_height = original_height / format.height;
 
-  for y in 0..ASCII_FRAME_HEIGHT {
-    for x in 0..ASCII_FRAME_WIDTH {
+  for y in 0..format.height {
+    for x in 0..format.width {
       let mut sum: usize = 0;
       let mut count = 0;
 
@@ -152,18 +224,18 @@ pub fn yuv_to_ascii_image(yuv: &[u8], original_width: usize, original_height: us
     }
   }
 
-  build_ascii_from_grayscale(downscaled_grayscale)
+  build_ascii_from_grayscale(downscaled_grayscale, format.width)
 }
 
-pub fn spawn_buffer_to_ascii_task (buffer: Buffer, ascii_sender: Sender<(String, u128)>, seq_num: u128) {
+pub fn spawn_buffer_to_ascii_task (buffer: Buffer, ascii_sender: Sender<(String, u128)>, seq_num: u128, format: VideoFormat) {
   thread::spawn(move || {
     let width = buffer.resolution().width();
     let height = buffer.resolution().height();
     
     let buf = buffer.buffer();
     let ascii_frame = match buffer.source_frame_format() {
-      FrameFormat::YUYV => yuv_to_ascii_image(buf, width as usize, height as usize),
-      FrameFormat::MJPEG => jpeg_to_ascii_image(buf),
+      FrameFormat::YUYV => yuv_to_ascii_image(buf, width as usize, height as usize, &format),
+      FrameFormat::MJPEG => jpeg_to_ascii_image(buf, &format),
       _ => {
         println!("ERROR: unsupported frame format: {}", buffer.source_frame_format());
 
@@ -1,16 +1,22 @@
-use std::{sync::Arc, thread, time::Duration};
+use std::{sync::Arc, thread, time::{Duration, Instant}};
 use nokhwa::{pixel_format::RgbFormat, utils::{CameraIndex, RequestedFormat, RequestedFormatType}, CallbackCamera};
 use crossbeam::channel::{self, Sender, Receiver};
 
-use super::ascii_frame;
-use crate::masp::{sender::MaspSender, message::PacketType};
+use super::ascii_frame::{self, VideoFormat};
+use crate::masp::{sender::MaspSender, message::PacketType, shutdown::{DisconnectReason, Shutdown}};
 
-use tokio::{task, sync::Mutex as TokioMutex};
+use tokio::{task, sync::{mpsc, Mutex as TokioMutex}};
 
 const FPS: u64 = 24;
 pub const FRAME_RATE: u64 = 1000 / FPS;
 
-pub async fn run(mut sender: MaspSender) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// Streams camera frames as ASCII video in the negotiated format until the session shuts down.
+pub async fn run(
+  mut sender: MaspSender,
+  format: VideoFormat,
+  shutdown: Shutdown
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+  let target_bitrate = sender.subscribe_target_bitrate();
   let (frame_sender, frame_receiver) = channel::unbounded();
   let (ascii_frame_sender, ascii_frame_receiver): (Sender<(String, u128)>, Receiver<(String, u128)>) = channel::unbounded();
 
@@ -18,13 +24,14 @@ pub async fn run(mut sender: MaspSender) -> Result<(), Box<dyn std::error::Error
     CameraIndex::Index(0),
     RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestFrameRate),
     move |frame| {
-      frame_sender.send(frame).unwrap();
+      // the pipeline is gone once the session shuts down
+      let _ = frame_sender.send(frame);
 
       thread::sleep(Duration::from_millis(FRAME_RATE));
     }
-  ).unwrap();
+  )?;
 
-  camera.open_stream().unwrap();
+  camera.open_stream()?;
 
   let mut frames_buff = Arc::new(TokioMutex::new(Vec::<(String, u128)>::new()));
   let buffer_clone = Arc::clone(&mut frames_buff);
@@ -36,7 +43,9 @@ pub async fn run(mut sender: MaspSender) -> Result<(), Box<dyn std::error::Error
   });
 
   let frames_render_task = task::spawn(async move {
-    while camera.is_stream_open().unwrap() {
+    let mut last_sent_at: Option<Instant> = None;
+
+    while camera.is_stream_open().unwrap_or(false) && !shutdown.is_triggered() {
       let mut locked_buffer_clone = buffer_clone.lock().await;
 
       if locked_buffer_clone.len() == 0 {
@@ -50,11 +59,29 @@ pub async fn run(mut sender: MaspSender) -> Result<(), Box<dyn std::error::Error
       let (frame, _) = locked_buffer_clone.first().unwrap();
       // ascii_frame::render(&frame.clone());
 
-      let compressed_frame = ascii_frame::compress_ascii_image(&frame.clone());
+      let compressed_frame = format.encode(frame);
+
+      // skip frames the link can't carry instead of queueing them up
+      let bitrate = (*target_bitrate.borrow()).max(1);
+      let frame_interval = Duration::from_secs_f64(compressed_frame.len() as f64 * 8.0 / bitrate as f64);
+      let within_bitrate = match last_sent_at {
+        Some(last_sent_at) => last_sent_at.elapsed() >= frame_interval,
+        None => true
+      };
+
+      if within_bitrate {
+        if let Err(e) = sender.send_data(PacketType::VideoData, compressed_frame).await {
+          shutdown.trigger(DisconnectReason::Failed(e.to_string()));
+          break;
+        }
+
+        last_sent_at = Some(Instant::now());
+      }
 
-      sender.send_data(PacketType::VideoData, compressed_frame).await.unwrap();
       locked_buffer_clone.remove(0);
     }
+
+    let _ = camera.stop_stream();
   });
 
   thread::spawn(move ||  {
@@ -66,12 +93,39 @@ pub async fn run(mut sender: MaspSender) -> Result<(), Box<dyn std::error::Error
       ascii_frame::spawn_buffer_to_ascii_task(
         raw_frame, 
         ascii_frame_sender.clone(), 
-        seq_num
+        seq_num,
+        format
       );
     };
   });
 
-  frames_render_task.await.unwrap();
+  frames_render_task.await?;
 
   Ok(())
 }
+
+
+/// Streams already rendered ASCII frames, e.g. synthetic ones in tests, until
+/// the session shuts down or the channel closes.
+pub async fn run_frames(
+  mut sender: MaspSender,
+  mut frames: mpsc::Receiver<String>,
+  format: VideoFormat,
+  shutdown: Shutdown
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+  loop {
+    let frame = tokio::select! {
+      frame = frames.recv() => match frame {
+        Some(frame) => frame,
+        None => return Ok(())
+      },
+      _ = shutdown.triggered() => return Ok(())
+    };
+
+    let compressed_frame = format.encode(&frame);
+
+    if let Err(e) = sender.send_data(PacketType::VideoData, compressed_frame).await {
+      return Err(e.to_string().into());
+    }
+  }
+}